@@ -0,0 +1,5 @@
+-- Purpose: uploads are attributed to the uploading user and their size is recorded.
+-- Files uploaded before this change are all attributed to user 1 and have no size.
+ALTER TABLE filestore ADD COLUMN IF NOT EXISTS size bigint;
+
+CREATE INDEX IF NOT EXISTS filestore_user_id_idx ON filestore (user_id);
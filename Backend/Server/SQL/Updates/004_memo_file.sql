@@ -0,0 +1,29 @@
+-- Purpose: remember which memos link to which uploaded files.
+-- The links are recorded when a memo is saved, from the /files/<uuid> references in its text.
+CREATE TABLE IF NOT EXISTS memo_file (
+  memo_id integer NOT NULL REFERENCES memo (id) ON DELETE CASCADE,
+  file_id uuid NOT NULL REFERENCES filestore (id) ON DELETE CASCADE,
+  PRIMARY KEY (memo_id, file_id)
+);
+
+CREATE INDEX IF NOT EXISTS memo_file_file_id_idx ON memo_file (file_id);
+
+-- the admin session sees all memos
+SELECT set_config('organizator.current_user', '0', false);
+
+-- record the links already present in the existing memos, the way they are recorded when a memo
+-- is saved: parsed from the title and the text separately, with the pattern of FILE_LINK_REGEX,
+-- and only for files the author of the memo uploaded or can read
+INSERT INTO memo_file (memo_id, file_id)
+SELECT DISTINCT memo.id, filestore.id
+FROM memo
+JOIN users ON users.id = memo.user_id
+CROSS JOIN LATERAL (
+  SELECT regexp_matches(memo.title, '/files/([0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})', 'g')
+  UNION ALL
+  SELECT regexp_matches(memo.memotext, '/files/([0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})', 'g')
+) AS link(uuid)
+JOIN filestore ON filestore.id = link.uuid[1]::uuid
+WHERE filestore.user_id = memo.user_id
+   OR EXISTS (SELECT 1 FROM file_user_access(filestore.id, users.username, 1))
+ON CONFLICT DO NOTHING;
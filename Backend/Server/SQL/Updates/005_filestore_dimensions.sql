@@ -0,0 +1,4 @@
+-- Purpose: record the dimensions of uploaded images so the editor can reserve layout space.
+-- They are filled in when the thumbnails are generated, older uploads have none.
+ALTER TABLE filestore ADD COLUMN IF NOT EXISTS width integer;
+ALTER TABLE filestore ADD COLUMN IF NOT EXISTS height integer;
@@ -0,0 +1,3 @@
+-- Purpose: mark the files encrypted in the browser, they are served as opaque bytes
+-- and no thumbnails are generated for them.
+ALTER TABLE filestore ADD COLUMN IF NOT EXISTS encrypted boolean NOT NULL DEFAULT false;
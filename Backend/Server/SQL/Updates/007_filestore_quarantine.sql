@@ -0,0 +1,3 @@
+-- Purpose: uploads the malware scanner flags are kept out of reach.
+-- The file is moved to quarantine and the signature that matched is recorded.
+ALTER TABLE filestore ADD COLUMN IF NOT EXISTS quarantine_reason text;
@@ -0,0 +1,10 @@
+-- Purpose: tokens ended by a logout are refused until they expire on their own.
+-- The services keep a copy of this table in memory and reload it periodically.
+CREATE TABLE IF NOT EXISTS revoked_token (
+  jti text PRIMARY KEY,
+  -- seconds since epoch, the exp claim of the token
+  expires_at bigint NOT NULL,
+  revoked_on bigint NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS revoked_token_expires_at_idx ON revoked_token (expires_at);
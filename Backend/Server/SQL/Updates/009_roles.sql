@@ -0,0 +1,27 @@
+-- Purpose: roles are assigned in the database instead of being derived from the user id.
+-- They are loaded at login and carried in the JWT.
+CREATE TABLE IF NOT EXISTS role (
+  name text PRIMARY KEY,
+  description text
+);
+
+CREATE TABLE IF NOT EXISTS user_role (
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  role text NOT NULL REFERENCES role (name) ON DELETE CASCADE,
+  PRIMARY KEY (user_id, role)
+);
+
+INSERT INTO role (name, description) VALUES
+  ('org', 'Use the memo application'),
+  ('orgadm', 'Administer the application'),
+  ('photo', 'Use the photo application')
+ON CONFLICT DO NOTHING;
+
+-- keep the roles the login handed out so far: org and photo for all, orgadm for user 1
+INSERT INTO user_role (user_id, role)
+SELECT users.id, role.name
+FROM users
+CROSS JOIN role
+WHERE role.name IN ('org', 'photo')
+   OR (role.name = 'orgadm' AND users.id = 1)
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,18 @@
+-- Purpose: long-lived refresh tokens, exchanged at /refresh for a new access token.
+-- Only the SHA-256 of a token is stored. Each use replaces the token with a new one
+-- of the same family; presenting a used token again revokes the whole family.
+CREATE TABLE IF NOT EXISTS refresh_token (
+  token_hash text PRIMARY KEY,
+  -- the login the token descends from, carried as sid in the access tokens
+  family uuid NOT NULL,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  -- seconds since epoch
+  issued_on bigint NOT NULL,
+  -- the same for the whole family, refreshing does not extend a session
+  expires_at bigint NOT NULL,
+  used_on bigint,
+  revoked boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX IF NOT EXISTS refresh_token_family_idx ON refresh_token (family);
+CREATE INDEX IF NOT EXISTS refresh_token_expires_at_idx ON refresh_token (expires_at);
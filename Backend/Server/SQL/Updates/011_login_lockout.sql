@@ -0,0 +1,21 @@
+-- Purpose: slow down password guessing.
+-- Accounts are locked for a while after too many consecutive failed logins,
+-- and the failures are kept for auditing.
+CREATE TABLE IF NOT EXISTS login_lockout (
+  user_id integer PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+  -- consecutive failures since the last successful login or lock
+  failed_logins integer NOT NULL DEFAULT 0,
+  -- seconds since epoch
+  locked_until bigint
+);
+
+CREATE TABLE IF NOT EXISTS login_failure (
+  id serial PRIMARY KEY,
+  -- as typed, it may not match any user
+  username text NOT NULL,
+  ip text,
+  reason text NOT NULL,
+  failed_on bigint NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS login_failure_failed_on_idx ON login_failure (failed_on);
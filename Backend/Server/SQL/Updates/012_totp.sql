@@ -0,0 +1,29 @@
+-- Purpose: optional second factor at login, time-based one-time passwords (RFC 6238).
+-- The secret is confirmed by a first code; recovery codes are Argon2 hashes.
+CREATE TABLE IF NOT EXISTS user_totp (
+  user_id integer PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+  -- base32, as shown to the authenticator app
+  secret text NOT NULL,
+  -- only confirmed secrets are asked for at login
+  confirmed boolean NOT NULL DEFAULT false,
+  -- seconds since epoch
+  created_on bigint NOT NULL,
+  -- codes of this step and before are refused, a code works only once
+  last_used_step bigint
+);
+
+CREATE TABLE IF NOT EXISTS totp_recovery_code (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  code_hash text NOT NULL,
+  used_on bigint
+);
+
+CREATE INDEX IF NOT EXISTS totp_recovery_code_user_id_idx ON totp_recovery_code (user_id);
+
+-- the password was right, the code is still to come
+CREATE TABLE IF NOT EXISTS totp_challenge (
+  challenge_hash text PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  expires_at bigint NOT NULL
+);
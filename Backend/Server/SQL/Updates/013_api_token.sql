@@ -0,0 +1,15 @@
+-- Purpose: personal access tokens, for scripts and integrations instead of a password.
+-- Only the SHA-256 of the token is stored; the token is shown once, when created.
+CREATE TABLE IF NOT EXISTS api_token (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name text NOT NULL,
+  token_hash text NOT NULL UNIQUE,
+  -- e.g. memo:read, files:write
+  scopes text[] NOT NULL,
+  -- seconds since epoch
+  created_on bigint NOT NULL,
+  -- never expires if null
+  expires_at bigint,
+  UNIQUE (user_id, name)
+);
@@ -0,0 +1,38 @@
+-- Purpose: users are administered through identity by the holders of the orgadm role,
+-- no longer by pasting statements as user 1.
+-- Account state the users table does not have, kept apart like the other login state.
+CREATE TABLE IF NOT EXISTS user_account (
+  user_id integer PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+  -- disabled users can not log in, their data stays
+  disabled boolean NOT NULL DEFAULT false,
+  -- set when an administrator chose the password
+  must_change_password boolean NOT NULL DEFAULT false,
+  -- seconds since epoch
+  last_login bigint
+);
+
+CREATE OR REPLACE FUNCTION current_user_is_admin() RETURNS boolean AS $$
+  SELECT EXISTS (
+    SELECT 1
+    FROM user_role
+    WHERE user_role.user_id = NULLIF(current_setting('organizator.current_user', true), '')::int
+      AND user_role.role = 'orgadm'
+  );
+$$ LANGUAGE sql STABLE;
+
+-- the policies of SQL/Security/database_design.md, with orgadm instead of user 1
+DROP POLICY IF EXISTS update_policy ON users;
+CREATE POLICY update_policy ON users
+FOR UPDATE
+USING (NULLIF(current_setting('organizator.current_user', true), '')::int = id
+       OR current_user_is_admin());
+
+DROP POLICY IF EXISTS insert_policy ON users;
+CREATE POLICY insert_policy ON users
+FOR INSERT
+WITH CHECK (current_user_is_admin());
+
+DROP POLICY IF EXISTS delete_policy ON users;
+CREATE POLICY delete_policy ON users
+FOR DELETE
+USING (current_user_is_admin());
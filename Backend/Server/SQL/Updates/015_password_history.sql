@@ -0,0 +1,11 @@
+-- Purpose: the passwords a user chose recently can not be chosen again.
+-- Argon2 hashes, only the last few are kept, as many as the password policy asks for.
+CREATE TABLE IF NOT EXISTS password_history (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  password_hash text NOT NULL,
+  -- seconds since epoch
+  changed_on bigint NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS password_history_user_id_idx ON password_history (user_id);
@@ -0,0 +1,14 @@
+-- Purpose: authorization codes of the OpenID Connect provider, traded once at /oidc/token.
+-- The code is stored hashed, with the PKCE challenge it has to be redeemed with.
+CREATE TABLE IF NOT EXISTS oidc_authorization_code (
+  code_hash text PRIMARY KEY,
+  client_id text NOT NULL,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  redirect_uri text NOT NULL,
+  scope text NOT NULL,
+  nonce text,
+  -- S256 of the code verifier, base64url
+  code_challenge text NOT NULL,
+  -- seconds since epoch
+  expires_at bigint NOT NULL
+);
@@ -0,0 +1,22 @@
+-- Purpose: login through the OpenID Connect provider of the organization.
+-- The accounts of the provider, its issuer and subject, linked to local users.
+CREATE TABLE IF NOT EXISTS user_external_identity (
+  issuer text NOT NULL,
+  subject text NOT NULL,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  -- seconds since epoch
+  linked_on bigint NOT NULL,
+  PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_external_identity_user_id_idx ON user_external_identity (user_id);
+
+-- the browser went to the provider, the state comes back with it
+CREATE TABLE IF NOT EXISTS oidc_login_state (
+  state_hash text PRIMARY KEY,
+  nonce text NOT NULL,
+  code_verifier text NOT NULL,
+  -- where the browser goes after the login
+  return_to text NOT NULL,
+  expires_at bigint NOT NULL
+);
@@ -0,0 +1,29 @@
+-- Purpose: client certificates registered to users, instead of taking the CN as the username.
+-- A certificate is known by its SHA-256 fingerprint, or by its issuer and serial.
+-- The DNs are stored in the canonical RFC 4514 form the services compare with.
+CREATE TABLE IF NOT EXISTS user_certificate (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  -- to tell the certificates of a user apart
+  name text NOT NULL,
+  subject_dn text NOT NULL,
+  issuer_dn text,
+  -- hex in upper case, without leading zeros
+  serial text,
+  -- hex in lower case, of the DER
+  fingerprint text,
+  -- seconds since epoch
+  created_on bigint NOT NULL,
+  -- still valid if null
+  revoked_on bigint,
+  CHECK (fingerprint IS NOT NULL OR (serial IS NOT NULL AND issuer_dn IS NOT NULL))
+);
+
+CREATE INDEX IF NOT EXISTS user_certificate_user_id_idx ON user_certificate (user_id);
+
+-- a certificate belongs to one user at a time
+CREATE UNIQUE INDEX IF NOT EXISTS user_certificate_fingerprint_idx ON user_certificate (fingerprint)
+WHERE revoked_on IS NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS user_certificate_serial_idx ON user_certificate (issuer_dn, serial)
+WHERE revoked_on IS NULL;
@@ -0,0 +1,22 @@
+-- Purpose: the sessions of the users, to list them and end them one by one.
+-- A session starts at a login and lasts as long as its refresh tokens, its id is their family.
+-- The services refuse the access tokens of an ended session, see revoked_until.
+CREATE TABLE IF NOT EXISTS user_session (
+  -- the family of the refresh tokens, carried as sid in the access tokens
+  id uuid PRIMARY KEY,
+  -- missing once the user is deleted, the ended session stays until revoked_until
+  user_id integer REFERENCES users (id) ON DELETE SET NULL,
+  user_agent text,
+  ip text,
+  -- seconds since epoch
+  created_on bigint NOT NULL,
+  last_refresh bigint,
+  expires_at bigint NOT NULL,
+  revoked_on bigint,
+  -- the last access token of the session expires then, until then the services refuse them
+  revoked_until bigint
+);
+
+CREATE INDEX IF NOT EXISTS user_session_user_id_idx ON user_session (user_id);
+CREATE INDEX IF NOT EXISTS user_session_revoked_until_idx ON user_session (revoked_until)
+WHERE revoked_until IS NOT NULL;
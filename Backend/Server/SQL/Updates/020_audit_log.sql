@@ -0,0 +1,30 @@
+-- Purpose: a durable record of logins, failed logins, password changes and admin actions.
+-- Rows are only ever added, the trigger refuses to change or remove them.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id bigserial PRIMARY KEY,
+  -- seconds since epoch
+  occurred_on bigint NOT NULL,
+  -- login, login_failed, password_changed, admin_action
+  event_type text NOT NULL,
+  -- whom the event is about, as typed for failed logins
+  username text,
+  -- who did it, when not the user, e.g. an administrator
+  actor text,
+  ip text,
+  details jsonb NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_occurred_on_idx ON audit_log (occurred_on);
+CREATE INDEX IF NOT EXISTS audit_log_username_idx ON audit_log (username);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_log is append-only, % refused', TG_OP;
+END;
+$$;
+
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+CREATE TRIGGER audit_log_append_only
+BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_log
+FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
@@ -132,3 +132,48 @@ pub async fn execute<'a>(
     trace!("Affected {} rows in the database", rows_affected);
     Ok((rows_affected, requester))
 }
+
+/// Place the user in the PostgreSQL session and return its id
+pub async fn set_current_user<'a>(
+    client: &Client,
+    username: &'a str,
+) -> Result<Requester<'a>, Error> {
+    let set_user = client
+        .prepare_cached(include_str!("sql/set_current_user.sql"))
+        .await?;
+    let row = client.query_one(&set_user, &[&username]).await?;
+    let requester = Requester::new(row.get::<_, i32>(0), username);
+    debug!("Requester is {:?}", requester);
+    Ok(requester)
+}
+
+/// Highest access level of a user on a memo group, 0 if none
+pub async fn get_memo_group_access(
+    client: &Client,
+    memo_group_id: i32,
+    user_id: i32,
+) -> Result<i32, Error> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/get_memo_group_access.sql"))
+        .await?;
+    let row = client.query_one(&stmt, &[&memo_group_id, &user_id]).await?;
+    Ok(row.get("access"))
+}
+
+/// Bytes in the files uploaded by a user
+pub async fn get_user_storage(client: &Client, user_id: i32) -> Result<i64, Error> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/get_user_storage.sql"))
+        .await?;
+    let row = client.query_one(&stmt, &[&user_id]).await?;
+    Ok(row.get("used"))
+}
+
+/// Bytes in the files uploaded into a memo group, by any user
+pub async fn get_memo_group_storage(client: &Client, memo_group_id: i32) -> Result<i64, Error> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/get_memo_group_storage.sql"))
+        .await?;
+    let row = client.query_one(&stmt, &[&memo_group_id]).await?;
+    Ok(row.get("used"))
+}
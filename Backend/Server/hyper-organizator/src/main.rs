@@ -1,6 +1,7 @@
 mod db;
 mod model;
 mod router;
+mod thumbnail;
 
 use lib_hyper_organizator::server;
 use router::swagger_json;
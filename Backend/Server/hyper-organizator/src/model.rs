@@ -162,7 +162,7 @@ impl DBPersistence for Memo {
 #[derive(Serialize, ToSchema)]
 pub struct GetWriteMemo {
     #[serde(flatten)]
-    memo: Option<Memo>,
+    pub memo: Option<Memo>,
 }
 
 impl From<Row> for GetWriteMemo {
@@ -281,6 +281,9 @@ impl Named for FilePermission {
     }
 }
 
+/// Extension of the files encrypted on the client
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+
 #[derive(Serialize, ToSchema)]
 pub struct FilestoreFileDB {
     pub id: Uuid,
@@ -288,6 +291,30 @@ pub struct FilestoreFileDB {
     pub filename: String,
     pub memo_group_id: Option<i32>,
     pub uploaded_on: i64,
+    /// size in bytes, not recorded for files uploaded before it was introduced
+    pub size: Option<i64>,
+    /// dimensions in pixels, only for images
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// encrypted in the browser, the server only sees opaque bytes
+    pub encrypted: bool,
+    /// set when the scanner found malware, the file is in quarantine
+    pub quarantine_reason: Option<String>,
+}
+
+impl FilestoreFileDB {
+    /// Files are stored under their uuid, keeping the extension of the original name.
+    /// Encrypted files get the `enc` extension, the clear content type does not apply to them.
+    pub fn stored_name(&self) -> String {
+        if self.encrypted {
+            return format!("{}.{ENCRYPTED_EXTENSION}", self.id);
+        }
+        format!(
+            "{}.{}",
+            self.id,
+            self.filename.split('.').next_back().unwrap_or_default()
+        )
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -319,6 +346,11 @@ impl From<Row> for FilestoreFileDB {
             filename: row.get("filename"),
             memo_group_id: row.get("memo_group_id"),
             uploaded_on: row.get("uploaded_on"),
+            size: row.get("size"),
+            width: row.get("width"),
+            height: row.get("height"),
+            encrypted: row.get("encrypted"),
+            quarantine_reason: row.get("quarantine_reason"),
         }
     }
 }
@@ -328,3 +360,129 @@ impl DBPersistence for FilestoreFileDB {
         include_str!("sql/admin/filestore.sql")
     }
 }
+
+/// A single filestore row, looked up by the file uuid
+pub struct FilestoreEntry(pub FilestoreFileDB);
+
+impl From<Row> for FilestoreEntry {
+    fn from(row: Row) -> Self {
+        Self(FilestoreFileDB::from(row))
+    }
+}
+
+impl DBPersistence for FilestoreEntry {
+    fn query() -> &'static str {
+        include_str!("sql/get_filestore_entry.sql")
+    }
+}
+
+/// A file uploaded by the current user, together with the name it is served under
+#[derive(Serialize, ToSchema)]
+pub struct UserFile {
+    #[serde(flatten)]
+    pub file: FilestoreFileDB,
+    /// name under which the file is served in /files/
+    pub stored_name: String,
+}
+
+impl From<Row> for UserFile {
+    fn from(row: Row) -> Self {
+        let file = FilestoreFileDB::from(row);
+        Self {
+            stored_name: file.stored_name(),
+            file,
+        }
+    }
+}
+
+impl DBPersistence for UserFile {
+    fn query() -> &'static str {
+        include_str!("sql/get_user_files.sql")
+    }
+}
+
+impl Named for Vec<UserFile> {
+    fn name() -> &'static str {
+        "files"
+    }
+}
+
+/// A file linked from a memo
+#[derive(Serialize, ToSchema)]
+#[serde(transparent)]
+pub struct MemoAttachment(pub UserFile);
+
+impl From<Row> for MemoAttachment {
+    fn from(row: Row) -> Self {
+        Self(UserFile::from(row))
+    }
+}
+
+impl DBPersistence for MemoAttachment {
+    fn query() -> &'static str {
+        include_str!("sql/get_memo_attachments.sql")
+    }
+}
+
+impl Named for Vec<MemoAttachment> {
+    fn name() -> &'static str {
+        "attachments"
+    }
+}
+
+/// A file in the filestore no memo links to
+pub struct UnreferencedFile(pub FilestoreFileDB);
+
+impl From<Row> for UnreferencedFile {
+    fn from(row: Row) -> Self {
+        Self(FilestoreFileDB::from(row))
+    }
+}
+
+impl DBPersistence for UnreferencedFile {
+    fn query() -> &'static str {
+        include_str!("sql/admin/unreferenced_files.sql")
+    }
+}
+
+/// Storage used by a memo group the current user has uploaded into
+#[derive(Serialize, ToSchema)]
+pub struct MemoGroupUsage {
+    pub memo_group_id: i32,
+    pub name: String,
+    /// bytes, uploads of all the users included
+    pub used: i64,
+}
+
+impl From<Row> for MemoGroupUsage {
+    fn from(row: Row) -> Self {
+        Self {
+            memo_group_id: row.get("memo_group_id"),
+            name: row.get("name"),
+            used: row.get("used"),
+        }
+    }
+}
+
+impl DBPersistence for MemoGroupUsage {
+    fn query() -> &'static str {
+        include_str!("sql/get_memo_group_usage.sql")
+    }
+}
+
+/// Storage used by the current user and the limits that apply
+#[derive(Serialize, ToSchema)]
+pub struct Quota {
+    /// bytes in the files uploaded by the user
+    pub used: i64,
+    /// missing when there is no limit
+    pub limit: Option<u64>,
+    pub memo_group_limit: Option<u64>,
+    pub memo_groups: Vec<MemoGroupUsage>,
+}
+
+impl Named for Quota {
+    fn name() -> &'static str {
+        "quota"
+    }
+}
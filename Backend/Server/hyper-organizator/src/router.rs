@@ -8,22 +8,38 @@ use crate::model::MemoTitle;
 use crate::model::Named;
 use crate::model::Requester;
 use crate::model::{
-    ExplicitPermission, FilePermission, FilestoreFile, FilestoreFileDB, GetWriteMemo,
+    ENCRYPTED_EXTENSION, ExplicitPermission, FilePermission, FilestoreEntry, FilestoreFile,
+    FilestoreFileDB, GetWriteMemo, MemoAttachment, MemoGroupUsage, Quota, UnreferencedFile,
+    UserFile,
 };
+use crate::thumbnail::{is_thumbnail, pick_width, process_upload, thumbnail_name};
+use deadpool_postgres::Pool;
 use http::StatusCode;
+use http::header::CONTENT_LENGTH;
 use http::{Method, Request, Response};
 use hyper::Body;
 use lazy_static::lazy_static;
-use lib_hyper_organizator::multipart::{Field, FileField, RegularField, handle_multipart};
+use lib_hyper_organizator::audit::{self, AuditEvent, AuditQuery};
+use lib_hyper_organizator::authentication::api_token::{
+    FILES_READ, FILES_WRITE, MEMO_READ, MEMO_WRITE, has_scope,
+};
+use lib_hyper_organizator::file_response::serve_file;
+use lib_hyper_organizator::multipart::{
+    Field, FileField, MultipartError, RegularField, handle_multipart,
+};
 use lib_hyper_organizator::postgres::get_connection;
 use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
 use lib_hyper_organizator::response_utils::parse_body;
+use lib_hyper_organizator::scanner::{
+    ClamdScanner, ScanResult, Scanner, ScannerConfig, quarantine,
+};
 use lib_hyper_organizator::server::SETTINGS;
-use lib_hyper_organizator::typedef::{GenericError, SQLstr, UserId, UserRoles};
+use lib_hyper_organizator::typedef::{ApiTokenScopes, GenericError, SQLstr, UserId, UserRoles};
 use lib_hyper_organizator::under_construction::default_response;
-use log::{debug, warn, error, trace};
+use log::{debug, warn, error, info, trace};
 use regex::Regex;
 use serde_json::json;
+use std::path::Path;
 use tokio_postgres::Error as PgError;
 
 /*
@@ -33,10 +49,15 @@ use tokio_postgres::Error as PgError;
  get(/memo/)                     get_memo_titles
  post(/memo/search)              search_memo
 ✓get(/memo/{id})                 get_memo
+✓get(/memo/{id}/attachments)     get_memo_attachments
  post(/memo/)                    write_memo
 ✓get(/memogroup)                 get_memo_group
  put(/upload)                    upload_file
+✓get(/files)                     get_user_files
  get(/file_auth)                 file_auth
+✓get(/files/{uuid}.{ext})        get_file
+✓get(/files/{uuid}/thumb)        get_thumbnail
+✓get(/quota)                     get_quota
  get(/explicit_permissions/{id}) explicit_permissions
 
 moved to identity:
@@ -53,6 +74,10 @@ lazy_static! {
     static ref MEMO_GROUP_GET_REGEX: Regex = Regex::new(r"^/memogroup/(\d+)$").unwrap();
     static ref EXPLICIT_PERMISSIONS_REGEX: Regex = Regex::new(r"^/explicit_permissions/(\d+)$").unwrap();
     static ref FILE_UUID_REGEX: Regex = Regex::new(r"^/files/(?<uuid>[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})\.").unwrap();
+    static ref FILE_LINK_REGEX: Regex = Regex::new(r"/files/(?<uuid>[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})").unwrap();
+    static ref MEMO_ATTACHMENTS_REGEX: Regex = Regex::new(r"^/memo/(\d+)/attachments$").unwrap();
+    static ref FILE_THUMBNAIL_REGEX: Regex = Regex::new(r"^/files/(?<uuid>[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})/thumb$").unwrap();
+    static ref FILE_GET_REGEX: Regex = Regex::new(r"^/files/(?<uuid>[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})\.[^/]+$").unwrap();
 }
 
 fn trim_trailing_slash(path: &str) -> &str {
@@ -63,24 +88,69 @@ fn trim_trailing_slash(path: &str) -> &str {
     }
 }
 
+/// The scope an API token needs for the route, None if no token may use it
+fn required_scope(method: &Method, path: &str) -> Option<&'static str> {
+    match (method, path) {
+        (&Method::POST, "/memo") => Some(MEMO_WRITE),
+        (&Method::POST, "/memo/search") => Some(MEMO_READ),
+        (&Method::PUT, "/upload") => Some(FILES_WRITE),
+        (&Method::GET, "/file_auth" | "/files" | "/quota") => Some(FILES_READ),
+        (&Method::GET, path)
+            if FILE_GET_REGEX.is_match(path) || FILE_THUMBNAIL_REGEX.is_match(path) =>
+        {
+            Some(FILES_READ)
+        }
+        (&Method::GET, path) if path.starts_with("/admin/") => None,
+        (&Method::GET, _) => Some(MEMO_READ),
+        _ => None,
+    }
+}
+
+/// Requests made with an API token are limited to the scopes of the token
+fn scope_allows<B>(request: &Request<B>) -> bool {
+    match required_scope(request.method(), trim_trailing_slash(request.uri().path())) {
+        Some(scope) => has_scope(request, scope),
+        None => request.extensions().get::<ApiTokenScopes>().is_none(),
+    }
+}
+
 /// All requests to the server are handled by this function.
 pub async fn router(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !scope_allows(&request) {
+        warn!(
+            "API token without the scope for {} {}",
+            request.method(),
+            request.uri().path()
+        );
+        return "The API token does not allow this"
+            .to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
     match (request.method(), trim_trailing_slash(request.uri().path())) {
         (&Method::GET, path) if MEMO_GET_REGEX.is_match(path) => get_memo(request).await,
+        (&Method::GET, path) if MEMO_ATTACHMENTS_REGEX.is_match(path) => {
+            get_memo_attachments(request).await
+        }
         (&Method::POST, "/memo") => write_memo(request).await,
         (&Method::GET, "/memogroup") => get_memogroups_for_user(request).await,
         (&Method::GET, "/memo") => get_memo_titles(request).await,
         (&Method::POST, "/memo/search") => memo_search(request).await,
         (&Method::GET, "/file_auth") => file_auth(request).await,
+        (&Method::GET, path) if FILE_GET_REGEX.is_match(path) => get_file(request).await,
+        (&Method::GET, path) if FILE_THUMBNAIL_REGEX.is_match(path) => get_thumbnail(request).await,
         (&Method::GET, path) if EXPLICIT_PERMISSIONS_REGEX.is_match(path) => {
             get_explicit_permissions(&request).await
         }
         (&Method::PUT, "/upload") => upload_file(request).await,
+        (&Method::GET, "/files") => get_user_files(request).await,
+        (&Method::GET, "/quota") => get_quota(request).await,
         (&Method::GET, "/usergroups") => get_usergroups(request).await,
         (&Method::GET, "/memogroups") => get_memogroups(request).await,
         (&Method::GET, "/admin/files") => file_list(request).await,
+        (&Method::POST, "/admin/files/gc") => file_garbage_collection(request).await,
         (&Method::GET, "/admin/memo_stats") => get_memo_stats(request).await,
+        (&Method::GET, "/admin/storage_stats") => get_storage_stats(request).await,
         (&Method::GET, "/admin/all_user_groups") => get_all_usergroups(request).await,
+        (&Method::GET, "/admin/audit") => get_audit_log(request).await,
         _ => default_response(request).await,
     }
 }
@@ -155,9 +225,65 @@ async fn write_memo(mut request: Request<Body>) -> Result<Response<Body>, Generi
         ],
     )
     .await;
+    if let Ok((
+        GetWriteMemo {
+            memo: Some(ref memo),
+        },
+        _,
+    )) = memo
+    {
+        let files = referenced_files(title, body);
+        trace!("Memo {} links to files {:?}", memo.id, files);
+        if let Err(e) = db::execute(
+            &db_client,
+            username,
+            include_str!("sql/link_memo_files.sql"),
+            &[&memo.id, &files, &username],
+        )
+        .await
+        {
+            error!(
+                "Could not record the files linked from memo {}: {e}",
+                memo.id
+            );
+        }
+    }
     build_json_response(memo)
 }
 
+/// The uuids of the uploaded files a memo links to, from the title and the body as stored.
+/// Migration 021 reads the existing memos the same way.
+fn referenced_files(title: &str, body: &str) -> Vec<uuid::Uuid> {
+    let mut files: Vec<uuid::Uuid> = [title, body]
+        .into_iter()
+        .flat_map(|text| FILE_LINK_REGEX.captures_iter(text))
+        .filter_map(|c| c.name("uuid")?.as_str().parse().ok())
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+#[utoipa::path(get, path="/memo/{id}/attachments",
+    responses(
+        (status=200, description="Files linked from the memo", body=Vec<MemoAttachment>),
+    ),
+    params(
+        ("id" = i32, Path, description="Memo id"),
+    ),
+)]
+async fn get_memo_attachments(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let (client, username) = get_client_and_user(&request).await?;
+
+    let path = request.uri().path();
+    let captures = MEMO_ATTACHMENTS_REGEX.captures(path).unwrap();
+    let memo_id = captures.get(1).unwrap().as_str().parse::<i32>()?;
+    let attachments: Result<(Vec<MemoAttachment>, Requester), _> =
+        db::get_multiple(&client, username, &[&memo_id], Select).await;
+
+    build_json_response(attachments)
+}
+
 #[utoipa::path(get, path="/memogroup",
     responses(
         (status=200, description="MemoGroup for current logged in user", body=Vec<MemoGroup>),
@@ -240,9 +366,133 @@ async fn file_auth(request: Request<Body>) -> Result<Response<Body>, GenericErro
     let file_auth: Result<(FilePermission, Requester), _> =
         db::get_single(&client, username, &[&uuid, &username, &level]).await;
     trace!("File auth for {uri} is {:?}", file_auth);
+    if file_auth.is_ok() {
+        let entry: Result<(FilestoreEntry, Requester), _> =
+            db::get_single(&client, username, &[&uuid]).await;
+        if let Ok((FilestoreEntry(entry), _)) = entry
+            && entry.quarantine_reason.is_some()
+        {
+            return quarantined_response(&entry);
+        }
+    }
     build_json_response(file_auth)
 }
 
+#[utoipa::path(get, path="/files/{file_name}",
+    responses(
+        (status=200, description="File content"),
+        (status=206, description="Requested byte range of the file"),
+        (status=304, description="File not modified since the ETag in If-None-Match"),
+        (status=416, description="Requested range not satisfiable"),
+    ),
+    params(
+        ("file_name" = String, Path, description="Stored file name, uuid and extension"),
+    ),
+)]
+/// Serve an attachment directly, for deployments without Nginx in front
+async fn get_file(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let (client, username) = get_client_and_user(&request).await?;
+
+    let captures = FILE_GET_REGEX.captures(request.uri().path()).unwrap();
+    let uuid = captures
+        .name("uuid")
+        .unwrap()
+        .as_str()
+        .parse::<uuid::Uuid>()?;
+
+    let entry = match readable_file_entry(&client, username, &uuid).await {
+        Ok(entry) => entry,
+        Err(e) => return handle_pg_error_response(e),
+    };
+    if entry.quarantine_reason.is_some() {
+        return quarantined_response(&entry);
+    }
+
+    // the name in the URL only picks the route, the stored name comes from the database
+    let settings = &*SETTINGS;
+    let path = Path::new(&settings.file_storage.path).join(entry.stored_name());
+    serve_file(&request, &path, &entry.filename, content_type(&entry)).await
+}
+
+/// Look up a file the current user is allowed to read
+async fn readable_file_entry(
+    client: &deadpool_postgres::Client,
+    username: &str,
+    uuid: &uuid::Uuid,
+) -> Result<FilestoreFileDB, PgError> {
+    let level: i32 = 1;
+    let _: (FilePermission, Requester) =
+        db::get_single(client, username, &[uuid, &username, &level]).await?;
+    let (FilestoreEntry(entry), _) = db::get_single(client, username, &[uuid]).await?;
+    Ok(entry)
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct ThumbnailParams {
+    /// width in pixels the client wants to display
+    w: Option<u32>,
+}
+
+#[utoipa::path(get, path="/files/{uuid}/thumb",
+    responses(
+        (status=200, description="Resized image, or the original when no thumbnail fits"),
+        (status=304, description="Image not modified since the ETag in If-None-Match"),
+    ),
+    params(
+        ("uuid" = String, Path, description="File uuid"),
+        ("w" = Option<u32>, Query, description="Width the image will be displayed at"),
+    ),
+)]
+/// Serve a thumbnail of an uploaded image
+async fn get_thumbnail(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let (client, username) = get_client_and_user(&request).await?;
+    let captures = FILE_THUMBNAIL_REGEX.captures(request.uri().path()).unwrap();
+    let uuid = captures
+        .name("uuid")
+        .unwrap()
+        .as_str()
+        .parse::<uuid::Uuid>()?;
+    let params: ThumbnailParams =
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default())?;
+
+    let entry = match readable_file_entry(&client, username, &uuid).await {
+        Ok(entry) => entry,
+        Err(e) => return handle_pg_error_response(e),
+    };
+    if entry.quarantine_reason.is_some() {
+        return quarantined_response(&entry);
+    }
+
+    let settings = &*SETTINGS;
+    let directory = Path::new(&settings.file_storage.path);
+    // there are no thumbnails of encrypted files
+    let thumbnail = pick_width(
+        params.w.unwrap_or(0),
+        &settings.file_storage.thumbnail_widths,
+    )
+    .filter(|_| !entry.encrypted)
+    .map(|width| directory.join(thumbnail_name(&uuid, width)))
+    .filter(|path| path.exists());
+    // images smaller than the thumbnail and files that are not images are served as they are
+    let path = thumbnail.unwrap_or_else(|| directory.join(entry.stored_name()));
+    trace!("Thumbnail of {uuid} served from {:?}", path);
+    serve_file(&request, &path, &entry.filename, content_type(&entry)).await
+}
+
+/// The scanner flagged the file, it is not served to anybody
+fn quarantined_response(entry: &FilestoreFileDB) -> Result<Response<Body>, GenericError> {
+    warn!(
+        "Access to quarantined file {} denied, {:?}",
+        entry.id, entry.quarantine_reason
+    );
+    "File quarantined".to_text_response_with_status(StatusCode::FORBIDDEN)
+}
+
+/// Encrypted files are opaque, the browser should not try to render them
+fn content_type(entry: &FilestoreFileDB) -> Option<&'static str> {
+    entry.encrypted.then_some("application/octet-stream")
+}
+
 #[derive(serde::Serialize, Debug)]
 struct UploadResponse {
     filename: String,
@@ -255,23 +505,116 @@ impl Named for UploadResponse {
     }
 }
 
+/// Access level required on a memo group to attach files to it
+const READ_WRITE_ACCESS: i32 = 2;
+
+/// Room left for the multipart boundaries and headers when comparing Content-Length to the quota
+const MULTIPART_OVERHEAD: u64 = 16 * 1024;
+
+/// The bytes the user may still upload, `None` if the upload is refused before reading it
+fn remaining_quota(quota: u64, used: i64, content_length: Option<u64>) -> Option<u64> {
+    let remaining = quota.saturating_sub(used.max(0) as u64);
+    if remaining == 0 || content_length.is_some_and(|len| len > remaining + MULTIPART_OVERHEAD) {
+        return None;
+    }
+    Some(remaining)
+}
+
+/// The memo group would hold more than its quota with the upload
+fn over_memo_group_quota(quota: u64, used: i64, size: i64) -> bool {
+    (used + size).max(0) as u64 > quota
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default)]
+struct UploadParams {
+    /// the same as the memo_group_id field; in the query its quota is checked while streaming
+    memo_group_id: Option<i32>,
+}
+
 async fn upload_file(request: Request<Body>) -> Result<Response<Body>, GenericError> {
     let (client, username) = get_client_and_user(&request)
         .await
         .map(|(c, u)| (c, u.to_string()))?;
+    let requester_id = match db::set_current_user(&client, &username).await {
+        Ok(requester) => requester.id,
+        Err(e) => return handle_pg_error_response(e),
+    };
 
+    let params: UploadParams =
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default()).unwrap_or_default();
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
     let settings = &*SETTINGS;
-    let fields = handle_multipart(request, &settings.file_storage.path).await?;
+    let user_remaining = match settings.file_storage.user_quota {
+        Some(quota) => {
+            let used = match db::get_user_storage(&client, requester_id).await {
+                Ok(used) => used,
+                Err(e) => return handle_pg_error_response(e),
+            };
+            let Some(remaining) = remaining_quota(quota, used, content_length) else {
+                warn!("User {username} uses {used} bytes, upload of {content_length:?} rejected");
+                return "Storage quota exceeded"
+                    .to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+            };
+            remaining
+        }
+        None => u64::MAX,
+    };
+    let group_remaining = match (
+        params.memo_group_id.filter(|id| *id > 0),
+        settings.file_storage.memo_group_quota,
+    ) {
+        (Some(memo_group_id), Some(quota)) => {
+            let used = match db::get_memo_group_storage(&client, memo_group_id).await {
+                Ok(used) => used,
+                Err(e) => return handle_pg_error_response(e),
+            };
+            let Some(remaining) = remaining_quota(quota, used, content_length) else {
+                warn!(
+                    "Memo group {memo_group_id} uses {used} bytes, upload of {content_length:?} rejected"
+                );
+                return "Memo group storage quota exceeded"
+                    .to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+            };
+            remaining
+        }
+        _ => u64::MAX,
+    };
+    let max_file_size = user_remaining.min(group_remaining);
+
+    let pool = request.extensions().get::<Pool>().cloned();
+    let fields = match handle_multipart(request, &settings.file_storage.path, max_file_size).await {
+        Ok(fields) => fields,
+        Err(MultipartError::TooLarge(limit)) if group_remaining < user_remaining => {
+            warn!("Upload of {username} went over the memo group quota, {limit} bytes left");
+            return "Memo group storage quota exceeded"
+                .to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Err(MultipartError::TooLarge(limit)) => {
+            warn!("User {username} went over the quota, {limit} bytes left, upload discarded");
+            return "Storage quota exceeded"
+                .to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Err(e) => return Err(e.into()),
+    };
 
     debug!("Fields: {:?}", fields);
 
     let mut group_id = None;
     let mut generated_name = None;
     let mut original_filename = None;
+    let mut encrypted = false;
     fields.into_iter().for_each(|field| match field {
         Field::Regular(RegularField { name, value }) if name == "memo_group_id" => {
             group_id = value.parse::<i32>().ok();
         }
+        Field::Regular(RegularField { name, value }) if name == "encrypted" => {
+            encrypted = matches!(value.trim(), "true" | "1");
+        }
         Field::File(FileField {
             upload_name,
             file_name,
@@ -286,56 +629,250 @@ async fn upload_file(request: Request<Body>) -> Result<Response<Body>, GenericEr
         "group_id: {:?}, generated_name: {:?}, original_filename: {:?}",
         group_id, generated_name, original_filename
     );
-    if let (Some(memo_group_id), Some(generated_name), Some(original_filename)) =
-        (group_id, generated_name, original_filename)
+    if let (Some(query_id), Some(field_id)) = (params.memo_group_id, group_id)
+        && query_id != field_id
     {
-        debug!("Save entry to filestore table");
-        let uuid = generated_name[..generated_name.rfind('.').unwrap()].parse::<uuid::Uuid>()?;
-        // FIXME the user id should come from the session, now hardcoded 1 to pass compilation
-        match db::execute(
-            &client,
-            &username,
-            include_str!("sql/insert_filestore.sql"),
-            &[
-                &uuid,
-                &1,
-                &original_filename,
-                &memo_group_id,
-                &millis_since_epoch(),
-            ],
-        )
-        .await
-        {
-            Ok((rows_inserted, requester)) => {
-                debug!(
-                    "Number of rows inserted into filestore table: {}",
-                    rows_inserted
+        if let Some(generated_name) = generated_name {
+            discard_upload(&generated_name).await;
+        }
+        return "The memo group of the query and of the form differ"
+            .to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let group_id = group_id.or(params.memo_group_id);
+    let (group_id, generated_name, original_filename) =
+        match (group_id, generated_name, original_filename) {
+            (Some(group_id), Some(generated_name), Some(original_filename)) => {
+                (group_id, generated_name, original_filename)
+            }
+            (_, generated_name, _) => {
+                if let Some(generated_name) = generated_name {
+                    discard_upload(&generated_name).await;
+                }
+                return "File uploaded without a memo group"
+                    .to_text_response_with_status(StatusCode::BAD_REQUEST);
+            }
+        };
+
+    // a memo group id that is not positive means the file is only visible to its owner
+    let memo_group_id = (group_id > 0).then_some(group_id);
+    if let Some(memo_group_id) = memo_group_id {
+        match db::get_memo_group_access(&client, memo_group_id, requester_id).await {
+            Ok(access) if access >= READ_WRITE_ACCESS => (),
+            Ok(access) => {
+                warn!(
+                    "User {username} has access {access} to memo group {memo_group_id}, upload rejected"
                 );
-                build_json_response(Ok((
-                    UploadResponse {
-                        filename: generated_name,
-                        original_filename,
-                    },
-                    requester,
-                )))
+                discard_upload(&generated_name).await;
+                return "Memo group is not writable"
+                    .to_text_response_with_status(StatusCode::FORBIDDEN);
             }
             Err(e) => {
-                error!("Something went wrong: {:?}", e);
-                "File not uploaded".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+                discard_upload(&generated_name).await;
+                return handle_pg_error_response(e);
             }
         }
+    }
+
+    debug!("Save entry to filestore table");
+    let uuid = generated_name[..generated_name.rfind('.').unwrap()].parse::<uuid::Uuid>()?;
+    // the extension of the clear file would get it served with the wrong content type
+    let generated_name = if encrypted {
+        let encrypted_name = format!("{uuid}.{ENCRYPTED_EXTENSION}");
+        let directory = Path::new(&settings.file_storage.path);
+        tokio::fs::rename(
+            directory.join(&generated_name),
+            directory.join(&encrypted_name),
+        )
+        .await?;
+        encrypted_name
     } else {
-        "File uploaded".to_text_response_with_status(StatusCode::BAD_REQUEST)
+        generated_name
+    };
+    let size = tokio::fs::metadata(Path::new(&settings.file_storage.path).join(&generated_name))
+        .await?
+        .len() as i64;
+    // the limit while streaming missed the uploads to the group that ended meanwhile, and
+    // clients that name the group only in the form
+    if let (Some(memo_group_id), Some(quota)) =
+        (memo_group_id, settings.file_storage.memo_group_quota)
+    {
+        match db::get_memo_group_storage(&client, memo_group_id).await {
+            Ok(used) if over_memo_group_quota(quota, used, size) => {
+                warn!("Memo group {memo_group_id} uses {used} bytes, upload of {size} rejected");
+                discard_upload(&generated_name).await;
+                return "Memo group storage quota exceeded"
+                    .to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                discard_upload(&generated_name).await;
+                return handle_pg_error_response(e);
+            }
+        }
+    }
+    // there is nothing to scan in an encrypted file
+    let quarantine_reason = match (&settings.file_storage.scanner, encrypted) {
+        (Some(config), false) => {
+            let scanner = ClamdScanner::from_config(config);
+            let path = Path::new(&settings.file_storage.path).join(&generated_name);
+            match scan_upload(&scanner, config, &path).await {
+                Ok(quarantine_reason) => quarantine_reason,
+                Err(e) => {
+                    error!("Could not scan {generated_name}, upload refused: {e}");
+                    discard_upload(&generated_name).await;
+                    return "The virus scanner is unavailable, try again later"
+                        .to_text_response_with_status(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+        }
+        _ => None,
+    };
+    match db::execute(
+        &client,
+        &username,
+        include_str!("sql/insert_filestore.sql"),
+        &[
+            &uuid,
+            &requester_id,
+            &original_filename,
+            &memo_group_id,
+            &millis_since_epoch(),
+            &size,
+            &encrypted,
+            &quarantine_reason,
+        ],
+    )
+    .await
+    {
+        Ok(_) if quarantine_reason.is_some() => "File rejected by the virus scanner"
+            .to_text_response_with_status(StatusCode::UNPROCESSABLE_ENTITY),
+        Ok((rows_inserted, requester)) => {
+            debug!(
+                "Number of rows inserted into filestore table: {}",
+                rows_inserted
+            );
+            // nothing to learn from the content of an encrypted file
+            if let (Some(pool), false) = (pool, encrypted) {
+                tokio::spawn(process_upload(
+                    pool,
+                    username.clone(),
+                    uuid,
+                    generated_name.clone(),
+                ));
+            }
+            build_json_response(Ok((
+                UploadResponse {
+                    filename: generated_name,
+                    original_filename,
+                },
+                requester,
+            )))
+        }
+        Err(e) => {
+            error!("Something went wrong: {:?}", e);
+            discard_upload(&generated_name).await;
+            "File not uploaded".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
     }
 }
 
+/// Scan a stored upload, infected files are moved to quarantine and the signature found is
+/// returned. The error of the scanner is only returned when it fails closed.
+async fn scan_upload(
+    scanner: &impl Scanner,
+    config: &ScannerConfig,
+    path: &Path,
+) -> Result<Option<String>, GenericError> {
+    let signature = match scanner.scan(path).await {
+        Ok(ScanResult::Clean) => return Ok(None),
+        Ok(ScanResult::Infected(signature)) => signature,
+        Err(e) if config.fail_closed => return Err(e),
+        Err(e) => {
+            // an unavailable scanner should not stop all uploads, unless configured so
+            error!("Could not scan {:?}, accepted unscanned: {e}", path);
+            return Ok(None);
+        }
+    };
+    warn!("Upload {:?} is infected with {signature}", path);
+    if let Err(e) = quarantine(path, Path::new(&config.quarantine_path)).await {
+        error!("Could not quarantine {:?}, removing it: {e}", path);
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            error!("Could not remove infected upload {:?}: {e}", path);
+        }
+    }
+    Ok(Some(signature))
+}
+
+/// Remove a file that was written to storage but will not be recorded in the filestore
+async fn discard_upload(generated_name: &str) {
+    let path = Path::new(&SETTINGS.file_storage.path).join(generated_name);
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        error!("Could not remove rejected upload {:?}: {e}", path);
+    }
+}
+
+#[utoipa::path(get, path="/quota",
+    responses(
+        (status=200, description="Storage used by the current logged in user and the limits", body=Quota),
+    ),
+)]
+async fn get_quota(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let (client, username) = get_client_and_user(&request).await?;
+
+    let (memo_groups, requester): (Vec<MemoGroupUsage>, _) =
+        match db::get_multiple(&client, username, &[], Select).await {
+            Ok(result) => result,
+            Err(e) => return handle_pg_error_response(e),
+        };
+    let used = match db::get_user_storage(&client, requester.id).await {
+        Ok(used) => used,
+        Err(e) => return handle_pg_error_response(e),
+    };
+    let settings = &*SETTINGS;
+    build_json_response(Ok((
+        Quota {
+            used,
+            limit: settings.file_storage.user_quota,
+            memo_group_limit: settings.file_storage.memo_group_quota,
+            memo_groups,
+        },
+        requester,
+    )))
+}
+
+#[utoipa::path(get, path="/files",
+    responses(
+        (status=200, description="Files uploaded by the current logged in user", body=Vec<UserFile>),
+    ),
+)]
+async fn get_user_files(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let (client, username) = get_client_and_user(&request).await?;
+
+    let files: Result<(Vec<UserFile>, Requester), _> =
+        db::get_multiple(&client, username, &[], Select).await;
+    let files = match files {
+        Ok((mut files, requester)) => {
+            // older uploads have no size recorded, take it from the storage
+            for user_file in files.iter_mut().filter(|f| f.file.size.is_none()) {
+                let path = Path::new(&SETTINGS.file_storage.path).join(&user_file.stored_name);
+                user_file.file.size = tokio::fs::metadata(path).await.ok().map(|m| m.len() as i64);
+            }
+            Ok((files, requester))
+        }
+        Err(e) => Err(e),
+    };
+    build_json_response(files)
+}
+
 #[derive(serde::Serialize)]
-struct FilestoreResult<'a> {
-    db_only: Vec<&'a FilestoreFileDB>,
+struct FilestoreResult {
+    db_only: Vec<FilestoreFileDB>,
     dir_only: Vec<FilestoreFile>,
+    /// present in the database and on disk, but no memo links to them
+    unreferenced: Vec<FilestoreFileDB>,
 }
 
-impl Named for FilestoreResult<'_> {
+impl Named for FilestoreResult {
     fn name() -> &'static str {
         "filestore"
     }
@@ -348,17 +885,43 @@ async fn file_list(request: Request<Body>) -> Result<Response<Body>, GenericErro
     }
     let (client, username) = get_client_and_user(&request).await?;
 
-    let (files, requester) = db::get_multiple(&client, username, &[], Select).await?;
+    let result = classify_files(&client, username).await?;
+    build_json_response(Ok(result))
+}
+
+/// Compare the filestore table with the storage directory and the memo links
+async fn classify_files<'a>(
+    client: &deadpool_postgres::Client,
+    username: &'a str,
+) -> Result<(FilestoreResult, Requester<'a>), GenericError> {
+    let (files, requester): (Vec<FilestoreFileDB>, _) =
+        db::get_multiple(client, username, &[], Select).await?;
+    // quarantined files are missing from the storage on purpose, the record says why
+    let files: Vec<FilestoreFileDB> = files
+        .into_iter()
+        .filter(|f| f.quarantine_reason.is_none())
+        .collect();
+    let (unreferenced, _): (Vec<UnreferencedFile>, _) =
+        db::get_multiple(client, username, &[], Select).await?;
     let files_in_dir = ls()?;
+    // a thumbnail left behind does not make up for a missing original
     let set: HashSet<&str> = files_in_dir
         .iter()
+        .filter(|f| !is_thumbnail(&f.filename))
         .map(|f| f.filename_no_extension())
         .collect();
-    let db_only: Vec<&FilestoreFileDB> = files
+    let (db_only, on_disk): (Vec<FilestoreFileDB>, Vec<FilestoreFileDB>) = files
+        .into_iter()
+        .partition(|f| !set.contains(f.id.to_string().as_str()));
+    let db_set = on_disk
         .iter()
-        .filter(|f: &&FilestoreFileDB| !set.contains(f.id.to_string().as_str()))
-        .collect();
-    let db_set = files.iter().map(|f| f.id).collect::<HashSet<uuid::Uuid>>();
+        .chain(db_only.iter())
+        .map(|f| f.id)
+        .collect::<HashSet<uuid::Uuid>>();
+    let on_disk_set = on_disk
+        .iter()
+        .map(|f| f.id)
+        .collect::<HashSet<uuid::Uuid>>();
     let dir_only: Vec<FilestoreFile> = files_in_dir
         .into_iter()
         .filter(|f| {
@@ -369,8 +932,134 @@ async fn file_list(request: Request<Body>) -> Result<Response<Body>, GenericErro
             }
         })
         .collect();
+    let unreferenced = unreferenced
+        .into_iter()
+        .map(|UnreferencedFile(f)| f)
+        .filter(|f| on_disk_set.contains(&f.id))
+        .collect();
+
+    Ok((
+        FilestoreResult {
+            db_only,
+            dir_only,
+            unreferenced,
+        },
+        requester,
+    ))
+}
+
+fn default_min_age_hours() -> u64 {
+    24
+}
+
+#[derive(serde::Deserialize, Debug, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GarbageCollectionParams {
+    /// without it nothing is deleted, the response shows what would be
+    #[serde(default)]
+    confirm: bool,
+    /// unreferenced files younger than this may still be linked from a memo being edited
+    #[serde(default = "default_min_age_hours")]
+    min_age_hours: u64,
+}
+
+#[derive(serde::Serialize, ToSchema)]
+struct GarbageCollectionResult {
+    deleted: bool,
+    /// database entries whose file is gone
+    db_only: Vec<FilestoreFileDB>,
+    /// files on disk the database does not know about
+    dir_only: Vec<FilestoreFile>,
+    /// files no memo links to
+    unreferenced: Vec<FilestoreFileDB>,
+}
+
+impl Named for GarbageCollectionResult {
+    fn name() -> &'static str {
+        "garbage"
+    }
+}
+
+#[utoipa::path(post, path="/admin/files/gc",
+    params(GarbageCollectionParams),
+    responses(
+        (status=200, description="The orphan files, deleted with confirm=true", body=GarbageCollectionResult),
+        (status=400, description="Malformed query"),
+        (status=403, description="Reserved for administrators"),
+    ),
+)]
+/// Remove orphan files, dry run unless `confirm=true` is passed in the query
+async fn file_garbage_collection(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let params: GarbageCollectionParams =
+        match serde_urlencoded::from_str(request.uri().query().unwrap_or_default()) {
+            Ok(params) => params,
+            Err(e) => {
+                return format!("Bad query: {e}")
+                    .to_text_response_with_status(StatusCode::BAD_REQUEST);
+            }
+        };
+    let (client, username) = get_client_and_user(&request).await?;
+
+    let (classified, requester) = classify_files(&client, username).await?;
+    let oldest = millis_since_epoch() - (params.min_age_hours * 3_600_000) as i64;
+    let unreferenced: Vec<FilestoreFileDB> = classified
+        .unreferenced
+        .into_iter()
+        .filter(|f| f.uploaded_on < oldest)
+        .collect();
+    // only files named like uploads are ours to delete
+    let dir_only: Vec<FilestoreFile> = classified
+        .dir_only
+        .into_iter()
+        .filter(|f| f.filename_no_extension().parse::<uuid::Uuid>().is_ok())
+        .collect();
+
+    if params.confirm {
+        let ids: Vec<uuid::Uuid> = classified
+            .db_only
+            .iter()
+            .chain(unreferenced.iter())
+            .map(|f| f.id)
+            .collect();
+        let (rows, _) = db::execute(
+            &client,
+            username,
+            include_str!("sql/admin/delete_filestore.sql"),
+            &[&ids],
+        )
+        .await?;
+        info!("User {username} removed {rows} entries from the filestore table");
+        let event = AuditEvent::admin(username, "files_gc", None)
+            .from_request(&request)
+            .detail("removed_entries", rows)
+            .detail("removed_files", unreferenced.len() + dir_only.len());
+        audit::record(&client, event).await?;
 
-    build_json_response(Ok((FilestoreResult { db_only, dir_only }, requester)))
+        // the thumbnails share the uuid, they go with the entry
+        let removed_ids: HashSet<String> = ids.iter().map(uuid::Uuid::to_string).collect();
+        for file in ls()?.iter().filter(|f| {
+            removed_ids.contains(f.filename_no_extension())
+                || dir_only.iter().any(|d| d.filename == f.filename)
+        }) {
+            let path = Path::new(&SETTINGS.file_storage.path).join(&file.filename);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                error!("Could not remove {:?}: {e}", path);
+            }
+        }
+    }
+
+    build_json_response(Ok((
+        GarbageCollectionResult {
+            deleted: params.confirm,
+            db_only: classified.db_only,
+            dir_only,
+            unreferenced,
+        },
+        requester,
+    )))
 }
 
 fn ls() -> Result<Vec<FilestoreFile>, GenericError> {
@@ -406,6 +1095,32 @@ async fn get_memo_stats(request: Request<Body>) -> Result<Response<Body>, Generi
     build_simple_json_response(json.map(|(r, _)| r))
 }
 
+#[utoipa::path(get, path="/admin/storage_stats",
+    responses(
+        (status=200, description="users and memo_groups with their files and bytes used, the total, user_quota and memo_group_quota"),
+        (status=403, description="Reserved for administrators"),
+    ),
+)]
+/// Storage used per user and per memo group, with the configured quotas
+async fn get_storage_stats(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let client = get_connection(&request).await?;
+
+    let settings = &*SETTINGS;
+    let user_quota = settings.file_storage.user_quota.map(|q| q as i64);
+    let memo_group_quota = settings.file_storage.memo_group_quota.map(|q| q as i64);
+    let json = db::get_json(
+        &client,
+        "admin",
+        SQLstr(include_str!("sql/admin/storage_stats.sql")),
+        &[&user_quota, &memo_group_quota],
+    )
+    .await;
+    build_simple_json_response(json.map(|(r, _)| r))
+}
+
 // TODO add swagger
 async fn get_all_usergroups(request: Request<Body>) -> Result<Response<Body>, GenericError> {
     if !is_admin(&request) {
@@ -423,6 +1138,30 @@ async fn get_all_usergroups(request: Request<Body>) -> Result<Response<Body>, Ge
     build_simple_json_response(json.map(|(r, _)| r))
 }
 
+#[utoipa::path(get, path="/admin/audit",
+    params(AuditQuery),
+    responses(
+        (status=200, description="The events, the oldest first: a JSON array, or JSON lines with format=jsonl or Accept: application/x-ndjson"),
+        (status=400, description="Malformed query"),
+        (status=403, description="Reserved for administrators"),
+    ),
+)]
+/// Logins, failed logins, password changes and administrator actions, recorded by all services
+async fn get_audit_log(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let query = match AuditQuery::of(&request) {
+        Ok(query) => query,
+        Err(e) => {
+            return format!("Bad query: {e}").to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let client = get_connection(&request).await?;
+    let events = audit::events(&client, &query).await?;
+    audit::events_response(&events, query.wants_json_lines(&request))
+}
+
 async fn get_usergroups(request: Request<Body>) -> Result<Response<Body>, GenericError> {
     let (client, username) = get_client_and_user(&request).await?;
 
@@ -541,28 +1280,47 @@ pub use swagger::swagger_json;
 use utoipa::ToSchema;
 mod swagger {
     use crate::model::{
-        ExplicitPermission, GetWriteMemo, Memo, MemoGroup, MemoTitle, MemoTitleList, MemoUser,
-        Requester, User,
+        ExplicitPermission, FilestoreFile, FilestoreFileDB, GetWriteMemo, Memo, MemoAttachment, MemoGroup,
+        MemoGroupUsage, MemoTitle, MemoTitleList, MemoUser, Quota, Requester, User, UserFile,
     };
     use utoipa::{
         Modify, OpenApi,
-        openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     };
 
     #[derive(OpenApi)]
     #[openapi(
-        paths(super::get_memo, super::get_memogroups_for_user, super::get_explicit_permissions),
+        paths(
+            super::get_memo,
+            super::get_memogroups_for_user,
+            super::get_explicit_permissions,
+            super::get_file,
+            super::get_user_files,
+            super::get_memo_attachments,
+            super::get_thumbnail,
+            super::get_quota,
+            super::file_garbage_collection,
+            super::get_storage_stats,
+            super::get_audit_log,
+        ),
         components(
           schemas(
             ExplicitPermission,
+            FilestoreFile,
+            FilestoreFileDB,
             GetWriteMemo,
             Memo,
+            MemoAttachment,
             MemoGroup,
+            MemoGroupUsage,
             MemoTitle,
             MemoTitleList,
             MemoUser,
+            Quota,
             User,
             Requester,
+            UserFile,
+            super::GarbageCollectionResult,
           ),
         ),
         modifiers(&SecurityAddonBearer),
@@ -570,17 +1328,6 @@ mod swagger {
     )]
     pub struct ApiDoc;
 
-    struct SecurityAddon;
-    impl Modify for SecurityAddon {
-        fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
-            let components = openapi.components.as_mut().unwrap();
-            components.add_security_scheme(
-                "api_key",
-                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Authorization"))),
-            );
-        }
-    }
-
     struct SecurityAddonBearer;
     impl Modify for SecurityAddonBearer {
         fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
@@ -601,3 +1348,99 @@ mod swagger {
         serde_json::to_string_pretty(&ApiDoc::openapi()).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_hyper_organizator::scanner::{EICAR, FakeScanner};
+
+    #[test]
+    fn test_remaining_quota() {
+        assert_eq!(remaining_quota(1000, 400, None), Some(600));
+        assert_eq!(remaining_quota(1000, 400, Some(600)), Some(600));
+        // the multipart framing is not counted against the quota
+        assert_eq!(
+            remaining_quota(1000, 400, Some(600 + MULTIPART_OVERHEAD)),
+            Some(600)
+        );
+        assert_eq!(
+            remaining_quota(1000, 400, Some(601 + MULTIPART_OVERHEAD)),
+            None
+        );
+        // full, or over after the quota was lowered
+        assert_eq!(remaining_quota(1000, 1000, None), None);
+        assert_eq!(remaining_quota(1000, 5000, Some(1)), None);
+        // a negative sum from the database counts as nothing used
+        assert_eq!(remaining_quota(1000, -1, None), Some(1000));
+    }
+
+    #[test]
+    fn test_over_memo_group_quota() {
+        assert!(!over_memo_group_quota(1000, 400, 600));
+        assert!(over_memo_group_quota(1000, 400, 601));
+        assert!(over_memo_group_quota(1000, 1000, 1));
+        assert!(!over_memo_group_quota(1000, 0, 0));
+    }
+
+    #[test]
+    fn test_referenced_files() {
+        let photo = "0b6f8c3e-2a4d-4c1b-9e7f-5d3a2b1c0e9f";
+        let upper = "A1B2C3D4-E5F6-4A7B-8C9D-0E1F2A3B4C5D";
+        let text =
+            format!("Trip /files/{photo}.jpg\n![map](/files/{upper}) /files/{photo}.w320.jpg");
+        let (title, body) = split_and_trim(&text);
+        let files = referenced_files(title, body);
+        let mut expected: Vec<uuid::Uuid> = vec![photo.parse().unwrap(), upper.parse().unwrap()];
+        expected.sort();
+        assert_eq!(files, expected);
+
+        // not a link to an upload, or not a whole uuid
+        assert!(referenced_files("/photos/0b6f8c3e-2a4d-4c1b-9e7f-5d3a2b1c0e9f", "").is_empty());
+        assert!(referenced_files("", "/files/0b6f8c3e-2a4d-4c1b-9e7f").is_empty());
+        // a uuid is not put together from the end of the title and the start of the body
+        assert!(referenced_files("/files/0b6f8c3e-2a4d", "-4c1b-9e7f-5d3a2b1c0e9f").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_infected_upload_quarantined() -> Result<(), GenericError> {
+        let dir = std::env::temp_dir().join(format!("scan_upload_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let config = ScannerConfig {
+            quarantine_path: dir.join("quarantine").to_string_lossy().to_string(),
+            ..ScannerConfig::default()
+        };
+        let clean = dir.join("clean.txt");
+        tokio::fs::write(&clean, b"harmless").await?;
+        assert_eq!(scan_upload(&FakeScanner::default(), &config, &clean).await?, None);
+        assert!(clean.exists());
+
+        let infected = dir.join("infected.txt");
+        tokio::fs::write(&infected, EICAR).await?;
+        let reason = scan_upload(&FakeScanner::default(), &config, &infected).await?;
+        assert_eq!(reason.as_deref(), Some("Fake-Signature"));
+        assert!(!infected.exists());
+        assert!(dir.join("quarantine/infected.txt").exists());
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scanner_unavailable() -> Result<(), GenericError> {
+        let file = std::env::temp_dir().join(format!("scan_unavailable_{}", std::process::id()));
+        tokio::fs::write(&file, EICAR).await?;
+        let config = ScannerConfig {
+            address: "/nonexistent/clamd.ctl".to_string(),
+            ..ScannerConfig::default()
+        };
+        let scanner = ClamdScanner::from_config(&config);
+        // accepted unscanned by default
+        assert_eq!(scan_upload(&scanner, &config, &file).await?, None);
+        let fail_closed = ScannerConfig {
+            fail_closed: true,
+            ..config
+        };
+        assert!(scan_upload(&scanner, &fail_closed, &file).await.is_err());
+        tokio::fs::remove_file(&file).await?;
+        Ok(())
+    }
+}
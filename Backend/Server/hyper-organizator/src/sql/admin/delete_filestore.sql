@@ -0,0 +1,2 @@
+-- $1 array of file uuids
+DELETE FROM filestore WHERE id = ANY($1);
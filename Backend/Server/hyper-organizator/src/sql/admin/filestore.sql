@@ -1,3 +1,3 @@
-select id, user_id, filename, memo_group_id, uploaded_on
+select id, user_id, filename, memo_group_id, uploaded_on, size, width, height, encrypted,
+       quarantine_reason
 from filestore;
-
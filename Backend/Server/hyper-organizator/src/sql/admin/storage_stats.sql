@@ -0,0 +1,29 @@
+-- $1 quota per user in bytes, null for no limit
+-- $2 quota per memo group in bytes, null for no limit
+SELECT json_build_object(
+  'users', (
+    SELECT json_agg(row_to_json(u))
+    FROM (
+      SELECT users.username, filestore.user_id, count(*) files,
+             COALESCE(SUM(filestore.size), 0)::BIGINT used
+      FROM filestore INNER JOIN users
+        ON filestore.user_id = users.id
+      GROUP BY filestore.user_id, users.username
+      ORDER BY used DESC
+    ) u
+  ),
+  'memo_groups', (
+    SELECT json_agg(row_to_json(g))
+    FROM (
+      SELECT memo_group.name, filestore.memo_group_id, count(*) files,
+             COALESCE(SUM(filestore.size), 0)::BIGINT used
+      FROM filestore INNER JOIN memo_group
+        ON filestore.memo_group_id = memo_group.id
+      GROUP BY filestore.memo_group_id, memo_group.name
+      ORDER BY used DESC
+    ) g
+  ),
+  'total', (SELECT COALESCE(SUM(size), 0)::BIGINT FROM filestore),
+  'user_quota', $1::BIGINT,
+  'memo_group_quota', $2::BIGINT
+)::text AS json;
@@ -0,0 +1,5 @@
+-- files no memo links to
+SELECT id, user_id, filename, memo_group_id, uploaded_on, size, width, height, encrypted,
+       quarantine_reason
+FROM filestore
+WHERE NOT EXISTS (SELECT 1 FROM memo_file WHERE memo_file.file_id = filestore.id);
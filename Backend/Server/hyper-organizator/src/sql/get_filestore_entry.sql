@@ -0,0 +1,5 @@
+-- $1 uuid (no extension)
+SELECT id, user_id, filename, memo_group_id, uploaded_on, size, width, height, encrypted,
+       quarantine_reason
+FROM filestore
+WHERE id = $1;
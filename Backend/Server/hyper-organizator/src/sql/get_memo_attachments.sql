@@ -0,0 +1,10 @@
+-- files linked from a memo, the memo has to be visible to the current user
+-- $1 memo_id
+SELECT filestore.id, filestore.user_id, filestore.filename, filestore.memo_group_id,
+       filestore.uploaded_on, filestore.size, filestore.width, filestore.height,
+       filestore.encrypted, filestore.quarantine_reason
+FROM memo_file
+JOIN memo ON memo.id = memo_file.memo_id
+JOIN filestore ON filestore.id = memo_file.file_id
+WHERE memo_file.memo_id = $1
+ORDER BY filestore.filename;
@@ -0,0 +1,14 @@
+-- highest access level a user has on a memo group, the owner has read-write access
+-- $1 memo_group_id
+-- $2 user_id
+SELECT COALESCE(MAX(access), 0) AS access
+FROM (
+  SELECT 2 AS access
+  FROM memo_group
+  WHERE memo_group.id = $1 AND memo_group.user_id = $2
+  UNION ALL
+  SELECT memo_acl.access
+  FROM memo_acl
+  JOIN user_group_detail ON user_group_detail.user_group_id = memo_acl.user_group_id
+  WHERE memo_acl.memo_group_id = $1 AND user_group_detail.user_id = $2
+) AS access_levels;
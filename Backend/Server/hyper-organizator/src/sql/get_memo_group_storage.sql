@@ -0,0 +1,5 @@
+-- bytes in the files uploaded into a memo group
+-- $1 memo_group_id
+SELECT COALESCE(SUM(size), 0)::BIGINT AS used
+FROM filestore
+WHERE memo_group_id = $1;
@@ -0,0 +1,12 @@
+-- storage used by the memo groups the current user has uploaded into
+SELECT memo_group.id AS memo_group_id, memo_group.name,
+       COALESCE(SUM(filestore.size), 0)::BIGINT AS used
+FROM memo_group
+JOIN filestore ON filestore.memo_group_id = memo_group.id
+WHERE memo_group.id IN (
+  SELECT memo_group_id
+  FROM filestore
+  WHERE user_id = current_setting('organizator.current_user')::INTEGER
+)
+GROUP BY memo_group.id, memo_group.name
+ORDER BY memo_group.name;
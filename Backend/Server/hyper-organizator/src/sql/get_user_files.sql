@@ -0,0 +1,6 @@
+-- files uploaded by the current user, newest first
+SELECT id, user_id, filename, memo_group_id, uploaded_on, size, width, height, encrypted,
+       quarantine_reason
+FROM filestore
+WHERE user_id = current_setting('organizator.current_user')::INTEGER
+ORDER BY uploaded_on DESC;
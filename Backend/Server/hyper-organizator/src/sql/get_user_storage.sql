@@ -0,0 +1,5 @@
+-- bytes in the files uploaded by a user
+-- $1 user_id
+SELECT COALESCE(SUM(size), 0)::BIGINT AS used
+FROM filestore
+WHERE user_id = $1;
@@ -1,10 +1,11 @@
 -- $1 uuid (no extension)
 -- $2 user_id
 -- $3 filename
--- $4 memo_group_id
+-- $4 memo_group_id, null when the file is not shared through a memo group
 -- $5 uploaded_on
+-- $6 size in bytes
+-- $7 encrypted on the client
+-- $8 signature found by the scanner, null for clean files
 INSERT INTO
-  filestore(id, user_id, filename, memo_group_id, uploaded_on)
-SELECT $1, $2, $3, memo_group.id, $5
-FROM users LEFT JOIN memo_group ON users.id = memo_group.user_id AND memo_group.id = $4
-WHERE users.id = $2
+  filestore(id, user_id, filename, memo_group_id, uploaded_on, size, encrypted, quarantine_reason)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
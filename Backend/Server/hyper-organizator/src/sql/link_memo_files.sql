@@ -0,0 +1,16 @@
+-- replace the files linked from a memo, only with files the requester uploaded or can read,
+-- the attachments of a memo do not show the files of others
+-- $1 memo_id
+-- $2 array of file uuids referenced in the memo title and text
+-- $3 username of the requester
+WITH unlinked AS (
+  DELETE FROM memo_file
+  WHERE memo_id = $1 AND file_id <> ALL($2)
+)
+INSERT INTO memo_file (memo_id, file_id)
+SELECT $1, filestore.id
+FROM filestore
+WHERE filestore.id = ANY($2)
+  AND (filestore.user_id = current_setting('organizator.current_user')::INTEGER
+       OR EXISTS (SELECT 1 FROM file_user_access(filestore.id, $3, 1)))
+ON CONFLICT DO NOTHING;
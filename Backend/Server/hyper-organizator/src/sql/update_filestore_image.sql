@@ -0,0 +1,8 @@
+-- filled in after the upload has been processed in the background
+-- $1 uuid (no extension)
+-- $2 width in pixels
+-- $3 height in pixels
+-- $4 size in bytes, changes when the metadata is stripped
+UPDATE filestore
+SET width = $2, height = $3, size = $4
+WHERE id = $1;
@@ -0,0 +1,297 @@
+//! Thumbnails and metadata cleanup for uploaded images.
+//!
+//! Runs in the background after the upload has been answered. Thumbnails are stored next to
+//! the original as `{uuid}.w{width}.jpg`, so they share the uuid prefix and go away with it.
+use crate::db;
+use deadpool_postgres::Pool;
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use lib_hyper_organizator::server::SETTINGS;
+use lib_hyper_organizator::typedef::GenericError;
+use log::{debug, error, trace};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// What we learned about an uploaded image
+#[derive(Debug)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    size: u64,
+}
+
+pub fn thumbnail_name(uuid: &Uuid, width: u32) -> String {
+    format!("{uuid}.w{width}.jpg")
+}
+
+/// Named like the thumbnails, `{uuid}.w{width}.jpg`
+pub fn is_thumbnail(file_name: &str) -> bool {
+    file_name
+        .strip_suffix(".jpg")
+        .and_then(|rest| rest.split_once(".w"))
+        .is_some_and(|(uuid, width)| uuid.parse::<Uuid>().is_ok() && width.parse::<u32>().is_ok())
+}
+
+/// The smallest configured width that is at least the requested one, the largest otherwise
+pub fn pick_width(requested: u32, widths: &[u32]) -> Option<u32> {
+    widths
+        .iter()
+        .copied()
+        .filter(|w| *w >= requested)
+        .min()
+        .or_else(|| widths.iter().copied().max())
+}
+
+/// Generate thumbnails for a freshly uploaded file and record its dimensions.
+/// Files that are not images are left alone.
+pub async fn process_upload(pool: Pool, username: String, uuid: Uuid, stored_name: String) {
+    let settings = &*SETTINGS;
+    let directory = PathBuf::from(&settings.file_storage.path);
+    let widths = settings.file_storage.thumbnail_widths.clone();
+    let strip_exif = settings.file_storage.strip_exif;
+
+    let info = tokio::task::spawn_blocking(move || {
+        process_image(&directory, &uuid, &stored_name, &widths, strip_exif)
+    })
+    .await;
+    let info = match info {
+        Ok(Ok(Some(info))) => info,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            error!("Could not process upload {uuid}: {e}");
+            return;
+        }
+        Err(e) => {
+            error!("Processing of upload {uuid} did not finish: {e}");
+            return;
+        }
+    };
+    debug!("Upload {uuid} is an image: {:?}", info);
+
+    let client = match pool.get().await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("No database connection to record the dimensions of {uuid}: {e}");
+            return;
+        }
+    };
+    if let Err(e) = db::execute(
+        &client,
+        &username,
+        include_str!("sql/update_filestore_image.sql"),
+        &[
+            &uuid,
+            &(info.width as i32),
+            &(info.height as i32),
+            &(info.size as i64),
+        ],
+    )
+    .await
+    {
+        error!("Could not record the dimensions of {uuid}: {e}");
+    }
+}
+
+fn process_image(
+    directory: &Path,
+    uuid: &Uuid,
+    stored_name: &str,
+    widths: &[u32],
+    strip_exif: bool,
+) -> Result<Option<ImageInfo>, GenericError> {
+    let path = directory.join(stored_name);
+    let reader = ImageReader::open(&path)?.with_guessed_format()?;
+    let Some(format) = reader.format() else {
+        trace!("{stored_name} is not an image");
+        return Ok(None);
+    };
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    for width in widths.iter().copied().filter(|w| *w < image.width()) {
+        let thumbnail = image.thumbnail(width, u32::MAX).to_rgb8();
+        let mut encoded = Vec::new();
+        JpegEncoder::new_with_quality(&mut encoded, THUMBNAIL_QUALITY).encode_image(&thumbnail)?;
+        replace_file(&directory.join(thumbnail_name(uuid, width)), &encoded)?;
+    }
+
+    if strip_exif && format == ImageFormat::Jpeg {
+        let original = std::fs::read(&path)?;
+        match without_exif(&original, orientation.to_exif()) {
+            Some(stripped) if stripped.len() != original.len() => {
+                debug!("Removed the EXIF metadata from {stored_name}");
+                replace_file(&path, &stripped)?;
+            }
+            Some(_) => (),
+            None => error!("Could not parse {stored_name} as JPEG, metadata left in place"),
+        }
+    }
+
+    Ok(Some(ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        size: std::fs::metadata(&path)?.len(),
+    }))
+}
+
+/// Write next to the target and rename, so a file being served is never half written
+fn replace_file(path: &Path, content: &[u8]) -> Result<(), GenericError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, content)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Copy a JPEG leaving out the EXIF segments, they carry the camera location.
+/// The orientation is put back in a minimal EXIF segment, viewers need it to display the photo upright.
+fn without_exif(jpeg: &[u8], orientation: u8) -> Option<Vec<u8>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut result = Vec::with_capacity(jpeg.len());
+    result.extend_from_slice(&jpeg[..2]);
+    let mut orientation_written = orientation == 1;
+    let mut pos = 2;
+    loop {
+        if *jpeg.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *jpeg.get(pos + 1)?;
+        match marker {
+            // fill byte
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // start of scan, the entropy coded data follows, no more metadata
+            0xDA | 0xD9 => {
+                result.extend_from_slice(&jpeg[pos..]);
+                return Some(result);
+            }
+            // markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                result.extend_from_slice(&jpeg[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => (),
+        }
+        // the length counts its own two bytes
+        let length = u16::from_be_bytes([*jpeg.get(pos + 2)?, *jpeg.get(pos + 3)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        let segment = jpeg.get(pos..pos + 2 + length)?;
+        if marker == 0xE1 && segment.len() >= 10 && segment[4..].starts_with(b"Exif\0\0") {
+            if !orientation_written {
+                result.extend_from_slice(&orientation_segment(orientation));
+                orientation_written = true;
+            }
+        } else {
+            result.extend_from_slice(segment);
+        }
+        pos += 2 + length;
+    }
+}
+
+/// An APP1 EXIF segment with a single IFD entry, the orientation
+fn orientation_segment(orientation: u8) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(32);
+    payload.extend_from_slice(b"Exif\0\0");
+    // big endian TIFF header, first IFD at offset 8
+    payload.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    // one entry: tag 0x0112, type SHORT, count 1, value left aligned
+    payload.extend_from_slice(&[0x00, 0x01]);
+    payload.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+    payload.extend_from_slice(&[0x00, orientation, 0x00, 0x00]);
+    // no next IFD
+    payload.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    segment.extend_from_slice(&payload);
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of image, an APP1 segment with the payload, start of scan and some data
+    fn jpeg_with_app1(payload: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        jpeg.extend_from_slice(payload);
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x12, 0x34, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn test_pick_width() {
+        let widths = [160, 640, 320];
+        assert_eq!(pick_width(100, &widths), Some(160));
+        assert_eq!(pick_width(320, &widths), Some(320));
+        assert_eq!(pick_width(321, &widths), Some(640));
+        assert_eq!(pick_width(2000, &widths), Some(640));
+        assert_eq!(pick_width(100, &[]), None);
+    }
+
+    #[test]
+    fn test_is_thumbnail() {
+        let uuid = Uuid::new_v4();
+        assert!(is_thumbnail(&thumbnail_name(&uuid, 320)));
+        assert!(!is_thumbnail(&format!("{uuid}.jpg")));
+        assert!(!is_thumbnail(&format!("{uuid}.wide.jpg")));
+        assert!(!is_thumbnail("holiday.w320.jpg"));
+    }
+
+    #[test]
+    fn test_orientation_segment() {
+        let segment = orientation_segment(6);
+        assert_eq!(&segment[..2], &[0xFF, 0xE1]);
+        let length = u16::from_be_bytes([segment[2], segment[3]]) as usize;
+        assert_eq!(length + 2, segment.len());
+        assert!(segment[4..].starts_with(b"Exif\0\0"));
+        // the value of the orientation entry
+        assert_eq!(
+            &segment[segment.len() - 8..segment.len() - 4],
+            &[0, 6, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_without_exif() {
+        let jpeg = jpeg_with_app1(b"Exif\0\0MM\0\x2a GPS somewhere");
+        let upright = without_exif(&jpeg, 1).unwrap();
+        assert_eq!(upright, [0xFF, 0xD8, 0xFF, 0xDA, 0x12, 0x34, 0xFF, 0xD9]);
+        // the orientation is kept, alone
+        let rotated = without_exif(&jpeg, 6).unwrap();
+        let segment = orientation_segment(6);
+        assert_eq!(&rotated[2..2 + segment.len()], segment.as_slice());
+        assert!(rotated.ends_with(&[0xFF, 0xDA, 0x12, 0x34, 0xFF, 0xD9]));
+
+        // APP1 segments other than EXIF, e.g. XMP, stay
+        let xmp = jpeg_with_app1(b"http://ns.adobe.com/xap/1.0/\0");
+        assert_eq!(without_exif(&xmp, 1).unwrap(), xmp);
+        assert_eq!(without_exif(b"GIF89a", 1), None);
+    }
+
+    #[test]
+    fn test_without_exif_malformed() {
+        // APP1 segments too short for the EXIF header, an empty one is still well formed
+        for length in [0u8, 1, 2, 3] {
+            let jpeg = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, length, 0xFF, 0xD9];
+            let expected = (length == 2).then_some(jpeg.to_vec());
+            assert_eq!(without_exif(&jpeg, 1), expected);
+        }
+        let truncated = &jpeg_with_app1(b"Exif\0\0MM\0\x2a")[..10];
+        assert_eq!(without_exif(truncated, 1), None);
+        assert_eq!(without_exif(&[0xFF, 0xD8, 0xFF], 1), None);
+    }
+}
@@ -0,0 +1,35 @@
+#!/usr/bin/bash
+#
+set -e
+
+log() {
+  printf "\n%s\n" "$1"
+}
+
+host="http://localhost:8080"
+crl="curl --fail-with-body -s"
+headers=$(mktemp)
+trap 'rm -f "$headers"' EXIT
+
+refresh_token() {
+  grep -i '^x-organizator-refresh-token:' "$headers" | cut -d' ' -f2 | tr -d '\r'
+}
+
+# read the current password from stdin
+read -s -p "Current password for ${USERNAME}: " current_password
+
+$crl -D "$headers" -o /dev/null "$host/login" -d "username=${USERNAME}&password=$current_password"
+FIRST=$(refresh_token)
+log "Logged in as ${USERNAME}"
+
+$crl -D "$headers" -o /dev/null "$host/refresh" -H "x-organizator-refresh-token: $FIRST"
+SECOND=$(refresh_token)
+log "Refresh token rotated"
+
+status=$(curl -s -o /dev/null -w "%{http_code}" "$host/refresh" -H "x-organizator-refresh-token: $FIRST")
+[ "$status" = "401" ]
+log "Used refresh token refused"
+
+status=$(curl -s -o /dev/null -w "%{http_code}" "$host/refresh" -H "x-organizator-refresh-token: $SECOND")
+[ "$status" = "401" ]
+log "The rest of the family was revoked with it"
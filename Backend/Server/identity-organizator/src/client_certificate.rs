@@ -0,0 +1,289 @@
+//! Client certificates registered to users, nginx verifies them against the CA and the
+//! services look the user up by fingerprint, or by issuer and serial.
+//!
+//! Users register and revoke their own certificates, administrators those of anyone.
+//! A revoked certificate is kept to show when it was revoked.
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::audit::{self, AuditEvent, CERTIFICATE_CHANGED};
+use lib_hyper_organizator::authentication::client_certificate::{
+    CertificateList, DistinguishedName, UserCertificate, fingerprint_from_pem,
+    normalize_fingerprint, normalize_serial,
+};
+use lib_hyper_organizator::postgres::get_connection;
+use lib_hyper_organizator::response_utils::{IntoResultHyperResponse, parse_body};
+use lib_hyper_organizator::typedef::{GenericError, UserId};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::Row;
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::router::{managed_login, refuse_api_token};
+
+/// Followed by the id of the certificate to revoke
+pub const CERTIFICATE_PATH_PREFIX: &str = "/certificates/";
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct CertificateForm {
+    /// the requester if missing, someone else only for administrators
+    username: Option<String>,
+    /// to tell the certificates of a user apart
+    name: String,
+    /// RFC 4514, as nginx has it in $ssl_client_s_dn
+    subject_dn: String,
+    /// RFC 4514, as nginx has it in $ssl_client_i_dn
+    issuer_dn: Option<String>,
+    /// hex
+    serial: Option<String>,
+    /// SHA-256 of the DER, hex
+    fingerprint: Option<String>,
+    /// PEM, its fingerprint is taken instead of the fingerprint field
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+pub struct CertificateQuery {
+    /// the requester if missing, someone else only for administrators
+    username: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct CertificateInfo {
+    id: i32,
+    name: String,
+    subject_dn: String,
+    issuer_dn: Option<String>,
+    serial: Option<String>,
+    fingerprint: Option<String>,
+    /// seconds since epoch
+    created_on: i64,
+    /// seconds since epoch, still valid if missing
+    revoked_on: Option<i64>,
+}
+
+impl From<Row> for CertificateInfo {
+    fn from(row: Row) -> Self {
+        CertificateInfo {
+            id: row.get("id"),
+            name: row.get("name"),
+            subject_dn: row.get("subject_dn"),
+            issuer_dn: row.get("issuer_dn"),
+            serial: row.get("serial"),
+            fingerprint: row.get("fingerprint"),
+            created_on: row.get("created_on"),
+            revoked_on: row.get("revoked_on"),
+        }
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// The certificate in the form the services compare, or what is wrong with the form
+fn registered_certificate(form: &CertificateForm) -> Result<UserCertificate, String> {
+    let subject =
+        DistinguishedName::parse(&form.subject_dn).map_err(|e| format!("Bad subject DN: {e}"))?;
+    if subject.rdns.is_empty() {
+        return Err("The subject DN is required".to_string());
+    }
+    let issuer_dn = non_empty(&form.issuer_dn)
+        .map(|dn| DistinguishedName::parse(dn).map(|dn| dn.to_string()))
+        .transpose()
+        .map_err(|e| format!("Bad issuer DN: {e}"))?;
+    let serial = non_empty(&form.serial)
+        .map(|serial| normalize_serial(serial).ok_or("Bad serial"))
+        .transpose()?;
+    let fingerprint = match (non_empty(&form.certificate), non_empty(&form.fingerprint)) {
+        (Some(pem), _) => Some(fingerprint_from_pem(pem).ok_or("Bad certificate")?),
+        (None, Some(fingerprint)) => {
+            Some(normalize_fingerprint(fingerprint).ok_or("Bad fingerprint")?)
+        }
+        (None, None) => None,
+    };
+    // a serial is only unique for its issuer
+    if fingerprint.is_none() && (serial.is_none() || issuer_dn.is_none()) {
+        return Err("A certificate, a fingerprint, or the issuer and serial are required".into());
+    }
+    Ok(UserCertificate {
+        id: 0,
+        username: String::new(),
+        subject_dn: subject.to_string(),
+        issuer_dn,
+        serial,
+        fingerprint,
+    })
+}
+
+#[utoipa::path(post, path="/certificates",
+    request_body(content = CertificateForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Certificate registered, its id"),
+        (status=400, description="Missing name, malformed DN, serial or fingerprint"),
+        (status=403, description="Not with an API token, or the certificate of someone else"),
+        (status=404, description="Unknown user"),
+        (status=409, description="The certificate is registered already"),
+    ),
+)]
+/// Let a certificate verified by nginx log in as the user
+pub async fn register(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let form: CertificateForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(certificates) = request.extensions().get::<Arc<CertificateList>>() else {
+        return "No certificate list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let name = form.name.trim();
+    if name.is_empty() {
+        return "Name is required".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let mut certificate = match registered_certificate(&form) {
+        Ok(certificate) => certificate,
+        Err(message) => return message.to_text_response_with_status(StatusCode::BAD_REQUEST),
+    };
+
+    let client = get_connection(&request).await?;
+    let login = match managed_login(&request, &client, requester, form.username.as_deref()).await? {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_user_certificate.sql"))
+        .await?;
+    let now = get_current_timestamp() as i64;
+    let Some(row) = client
+        .query_opt(
+            &stmt,
+            &[
+                &login.id,
+                &name,
+                &certificate.subject_dn,
+                &certificate.issuer_dn,
+                &certificate.serial,
+                &certificate.fingerprint,
+                &now,
+            ],
+        )
+        .await?
+    else {
+        return "The certificate is registered already"
+            .to_text_response_with_status(StatusCode::CONFLICT);
+    };
+    certificate.id = row.get("id");
+    certificate.username = form.username.as_deref().unwrap_or(requester).to_string();
+    let event = AuditEvent::new(CERTIFICATE_CHANGED, &certificate.username)
+        .by(requester)
+        .from_request(&request)
+        .detail("action", "registered")
+        .detail("certificate", certificate.id)
+        .detail("subject", certificate.subject_dn.as_str());
+    audit::record(&client, event).await?;
+    info!(
+        "User 「{requester}」 registered certificate {} 「{}」 for 「{}」",
+        certificate.id, certificate.subject_dn, certificate.username
+    );
+    let id = certificate.id;
+    // usable here at once, the other services pick it up at their next reload
+    certificates.insert(certificate);
+    id.to_string().to_text_response()
+}
+
+#[utoipa::path(get, path="/certificates",
+    params(CertificateQuery),
+    responses(
+        (status=200, description="The certificates of the user, the revoked ones too", body=Vec<CertificateInfo>),
+        (status=403, description="Not with an API token, or the certificates of someone else"),
+        (status=404, description="Unknown user"),
+    ),
+)]
+pub async fn list(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let query: CertificateQuery =
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default()).unwrap_or_default();
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let client = get_connection(&request).await?;
+    let login = match managed_login(&request, &client, requester, query.username.as_deref()).await?
+    {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    let stmt = client
+        .prepare_cached(include_str!("sql/user_certificates.sql"))
+        .await?;
+    let rows = client.query(&stmt, &[&login.id]).await?;
+    let certificates: Vec<CertificateInfo> = rows.into_iter().map(CertificateInfo::from).collect();
+    serde_json::to_string(&certificates)?.to_json_response()
+}
+
+#[utoipa::path(delete, path="/certificates/{id}",
+    params(
+        ("id" = i32, Path, description="Id of the certificate"),
+        CertificateQuery,
+    ),
+    responses(
+        (status=200, description="Certificate revoked"),
+        (status=403, description="Not with an API token, or the certificate of someone else"),
+        (status=404, description="No such certificate of the user, or revoked already"),
+    ),
+)]
+pub async fn revoke(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let query: CertificateQuery =
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default()).unwrap_or_default();
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(certificates) = request.extensions().get::<Arc<CertificateList>>() else {
+        return "No certificate list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let Some(Ok(id)) = request
+        .uri()
+        .path()
+        .strip_prefix(CERTIFICATE_PATH_PREFIX)
+        .map(str::parse::<i32>)
+    else {
+        return "Bad certificate id".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let client = get_connection(&request).await?;
+    let login = match managed_login(&request, &client, requester, query.username.as_deref()).await?
+    {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    let stmt = client
+        .prepare_cached(include_str!("sql/revoke_user_certificate.sql"))
+        .await?;
+    let now = get_current_timestamp() as i64;
+    if client
+        .query_opt(&stmt, &[&id, &login.id, &now])
+        .await?
+        .is_none()
+    {
+        return "No such certificate".to_text_response_with_status(StatusCode::NOT_FOUND);
+    }
+    certificates.remove(id);
+    let username = query.username.as_deref().unwrap_or(requester);
+    let event = AuditEvent::new(CERTIFICATE_CHANGED, username)
+        .by(requester)
+        .from_request(&request)
+        .detail("action", "revoked")
+        .detail("certificate", id);
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 revoked certificate {id} of 「{username}」");
+    "Certificate revoked".to_text_response()
+}
@@ -2,13 +2,26 @@ use deadpool_postgres::Client;
 use lib_hyper_organizator::typedef::GenericError;
 use serde::Serialize;
 use tokio_postgres::Row;
+use tokio_postgres::error::SqlState;
 use tracing::debug;
+use utoipa::ToSchema;
 
 #[derive(Serialize, Debug)]
 pub struct Login {
     pub id: i32,
     pub username: Option<String>,
     pub password_hash: Option<String>,
+    /// from before Argon2, replaced at the next login
+    pub pbkdf2: Option<Vec<u8>>,
+    pub salt: Option<Vec<u8>>,
+    pub roles: Vec<String>,
+    /// seconds since epoch, set after too many failed logins
+    pub locked_until: Option<i64>,
+    /// a code from the authenticator is required after the password
+    pub totp_enabled: bool,
+    pub disabled: bool,
+    /// the password was chosen by an administrator
+    pub must_change_password: bool,
 }
 
 impl From<Row> for Login {
@@ -17,16 +30,127 @@ impl From<Row> for Login {
             id: row.get("id"),
             username: row.get("username"),
             password_hash: row.get("password_hash"),
+            pbkdf2: row.get("pbkdf2"),
+            salt: row.get("salt"),
+            roles: row.get("roles"),
+            locked_until: row.get("locked_until"),
+            totp_enabled: row.get("totp_enabled"),
+            disabled: row.get("disabled"),
+            must_change_password: row.get("must_change_password"),
         }
     }
 }
 
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UserRoleList {
+    pub username: String,
+    pub roles: Vec<String>,
+}
+
+impl From<Row> for UserRoleList {
+    fn from(row: Row) -> Self {
+        UserRoleList {
+            username: row.get("username"),
+            roles: row.get("roles"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UserAccount {
+    pub username: String,
+    pub roles: Vec<String>,
+    pub disabled: bool,
+    pub must_change_password: bool,
+    /// seconds since epoch, never logged in if missing
+    pub last_login: Option<i64>,
+}
+
+impl From<Row> for UserAccount {
+    fn from(row: Row) -> Self {
+        UserAccount {
+            username: row.get("username"),
+            roles: row.get("roles"),
+            disabled: row.get("disabled"),
+            must_change_password: row.get("must_change_password"),
+            last_login: row.get("last_login"),
+        }
+    }
+}
+
+pub enum RoleChange {
+    Grant,
+    Revoke,
+}
+
 pub async fn fetch_login(client: &Client, username: &str) -> Result<Login, GenericError> {
     let stmt = client.prepare_cached(include_str!("sql/login.sql")).await?;
     let row = client.query_one(&stmt, &[&username]).await?;
     Ok(Login::from(row))
 }
 
+/// Like fetch_login, but a missing user is not an error
+pub async fn find_login(client: &Client, username: &str) -> Result<Option<Login>, GenericError> {
+    let stmt = client.prepare_cached(include_str!("sql/login.sql")).await?;
+    let row = client.query_opt(&stmt, &[&username]).await?;
+    Ok(row.map(Login::from))
+}
+
+/// Count a failed login, the account is locked until `lock_until` if it reaches the threshold.
+/// Returns true if the account got locked.
+pub async fn count_failed_login(
+    client: &Client,
+    user_id: i32,
+    threshold: i32,
+    lock_until: i64,
+) -> Result<bool, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/count_failed_login.sql"))
+        .await?;
+    let failed_logins: i32 = client.query_one(&stmt, &[&user_id]).await?.get(0);
+    if failed_logins < threshold {
+        return Ok(false);
+    }
+    let stmt = client
+        .prepare_cached(include_str!("sql/lock_login.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id, &lock_until]).await?;
+    Ok(true)
+}
+
+pub async fn clear_failed_logins(client: &Client, user_id: i32) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/clear_failed_logins.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id]).await?;
+    Ok(())
+}
+
+pub async fn record_login(client: &Client, user_id: i32, now: i64) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/record_login.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id, &now]).await?;
+    Ok(())
+}
+
+/// Keep a failed login for auditing
+pub async fn record_login_failure(
+    client: &Client,
+    username: &str,
+    ip: Option<&str>,
+    reason: &str,
+    failed_on: i64,
+) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_login_failure.sql"))
+        .await?;
+    client
+        .execute(&stmt, &[&username, &ip, &reason, &failed_on])
+        .await?;
+    Ok(())
+}
+
 pub async fn update_password(
     db_client: &Client,
     requester: &str,
@@ -54,3 +178,284 @@ pub async fn update_password(
     }
     Ok(())
 }
+
+pub async fn fetch_user_roles(client: &Client) -> Result<Vec<UserRoleList>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/user_roles.sql"))
+        .await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows.into_iter().map(UserRoleList::from).collect())
+}
+
+/// Grant or revoke a role, returns false if the user or the role do not exist
+pub async fn change_role(
+    client: &Client,
+    username: &str,
+    role: &str,
+    change: RoleChange,
+) -> Result<bool, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/find_user_role.sql"))
+        .await?;
+    let Some(row) = client.query_opt(&stmt, &[&username, &role]).await? else {
+        return Ok(false);
+    };
+    let user_id: i32 = row.get("user_id");
+    let sql = match change {
+        RoleChange::Grant => include_str!("sql/grant_role.sql"),
+        RoleChange::Revoke => include_str!("sql/revoke_role.sql"),
+    };
+    let stmt = client.prepare_cached(sql).await?;
+    let rows = client.execute(&stmt, &[&user_id, &role]).await?;
+    debug!("Role {role} of {username} changed, {rows} rows affected");
+    Ok(true)
+}
+
+/// An API token as shown to its owner, the token itself is not kept
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ApiTokenInfo {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// seconds since epoch
+    pub created_on: i64,
+    /// seconds since epoch, never if missing
+    pub expires_at: Option<i64>,
+}
+
+impl From<Row> for ApiTokenInfo {
+    fn from(row: Row) -> Self {
+        ApiTokenInfo {
+            id: row.get("id"),
+            name: row.get("name"),
+            scopes: row.get("scopes"),
+            created_on: row.get("created_on"),
+            expires_at: row.get("expires_at"),
+        }
+    }
+}
+
+/// Store the hash of a new token, returns its id or None if the user has one with that name
+pub async fn insert_api_token(
+    client: &Client,
+    user_id: i32,
+    name: &str,
+    token_hash: &str,
+    scopes: &[String],
+    created_on: i64,
+    expires_at: Option<i64>,
+) -> Result<Option<i32>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_api_token.sql"))
+        .await?;
+    let row = client
+        .query_opt(
+            &stmt,
+            &[
+                &user_id,
+                &name,
+                &token_hash,
+                &scopes,
+                &created_on,
+                &expires_at,
+            ],
+        )
+        .await?;
+    Ok(row.map(|row| row.get("id")))
+}
+
+pub async fn fetch_api_tokens(
+    client: &Client,
+    user_id: i32,
+) -> Result<Vec<ApiTokenInfo>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/user_api_tokens.sql"))
+        .await?;
+    let rows = client.query(&stmt, &[&user_id]).await?;
+    Ok(rows.into_iter().map(ApiTokenInfo::from).collect())
+}
+
+/// Revoke a token of the user, returns its hash or None if the user has no such token
+pub async fn delete_api_token(
+    client: &Client,
+    user_id: i32,
+    id: i32,
+) -> Result<Option<String>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_api_token.sql"))
+        .await?;
+    let row = client.query_opt(&stmt, &[&id, &user_id]).await?;
+    Ok(row.map(|row| row.get("token_hash")))
+}
+
+pub async fn fetch_users(client: &Client) -> Result<Vec<UserAccount>, GenericError> {
+    let stmt = client.prepare_cached(include_str!("sql/users.sql")).await?;
+    let rows = client.query(&stmt, &[]).await?;
+    Ok(rows.into_iter().map(UserAccount::from).collect())
+}
+
+/// Create a user with the role and the default memo groups, all or nothing.
+/// Returns false if the username is taken.
+pub async fn create_user(
+    client: &mut Client,
+    requester: &str,
+    username: &str,
+    password_hash: &str,
+    role: &str,
+) -> Result<bool, GenericError> {
+    let transaction = client.transaction().await?;
+    // the row level security of users lets only administrators insert
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/set_requester.sql"))
+        .await?;
+    transaction.execute(&stmt, &[&requester]).await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/insert_user.sql"))
+        .await?;
+    let Some(row) = transaction
+        .query_opt(&stmt, &[&username, &password_hash])
+        .await?
+    else {
+        return Ok(false);
+    };
+    let user_id: i32 = row.get("id");
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/insert_user_account.sql"))
+        .await?;
+    transaction.execute(&stmt, &[&user_id, &true]).await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/grant_role.sql"))
+        .await?;
+    transaction.execute(&stmt, &[&user_id, &role]).await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/create_default_memo_groups.sql"))
+        .await?;
+    transaction.execute(&stmt, &[&user_id, &username]).await?;
+    transaction.commit().await?;
+    debug!("User {username} created with id {user_id}");
+    Ok(true)
+}
+
+pub async fn set_user_disabled(
+    client: &Client,
+    user_id: i32,
+    disabled: bool,
+) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/set_user_disabled.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id, &disabled]).await?;
+    Ok(())
+}
+
+pub async fn set_must_change_password(
+    client: &Client,
+    user_id: i32,
+    must_change_password: bool,
+) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/set_must_change_password.sql"))
+        .await?;
+    client
+        .execute(&stmt, &[&user_id, &must_change_password])
+        .await?;
+    Ok(())
+}
+
+/// Returns false if the new username is taken
+pub async fn rename_user(
+    client: &Client,
+    requester: &str,
+    username: &str,
+    new_username: &str,
+) -> Result<bool, GenericError> {
+    if find_login(client, new_username).await?.is_some() {
+        return Ok(false);
+    }
+    let stmt = client
+        .prepare_cached(include_str!("sql/set_requester.sql"))
+        .await?;
+    client.execute(&stmt, &[&requester]).await?;
+    let stmt = client
+        .prepare_cached(include_str!("sql/rename_user.sql"))
+        .await?;
+    let rows = client.execute(&stmt, &[&username, &new_username]).await?;
+    if rows == 0 {
+        return Err(GenericError::from(format!(
+            "No rows updated when renaming user 「{username}」"
+        )));
+    }
+    Ok(true)
+}
+
+/// Delete a user and the group memberships, all or nothing.
+/// Returns false if the user still owns data, memos or files.
+pub async fn delete_user(
+    client: &mut Client,
+    requester: &str,
+    user_id: i32,
+) -> Result<bool, GenericError> {
+    let transaction = client.transaction().await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/set_requester.sql"))
+        .await?;
+    transaction.execute(&stmt, &[&requester]).await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/delete_user_groups.sql"))
+        .await?;
+    transaction.execute(&stmt, &[&user_id]).await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/delete_user.sql"))
+        .await?;
+    match transaction.execute(&stmt, &[&user_id]).await {
+        Ok(0) => Err(GenericError::from(format!(
+            "No rows deleted when deleting user {user_id}"
+        ))),
+        Ok(_) => {
+            transaction.commit().await?;
+            Ok(true)
+        }
+        Err(e) if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+            debug!("User {user_id} is still referenced: {e}");
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The hashes of the latest passwords the user chose, newest first
+pub async fn fetch_password_history(
+    client: &Client,
+    user_id: i32,
+    count: usize,
+) -> Result<Vec<String>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/password_history.sql"))
+        .await?;
+    let rows = client.query(&stmt, &[&user_id, &(count as i64)]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get("password_hash"))
+        .collect())
+}
+
+/// Remember a password the user chose, only the latest `keep` are kept
+pub async fn record_password(
+    client: &Client,
+    user_id: i32,
+    password_hash: &str,
+    now: i64,
+    keep: usize,
+) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_password_history.sql"))
+        .await?;
+    client
+        .execute(&stmt, &[&user_id, &password_hash, &now])
+        .await?;
+    let stmt = client
+        .prepare_cached(include_str!("sql/prune_password_history.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id, &(keep as i64)]).await?;
+    Ok(())
+}
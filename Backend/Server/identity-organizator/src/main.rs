@@ -1,5 +1,11 @@
+mod client_certificate;
 mod db;
+mod oidc;
+mod refresh_token;
 mod router;
+mod second_factor;
+mod session;
+mod single_sign_on;
 use lib_hyper_organizator::server;
 use router::swagger_json;
 
@@ -0,0 +1,721 @@
+//! Minimal OpenID Connect provider, so other applications can log in our users.
+//!
+//! Only the authorization code flow with PKCE (S256) is supported. The browser brings the
+//! session cookie to `/oidc/authorize`, users without one are sent to the login page first.
+//! The code is traded once at `/oidc/token` for an access token and a signed ID token.
+//! The clients are registered in the settings, there is no consent screen.
+use deadpool_postgres::Client;
+use http::header::{AUTHORIZATION, CACHE_CONTROL, LOCATION, PRAGMA, WWW_AUTHENTICATE};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::authentication::api_token::hash_token;
+use lib_hyper_organizator::authentication::check_security::{
+    check_client_jwt_header, check_jwt_header,
+};
+use lib_hyper_organizator::authentication::jot::TokenIssuer;
+use lib_hyper_organizator::authentication::oidc::{
+    IdTokenClaims, OidcClient, OidcConfig, PKCE_METHOD, verify_pkce,
+};
+use lib_hyper_organizator::postgres::get_connection;
+use lib_hyper_organizator::response_utils::{IntoResultHyperResponse, parse_body};
+use lib_hyper_organizator::server::SETTINGS;
+use lib_hyper_organizator::typedef::{GenericError, UserId};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{info, warn};
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::db::find_login;
+use crate::refresh_token::{self, random_token};
+use crate::session::{self, SessionOrigin};
+
+/// Seconds from the redirect to the client until it redeems the code
+const CODE_EXPIRY: u64 = 60;
+const OPENID_SCOPE: &str = "openid";
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+pub struct AuthorizeQuery {
+    /// only `code`
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    /// has to include `openid`
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    /// base64url of the SHA-256 of the code verifier
+    code_challenge: String,
+    /// only `S256`
+    code_challenge_method: Option<String>,
+    /// `none` fails instead of asking the user to log in
+    prompt: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct TokenForm {
+    /// only `authorization_code`
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    code_verifier: String,
+    /// unless sent with HTTP Basic authentication
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    id_token: String,
+    scope: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UserInfo {
+    /// the user id, as in the ID token
+    sub: String,
+    preferred_username: String,
+    roles: Vec<String>,
+}
+
+/// Error response of RFC 6749
+#[derive(Serialize, Debug, ToSchema)]
+pub struct OAuthError {
+    error: &'static str,
+    error_description: &'static str,
+}
+
+/// A code not redeemed yet
+struct AuthorizationCode {
+    client_id: String,
+    user_id: i32,
+    username: String,
+    redirect_uri: String,
+    scope: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    expires_at: i64,
+}
+
+fn not_configured() -> Result<Response<Body>, GenericError> {
+    "OpenID Connect is not configured".to_text_response_with_status(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(get, path="/oidc/.well-known/openid-configuration",
+    responses(
+        (status=200, description="The OpenID Connect discovery document"),
+        (status=404, description="No issuer configured"),
+    ),
+)]
+pub async fn discovery(_request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let oidc = &SETTINGS.security.oidc;
+    if !oidc.is_enabled() {
+        return not_configured();
+    }
+    let issuer = oidc.issuer.trim_end_matches('/');
+    serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "userinfo_endpoint": format!("{issuer}/userinfo"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "scopes_supported": [OPENID_SCOPE, "profile"],
+        "claims_supported": ["sub", "preferred_username", "roles"],
+        "code_challenge_methods_supported": [PKCE_METHOD],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+    })
+    .to_string()
+    .to_json_response()
+}
+
+fn redirect(location: &str) -> Result<Response<Body>, GenericError> {
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .header("server", "hyper")
+        .body(Body::empty())?)
+}
+
+/// Back to the client with the parameters added to its redirect URI
+fn redirect_to_client(
+    redirect_uri: &str,
+    params: &[(&str, &str)],
+    state: Option<&str>,
+) -> Result<Response<Body>, GenericError> {
+    let mut url = Url::parse(redirect_uri)?;
+    url.query_pairs_mut()
+        .extend_pairs(params)
+        .extend_pairs(state.map(|state| ("state", state)));
+    redirect(url.as_str())
+}
+
+#[utoipa::path(get, path="/oidc/authorize",
+    params(AuthorizeQuery),
+    responses(
+        (status=302, description="To the client with the code or the error, or to the login page"),
+        (status=400, description="Unknown client or redirect URI"),
+    ),
+)]
+/// The user logged in with the session cookie is sent back to the client with a code
+pub async fn authorize(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let oidc = &SETTINGS.security.oidc;
+    if !oidc.is_enabled() {
+        return not_configured();
+    }
+    let query_string = request.uri().query().unwrap_or_default().to_string();
+    let Ok(query) = serde_urlencoded::from_str::<AuthorizeQuery>(&query_string) else {
+        return "Malformed authorization request"
+            .to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    if let Some(response) = refuse_authorization(oidc, &query) {
+        return response;
+    }
+    let state = query.state.as_deref();
+    let refuse = |error: &str| redirect_to_client(&query.redirect_uri, &[("error", error)], state);
+
+    let Some(UserId(username)) = check_jwt_header(&mut request) else {
+        if query.prompt.as_deref() == Some("none") {
+            return refuse("login_required");
+        }
+        let authorize_url = format!(
+            "{}/authorize?{query_string}",
+            oidc.issuer.trim_end_matches('/')
+        );
+        let login_url = format!(
+            "{}{}",
+            oidc.login_url,
+            url::form_urlencoded::byte_serialize(authorize_url.as_bytes()).collect::<String>()
+        );
+        return redirect(&login_url);
+    };
+    let client_db = get_connection(&request).await?;
+    let user_id = match find_login(&client_db, &username).await? {
+        Some(login) if !login.disabled => login.id,
+        _ => {
+            warn!("Authorization refused to disabled or deleted user 「{username}」");
+            return refuse("access_denied");
+        }
+    };
+
+    let code = random_token()?;
+    insert_code(
+        &client_db,
+        &hash_token(&code),
+        &AuthorizationCode {
+            client_id: query.client_id.clone(),
+            user_id,
+            username: username.clone(),
+            redirect_uri: query.redirect_uri.clone(),
+            scope: query.scope.clone(),
+            nonce: query.nonce.clone(),
+            code_challenge: query.code_challenge.clone(),
+            expires_at: (get_current_timestamp() + CODE_EXPIRY) as i64,
+        },
+    )
+    .await?;
+    info!(
+        "Authorization code for 「{username}」 issued to 「{}」",
+        query.client_id
+    );
+    redirect_to_client(&query.redirect_uri, &[("code", &code)], state)
+}
+
+/// The response to a malformed request, checked before anyone logs in
+fn refuse_authorization(
+    oidc: &OidcConfig,
+    query: &AuthorizeQuery,
+) -> Option<Result<Response<Body>, GenericError>> {
+    // without a trusted redirect URI the errors can only be shown here
+    let Some(client) = oidc.client(&query.client_id) else {
+        warn!(
+            "Authorization request for unknown client 「{}」",
+            query.client_id
+        );
+        return Some("Unknown client".to_text_response_with_status(StatusCode::BAD_REQUEST));
+    };
+    if !client.allows_redirect(&query.redirect_uri) {
+        warn!(
+            "Redirect URI 「{}」 not registered for 「{}」",
+            query.redirect_uri, query.client_id
+        );
+        return Some(
+            "Redirect URI not registered".to_text_response_with_status(StatusCode::BAD_REQUEST),
+        );
+    }
+    let error = if query.response_type != "code" {
+        "unsupported_response_type"
+    } else if !query.scope.split_whitespace().any(|s| s == OPENID_SCOPE) {
+        "invalid_scope"
+    } else if query.code_challenge.is_empty()
+        || query.code_challenge_method.as_deref() != Some(PKCE_METHOD)
+    {
+        "invalid_request"
+    } else {
+        return None;
+    };
+    let params = [("error", error)];
+    Some(redirect_to_client(
+        &query.redirect_uri,
+        &params,
+        query.state.as_deref(),
+    ))
+}
+
+async fn insert_code(
+    client: &Client,
+    code_hash: &str,
+    code: &AuthorizationCode,
+) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_authorization_code.sql"))
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &code_hash,
+                &code.client_id,
+                &code.user_id,
+                &code.redirect_uri,
+                &code.scope,
+                &code.nonce,
+                &code.code_challenge,
+                &code.expires_at,
+                &(get_current_timestamp() as i64),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Redeem a code, it is gone afterwards whatever the outcome
+async fn use_code(client: &Client, code: &str) -> Result<Option<AuthorizationCode>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/use_authorization_code.sql"))
+        .await?;
+    let row = client.query_opt(&stmt, &[&hash_token(code)]).await?;
+    Ok(row.map(|row| AuthorizationCode {
+        client_id: row.get("client_id"),
+        user_id: row.get("user_id"),
+        username: row.get("username"),
+        redirect_uri: row.get("redirect_uri"),
+        scope: row.get("scope"),
+        nonce: row.get("nonce"),
+        code_challenge: row.get("code_challenge"),
+        expires_at: row.get("expires_at"),
+    }))
+}
+
+fn oauth_error(
+    status: StatusCode,
+    error: &'static str,
+    error_description: &'static str,
+) -> Result<Response<Body>, GenericError> {
+    let body = serde_json::to_string(&OAuthError {
+        error,
+        error_description,
+    })?;
+    let mut builder = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .header("server", "hyper");
+    if status == StatusCode::UNAUTHORIZED {
+        builder = builder.header(WWW_AUTHENTICATE, "Basic");
+    }
+    Ok(builder.body(Body::from(body))?)
+}
+
+impl AuthorizationCode {
+    /// Not expired, issued to the client for the same redirect and the verifier matches
+    fn redeemable(&self, client: &OidcClient, form: &TokenForm, now: u64) -> bool {
+        self.expires_at > now as i64
+            && self.client_id == client.client_id
+            && self.redirect_uri == form.redirect_uri
+            && verify_pkce(&form.code_verifier, &self.code_challenge)
+    }
+}
+
+/// Status, error and description of an error response
+type TokenRefusal = (StatusCode, &'static str, &'static str);
+
+/// The client, authenticated, asking for a supported grant
+fn token_client<'a>(
+    oidc: &'a OidcConfig,
+    basic: Option<(String, String)>,
+    form: &TokenForm,
+) -> Result<&'a OidcClient, TokenRefusal> {
+    let (client_id, client_secret) = match basic {
+        Some((client_id, client_secret)) => (client_id, Some(client_secret)),
+        None => (
+            form.client_id.clone().unwrap_or_default(),
+            form.client_secret.clone(),
+        ),
+    };
+    let Some(client) = oidc
+        .client(&client_id)
+        .filter(|client| client.authenticate(client_secret.as_deref()))
+    else {
+        warn!("Failed authentication of the OIDC client 「{client_id}」");
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "invalid_client",
+            "Unknown client or wrong secret",
+        ));
+    };
+    if form.grant_type != "authorization_code" {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "Only authorization_code is supported",
+        ));
+    }
+    Ok(client)
+}
+
+/// Client id and secret from HTTP Basic authentication
+fn basic_credentials<B>(request: &Request<B>) -> Option<(String, String)> {
+    let encoded = request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((client_id.to_string(), client_secret.to_string()))
+}
+
+#[utoipa::path(post, path="/oidc/token",
+    request_body(content = TokenForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="The access token and the ID token", body=TokenResponse),
+        (status=400, description="Bad request or code", body=OAuthError),
+        (status=401, description="Unknown client or wrong secret", body=OAuthError),
+    ),
+)]
+/// Trade an authorization code for the tokens
+pub async fn token(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let oidc = &SETTINGS.security.oidc;
+    if !oidc.is_enabled() {
+        return not_configured();
+    }
+    let basic = basic_credentials(&request);
+    let Ok(form) = parse_body::<TokenForm>(&mut request).await else {
+        return oauth_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "Malformed token request",
+        );
+    };
+    let client = match token_client(oidc, basic, &form) {
+        Ok(client) => client,
+        Err((status, error, error_description)) => {
+            return oauth_error(status, error, error_description);
+        }
+    };
+
+    let client_db = get_connection(&request).await?;
+    let now = get_current_timestamp();
+    let code = match use_code(&client_db, &form.code).await? {
+        Some(code) if code.redeemable(client, &form, now) => code,
+        _ => {
+            warn!(
+                "Invalid authorization code presented by 「{}」",
+                client.client_id
+            );
+            return oauth_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+                "Invalid or expired code",
+            );
+        }
+    };
+    let login = match find_login(&client_db, &code.username).await? {
+        Some(login) if login.id == code.user_id && !login.disabled => login,
+        _ => {
+            return oauth_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+                "The user can no longer log in",
+            );
+        }
+    };
+
+    let Some(issuer) = request.extensions().get::<Arc<TokenIssuer>>() else {
+        return "No TokenIssuer".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    // a session of its own, the user sees it and can end it; there is nothing to refresh
+    let session = refresh_token::issue(&client_db, login.id, issuer.session_expiry).await?;
+    session::start(&client_db, &session, login.id, &SessionOrigin::of(&request)).await?;
+    // only for the client and the userinfo endpoint, without roles the services would refuse
+    let access_token = issuer.generate_token_for(
+        &code.username,
+        &[],
+        &session.family.to_string(),
+        &client.client_id,
+    )?;
+    let id_token = issuer.sign(&IdTokenClaims {
+        iss: oidc.issuer.trim_end_matches('/').to_string(),
+        sub: login.id.to_string(),
+        aud: client.client_id.clone(),
+        exp: now + issuer.session_expiry,
+        iat: now,
+        nonce: code.nonce,
+        preferred_username: code.username.clone(),
+    })?;
+    info!(
+        "Tokens for 「{}」 issued to 「{}」",
+        code.username, client.client_id
+    );
+    let body = serde_json::to_string(&TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: issuer.session_expiry,
+        id_token,
+        scope: code.scope,
+    })?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .header(PRAGMA, "no-cache")
+        .header("server", "hyper")
+        .body(Body::from(body))?)
+}
+
+#[utoipa::path(get, path="/oidc/userinfo",
+    responses(
+        (status=200, description="Claims about the owner of the access token", body=UserInfo),
+        (status=401, description="No access token of a client or of the organizator services"),
+    ),
+)]
+/// Not checked by the authorization layer, the tokens of the clients are refused there
+pub async fn userinfo(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let security = &SETTINGS.security;
+    if !security.oidc.is_enabled() {
+        return not_configured();
+    }
+    let accepts = |aud: &str| aud == security.audience || security.oidc.client(aud).is_some();
+    let Some(UserId(ref username)) = check_client_jwt_header(&mut request, &accepts) else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let client = get_connection(&request).await?;
+    let Some(login) = find_login(&client, username).await? else {
+        return "Unknown user".to_text_response_with_status(StatusCode::NOT_FOUND);
+    };
+    serde_json::to_string(&UserInfo {
+        sub: login.id.to_string(),
+        preferred_username: username.clone(),
+        roles: login.roles,
+    })?
+    .to_json_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::LOCATION;
+    use lib_hyper_organizator::authentication::oidc::code_challenge;
+
+    const REDIRECT_URI: &str = "https://wiki.example/callback";
+    // RFC 7636, appendix B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            issuer: "https://organizator.example/organizator/oidc".to_string(),
+            clients: vec![
+                OidcClient {
+                    client_id: "wiki".to_string(),
+                    secret_name: None,
+                    redirect_uris: vec![REDIRECT_URI.to_string()],
+                },
+                OidcClient {
+                    client_id: "crm".to_string(),
+                    secret_name: Some("no_such_oidc_client_secret".to_string()),
+                    redirect_uris: vec![],
+                },
+            ],
+            ..OidcConfig::default()
+        }
+    }
+
+    fn authorize_query() -> AuthorizeQuery {
+        AuthorizeQuery {
+            response_type: "code".to_string(),
+            client_id: "wiki".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: "openid profile".to_string(),
+            state: Some("xyz".to_string()),
+            code_challenge: code_challenge(VERIFIER),
+            code_challenge_method: Some(PKCE_METHOD.to_string()),
+            ..AuthorizeQuery::default()
+        }
+    }
+
+    fn error_redirect(response: Option<Result<Response<Body>, GenericError>>) -> String {
+        let response = response.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        response.headers()[LOCATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_authorize_refused() {
+        let oidc = config();
+        assert!(refuse_authorization(&oidc, &authorize_query()).is_none());
+
+        // not sent to the client, it may not be the one asking
+        for query in [
+            AuthorizeQuery {
+                client_id: "unknown".to_string(),
+                ..authorize_query()
+            },
+            AuthorizeQuery {
+                redirect_uri: "https://evil.example/callback".to_string(),
+                ..authorize_query()
+            },
+        ] {
+            let response = refuse_authorization(&oidc, &query).unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(!response.headers().contains_key(LOCATION));
+        }
+
+        let implicit = AuthorizeQuery {
+            response_type: "token".to_string(),
+            ..authorize_query()
+        };
+        assert_eq!(
+            error_redirect(refuse_authorization(&oidc, &implicit)),
+            format!("{REDIRECT_URI}?error=unsupported_response_type&state=xyz")
+        );
+        let no_openid = AuthorizeQuery {
+            scope: "profile".to_string(),
+            ..authorize_query()
+        };
+        assert!(error_redirect(refuse_authorization(&oidc, &no_openid)).contains("invalid_scope"));
+        let plain = AuthorizeQuery {
+            code_challenge_method: Some("plain".to_string()),
+            ..authorize_query()
+        };
+        assert!(error_redirect(refuse_authorization(&oidc, &plain)).contains("invalid_request"));
+        let no_challenge = AuthorizeQuery {
+            code_challenge: String::new(),
+            ..authorize_query()
+        };
+        assert!(
+            error_redirect(refuse_authorization(&oidc, &no_challenge)).contains("invalid_request")
+        );
+    }
+
+    fn token_form() -> TokenForm {
+        TokenForm {
+            grant_type: "authorization_code".to_string(),
+            code: "code".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            code_verifier: VERIFIER.to_string(),
+            client_id: Some("wiki".to_string()),
+            client_secret: None,
+        }
+    }
+
+    #[test]
+    fn test_token_client_refused() {
+        let oidc = config();
+        assert_eq!(
+            token_client(&oidc, None, &token_form()).unwrap().client_id,
+            "wiki"
+        );
+
+        let unknown = TokenForm {
+            client_id: Some("unknown".to_string()),
+            ..token_form()
+        };
+        let (status, error, _) = token_client(&oidc, None, &unknown).unwrap_err();
+        assert_eq!(
+            (status, error),
+            (StatusCode::UNAUTHORIZED, "invalid_client")
+        );
+        // a confidential client whose secret can not be checked is refused
+        let basic = Some(("crm".to_string(), "secret".to_string()));
+        assert_eq!(
+            token_client(&oidc, basic, &token_form()).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let refresh = TokenForm {
+            grant_type: "refresh_token".to_string(),
+            ..token_form()
+        };
+        assert_eq!(
+            token_client(&oidc, None, &refresh).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        let response = oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "").unwrap();
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Basic");
+    }
+
+    #[test]
+    fn test_code_redeemable() {
+        let oidc = config();
+        let wiki = oidc.client("wiki").unwrap();
+        let now = get_current_timestamp();
+        let code = AuthorizationCode {
+            client_id: "wiki".to_string(),
+            user_id: 1,
+            username: "admin".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: OPENID_SCOPE.to_string(),
+            nonce: None,
+            code_challenge: code_challenge(VERIFIER),
+            expires_at: (now + CODE_EXPIRY) as i64,
+        };
+        assert!(code.redeemable(wiki, &token_form(), now));
+        assert!(!code.redeemable(wiki, &token_form(), now + CODE_EXPIRY));
+        let other = OidcClient {
+            client_id: "other".to_string(),
+            ..wiki.clone()
+        };
+        assert!(!code.redeemable(&other, &token_form(), now));
+        let elsewhere = TokenForm {
+            redirect_uri: "https://wiki.example/other".to_string(),
+            ..token_form()
+        };
+        assert!(!code.redeemable(wiki, &elsewhere, now));
+        let wrong_verifier = TokenForm {
+            code_verifier: VERIFIER.replace('d', "e"),
+            ..token_form()
+        };
+        assert!(!code.redeemable(wiki, &wrong_verifier, now));
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        let request = Request::post("/oidc/token")
+            .header(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode("wiki:s3:cret")),
+            )
+            .body(())
+            .unwrap();
+        assert_eq!(
+            basic_credentials(&request),
+            Some(("wiki".to_string(), "s3:cret".to_string()))
+        );
+        let bearer = Request::post("/oidc/token")
+            .header(AUTHORIZATION, "Bearer token")
+            .body(())
+            .unwrap();
+        assert_eq!(basic_credentials(&bearer), None);
+    }
+}
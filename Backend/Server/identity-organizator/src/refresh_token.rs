@@ -0,0 +1,154 @@
+//! Long-lived opaque tokens exchanged at `/refresh` for a new access token.
+//!
+//! Each use replaces the token with a new one of the same family, the family being
+//! the login they descend from. A used token presented again means it leaked,
+//! so the whole family is revoked and its holder has to log in again.
+use base64::alphabet::URL_SAFE;
+use base64::engine::fast_portable::{FastPortable, NO_PAD};
+use deadpool_postgres::{Client, GenericClient};
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::authentication::api_token::hash_token;
+use lib_hyper_organizator::typedef::GenericError;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// A token and the session it belongs to
+pub struct RefreshToken {
+    pub token: String,
+    pub family: Uuid,
+    /// seconds since epoch, the same for the whole family
+    pub expires_at: u64,
+}
+
+pub enum Rotation {
+    /// The token was valid, here is its replacement
+    Rotated {
+        refresh_token: RefreshToken,
+        username: String,
+    },
+    /// Already used, the session has to end
+    Reused { family: Uuid },
+    /// Unknown, expired or revoked
+    Invalid,
+}
+
+/// 256 random bits, URL safe
+pub fn random_token() -> Result<String, GenericError> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| GenericError::from("Could not generate a random token"))?;
+    Ok(base64::encode_engine(
+        bytes,
+        &FastPortable::from(&URL_SAFE, NO_PAD),
+    ))
+}
+
+async fn insert(
+    client: &impl GenericClient,
+    family: Uuid,
+    user_id: i32,
+    expires_at: u64,
+) -> Result<RefreshToken, GenericError> {
+    let token = random_token()?;
+    let now = get_current_timestamp() as i64;
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_refresh_token.sql"))
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &hash_token(&token),
+                &family,
+                &user_id,
+                &now,
+                &(expires_at as i64),
+            ],
+        )
+        .await?;
+    Ok(RefreshToken {
+        token,
+        family,
+        expires_at,
+    })
+}
+
+/// Start a new session for the user, the expired tokens of old sessions are dropped
+pub async fn issue(
+    client: &Client,
+    user_id: i32,
+    expiry: u64,
+) -> Result<RefreshToken, GenericError> {
+    let now = get_current_timestamp();
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_expired_refresh_tokens.sql"))
+        .await?;
+    let deleted = client.execute(&stmt, &[&(now as i64)]).await?;
+    debug!("Removed {deleted} expired refresh tokens");
+    insert(client, Uuid::new_v4(), user_id, now + expiry).await
+}
+
+/// Exchange a token for a new one of the same family, it expires when the family does.
+/// The old token is only marked used once its successor is stored.
+pub async fn rotate(client: &mut Client, token: &str) -> Result<Rotation, GenericError> {
+    let token_hash = hash_token(token);
+    let now = get_current_timestamp() as i64;
+    let transaction = client.transaction().await?;
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/use_refresh_token.sql"))
+        .await?;
+    if let Some(row) = transaction.query_opt(&stmt, &[&token_hash, &now]).await? {
+        let expires_at: i64 = row.get("expires_at");
+        let refresh_token = insert(
+            &transaction,
+            row.get("family"),
+            row.get("user_id"),
+            expires_at as u64,
+        )
+        .await?;
+        transaction.commit().await?;
+        return Ok(Rotation::Rotated {
+            refresh_token,
+            username: row.get("username"),
+        });
+    }
+
+    let stmt = transaction
+        .prepare_cached(include_str!("sql/find_refresh_token.sql"))
+        .await?;
+    match transaction.query_opt(&stmt, &[&token_hash]).await? {
+        Some(row) if row.get::<_, bool>("used") => {
+            let family: Uuid = row.get("family");
+            warn!("Refresh token of family {family} used twice, the session ends");
+            Ok(Rotation::Reused { family })
+        }
+        _ => Ok(Rotation::Invalid),
+    }
+}
+
+/// The session of a token, used, expired or revoked alike
+pub async fn family(client: &Client, token: &str) -> Result<Option<Uuid>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/find_refresh_token.sql"))
+        .await?;
+    let row = client.query_opt(&stmt, &[&hash_token(token)]).await?;
+    Ok(row.map(|row| row.get("family")))
+}
+
+/// End a session, returns false if it was already ended
+pub async fn revoke_family(client: &Client, family: Uuid) -> Result<bool, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/revoke_refresh_family.sql"))
+        .await?;
+    Ok(client.execute(&stmt, &[&family]).await? > 0)
+}
+
+/// End all the sessions of a user
+pub async fn revoke_user(client: &Client, user_id: i32) -> Result<u64, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/revoke_user_refresh_tokens.sql"))
+        .await?;
+    Ok(client.execute(&stmt, &[&user_id]).await?)
+}
@@ -1,30 +1,130 @@
-use argon2::{
-    Argon2,
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
-};
+use deadpool_postgres::Client;
 use http::{Method, Request, Response, StatusCode};
 use hyper::Body;
-use lib_hyper_organizator::authentication::check_security::create_security_cookie;
-use lib_hyper_organizator::authentication::jot::Jot;
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::audit::{
+    self, API_TOKEN_CHANGED, AuditEvent, LOGIN, LOGIN_FAILED, PASSWORD_CHANGED,
+};
+use lib_hyper_organizator::authentication::api_token::{
+    self, ApiToken, ApiTokenList, SCOPES, hash_token,
+};
+use lib_hyper_organizator::authentication::check_security::{
+    clear_security_cookie, create_security_cookie, extract_all_jwt, get_cookie_value,
+};
+use lib_hyper_organizator::authentication::client_certificate::CertificateList;
+use lib_hyper_organizator::authentication::jot::{TokenIssuer, TokenVerifier};
+use lib_hyper_organizator::authentication::password_hash::{StoredPassword, Verification};
+use lib_hyper_organizator::authentication::password_policy::{Violation, Violations};
+use lib_hyper_organizator::authentication::rate_limit::{SlidingWindow, client_ip};
+use lib_hyper_organizator::authentication::revocation::{RevocationList, revoke};
+use lib_hyper_organizator::authentication::totp;
 use lib_hyper_organizator::postgres::get_connection;
 use lib_hyper_organizator::response_utils::{IntoResultHyperResponse, parse_body};
-use lib_hyper_organizator::typedef::{GenericError, UserId};
+use lib_hyper_organizator::server::SETTINGS;
+use lib_hyper_organizator::typedef::{ApiTokenScopes, GenericError, UserId, UserRoles};
 use lib_hyper_organizator::under_construction::default_response;
-use serde::Deserialize;
-use std::sync::Arc;
+use qrcode::QrCode;
+use qrcode::render::svg;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 use tracing::{error, info, warn};
 use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::client_certificate::{self, CERTIFICATE_PATH_PREFIX};
+use crate::db::{self, ApiTokenInfo, Login, RoleChange, UserAccount, UserRoleList, fetch_login};
+use crate::oidc;
+use crate::refresh_token::{self, RefreshToken, Rotation};
+use crate::second_factor;
+use crate::session::{self, SESSION_PATH_PREFIX, SessionOrigin};
+use crate::single_sign_on;
+
+/// Role required to administer users
+pub(crate) const ADMIN_ROLE: &str = "orgadm";
+/// Role of new users, the others are granted separately
+const DEFAULT_ROLE: &str = "org";
+
+/// Clients without cookies get the refresh token in this header and send it back in it
+const REFRESH_TOKEN_HEADER: &str = "x-organizator-refresh-token";
+const REFRESH_COOKIE_NAME_PREFIX: &str = "__Secure-refresh=";
+/// Browsers send the refresh cookie only to the refresh endpoint, as published by nginx
+const REFRESH_COOKIE_PATH: &str = "/organizator/refresh";
 
-use crate::db::{self, Login, fetch_login};
+/// Set on the login response when the password has to be changed, the login is retried
+/// with the new one in the new_password field
+const PASSWORD_CHANGE_HEADER: &str = "x-organizator-password-change";
+
+/// Set on the login response when a code from the authenticator app has to follow
+const TOTP_CHALLENGE_HEADER: &str = "x-organizator-totp-challenge";
+/// Shown by the authenticator app next to the account
+const TOTP_ISSUER: &str = "Organizator";
+
+/// Followed by the id of the token to revoke
+const API_TOKEN_PATH_PREFIX: &str = "/tokens/";
+
+/// Login attempts per username
+static LOGIN_BY_USERNAME: LazyLock<SlidingWindow> = LazyLock::new(|| {
+    let limits = &SETTINGS.security.login_limits;
+    SlidingWindow::new(Duration::from_secs(limits.window), limits.per_username)
+});
+/// Login attempts per client address
+static LOGIN_BY_IP: LazyLock<SlidingWindow> = LazyLock::new(|| {
+    let limits = &SETTINGS.security.login_limits;
+    SlidingWindow::new(Duration::from_secs(limits.window), limits.per_ip)
+});
+/// Checked for unknown users, with the configured cost, so they take as long as the known ones
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    refresh_token::random_token()
+        .and_then(|password| hash_password(&password))
+        .unwrap_or_default()
+});
 
 /// All requests to the server are handled by this function.
 pub async fn router(request: Request<Body>) -> Result<Response<Body>, GenericError> {
     match (request.method(), request.uri().path()) {
         (&Method::POST, "/login") => login(request).await,
+        (&Method::POST, "/login/totp") => login_totp(request).await,
+        (&Method::GET, "/login/oidc") => single_sign_on::login(request).await,
+        (&Method::GET, "/login/oidc/callback") => single_sign_on::callback(request).await,
         (&Method::GET, "/refresh") => refresh(request).await,
         (&Method::GET, "/logout") => logout(request).await,
         (&Method::GET, "/public") => public_key(request).await,
+        (&Method::GET, "/.well-known/jwks.json") => jwks(request).await,
         (&Method::POST, "/password") => update_password(request).await,
+        (&Method::GET, "/roles") => list_roles(request).await,
+        (&Method::POST, "/roles/grant") => change_role(request, RoleChange::Grant).await,
+        (&Method::POST, "/roles/revoke") => change_role(request, RoleChange::Revoke).await,
+        (&Method::POST, "/totp/enrol") => enrol_totp(request).await,
+        (&Method::POST, "/totp/confirm") => confirm_totp(request).await,
+        (&Method::POST, "/totp/reset") => reset_totp(request).await,
+        (&Method::GET, "/users") => list_users(request).await,
+        (&Method::POST, "/users") => create_user(request).await,
+        (&Method::POST, "/users/disable") => set_user_disabled(request, true).await,
+        (&Method::POST, "/users/enable") => set_user_disabled(request, false).await,
+        (&Method::POST, "/users/rename") => rename_user(request).await,
+        (&Method::POST, "/users/delete") => delete_user(request).await,
+        (&Method::POST, "/users/password") => reset_password(request).await,
+        (&Method::POST, "/tokens") => create_api_token(request).await,
+        (&Method::GET, "/tokens") => list_api_tokens(request).await,
+        (&Method::POST, "/certificates") => client_certificate::register(request).await,
+        (&Method::GET, "/certificates") => client_certificate::list(request).await,
+        (&Method::GET, "/sessions") => session::list(request).await,
+        (&Method::DELETE, "/sessions") => session::revoke_all(request).await,
+        (&Method::GET, "/oidc/.well-known/openid-configuration") => oidc::discovery(request).await,
+        (&Method::GET, "/oidc/authorize") => oidc::authorize(request).await,
+        (&Method::POST, "/oidc/token") => oidc::token(request).await,
+        (&Method::GET, "/oidc/userinfo") => oidc::userinfo(request).await,
+        (&Method::GET, "/oidc/jwks") => jwks(request).await,
+        (&Method::DELETE, path) if path.starts_with(API_TOKEN_PATH_PREFIX) => {
+            delete_api_token(request).await
+        }
+        (&Method::DELETE, path) if path.starts_with(CERTIFICATE_PATH_PREFIX) => {
+            client_certificate::revoke(request).await
+        }
+        (&Method::DELETE, path) if path.starts_with(SESSION_PATH_PREFIX) => {
+            session::revoke(request).await
+        }
 
         _ => default_response(request).await,
     }
@@ -35,6 +135,9 @@ struct LoginForm {
     username: String,
     #[schema(format = Password)]
     password: String,
+    /// required when the password was set by an administrator
+    #[schema(format = Password)]
+    new_password: Option<String>,
 }
 
 #[utoipa::path(post, path="/login", 
@@ -42,36 +145,334 @@ struct LoginForm {
     //request_body=LoginForm,
     request_body(content = LoginForm, content_type = "application/x-www-form-urlencoded"),
     responses(
-        (status=200, description="Login successful", body=String),
+        (status=200, description="Login successful, the access token; the refresh token is in the x-organizator-refresh-token header", body=String),
+        (status=204, description="Login successful, tokens set as cookies, for the web client"),
+        (status=401, description="Bad username or password, or the second factor is required: the challenge is in the x-organizator-totp-challenge header"),
+        (status=400, description="The new_password breaks the password policy, the violations as JSON"),
+        (status=403, description="The password has to be changed, login again with new_password, or send it with the second factor; x-organizator-password-change header set"),
+        (status=429, description="Too many attempts or account locked, see Retry-After"),
     ),
 )]
 async fn login(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
     let form: LoginForm = parse_body(&mut request).await?;
 
-    let client = get_connection(&request).await?;
-
     if form.username.is_empty() {
         error!("Username is empty");
         return "Username is empty".to_text_response_with_status(StatusCode::UNAUTHORIZED);
     }
-    let login = fetch_login(&client, &form.username).await?;
-    if !verify_password(&form.password, &login) {
-        return "Bad password".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+
+    // throttled before the database and the password hash get any work
+    let ip = client_ip(&request);
+    if let Err(wait) = throttle(ip.as_deref(), &form.username) {
+        return too_many_attempts(wait);
     }
 
-    let Some(jot) = request.extensions().get::<Arc<Jot>>() else {
-        return "No Jot".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    let client = get_connection(&request).await?;
+    let now = get_current_timestamp() as i64;
+
+    let Some(login) = db::find_login(&client, &form.username).await? else {
+        let hashing = &SETTINGS.security.password_hashing;
+        hashing.verify(&form.password, StoredPassword::Phc(&DUMMY_PASSWORD_HASH));
+        return login_failed(&client, &form.username, ip.as_deref(), "unknown user", now).await;
     };
-    let roles = if login.id == 1 {
-        vec!["org", "orgadm", "photo"]
-    } else {
-        vec!["org", "photo"]
+    if let Some(response) = refuse_locked(&client, &login, ip.as_deref(), now).await? {
+        return Ok(response);
+    }
+    match verify_password(&form.password, &login) {
+        Verification::Invalid => {
+            return count_failed_login(&client, &login, ip.as_deref(), "bad password", now).await;
+        }
+        Verification::Valid => {}
+        Verification::ValidNeedsRehash => rehash_password(&client, &login, &form.password).await?,
+    }
+    if login.disabled {
+        return login_failed(&client, &form.username, ip.as_deref(), "disabled", now).await;
+    }
+
+    // the password set by an administrator is replaced only once all the factors are checked
+    if login.totp_enabled {
+        let challenge = second_factor::create_challenge(&client, login.id).await?;
+        info!("User 「{}」 has to send the second factor", form.username);
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header("content-type", "text/plain; charset=utf-8")
+            .header(TOTP_CHALLENGE_HEADER, challenge)
+            .header("server", "hyper")
+            .body(Body::from("Second factor required"))?);
+    }
+    if let Some(response) =
+        change_required_password(&request, &client, &login, form.new_password.as_deref()).await?
+    {
+        return Ok(response);
+    }
+    complete_login(&request, &client, &login).await
+}
+
+fn password_change_required() -> Result<Response<Body>, GenericError> {
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header(PASSWORD_CHANGE_HEADER, "required")
+        .header("server", "hyper")
+        .body(Body::from("Password change required"))?)
+}
+
+/// A new password, different from the current one
+fn chosen_password<'a>(new_password: Option<&'a str>, login: &Login) -> Option<&'a str> {
+    let new_password = new_password.filter(|password| !password.is_empty())?;
+    let hashing = &SETTINGS.security.password_hashing;
+    let same = login.password_hash.as_deref().is_some_and(|hash| {
+        hashing.verify(new_password, StoredPassword::Phc(hash)) != Verification::Invalid
+    });
+    (!same).then_some(new_password)
+}
+
+/// Replace the password set by an administrator with the one the user chose, the response
+/// if it is missing or refused. Called when all the factors were checked.
+async fn change_required_password(
+    request: &Request<Body>,
+    client: &Client,
+    login: &Login,
+    new_password: Option<&str>,
+) -> Result<Option<Response<Body>>, GenericError> {
+    if !login.must_change_password {
+        return Ok(None);
+    }
+    let username = login.username.as_deref().unwrap_or_default();
+    let Some(new_password) = chosen_password(new_password, login) else {
+        info!("User 「{username}」 has to change the password");
+        return password_change_required().map(Some);
+    };
+    if let Some(response) = refuse_password(client, login, new_password).await? {
+        return Ok(Some(response));
+    }
+    let password_hash = hash_password(new_password)?;
+    db::update_password(client, username, username, &password_hash).await?;
+    record_password(client, login.id, &password_hash).await?;
+    db::set_must_change_password(client, login.id, false).await?;
+    let event = AuditEvent::new(PASSWORD_CHANGED, username)
+        .from_request(request)
+        .detail("required", true);
+    audit::record(client, event).await?;
+    info!("User 「{username}」 changed the password set by an administrator");
+    Ok(None)
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct TotpLoginForm {
+    /// from the x-organizator-totp-challenge header of the login response
+    challenge: String,
+    /// from the authenticator app, or a recovery code
+    code: String,
+    /// required when the password was set by an administrator
+    #[schema(format = Password)]
+    new_password: Option<String>,
+}
+
+#[utoipa::path(post, path="/login/totp",
+    request_body(content = TotpLoginForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Login successful, the access token; the refresh token is in the x-organizator-refresh-token header", body=String),
+        (status=204, description="Login successful, tokens set as cookies, for the web client"),
+        (status=401, description="Bad code or expired challenge"),
+        (status=400, description="The new_password breaks the password policy, the violations as JSON"),
+        (status=403, description="The password has to be changed, send the code again with new_password; x-organizator-password-change header set"),
+        (status=429, description="Too many attempts or account locked, see Retry-After"),
+    ),
+)]
+/// Second step of a login with a second factor
+async fn login_totp(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let form: TotpLoginForm = parse_body(&mut request).await?;
+    let ip = client_ip(&request);
+    if let Err(wait) = LOGIN_BY_IP.attempt(ip.as_deref().unwrap_or("unknown")) {
+        return too_many_attempts(wait);
+    }
+
+    let client = get_connection(&request).await?;
+    let now = get_current_timestamp() as i64;
+    let Some(username) = second_factor::challenge_user(&client, &form.challenge).await? else {
+        warn!("Unknown or expired second factor challenge from {ip:?}");
+        return "Login again".to_text_response_with_status(StatusCode::UNAUTHORIZED);
     };
-    let new_token: String = jot.generate_token(&form.username, &roles)?;
-    info!("User 「{}」 logged in", &form.username);
+    if let Err(wait) = LOGIN_BY_USERNAME.attempt(&username) {
+        return too_many_attempts(wait);
+    }
+    let login = fetch_login(&client, &username).await?;
+    if let Some(response) = refuse_locked(&client, &login, ip.as_deref(), now).await? {
+        return Ok(response);
+    }
+    // asked before the code is used up, the challenge stays valid for the next attempt
+    if login.must_change_password && chosen_password(form.new_password.as_deref(), &login).is_none()
+    {
+        info!("User 「{username}」 has to change the password");
+        return password_change_required();
+    }
+    if !second_factor::verify(&client, login.id, &form.code).await? {
+        return count_failed_login(&client, &login, ip.as_deref(), "bad second factor", now).await;
+    }
+    if let Some(response) =
+        change_required_password(&request, &client, &login, form.new_password.as_deref()).await?
+    {
+        return Ok(response);
+    }
+    second_factor::end_challenge(&client, &form.challenge).await?;
+    complete_login(&request, &client, &login).await
+}
+
+/// Count the attempt, returns how long to wait if there were too many
+fn throttle(ip: Option<&str>, username: &str) -> Result<(), Duration> {
+    LOGIN_BY_IP
+        .attempt(ip.unwrap_or("unknown"))
+        .and_then(|_| LOGIN_BY_USERNAME.attempt(username))
+        .inspect_err(|wait| {
+            warn!("Too many logins for 「{username}」 from {ip:?}, next one allowed in {wait:?}")
+        })
+}
+
+/// The 429 response if the account is locked
+async fn refuse_locked(
+    client: &Client,
+    login: &Login,
+    ip: Option<&str>,
+    now: i64,
+) -> Result<Option<Response<Body>>, GenericError> {
+    let username = login.username.as_deref().unwrap_or_default();
+    match login.locked_until {
+        Some(locked_until) if locked_until > now => {
+            db::record_login_failure(client, username, ip, "locked", now).await?;
+            let event = AuditEvent::new(LOGIN_FAILED, username)
+                .ip(ip)
+                .detail("reason", "locked");
+            audit::record(client, event).await?;
+            warn!("Login for locked account 「{username}」 from {ip:?}");
+            too_many_attempts(Duration::from_secs((locked_until - now) as u64)).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A wrong password or code, enough of them lock the account
+async fn count_failed_login(
+    client: &Client,
+    login: &Login,
+    ip: Option<&str>,
+    reason: &str,
+    now: i64,
+) -> Result<Response<Body>, GenericError> {
+    let limits = &SETTINGS.security.login_limits;
+    let username = login.username.as_deref().unwrap_or_default();
+    let lock_until = now + limits.lockout_duration as i64;
+    if db::count_failed_login(client, login.id, limits.lockout_threshold, lock_until).await? {
+        warn!(
+            "Account 「{username}」 locked for {}s after {} failed logins",
+            limits.lockout_duration, limits.lockout_threshold
+        );
+    }
+    login_failed(client, username, ip, reason, now).await
+}
+
+/// All the factors were right, hand out the tokens
+async fn complete_login<B>(
+    request: &Request<B>,
+    client: &Client,
+    login: &Login,
+) -> Result<Response<Body>, GenericError> {
+    let Some(issuer) = request.extensions().get::<Arc<TokenIssuer>>() else {
+        return "No TokenIssuer".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let origin = SessionOrigin::of(request);
+    let (new_token, refresh_token) = new_session(issuer, client, login, &origin).await?;
+    let username = login.username.as_deref().unwrap_or_default();
+    let method = if login.totp_enabled { "password+totp" } else { "password" };
+    let event = AuditEvent::new(LOGIN, username)
+        .from_request(request)
+        .detail("method", method);
+    audit::record(client, event).await?;
+    info!("User 「{username}」 logged in");
+    token_response(request, new_token, &refresh_token)
+}
+
+/// Start a session for the user, the access token and the refresh token of the session
+pub(crate) async fn new_session(
+    issuer: &TokenIssuer,
+    client: &Client,
+    login: &Login,
+    origin: &SessionOrigin,
+) -> Result<(String, RefreshToken), GenericError> {
+    db::clear_failed_logins(client, login.id).await?;
+    db::record_login(client, login.id, get_current_timestamp() as i64).await?;
+
+    let username = login.username.as_deref().unwrap_or_default();
+    let refresh_token =
+        refresh_token::issue(client, login.id, SETTINGS.security.refresh_token_expiry).await?;
+    session::start(client, &refresh_token, login.id, origin).await?;
+    let roles: Vec<&str> = login.roles.iter().map(String::as_str).collect();
+    let new_token: String =
+        issuer.generate_session_token(username, &roles, &refresh_token.family.to_string())?;
+    Ok((new_token, refresh_token))
+}
+
+/// Audit the failure, the client is not told what was wrong
+async fn login_failed(
+    client: &Client,
+    username: &str,
+    ip: Option<&str>,
+    reason: &str,
+    now: i64,
+) -> Result<Response<Body>, GenericError> {
+    warn!("Failed login for 「{username}」 from {ip:?}: {reason}");
+    db::record_login_failure(client, username, ip, reason, now).await?;
+    let event = AuditEvent::new(LOGIN_FAILED, username)
+        .ip(ip)
+        .detail("reason", reason);
+    audit::record(client, event).await?;
+    "Bad username or password".to_text_response_with_status(StatusCode::UNAUTHORIZED)
+}
+
+fn too_many_attempts(wait: Duration) -> Result<Response<Body>, GenericError> {
+    // whole seconds, rounded up so the client does not come back too early
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Ok(Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header("Retry-After", retry_after.max(1))
+        .header("server", "hyper")
+        .body(Body::from("Too many login attempts, try again later"))?)
+}
+
+/// The account is changed with a login, an API token only reaches the memos; a stolen token can
+/// not mint more tokens, take over the second factor or act as an administrator
+pub(crate) fn refuse_api_token<B>(
+    request: &Request<B>,
+) -> Option<Result<Response<Body>, GenericError>> {
+    request.extensions().get::<ApiTokenScopes>().map(|_| {
+        "Not with an API token, log in".to_text_response_with_status(StatusCode::FORBIDDEN)
+    })
+}
+
+pub(crate) fn create_refresh_cookie(refresh_token: &str) -> String {
+    format!(
+        "{REFRESH_COOKIE_NAME_PREFIX}{refresh_token}; HttpOnly; Secure; SameSite=Strict; Path={REFRESH_COOKIE_PATH}; Max-Age={}",
+        SETTINGS.security.refresh_token_expiry
+    )
+}
+
+fn clear_refresh_cookie() -> String {
+    format!(
+        "{REFRESH_COOKIE_NAME_PREFIX}; HttpOnly; Secure; SameSite=Strict; Path={REFRESH_COOKIE_PATH}; Max-Age=0"
+    )
+}
+
+/// Hand new tokens to the client
+fn token_response<B>(
+    request: &Request<B>,
+    new_token: String,
+    refresh_token: &RefreshToken,
+) -> Result<Response<Body>, GenericError> {
     let cookie = create_security_cookie(&new_token);
+    let refresh_cookie = create_refresh_cookie(&refresh_token.token);
 
-    // if it has the web client header, return just the cookie
+    // if it has the web client header, return just the cookies
     if request
         .headers()
         .get("x-organizator-client-version")
@@ -81,6 +482,7 @@ async fn login(mut request: Request<Body>) -> Result<Response<Body>, GenericErro
             .status(StatusCode::NO_CONTENT)
             .header("content-type", "text/plain; charset=utf-8")
             .header("Set-Cookie", cookie)
+            .header("Set-Cookie", refresh_cookie)
             .header("server", "hyper")
             .body(Body::empty())?)
     } else {
@@ -88,30 +490,95 @@ async fn login(mut request: Request<Body>) -> Result<Response<Body>, GenericErro
             .status(StatusCode::OK)
             .header("content-type", "text/plain; charset=utf-8")
             .header("Set-Cookie", cookie)
+            .header("Set-Cookie", refresh_cookie)
+            .header(REFRESH_TOKEN_HEADER, &refresh_token.token)
             .header("server", "hyper")
             .body(Body::from(new_token))?)
     }
 }
 
-pub fn verify_password(password: &str, login: &Login) -> bool {
-    if let Some(password_hash_string) = &login.password_hash {
-        let password_hash = PasswordHash::new(password_hash_string).unwrap();
-        let ok = Argon2::default()
-            .verify_password(password.as_bytes(), &password_hash)
-            .is_ok();
-        if ok {
-            info!("Password for user 「{:?}」is correct", login.username);
-        } else {
-            warn!(
-                "Password hash found for user 「{:?}」 but password is incorrect",
-                login.username
-            );
+pub fn verify_password(password: &str, login: &Login) -> Verification {
+    let stored = match (&login.password_hash, &login.pbkdf2, &login.salt) {
+        (Some(password_hash), _, _) => StoredPassword::Phc(password_hash),
+        (None, Some(hash), Some(salt)) => StoredPassword::Pbkdf2 { hash, salt },
+        _ => {
+            warn!("No password hash found for user 「{:?}」", login.username);
+            return Verification::Invalid;
         }
-        ok
+    };
+    let verification = SETTINGS.security.password_hashing.verify(password, stored);
+    if verification == Verification::Invalid {
+        warn!(
+            "Password hash found for user 「{:?}」 but password is incorrect",
+            login.username
+        );
     } else {
-        warn!("No password hash found for user 「{:?}」", login.username);
-        false
+        info!("Password for user 「{:?}」is correct", login.username);
     }
+    verification
+}
+
+/// A new hash and salt for the password, with the configured cost
+fn hash_password(password: &str) -> Result<String, GenericError> {
+    SETTINGS.security.password_hashing.hash(password)
+}
+
+/// Replace a legacy or weaker hash while the password is at hand
+async fn rehash_password(
+    client: &Client,
+    login: &Login,
+    password: &str,
+) -> Result<(), GenericError> {
+    let username = login.username.as_deref().unwrap_or_default();
+    let password_hash = hash_password(password)?;
+    db::update_password(client, username, username, &password_hash).await?;
+    info!("Password hash of 「{username}」 upgraded");
+    Ok(())
+}
+
+/// The response refusing the password, None if the policy accepts it
+async fn refuse_password(
+    client: &Client,
+    login: &Login,
+    password: &str,
+) -> Result<Option<Response<Body>>, GenericError> {
+    let policy = &SETTINGS.security.password_policy;
+    let username = login.username.as_deref().unwrap_or_default();
+    let mut violations = policy.check(password, &[username]);
+    // nor is a password too long hashed to compare it with the previous ones
+    let too_long = matches!(violations.as_slice(), [Violation::TooLong { .. }]);
+    if policy.history > 0 && !too_long {
+        // the current one counts even if it was set before the history was kept
+        let mut hashes = db::fetch_password_history(client, login.id, policy.history).await?;
+        hashes.extend(login.password_hash.clone());
+        let hashing = &SETTINGS.security.password_hashing;
+        let reused = hashes.iter().any(|hash| {
+            hashing.verify(password, StoredPassword::Phc(hash)) != Verification::Invalid
+        });
+        if reused {
+            violations.push(Violation::Reused {
+                history: policy.history,
+            });
+        }
+    }
+    if violations.is_empty() {
+        return Ok(None);
+    }
+    info!("Password of 「{username}」 refused: {violations:?}");
+    serde_json::to_string(&Violations { violations })?
+        .to_json_response_with_status(StatusCode::BAD_REQUEST)
+        .map(Some)
+}
+
+/// Keep the hash of a password the user chose, it can not be chosen again for a while
+async fn record_password(
+    client: &Client,
+    user_id: i32,
+    password_hash: &str,
+) -> Result<(), GenericError> {
+    let now = get_current_timestamp() as i64;
+    let keep = SETTINGS.security.password_policy.history;
+    db::record_password(client, user_id, password_hash, now, keep).await
 }
 
 #[derive(Deserialize, Debug, Clone, ToSchema)]
@@ -122,6 +589,9 @@ struct ChangePasswordForm {
 }
 
 async fn update_password(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
     let form: ChangePasswordForm = parse_body(&mut request).await?;
 
     // get the current logged in user from the request
@@ -133,48 +603,873 @@ async fn update_password(mut request: Request<Body>) -> Result<Response<Body>, G
 
     // check the old password was correctly supplied
     let login = fetch_login(&client, requester).await?;
-    if !verify_password(&form.old_password, &login) {
+    if verify_password(&form.old_password, &login) == Verification::Invalid {
         return "Bad old password".to_text_response_with_status(StatusCode::UNAUTHORIZED);
     }
 
-    // compute the new password hash and salt
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(form.new_password.as_bytes(), &salt)?
-        .to_string();
-
     // use the form username if supplied and not empty, otherwise use the requester
     let username = match form.username {
         Some(ref username) if !username.is_empty() => username,
         _ => requester,
     };
+    let target = if username == requester {
+        login
+    } else {
+        let Some(target) = db::find_login(&client, username).await? else {
+            return "Unknown user".to_text_response_with_status(StatusCode::NOT_FOUND);
+        };
+        target
+    };
+    if let Some(response) = refuse_password(&client, &target, &form.new_password).await? {
+        return Ok(response);
+    }
+
+    let password_hash = hash_password(&form.new_password)?;
     db::update_password(&client, requester, username, &password_hash).await?;
+    record_password(&client, target.id, &password_hash).await?;
+    if username == requester {
+        db::set_must_change_password(&client, target.id, false).await?;
+    }
+    let event = AuditEvent::new(PASSWORD_CHANGED, username)
+        .by(requester)
+        .from_request(&request);
+    audit::record(&client, event).await?;
     info!("User 「{requester}」 updated password for 「{username}」");
     "Password updated".to_text_response()
 }
 
+#[utoipa::path(get, path="/refresh",
+    responses(
+        (status=200, description="New access token; the new refresh token is in the x-organizator-refresh-token header", body=String),
+        (status=204, description="New tokens set as cookies, for the web client"),
+        (status=401, description="Refresh token missing, expired, revoked or already used"),
+    ),
+)]
+/// Exchange a refresh token for a new access token and a new refresh token. The other
+/// services send clients here when their access token is in the grace period.
 async fn refresh(request: Request<Body>) -> Result<Response<Body>, GenericError> {
-    let Some(jot) = request.extensions().get::<Arc<Jot>>() else {
-        return "No Jot".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    let Some(issuer) = request.extensions().get::<Arc<TokenIssuer>>() else {
+        return "No TokenIssuer".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let presented = match request.headers().get(REFRESH_TOKEN_HEADER) {
+        Some(header) => Some(header.to_str()?),
+        None => get_cookie_value(&request, REFRESH_COOKIE_NAME_PREFIX),
     };
-    let Some(token) = request.headers().get("Authorization") else {
-        return "No Authorization header".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    let Some(presented) = presented else {
+        return "No refresh token".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let mut client = get_connection(&request).await?;
+    let (refresh_token, username) = match refresh_token::rotate(&mut client, presented).await? {
+        Rotation::Rotated {
+            refresh_token,
+            username,
+        } => (refresh_token, username),
+        Rotation::Reused { family } => {
+            if let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() {
+                session::end(&client, revocation_list, family, None).await?;
+            }
+            return "Refresh token already used, log in again"
+                .to_text_response_with_status(StatusCode::UNAUTHORIZED);
+        }
+        Rotation::Invalid => {
+            return "Invalid refresh token".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+        }
     };
-    let token = token.to_str()?;
-    let new_token = jot.refresh_token(token)?;
-    new_token.to_text_response()
+    session::touch(&client, refresh_token.family).await?;
+    // pick up the roles granted or revoked since the last token was issued
+    let login = fetch_login(&client, &username).await?;
+    if login.disabled {
+        warn!("Refresh for disabled user 「{username}」");
+        return "User disabled".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    }
+    let roles: Vec<&str> = login.roles.iter().map(String::as_str).collect();
+    let new_token =
+        issuer.generate_session_token(&username, &roles, &refresh_token.family.to_string())?;
+    info!("Token of 「{username}」 refreshed");
+    token_response(&request, new_token, &refresh_token)
+}
+
+fn is_admin<B>(request: &Request<B>) -> bool {
+    request
+        .extensions()
+        .get::<UserRoles>()
+        .is_some_and(|UserRoles(roles)| roles.iter().any(|r| r == ADMIN_ROLE))
+}
+
+#[utoipa::path(get, path="/roles",
+    responses(
+        (status=200, description="Users and the roles granted to them", body=Vec<UserRoleList>),
+        (status=403, description="Reserved for administrators, not with an API token"),
+    ),
+)]
+async fn list_roles(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let client = get_connection(&request).await?;
+    let roles = db::fetch_user_roles(&client).await?;
+    serde_json::to_string(&roles)?.to_json_response()
 }
 
-async fn logout(_request: Request<Body>) -> Result<Response<Body>, GenericError> {
-    "Not implemented".to_text_response_with_status(StatusCode::NOT_IMPLEMENTED)
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct RoleForm {
+    username: String,
+    role: String,
+}
+
+#[utoipa::path(post, path="/roles/{action}",
+    request_body(content = RoleForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Role granted or revoked, effective at the next login or refresh"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=404, description="Unknown user or role"),
+    ),
+    params(
+        ("action" = String, Path, description="grant or revoke"),
+    ),
+)]
+async fn change_role(
+    mut request: Request<Body>,
+    change: RoleChange,
+) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: RoleForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    // an administrator locking themselves out would need the database to get back in
+    if matches!(change, RoleChange::Revoke)
+        && form.role == ADMIN_ROLE
+        && &form.username == requester
+    {
+        return "Can not revoke your own administrator role"
+            .to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+
+    let client = get_connection(&request).await?;
+    let action = match change {
+        RoleChange::Grant => "granted",
+        RoleChange::Revoke => "revoked",
+    };
+    if !db::change_role(&client, &form.username, &form.role, change).await? {
+        return "Unknown user or role".to_text_response_with_status(StatusCode::NOT_FOUND);
+    }
+    let event = AuditEvent::admin(requester, &format!("role_{action}"), Some(&form.username))
+        .from_request(&request)
+        .detail("role", form.role.as_str());
+    audit::record(&client, event).await?;
+    info!(
+        "User 「{requester}」 {action} role {} for 「{}」",
+        form.role, form.username
+    );
+    format!("Role {action}").to_text_response()
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+struct TotpEnrolment {
+    /// for the authenticator app
+    uri: String,
+    /// the uri as QR code
+    svg: String,
+}
+
+#[utoipa::path(post, path="/totp/enrol",
+    responses(
+        (status=200, description="New secret, active after confirmation", body=TotpEnrolment),
+        (status=409, description="The second factor is already enabled"),
+        (status=403, description="Not with an API token"),
+    ),
+)]
+/// Start the enrolment of an authenticator app for the requester
+async fn enrol_totp(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let client = get_connection(&request).await?;
+    let login = fetch_login(&client, requester).await?;
+    let Some(secret) = second_factor::enrol(&client, login.id).await? else {
+        return "Second factor already enabled, ask an administrator to reset it"
+            .to_text_response_with_status(StatusCode::CONFLICT);
+    };
+    let uri = totp::otpauth_uri(TOTP_ISSUER, requester, &secret);
+    let svg = QrCode::new(uri.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    info!("User 「{requester}」 started the second factor enrolment");
+    serde_json::to_string(&TotpEnrolment { uri, svg })?.to_json_response()
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct TotpCodeForm {
+    code: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+struct RecoveryCodes {
+    /// each one can replace a code from the app once, they are not shown again
+    recovery_codes: Vec<String>,
+}
+
+#[utoipa::path(post, path="/totp/confirm",
+    request_body(content = TotpCodeForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Second factor enabled", body=RecoveryCodes),
+        (status=400, description="Wrong code or nothing to confirm"),
+        (status=403, description="Not with an API token"),
+    ),
+)]
+/// Enable the enrolled secret with a first code from the app
+async fn confirm_totp(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let form: TotpCodeForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let client = get_connection(&request).await?;
+    let login = fetch_login(&client, requester).await?;
+    let Some(recovery_codes) = second_factor::confirm(&client, login.id, &form.code).await? else {
+        return "Wrong code or nothing to confirm"
+            .to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    info!("User 「{requester}」 enabled the second factor");
+    serde_json::to_string(&RecoveryCodes { recovery_codes })?.to_json_response()
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct UsernameForm {
+    username: String,
+}
+
+#[utoipa::path(post, path="/totp/reset",
+    request_body(content = UsernameForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Second factor and recovery codes removed"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=404, description="Unknown user"),
+    ),
+)]
+/// For users who lost both the device and the recovery codes
+async fn reset_totp(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: UsernameForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let client = get_connection(&request).await?;
+    let Some(login) = db::find_login(&client, &form.username).await? else {
+        return "Unknown user".to_text_response_with_status(StatusCode::NOT_FOUND);
+    };
+    second_factor::reset(&client, login.id).await?;
+    let event = AuditEvent::admin(requester, "second_factor_removed", Some(&form.username))
+        .from_request(&request);
+    audit::record(&client, event).await?;
+    info!(
+        "User 「{requester}」 removed the second factor of 「{}」",
+        form.username
+    );
+    "Second factor removed".to_text_response()
+}
+
+#[utoipa::path(get, path="/users",
+    responses(
+        (status=200, description="Users with their roles, account state and last login", body=Vec<UserAccount>),
+        (status=403, description="Reserved for administrators, not with an API token"),
+    ),
+)]
+async fn list_users(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let client = get_connection(&request).await?;
+    let users = db::fetch_users(&client).await?;
+    serde_json::to_string(&users)?.to_json_response()
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct UserForm {
+    username: String,
+    /// to be changed by the user at the first login
+    #[schema(format = Password)]
+    password: String,
+}
+
+#[utoipa::path(post, path="/users",
+    request_body(content = UserForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="User created with the org role and the default memo groups"),
+        (status=400, description="Username missing, or the password breaks the password policy: the violations as JSON"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=409, description="The username is taken"),
+    ),
+)]
+async fn create_user(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: UserForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let username = form.username.trim();
+    if username.is_empty() {
+        return "Username is required".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let violations = SETTINGS
+        .security
+        .password_policy
+        .check(&form.password, &[username]);
+    if !violations.is_empty() {
+        return serde_json::to_string(&Violations { violations })?
+            .to_json_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let password_hash = hash_password(&form.password)?;
+    let mut client = get_connection(&request).await?;
+    if !db::create_user(
+        &mut client,
+        requester,
+        username,
+        &password_hash,
+        DEFAULT_ROLE,
+    )
+    .await?
+    {
+        return "Username taken".to_text_response_with_status(StatusCode::CONFLICT);
+    }
+    let event = AuditEvent::admin(requester, "user_created", Some(username)).from_request(&request);
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 created user 「{username}」");
+    "User created".to_text_response()
+}
+
+/// The user whose own things are managed: the requester, or someone an administrator names.
+/// With the response if the requester may not.
+pub(crate) async fn managed_login(
+    request: &Request<Body>,
+    client: &Client,
+    requester: &str,
+    username: Option<&str>,
+) -> Result<Result<Login, Response<Body>>, GenericError> {
+    match username.filter(|username| *username != requester) {
+        None => Ok(Ok(fetch_login(client, requester).await?)),
+        Some(_) if !is_admin(request) => "Reserved for administrators"
+            .to_text_response_with_status(StatusCode::FORBIDDEN)
+            .map(Err),
+        Some(username) => match db::find_login(client, username).await? {
+            Some(login) => Ok(Ok(login)),
+            None => "Unknown user"
+                .to_text_response_with_status(StatusCode::NOT_FOUND)
+                .map(Err),
+        },
+    }
+}
+
+/// Administrators do not disable, rename, delete or reset their own account
+fn refuse_own_account(
+    requester: &str,
+    username: &str,
+) -> Option<Result<Response<Body>, GenericError>> {
+    // an administrator locking themselves out would need the database to get back in
+    (username == requester).then(|| {
+        "Can not do this to your own account".to_text_response_with_status(StatusCode::BAD_REQUEST)
+    })
+}
+
+/// The account an administrator acts on, after `refuse_own_account`
+async fn administered_login(
+    client: &Client,
+    username: &str,
+) -> Result<Result<Login, Response<Body>>, GenericError> {
+    match db::find_login(client, username).await? {
+        Some(login) => Ok(Ok(login)),
+        None => "Unknown user"
+            .to_text_response_with_status(StatusCode::NOT_FOUND)
+            .map(Err),
+    }
+}
+
+#[utoipa::path(post, path="/users/{action}",
+    request_body(content = UsernameForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="User disabled, the sessions, API tokens and certificates ended, or enabled again"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=404, description="Unknown user"),
+    ),
+    params(
+        ("action" = String, Path, description="disable or enable"),
+    ),
+)]
+async fn set_user_disabled(
+    mut request: Request<Body>,
+    disabled: bool,
+) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: UsernameForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    if let Some(response) = refuse_own_account(requester, &form.username) {
+        return response;
+    }
+    let client = get_connection(&request).await?;
+    let login = match administered_login(&client, &form.username).await? {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    db::set_user_disabled(&client, login.id, disabled).await?;
+    let action = if disabled { "user_disabled" } else { "user_enabled" };
+    let event = AuditEvent::admin(requester, action, Some(&form.username)).from_request(&request);
+    audit::record(&client, event).await?;
+    if !disabled {
+        info!("User 「{requester}」 enabled 「{}」", form.username);
+        return "User enabled".to_text_response();
+    }
+    // the access tokens already issued are refused as well
+    let sessions = session::end_all(&client, revocation_list, login.id).await?;
+    if let Some(api_tokens) = request.extensions().get::<Arc<ApiTokenList>>() {
+        api_tokens.remove_user(&form.username);
+    }
+    if let Some(certificates) = request.extensions().get::<Arc<CertificateList>>() {
+        certificates.remove_user(&form.username);
+    }
+    info!(
+        "User 「{requester}」 disabled 「{}」, {sessions} sessions ended",
+        form.username
+    );
+    "User disabled".to_text_response()
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct RenameForm {
+    username: String,
+    new_username: String,
+}
+
+#[utoipa::path(post, path="/users/rename",
+    request_body(content = RenameForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="User renamed, the user has to log in again"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=404, description="Unknown user"),
+        (status=409, description="The new username is taken"),
+    ),
+)]
+async fn rename_user(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: RenameForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let new_username = form.new_username.trim();
+    if new_username.is_empty() {
+        return "New username is required".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    if let Some(response) = refuse_own_account(requester, &form.username) {
+        return response;
+    }
+    let client = get_connection(&request).await?;
+    let login = match administered_login(&client, &form.username).await? {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    if !db::rename_user(&client, requester, &form.username, new_username).await? {
+        return "Username taken".to_text_response_with_status(StatusCode::CONFLICT);
+    }
+    // the tokens carry the old name
+    session::end_all(&client, revocation_list, login.id).await?;
+    if let Some(api_tokens) = request.extensions().get::<Arc<ApiTokenList>>() {
+        api_tokens.remove_user(&form.username);
+    }
+    if let Some(certificates) = request.extensions().get::<Arc<CertificateList>>() {
+        certificates.remove_user(&form.username);
+    }
+    let event = AuditEvent::admin(requester, "user_renamed", Some(&form.username))
+        .from_request(&request)
+        .detail("new_username", new_username);
+    audit::record(&client, event).await?;
+    info!(
+        "User 「{requester}」 renamed 「{}」 to 「{new_username}」",
+        form.username
+    );
+    "User renamed".to_text_response()
+}
+
+#[utoipa::path(post, path="/users/delete",
+    request_body(content = UsernameForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="User deleted"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=404, description="Unknown user"),
+        (status=409, description="The user still owns memos or files, its sessions are ended anyway"),
+    ),
+)]
+async fn delete_user(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: UsernameForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    if let Some(response) = refuse_own_account(requester, &form.username) {
+        return response;
+    }
+    let mut client = get_connection(&request).await?;
+    let login = match administered_login(&client, &form.username).await? {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    // the ended sessions outlive the user, its access tokens are refused until they expire,
+    // also under the name of a user created later
+    session::end_all(&client, revocation_list, login.id).await?;
+    if !db::delete_user(&mut client, requester, login.id).await? {
+        return "The user still owns memos or files, disable the user instead"
+            .to_text_response_with_status(StatusCode::CONFLICT);
+    }
+    if let Some(api_tokens) = request.extensions().get::<Arc<ApiTokenList>>() {
+        api_tokens.remove_user(&form.username);
+    }
+    if let Some(certificates) = request.extensions().get::<Arc<CertificateList>>() {
+        certificates.remove_user(&form.username);
+    }
+    let event = AuditEvent::admin(requester, "user_deleted", Some(&form.username))
+        .from_request(&request)
+        .detail("user_id", login.id);
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 deleted 「{}」", form.username);
+    "User deleted".to_text_response()
+}
+
+#[utoipa::path(post, path="/users/password",
+    request_body(content = UserForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Password set, to be changed at the next login"),
+        (status=400, description="The password breaks the password policy, the violations as JSON"),
+        (status=403, description="Reserved for administrators, not with an API token"),
+        (status=404, description="Unknown user"),
+    ),
+)]
+/// For users who forgot their password
+async fn reset_password(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    if !is_admin(&request) {
+        return "Reserved for administrators".to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let form: UserForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    if let Some(response) = refuse_own_account(requester, &form.username) {
+        return response;
+    }
+    let client = get_connection(&request).await?;
+    let login = match administered_login(&client, &form.username).await? {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    if let Some(response) = refuse_password(&client, &login, &form.password).await? {
+        return Ok(response);
+    }
+    let password_hash = hash_password(&form.password)?;
+    db::update_password(&client, requester, &form.username, &password_hash).await?;
+    db::set_must_change_password(&client, login.id, true).await?;
+    // whoever knew the old password is logged out
+    session::end_all(&client, revocation_list, login.id).await?;
+    let event = AuditEvent::new(PASSWORD_CHANGED, &form.username)
+        .by(requester)
+        .from_request(&request)
+        .detail("reset", true);
+    audit::record(&client, event).await?;
+    info!(
+        "User 「{requester}」 reset the password of 「{}」",
+        form.username
+    );
+    "Password reset".to_text_response()
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct ApiTokenForm {
+    /// to tell the tokens of a user apart
+    name: String,
+    /// space separated, among memo:read, memo:write, files:read and files:write
+    scopes: String,
+    /// never expires if missing
+    expires_in_days: Option<u64>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+struct NewApiToken {
+    id: i32,
+    /// shown only this once
+    token: String,
+}
+
+#[utoipa::path(post, path="/tokens",
+    request_body(content = ApiTokenForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status=200, description="Token created, use it as Bearer token", body=NewApiToken),
+        (status=400, description="Unknown scope or missing name"),
+        (status=403, description="Not with an API token"),
+        (status=409, description="A token with that name already exists"),
+    ),
+)]
+/// Create a personal API token for the requester
+async fn create_api_token(mut request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let form: ApiTokenForm = parse_body(&mut request).await?;
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(api_tokens) = request.extensions().get::<Arc<ApiTokenList>>() else {
+        return "No API token list".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let name = form.name.trim();
+    let scopes: Vec<String> = form.scopes.split_whitespace().map(String::from).collect();
+    if name.is_empty() || scopes.is_empty() {
+        return "Name and scopes are required"
+            .to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    if let Some(unknown) = scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        return format!("Unknown scope {unknown}")
+            .to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+
+    let client = get_connection(&request).await?;
+    let login = fetch_login(&client, requester).await?;
+    let token = api_token::generate_token()?;
+    let token_hash = hash_token(&token);
+    let now = get_current_timestamp();
+    let expires_at = form.expires_in_days.map(|days| now + days * 24 * 3600);
+    let Some(id) = db::insert_api_token(
+        &client,
+        login.id,
+        name,
+        &token_hash,
+        &scopes,
+        now as i64,
+        expires_at.map(|exp| exp as i64),
+    )
+    .await?
+    else {
+        return "A token with that name already exists"
+            .to_text_response_with_status(StatusCode::CONFLICT);
+    };
+    // usable here at once, the other services pick it up at their next reload
+    api_tokens.insert(
+        token_hash,
+        ApiToken {
+            id,
+            username: requester.to_string(),
+            scopes,
+            expires_at,
+        },
+    );
+    let event = AuditEvent::new(API_TOKEN_CHANGED, requester)
+        .from_request(&request)
+        .detail("action", "created")
+        .detail("token", id)
+        .detail("name", name)
+        .detail("scopes", form.scopes.as_str());
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 created API token {id} 「{name}」");
+    serde_json::to_string(&NewApiToken { id, token })?.to_json_response()
+}
+
+#[utoipa::path(get, path="/tokens",
+    responses(
+        (status=200, description="The API tokens of the requester, without the tokens", body=Vec<ApiTokenInfo>),
+        (status=403, description="Not with an API token"),
+    ),
+)]
+async fn list_api_tokens(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let client = get_connection(&request).await?;
+    let login = fetch_login(&client, requester).await?;
+    let tokens = db::fetch_api_tokens(&client, login.id).await?;
+    serde_json::to_string(&tokens)?.to_json_response()
+}
+
+#[utoipa::path(delete, path="/tokens/{id}",
+    responses(
+        (status=200, description="Token revoked"),
+        (status=403, description="Not with an API token"),
+        (status=404, description="No such token of the requester"),
+    ),
+    params(
+        ("id" = i32, Path, description="Id of the token"),
+    ),
+)]
+async fn delete_api_token(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(api_tokens) = request.extensions().get::<Arc<ApiTokenList>>() else {
+        return "No API token list".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let Some(Ok(id)) = request
+        .uri()
+        .path()
+        .strip_prefix(API_TOKEN_PATH_PREFIX)
+        .map(str::parse::<i32>)
+    else {
+        return "Bad token id".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let client = get_connection(&request).await?;
+    let login = fetch_login(&client, requester).await?;
+    let Some(token_hash) = db::delete_api_token(&client, login.id, id).await? else {
+        return "No such token".to_text_response_with_status(StatusCode::NOT_FOUND);
+    };
+    api_tokens.remove(&token_hash);
+    let event = AuditEvent::new(API_TOKEN_CHANGED, requester)
+        .from_request(&request)
+        .detail("action", "revoked")
+        .detail("token", id);
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 revoked API token {id}");
+    "Token revoked".to_text_response()
+}
+
+#[utoipa::path(get, path="/logout",
+    responses(
+        (status=204, description="Tokens and sessions revoked, cookies cleared, expired tokens accepted"),
+    ),
+)]
+async fn logout(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let Some(verifier) = request.extensions().get::<Arc<TokenVerifier>>() else {
+        return "No TokenVerifier".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let client = get_connection(&request).await?;
+
+    // the bearer token and the cookie can be different tokens, end both; an expired token
+    // still names the session its refresh tokens belong to
+    let mut families = Vec::new();
+    for token in extract_all_jwt(&request) {
+        match verifier.validate_expired_token(token) {
+            Ok(claims) => {
+                if claims.jti.is_empty() {
+                    warn!("Token of 「{}」 has no id, can not revoke it", claims.sub);
+                } else if claims.exp > get_current_timestamp() {
+                    revoke(&client, revocation_list, &claims.jti, claims.exp).await?;
+                    info!(
+                        "User 「{}」 logged out, token {} revoked",
+                        claims.sub, claims.jti
+                    );
+                }
+                if let Ok(family) = Uuid::parse_str(&claims.sid) {
+                    families.push(family);
+                }
+            }
+            Err(e) => warn!("Invalid token presented at logout: {e}"),
+        }
+    }
+    // clients other than the browser hold the refresh token only
+    let presented = match request.headers().get(REFRESH_TOKEN_HEADER) {
+        Some(header) => header.to_str().ok(),
+        None => get_cookie_value(&request, REFRESH_COOKIE_NAME_PREFIX),
+    };
+    if let Some(presented) = presented
+        && let Some(family) = refresh_token::family(&client, presented).await?
+    {
+        families.push(family);
+    }
+    families.sort_unstable();
+    families.dedup();
+    // the refresh tokens of the session would hand out new access tokens
+    for family in families {
+        if session::end(&client, revocation_list, family, None).await? {
+            info!("Session {family} ended at logout");
+        }
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header("Set-Cookie", clear_security_cookie())
+        .header("Set-Cookie", clear_refresh_cookie())
+        .header("server", "hyper")
+        .body(Body::empty())?)
 }
 
 async fn public_key(request: Request<Body>) -> Result<Response<Body>, GenericError> {
-    let Some(jot) = request.extensions().get::<Arc<Jot>>() else {
-        return "No Jot".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    let Some(issuer) = request.extensions().get::<Arc<TokenIssuer>>() else {
+        return "No TokenIssuer".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    issuer.get_public_key().to_json_response()
+}
+
+#[utoipa::path(get, path="/.well-known/jwks.json",
+    responses(
+        (status=200, description="The keys tokens are signed with, as a JSON Web Key Set"),
+    ),
+)]
+async fn jwks(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    let Some(issuer) = request.extensions().get::<Arc<TokenIssuer>>() else {
+        return "No TokenIssuer".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
     };
-    jot.get_public_key().to_json_response()
+    issuer.get_jwks().to_json_response()
 }
 
 pub use swagger::swagger_json;
@@ -184,8 +1479,63 @@ mod swagger {
 
     #[derive(OpenApi)]
     #[openapi(
-        paths(super::login,),
-        components(schemas(LoginForm, ChangePasswordForm,))
+        paths(
+            super::login,
+            super::login_totp,
+            super::logout,
+            super::refresh,
+            super::jwks,
+            super::list_roles,
+            super::change_role,
+            super::enrol_totp,
+            super::confirm_totp,
+            super::reset_totp,
+            super::list_users,
+            super::create_user,
+            super::set_user_disabled,
+            super::rename_user,
+            super::delete_user,
+            super::reset_password,
+            super::create_api_token,
+            super::list_api_tokens,
+            super::delete_api_token,
+            crate::oidc::discovery,
+            crate::oidc::authorize,
+            crate::oidc::token,
+            crate::oidc::userinfo,
+            crate::single_sign_on::login,
+            crate::single_sign_on::callback,
+            crate::client_certificate::register,
+            crate::client_certificate::list,
+            crate::client_certificate::revoke,
+            crate::session::list,
+            crate::session::revoke,
+            crate::session::revoke_all,
+        ),
+        components(schemas(
+            LoginForm,
+            TotpLoginForm,
+            ChangePasswordForm,
+            RoleForm,
+            UserRoleList,
+            TotpEnrolment,
+            TotpCodeForm,
+            RecoveryCodes,
+            UsernameForm,
+            UserAccount,
+            UserForm,
+            RenameForm,
+            ApiTokenForm,
+            NewApiToken,
+            ApiTokenInfo,
+            oidc::TokenForm,
+            oidc::TokenResponse,
+            oidc::UserInfo,
+            oidc::OAuthError,
+            client_certificate::CertificateForm,
+            client_certificate::CertificateInfo,
+            session::SessionInfo,
+        ))
     )]
     pub struct ApiDoc;
 
@@ -193,3 +1543,178 @@ mod swagger {
         serde_json::to_string_pretty(&ApiDoc::openapi()).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refuse_api_token() {
+        let mut request = Request::post("/totp/enrol").body(()).unwrap();
+        assert!(refuse_api_token(&request).is_none());
+        request
+            .extensions_mut()
+            .insert(ApiTokenScopes(vec![api_token::MEMO_WRITE.to_string()]));
+        let response = refuse_api_token(&request).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_dummy_password_hash() {
+        // a real hash, or the unknown users would answer faster
+        assert!(DUMMY_PASSWORD_HASH.starts_with("$argon2"));
+        let hashing = &SETTINGS.security.password_hashing;
+        let verification = hashing.verify("password", StoredPassword::Phc(&DUMMY_PASSWORD_HASH));
+        assert_eq!(verification, Verification::Invalid);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_refused() {
+        // without the issuer, only the identity service has one
+        let request = Request::post("/refresh").body(Body::empty()).unwrap();
+        let response = refresh(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let issuer = Arc::new(TokenIssuer::new(&SETTINGS.security).unwrap());
+        let mut request = Request::post("/refresh").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(issuer);
+        let response = refresh(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_token_response() {
+        let refresh_token = RefreshToken {
+            token: "refresh".to_string(),
+            family: Uuid::new_v4(),
+            expires_at: 0,
+        };
+        let request = Request::post("/refresh").body(()).unwrap();
+        let response = token_response(&request, "jwt".to_string(), &refresh_token).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[REFRESH_TOKEN_HEADER], "refresh");
+        assert_eq!(response.headers().get_all("Set-Cookie").iter().count(), 2);
+
+        // the web client keeps both in cookies only
+        let request = Request::post("/refresh")
+            .header("x-organizator-client-version", "1")
+            .body(())
+            .unwrap();
+        let response = token_response(&request, "jwt".to_string(), &refresh_token).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get(REFRESH_TOKEN_HEADER).is_none());
+        assert_eq!(response.headers().get_all("Set-Cookie").iter().count(), 2);
+    }
+
+    /// A form posted by the user with the roles
+    fn form_request(username: &str, roles: &[&str], body: &str) -> Request<Body> {
+        let mut request = Request::post("/")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(UserId(username.to_string()));
+        request
+            .extensions_mut()
+            .insert(UserRoles(roles.iter().map(|r| r.to_string()).collect()));
+        request
+    }
+
+    #[test]
+    fn test_is_admin() {
+        assert!(is_admin(&form_request("admin", &["org", ADMIN_ROLE], "")));
+        assert!(!is_admin(&form_request("alice", &["org", "photo"], "")));
+        let request = Request::get("/roles").body(()).unwrap();
+        assert!(!is_admin(&request));
+    }
+
+    #[tokio::test]
+    async fn test_change_role_refused() {
+        // these are answered before the database is needed
+        let body = "username=admin&role=orgadm";
+        let response = change_role(form_request("alice", &["org"], body), RoleChange::Revoke)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = list_roles(form_request("alice", &["org"], ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let mut request = form_request("admin", &[ADMIN_ROLE], body);
+        request
+            .extensions_mut()
+            .insert(ApiTokenScopes(vec![api_token::MEMO_WRITE.to_string()]));
+        let response = change_role(request, RoleChange::Grant).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // an administrator can not revoke their own administrator role
+        let response = change_role(
+            form_request("admin", &[ADMIN_ROLE], body),
+            RoleChange::Revoke,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_user_administration_refused() {
+        // these are answered before the database is needed
+        let admin = |body: &str| form_request("admin", &[ADMIN_ROLE], body);
+        let status = |response: Result<Response<Body>, GenericError>| response.unwrap().status();
+
+        let body = "username=jane&password=a%20long%20enough%20password";
+        let request = form_request("alice", &["org"], body);
+        assert_eq!(status(create_user(request).await), StatusCode::FORBIDDEN);
+        let mut request = admin(body);
+        request
+            .extensions_mut()
+            .insert(ApiTokenScopes(vec![api_token::MEMO_WRITE.to_string()]));
+        assert_eq!(status(create_user(request).await), StatusCode::FORBIDDEN);
+        let request = admin("username=%20&password=a%20long%20enough%20password");
+        assert_eq!(status(create_user(request).await), StatusCode::BAD_REQUEST);
+        // the password policy applies to the first password as well
+        let request = admin("username=jane&password=jane");
+        assert_eq!(status(create_user(request).await), StatusCode::BAD_REQUEST);
+
+        let request = form_request("alice", &["org"], "username=jane");
+        assert_eq!(
+            status(set_user_disabled(request, true).await),
+            StatusCode::FORBIDDEN
+        );
+        let request = form_request("alice", &["org"], "username=jane");
+        assert_eq!(status(delete_user(request).await), StatusCode::FORBIDDEN);
+        let request = form_request("alice", &["org"], "username=jane&new_username=joan");
+        assert_eq!(status(rename_user(request).await), StatusCode::FORBIDDEN);
+
+        // an administrator does not lock themselves out
+        let with_revocations = |body: &str| {
+            let mut request = admin(body);
+            request
+                .extensions_mut()
+                .insert(Arc::new(RevocationList::default()));
+            request
+        };
+        let request = with_revocations("username=admin");
+        assert_eq!(
+            status(set_user_disabled(request, true).await),
+            StatusCode::BAD_REQUEST
+        );
+        let request = with_revocations("username=admin&new_username=root");
+        assert_eq!(status(rename_user(request).await), StatusCode::BAD_REQUEST);
+        let request = with_revocations("username=jane&new_username=%20");
+        assert_eq!(status(rename_user(request).await), StatusCode::BAD_REQUEST);
+        let request = with_revocations("username=admin");
+        assert_eq!(status(delete_user(request).await), StatusCode::BAD_REQUEST);
+        // the sessions of a deleted user have to be ended
+        let request = admin("username=jane");
+        assert_eq!(
+            status(delete_user(request).await),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        let request = with_revocations("username=admin&password=a%20long%20enough%20password");
+        assert_eq!(status(reset_password(request).await), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,199 @@
+//! Optional second factor at login, a code from an authenticator app.
+//!
+//! A secret is enrolled unconfirmed and becomes active with the first code it
+//! produces. Recovery codes, handed out at that moment, stand in for a lost device.
+//! A login with the right password gets a short-lived challenge, traded at
+//! `/login/totp` together with the code for the tokens.
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use deadpool_postgres::Client;
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::authentication::api_token::hash_token;
+use lib_hyper_organizator::authentication::totp;
+use lib_hyper_organizator::typedef::GenericError;
+use tracing::{debug, info};
+
+use crate::refresh_token::random_token;
+
+/// Seconds from the password to the code
+const CHALLENGE_EXPIRY: u64 = 300;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// 50 random bits, typed as two groups of 5
+const RECOVERY_CODE_BYTES: usize = 7;
+
+/// Start an enrolment, returns the new secret or None if one is already confirmed
+pub async fn enrol(client: &Client, user_id: i32) -> Result<Option<Vec<u8>>, GenericError> {
+    let secret = totp::generate_secret()?;
+    let now = get_current_timestamp() as i64;
+    let stmt = client
+        .prepare_cached(include_str!("sql/enrol_totp.sql"))
+        .await?;
+    let rows = client
+        .execute(&stmt, &[&user_id, &totp::base32_encode(&secret), &now])
+        .await?;
+    Ok((rows > 0).then_some(secret))
+}
+
+/// Check a code against the secret, a code is accepted once
+async fn use_code(
+    client: &Client,
+    user_id: i32,
+    code: &str,
+    confirmed: bool,
+) -> Result<bool, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/find_totp.sql"))
+        .await?;
+    let Some(row) = client.query_opt(&stmt, &[&user_id]).await? else {
+        return Ok(false);
+    };
+    if row.get::<_, bool>("confirmed") != confirmed {
+        return Ok(false);
+    }
+    let secret: String = row.get("secret");
+    let secret = totp::base32_decode(&secret).ok_or("Stored TOTP secret is not base32")?;
+    let Some(step) = totp::verify(
+        &secret,
+        code,
+        get_current_timestamp(),
+        row.get("last_used_step"),
+    ) else {
+        return Ok(false);
+    };
+    let stmt = client
+        .prepare_cached(include_str!("sql/use_totp_step.sql"))
+        .await?;
+    Ok(client.execute(&stmt, &[&user_id, &(step as i64)]).await? > 0)
+}
+
+/// Without dashes and case, the way it is hashed
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Activate the enrolled secret with its first code, returns the recovery codes or None
+/// if the code is wrong
+pub async fn confirm(
+    client: &Client,
+    user_id: i32,
+    code: &str,
+) -> Result<Option<Vec<String>>, GenericError> {
+    if !use_code(client, user_id, code, false).await? {
+        return Ok(None);
+    }
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_recovery_codes.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id]).await?;
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_recovery_code.sql"))
+        .await?;
+    let argon2 = Argon2::default();
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let bytes = totp::generate_secret()?;
+        let code = totp::base32_encode(&bytes[..RECOVERY_CODE_BYTES]);
+        let code = format!("{}-{}", &code[..5], &code[5..10]).to_lowercase();
+        let salt = SaltString::generate(&mut OsRng);
+        let code_hash = argon2
+            .hash_password(normalize_recovery_code(&code).as_bytes(), &salt)?
+            .to_string();
+        client.execute(&stmt, &[&user_id, &code_hash]).await?;
+        codes.push(code);
+    }
+    Ok(Some(codes))
+}
+
+/// A code from the app or an unused recovery code
+pub async fn verify(client: &Client, user_id: i32, code: &str) -> Result<bool, GenericError> {
+    if use_code(client, user_id, code, true).await? {
+        return Ok(true);
+    }
+    let code = normalize_recovery_code(code);
+    let stmt = client
+        .prepare_cached(include_str!("sql/unused_recovery_codes.sql"))
+        .await?;
+    let rows = client.query(&stmt, &[&user_id]).await?;
+    let argon2 = Argon2::default();
+    for row in rows {
+        let code_hash: String = row.get("code_hash");
+        if argon2
+            .verify_password(code.as_bytes(), &PasswordHash::new(&code_hash)?)
+            .is_ok()
+        {
+            let id: i32 = row.get("id");
+            let now = get_current_timestamp() as i64;
+            let stmt = client
+                .prepare_cached(include_str!("sql/use_recovery_code.sql"))
+                .await?;
+            let used = client.execute(&stmt, &[&id, &now]).await? > 0;
+            info!("Recovery code {id} of user {user_id} used");
+            return Ok(used);
+        }
+    }
+    Ok(false)
+}
+
+/// Remove the second factor and its recovery codes
+pub async fn reset(client: &Client, user_id: i32) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_recovery_codes.sql"))
+        .await?;
+    client.execute(&stmt, &[&user_id]).await?;
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_totp.sql"))
+        .await?;
+    let rows = client.execute(&stmt, &[&user_id]).await?;
+    debug!("Second factor of user {user_id} removed, {rows} rows");
+    Ok(())
+}
+
+/// The password was right, the code has to follow
+pub async fn create_challenge(client: &Client, user_id: i32) -> Result<String, GenericError> {
+    let challenge = random_token()?;
+    let now = get_current_timestamp();
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_totp_challenge.sql"))
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &hash_token(&challenge),
+                &user_id,
+                &((now + CHALLENGE_EXPIRY) as i64),
+                &(now as i64),
+            ],
+        )
+        .await?;
+    Ok(challenge)
+}
+
+/// The user a valid challenge was issued to
+pub async fn challenge_user(
+    client: &Client,
+    challenge: &str,
+) -> Result<Option<String>, GenericError> {
+    let now = get_current_timestamp() as i64;
+    let stmt = client
+        .prepare_cached(include_str!("sql/find_totp_challenge.sql"))
+        .await?;
+    let row = client
+        .query_opt(&stmt, &[&hash_token(challenge), &now])
+        .await?;
+    Ok(row.map(|row| row.get("username")))
+}
+
+/// A challenge is good for one login
+pub async fn end_challenge(client: &Client, challenge: &str) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_totp_challenge.sql"))
+        .await?;
+    client.execute(&stmt, &[&hash_token(challenge)]).await?;
+    Ok(())
+}
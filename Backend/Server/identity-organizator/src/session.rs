@@ -0,0 +1,314 @@
+//! The sessions of the users, from a login until its refresh tokens expire.
+//!
+//! A session is the family of its refresh tokens, its id is the `sid` of the access tokens.
+//! Ending one revokes the refresh tokens and tells the services to refuse the access tokens
+//! of the session, through the revocation list, until the last of them would have expired.
+use deadpool_postgres::Client;
+use http::header::USER_AGENT;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::audit::{self, AuditEvent, SESSION_ENDED};
+use lib_hyper_organizator::authentication::rate_limit::client_ip;
+use lib_hyper_organizator::authentication::revocation::RevocationList;
+use lib_hyper_organizator::postgres::get_connection;
+use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
+use lib_hyper_organizator::server::SETTINGS;
+use lib_hyper_organizator::typedef::{GenericError, SessionId, UserId};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_postgres::Row;
+use tracing::{debug, info};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::refresh_token::{self, RefreshToken};
+use crate::router::{managed_login, refuse_api_token};
+
+/// Followed by the id of the session to end
+pub const SESSION_PATH_PREFIX: &str = "/sessions/";
+
+/// Longer ones are cut, the header is the client's to fill
+const USER_AGENT_MAX_LENGTH: usize = 512;
+
+/// Where a login came from
+pub struct SessionOrigin {
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+impl SessionOrigin {
+    pub fn of<B>(request: &Request<B>) -> Self {
+        SessionOrigin {
+            user_agent: request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|agent| agent.chars().take(USER_AGENT_MAX_LENGTH).collect()),
+            ip: client_ip(request),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+pub struct SessionQuery {
+    /// the requester if missing, someone else only for administrators
+    username: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct SessionInfo {
+    id: String,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    /// seconds since epoch
+    created_on: i64,
+    /// seconds since epoch, never refreshed if missing
+    last_refresh: Option<i64>,
+    /// seconds since epoch, a login is needed after it
+    expires_at: i64,
+    /// the session of the token making the request
+    current: bool,
+}
+
+impl SessionInfo {
+    fn from_row(row: Row, current: Option<&str>) -> Self {
+        let id: Uuid = row.get("id");
+        let id = id.to_string();
+        SessionInfo {
+            current: current == Some(id.as_str()),
+            id,
+            user_agent: row.get("user_agent"),
+            ip: row.get("ip"),
+            created_on: row.get("created_on"),
+            last_refresh: row.get("last_refresh"),
+            expires_at: row.get("expires_at"),
+        }
+    }
+}
+
+/// Record the session of a new family of refresh tokens, the old sessions are dropped
+pub async fn start(
+    client: &Client,
+    refresh_token: &RefreshToken,
+    user_id: i32,
+    origin: &SessionOrigin,
+) -> Result<(), GenericError> {
+    let now = get_current_timestamp() as i64;
+    let stmt = client
+        .prepare_cached(include_str!("sql/delete_expired_sessions.sql"))
+        .await?;
+    let deleted = client.execute(&stmt, &[&now]).await?;
+    debug!("Removed {deleted} expired sessions");
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_user_session.sql"))
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &refresh_token.family,
+                &user_id,
+                &origin.user_agent,
+                &origin.ip,
+                &now,
+                &(refresh_token.expires_at as i64),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// A refresh token of the session was used
+pub async fn touch(client: &Client, family: Uuid) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/touch_user_session.sql"))
+        .await?;
+    let now = get_current_timestamp() as i64;
+    client.execute(&stmt, &[&family, &now]).await?;
+    Ok(())
+}
+
+/// When the last access token issued until now expires
+fn tokens_expire_at(now: u64) -> u64 {
+    let security = &SETTINGS.security;
+    now + security.session_expiry + security.session_expiry_grace_period
+}
+
+/// End a session, of that user only when given. Returns false if there was no such session
+/// still going; its refresh tokens are revoked anyway.
+pub async fn end(
+    client: &Client,
+    revocation_list: &RevocationList,
+    family: Uuid,
+    user_id: Option<i32>,
+) -> Result<bool, GenericError> {
+    let now = get_current_timestamp();
+    let until = tokens_expire_at(now);
+    let stmt = client
+        .prepare_cached(include_str!("sql/revoke_user_session.sql"))
+        .await?;
+    let ended = client
+        .query_opt(&stmt, &[&family, &(now as i64), &(until as i64), &user_id])
+        .await?
+        .is_some();
+    // sessions from before they were recorded have refresh tokens only
+    if ended || user_id.is_none() {
+        refresh_token::revoke_family(client, family).await?;
+        revocation_list.insert_session(&family.to_string(), until);
+    }
+    Ok(ended)
+}
+
+/// End all the sessions of a user, returns how many there were
+pub async fn end_all(
+    client: &Client,
+    revocation_list: &RevocationList,
+    user_id: i32,
+) -> Result<usize, GenericError> {
+    let now = get_current_timestamp();
+    let until = tokens_expire_at(now);
+    let stmt = client
+        .prepare_cached(include_str!("sql/revoke_user_sessions.sql"))
+        .await?;
+    let rows = client
+        .query(&stmt, &[&user_id, &(now as i64), &(until as i64)])
+        .await?;
+    refresh_token::revoke_user(client, user_id).await?;
+    for row in &rows {
+        let family: Uuid = row.get("id");
+        revocation_list.insert_session(&family.to_string(), until);
+    }
+    Ok(rows.len())
+}
+
+fn query(request: &Request<Body>) -> SessionQuery {
+    serde_urlencoded::from_str(request.uri().query().unwrap_or_default()).unwrap_or_default()
+}
+
+#[utoipa::path(get, path="/sessions",
+    params(SessionQuery),
+    responses(
+        (status=200, description="The sessions of the user still going, the latest first", body=Vec<SessionInfo>),
+        (status=403, description="Not with an API token, or the sessions of someone else"),
+        (status=404, description="Unknown user"),
+    ),
+)]
+pub async fn list(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let query = query(&request);
+    let client = get_connection(&request).await?;
+    let login = match managed_login(&request, &client, requester, query.username.as_deref()).await?
+    {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    let stmt = client
+        .prepare_cached(include_str!("sql/user_sessions.sql"))
+        .await?;
+    let now = get_current_timestamp() as i64;
+    let rows = client.query(&stmt, &[&login.id, &now]).await?;
+    let current = request
+        .extensions()
+        .get::<SessionId>()
+        .map(|s| s.0.as_str());
+    let sessions: Vec<SessionInfo> = rows
+        .into_iter()
+        .map(|row| SessionInfo::from_row(row, current))
+        .collect();
+    serde_json::to_string(&sessions)?.to_json_response()
+}
+
+#[utoipa::path(delete, path="/sessions/{id}",
+    params(
+        ("id" = String, Path, description="Id of the session"),
+        SessionQuery,
+    ),
+    responses(
+        (status=200, description="Session ended, its tokens are refused"),
+        (status=403, description="Not with an API token, or the session of someone else"),
+        (status=404, description="No such session of the user still going"),
+    ),
+)]
+pub async fn revoke(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let Some(Ok(family)) = request
+        .uri()
+        .path()
+        .strip_prefix(SESSION_PATH_PREFIX)
+        .map(Uuid::parse_str)
+    else {
+        return "Bad session id".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let query = query(&request);
+    let client = get_connection(&request).await?;
+    let login = match managed_login(&request, &client, requester, query.username.as_deref()).await?
+    {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    if !end(&client, revocation_list, family, Some(login.id)).await? {
+        return "No such session".to_text_response_with_status(StatusCode::NOT_FOUND);
+    }
+    let username = query.username.as_deref().unwrap_or(requester);
+    let event = AuditEvent::new(SESSION_ENDED, username)
+        .by(requester)
+        .from_request(&request)
+        .detail("session", family.to_string());
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 ended session {family} of 「{username}」");
+    "Session ended".to_text_response()
+}
+
+#[utoipa::path(delete, path="/sessions",
+    params(SessionQuery),
+    responses(
+        (status=200, description="All the sessions of the user ended, how many", body=usize),
+        (status=403, description="Not with an API token, or the sessions of someone else"),
+        (status=404, description="Unknown user"),
+    ),
+)]
+/// Log out everywhere, the session of the request included
+pub async fn revoke_all(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if let Some(response) = refuse_api_token(&request) {
+        return response;
+    }
+    let Some(UserId(requester)) = request.extensions().get::<UserId>() else {
+        return "User is not logged in".to_text_response_with_status(StatusCode::UNAUTHORIZED);
+    };
+    let Some(revocation_list) = request.extensions().get::<Arc<RevocationList>>() else {
+        return "No revocation list"
+            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let query = query(&request);
+    let client = get_connection(&request).await?;
+    let login = match managed_login(&request, &client, requester, query.username.as_deref()).await?
+    {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+    let ended = end_all(&client, revocation_list, login.id).await?;
+    let username = query.username.as_deref().unwrap_or(requester);
+    let event = AuditEvent::new(SESSION_ENDED, username)
+        .by(requester)
+        .from_request(&request)
+        .detail("sessions", ended);
+    audit::record(&client, event).await?;
+    info!("User 「{requester}」 ended the {ended} sessions of 「{username}」");
+    ended.to_string().to_text_response()
+}
@@ -0,0 +1,356 @@
+//! Login through the OpenID Connect provider of the organization, e.g. a Keycloak.
+//!
+//! `/login/oidc` sends the browser to the provider, the state and the PKCE verifier stay
+//! here and the state is also put in a cookie, so only the browser that started the login
+//! can finish it at `/login/oidc/callback`. The subject of the provider is linked to a local
+//! user the first time, by the configured claim, or a user is created if allowed. The
+//! session is then the same as after a password login; the second factor is left to the
+//! provider, so existing users are only linked when allowed, and never administrators or
+//! users with a second factor of their own.
+use deadpool_postgres::Client;
+use http::header::{LOCATION, SET_COOKIE};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use jsonwebtoken::get_current_timestamp;
+use lib_hyper_organizator::audit::{self, AuditEvent, LOGIN};
+use lib_hyper_organizator::authentication::api_token::hash_token;
+use lib_hyper_organizator::authentication::check_security::{
+    create_security_cookie, get_cookie_value,
+};
+use lib_hyper_organizator::authentication::jot::TokenIssuer;
+use lib_hyper_organizator::authentication::oidc::code_challenge;
+use lib_hyper_organizator::authentication::upstream_oidc::{UpstreamIdentity, UpstreamProvider};
+use lib_hyper_organizator::postgres::get_connection;
+use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
+use lib_hyper_organizator::server::SETTINGS;
+use lib_hyper_organizator::typedef::GenericError;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use tracing::{error, info, warn};
+use utoipa::IntoParams;
+
+use crate::db::{self, find_login};
+use crate::refresh_token::random_token;
+use crate::router::{ADMIN_ROLE, create_refresh_cookie, new_session};
+use crate::session::SessionOrigin;
+
+/// Seconds the user has to log in at the provider
+const STATE_EXPIRY: u64 = 600;
+const STATE_COOKIE_NAME_PREFIX: &str = "__Host-oidc-state=";
+
+/// Discovered at the first login, a failed discovery is tried again at the next one
+static PROVIDER: OnceCell<UpstreamProvider> = OnceCell::const_new();
+
+async fn provider() -> Result<&'static UpstreamProvider, GenericError> {
+    PROVIDER
+        .get_or_try_init(|| UpstreamProvider::discover(&SETTINGS.security.upstream_oidc))
+        .await
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+pub struct LoginQuery {
+    /// path to go to after the login, on this site
+    r: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+    /// set by the provider when the login failed
+    error: Option<String>,
+}
+
+/// Only paths of this site, anything else would make us an open redirect
+fn return_path(r: Option<&str>) -> &str {
+    match r {
+        Some(path) if path.starts_with('/') && !path.starts_with("//") && !path.contains('\\') => {
+            path
+        }
+        _ => "/",
+    }
+}
+
+/// Lax, the browser comes back from another site
+fn create_state_cookie(state: &str) -> String {
+    format!(
+        "{STATE_COOKIE_NAME_PREFIX}{state}; HttpOnly; Secure; SameSite=Lax; Path=/; Max-Age={STATE_EXPIRY}"
+    )
+}
+
+fn clear_state_cookie() -> String {
+    format!("{STATE_COOKIE_NAME_PREFIX}; HttpOnly; Secure; SameSite=Lax; Path=/; Max-Age=0")
+}
+
+fn not_configured() -> Result<Response<Body>, GenericError> {
+    "Login through OpenID Connect is not configured"
+        .to_text_response_with_status(StatusCode::NOT_FOUND)
+}
+
+/// The login did not work out, the state cookie goes anyway
+fn refuse(status: StatusCode, message: &'static str) -> Result<Response<Body>, GenericError> {
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header(SET_COOKIE, clear_state_cookie())
+        .header("server", "hyper")
+        .body(Body::from(message))?)
+}
+
+#[utoipa::path(get, path="/login/oidc",
+    params(LoginQuery),
+    responses(
+        (status=302, description="To the provider, the state is in the __Host-oidc-state cookie"),
+        (status=404, description="No provider configured"),
+    ),
+)]
+/// Start a login at the provider of the organization
+pub async fn login(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !SETTINGS.security.upstream_oidc.is_enabled() {
+        return not_configured();
+    }
+    let query: LoginQuery =
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default()).unwrap_or_default();
+    let provider = match provider().await {
+        Ok(provider) => provider,
+        Err(e) => {
+            error!("The OpenID Connect provider can not be reached: {e}");
+            return "The login provider can not be reached"
+                .to_text_response_with_status(StatusCode::BAD_GATEWAY);
+        }
+    };
+    let state = random_token()?;
+    let nonce = random_token()?;
+    let code_verifier = random_token()?;
+    let now = get_current_timestamp();
+
+    let client = get_connection(&request).await?;
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_oidc_login_state.sql"))
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[
+                &hash_token(&state),
+                &nonce,
+                &code_verifier,
+                &return_path(query.r.as_deref()),
+                &((now + STATE_EXPIRY) as i64),
+                &(now as i64),
+            ],
+        )
+        .await?;
+
+    let location = provider.authorization_url(&state, &nonce, &code_challenge(&code_verifier))?;
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .header(SET_COOKIE, create_state_cookie(&state))
+        .header("server", "hyper")
+        .body(Body::empty())?)
+}
+
+/// What was kept of the login while the browser was at the provider
+struct LoginState {
+    nonce: String,
+    code_verifier: String,
+    return_to: String,
+}
+
+async fn use_state(client: &Client, state: &str) -> Result<Option<LoginState>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/use_oidc_login_state.sql"))
+        .await?;
+    let now = get_current_timestamp() as i64;
+    let row = client.query_opt(&stmt, &[&hash_token(state), &now]).await?;
+    Ok(row.map(|row| LoginState {
+        nonce: row.get("nonce"),
+        code_verifier: row.get("code_verifier"),
+        return_to: row.get("return_to"),
+    }))
+}
+
+async fn link(
+    client: &Client,
+    identity: &UpstreamIdentity,
+    user_id: i32,
+) -> Result<(), GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/insert_external_identity.sql"))
+        .await?;
+    let now = get_current_timestamp() as i64;
+    client
+        .execute(
+            &stmt,
+            &[&identity.issuer, &identity.subject, &user_id, &now],
+        )
+        .await?;
+    Ok(())
+}
+
+/// The local username of the account of the provider, linking or creating it the first time
+async fn local_username(
+    client: &mut Client,
+    identity: &UpstreamIdentity,
+) -> Result<Option<String>, GenericError> {
+    let stmt = client
+        .prepare_cached(include_str!("sql/find_external_identity.sql"))
+        .await?;
+    if let Some(row) = client
+        .query_opt(&stmt, &[&identity.issuer, &identity.subject])
+        .await?
+    {
+        return Ok(Some(row.get("username")));
+    }
+
+    let config = &SETTINGS.security.upstream_oidc;
+    let Some(username) = identity.username(&config.username_claim) else {
+        warn!(
+            "Subject {} of {} has no usable {} claim",
+            identity.subject, identity.issuer, config.username_claim
+        );
+        return Ok(None);
+    };
+    if let Some(login) = find_login(client, username).await? {
+        // whoever controls the claim at the provider would get the account
+        if !config.link_existing_users {
+            info!(
+                "User 「{username}」 exists, linking it to subject {} is not allowed",
+                identity.subject
+            );
+            return Ok(None);
+        }
+        if login.totp_enabled || login.roles.iter().any(|role| role == ADMIN_ROLE) {
+            warn!(
+                "User 「{username}」 is an administrator or has a second factor, not linked to \
+                 subject {}",
+                identity.subject
+            );
+            return Ok(None);
+        }
+        link(client, identity, login.id).await?;
+        info!("User 「{username}」 linked to subject {}", identity.subject);
+        return Ok(Some(username.to_string()));
+    }
+    if !config.auto_provision || config.provisioned_by.is_empty() {
+        info!(
+            "No local user 「{username}」 for subject {}",
+            identity.subject
+        );
+        return Ok(None);
+    }
+
+    // nobody knows the password, the user logs in through the provider only
+    let password_hash = SETTINGS.security.password_hashing.hash(&random_token()?)?;
+    if !db::create_user(
+        client,
+        &config.provisioned_by,
+        username,
+        &password_hash,
+        &config.provisioned_role,
+    )
+    .await?
+    {
+        warn!("Could not create the user 「{username}」");
+        return Ok(None);
+    }
+    let login = db::fetch_login(client, username).await?;
+    link(client, identity, login.id).await?;
+    info!(
+        "User 「{username}」 created for subject {}",
+        identity.subject
+    );
+    Ok(Some(username.to_string()))
+}
+
+#[utoipa::path(get, path="/login/oidc/callback",
+    params(CallbackQuery),
+    responses(
+        (status=200, description="Logged in, the tokens are set as cookies and the page moves on to where the login started"),
+        (status=400, description="Unknown or expired state, or a state from another browser"),
+        (status=401, description="The provider refused the login or its answer did not check out"),
+        (status=403, description="No local user for the account, or the user is disabled"),
+    ),
+)]
+/// The provider sends the browser back here with the code
+pub async fn callback(request: Request<Body>) -> Result<Response<Body>, GenericError> {
+    if !SETTINGS.security.upstream_oidc.is_enabled() {
+        return not_configured();
+    }
+    let Ok(query) =
+        serde_urlencoded::from_str::<CallbackQuery>(request.uri().query().unwrap_or_default())
+    else {
+        return refuse(StatusCode::BAD_REQUEST, "Malformed callback");
+    };
+    if get_cookie_value(&request, STATE_COOKIE_NAME_PREFIX) != Some(query.state.as_str()) {
+        warn!("Login callback with a state from another browser");
+        return refuse(StatusCode::BAD_REQUEST, "The login was started elsewhere");
+    }
+    let mut client = get_connection(&request).await?;
+    let Some(login_state) = use_state(&client, &query.state).await? else {
+        return refuse(
+            StatusCode::BAD_REQUEST,
+            "The login took too long, start again",
+        );
+    };
+    if let Some(error) = query.error {
+        info!("The provider refused the login: {error}");
+        return refuse(StatusCode::UNAUTHORIZED, "The login was refused");
+    }
+    let Some(code) = query.code else {
+        return refuse(StatusCode::BAD_REQUEST, "Malformed callback");
+    };
+
+    let provider = provider().await?;
+    let identity = match provider
+        .exchange_code(&code, &login_state.code_verifier, &login_state.nonce)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("The answer of the provider did not check out: {e}");
+            return refuse(StatusCode::UNAUTHORIZED, "The login was refused");
+        }
+    };
+    let Some(username) = local_username(&mut client, &identity).await? else {
+        return refuse(StatusCode::FORBIDDEN, "There is no account for you here");
+    };
+    let login = db::fetch_login(&client, &username).await?;
+    if login.disabled {
+        warn!("Disabled user 「{username}」 tried to log in through the provider");
+        return refuse(StatusCode::FORBIDDEN, "The account is disabled");
+    }
+
+    let Some(issuer) = request.extensions().get::<Arc<TokenIssuer>>() else {
+        return "No TokenIssuer".to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let origin = SessionOrigin::of(&request);
+    let (new_token, refresh_token) = new_session(issuer, &client, &login, &origin).await?;
+    let event = AuditEvent::new(LOGIN, &username)
+        .from_request(&request)
+        .detail("method", "oidc")
+        .detail("issuer", identity.issuer.as_str());
+    audit::record(&client, event).await?;
+    info!("User 「{username}」 logged in through {}", identity.issuer);
+    // a redirect would still count as coming from the provider, and the strict session
+    // cookie would not be sent along; a page of our own moves on from this site
+    let return_to = login_state
+        .return_to
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;");
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/html; charset=utf-8")
+        .header(SET_COOKIE, create_security_cookie(&new_token))
+        .header(SET_COOKIE, create_refresh_cookie(&refresh_token.token))
+        .header(SET_COOKIE, clear_state_cookie())
+        .header("server", "hyper")
+        .body(Body::from(format!(
+            r#"<!DOCTYPE html><meta http-equiv="refresh" content="0;url={return_to}">"#
+        )))?)
+}
@@ -0,0 +1 @@
+DELETE FROM login_lockout WHERE user_id = $1;
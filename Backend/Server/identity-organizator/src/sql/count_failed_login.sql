@@ -0,0 +1,6 @@
+-- one more consecutive failure for the user
+INSERT INTO login_lockout (user_id, failed_logins)
+VALUES ($1, 1)
+ON CONFLICT (user_id) DO UPDATE
+SET failed_logins = login_lockout.failed_logins + 1
+RETURNING failed_logins;
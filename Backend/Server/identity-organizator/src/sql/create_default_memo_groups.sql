@@ -0,0 +1,2 @@
+-- the individual user group and the RO and RW memo groups
+SELECT create_default_memo_groups($1, $2);
@@ -0,0 +1,5 @@
+-- only the owner revokes a token, the hash tells the services which one
+DELETE FROM api_token
+WHERE id = $1
+  AND user_id = $2
+RETURNING token_hash;
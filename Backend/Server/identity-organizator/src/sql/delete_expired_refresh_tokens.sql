@@ -0,0 +1,2 @@
+-- expired tokens are refused anyway, no need to keep them
+DELETE FROM refresh_token WHERE expires_at <= $1;
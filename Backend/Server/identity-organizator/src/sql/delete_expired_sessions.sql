@@ -0,0 +1,4 @@
+-- over and without tokens the services could still accept, $1 is now
+DELETE FROM user_session
+WHERE expires_at <= $1
+  AND (revoked_until IS NULL OR revoked_until <= $1);
@@ -0,0 +1 @@
+DELETE FROM totp_recovery_code WHERE user_id = $1;
@@ -0,0 +1 @@
+DELETE FROM totp_challenge WHERE challenge_hash = $1;
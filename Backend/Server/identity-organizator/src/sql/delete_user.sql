@@ -0,0 +1,3 @@
+-- fails if the user still owns memos or files, disable the user instead
+DELETE FROM users
+WHERE id = $1;
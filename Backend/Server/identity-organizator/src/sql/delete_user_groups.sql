@@ -0,0 +1,3 @@
+-- the memberships of a user about to be deleted
+DELETE FROM user_group_detail
+WHERE user_id = $1;
@@ -0,0 +1,8 @@
+-- a new secret, replacing one never confirmed; a confirmed one stays
+INSERT INTO user_totp (user_id, secret, created_on)
+VALUES ($1, $2, $3)
+ON CONFLICT (user_id) DO UPDATE
+SET secret = EXCLUDED.secret,
+    created_on = EXCLUDED.created_on,
+    last_used_step = NULL
+WHERE NOT user_totp.confirmed;
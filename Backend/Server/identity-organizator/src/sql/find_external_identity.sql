@@ -0,0 +1,5 @@
+SELECT users.username
+FROM user_external_identity
+JOIN users ON users.id = user_external_identity.user_id
+WHERE user_external_identity.issuer = $1
+  AND user_external_identity.subject = $2;
@@ -0,0 +1,5 @@
+-- a token refused by use_refresh_token, to tell a reuse from an expired or unknown token
+SELECT family,
+       used_on IS NOT NULL AS used
+FROM refresh_token
+WHERE token_hash = $1;
@@ -0,0 +1,5 @@
+SELECT secret,
+       confirmed,
+       last_used_step
+FROM user_totp
+WHERE user_id = $1;
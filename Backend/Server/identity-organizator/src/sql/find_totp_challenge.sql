@@ -0,0 +1,5 @@
+SELECT users.username
+FROM totp_challenge
+JOIN users ON users.id = totp_challenge.user_id
+WHERE totp_challenge.challenge_hash = $1
+  AND totp_challenge.expires_at > $2;
@@ -0,0 +1,6 @@
+-- check both the user and the role exist
+SELECT users.id AS user_id,
+       role.name AS role
+FROM users, role
+WHERE users.username = $1
+  AND role.name = $2;
@@ -0,0 +1,5 @@
+-- store a new API token of the user, only its hash
+INSERT INTO api_token (user_id, name, token_hash, scopes, created_on, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (user_id, name) DO NOTHING
+RETURNING id;
@@ -0,0 +1,7 @@
+-- the expired codes of others go at the same time
+WITH expired AS (
+  DELETE FROM oidc_authorization_code WHERE expires_at <= $9
+)
+INSERT INTO oidc_authorization_code
+  (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8);
@@ -0,0 +1,4 @@
+-- link an account of the provider to a local user
+INSERT INTO user_external_identity (issuer, subject, user_id, linked_on)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,2 @@
+INSERT INTO login_failure (username, ip, reason, failed_on)
+VALUES ($1, $2, $3, $4);
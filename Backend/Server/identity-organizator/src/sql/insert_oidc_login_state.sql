@@ -0,0 +1,6 @@
+-- the expired states of others go at the same time
+WITH expired AS (
+  DELETE FROM oidc_login_state WHERE expires_at <= $6
+)
+INSERT INTO oidc_login_state (state_hash, nonce, code_verifier, return_to, expires_at)
+VALUES ($1, $2, $3, $4, $5);
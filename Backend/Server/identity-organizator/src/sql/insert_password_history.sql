@@ -0,0 +1,3 @@
+-- a password chosen by the user
+INSERT INTO password_history (user_id, password_hash, changed_on)
+VALUES ($1, $2, $3);
@@ -0,0 +1,2 @@
+INSERT INTO totp_recovery_code (user_id, code_hash)
+VALUES ($1, $2);
@@ -0,0 +1,3 @@
+-- store a new refresh token, only its hash
+INSERT INTO refresh_token (token_hash, family, user_id, issued_on, expires_at)
+VALUES ($1, $2, $3, $4, $5);
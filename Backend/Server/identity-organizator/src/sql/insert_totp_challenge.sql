@@ -0,0 +1,6 @@
+-- the expired challenges of others go at the same time
+WITH expired AS (
+  DELETE FROM totp_challenge WHERE expires_at <= $4
+)
+INSERT INTO totp_challenge (challenge_hash, user_id, expires_at)
+VALUES ($1, $2, $3);
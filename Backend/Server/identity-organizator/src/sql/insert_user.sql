@@ -0,0 +1,5 @@
+-- a new user, the requester has to be an administrator
+INSERT INTO users (username, password_hash)
+VALUES ($1, $2)
+ON CONFLICT DO NOTHING
+RETURNING id;
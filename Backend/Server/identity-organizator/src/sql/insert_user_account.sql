@@ -0,0 +1,3 @@
+-- account state of a new user
+INSERT INTO user_account (user_id, must_change_password)
+VALUES ($1, $2);
@@ -0,0 +1,5 @@
+-- register a certificate of the user, nothing if it is registered already
+INSERT INTO user_certificate (user_id, name, subject_dn, issuer_dn, serial, fingerprint, created_on)
+VALUES ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT DO NOTHING
+RETURNING id;
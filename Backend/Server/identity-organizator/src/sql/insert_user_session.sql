@@ -0,0 +1,3 @@
+-- a login started a session
+INSERT INTO user_session (id, user_id, user_agent, ip, created_on, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6);
@@ -0,0 +1,5 @@
+-- the failures are counted again from zero once the lock expires
+UPDATE login_lockout
+SET failed_logins = 0,
+    locked_until = $2
+WHERE user_id = $1;
@@ -1,6 +1,19 @@
 -- get the data required to check if the user credentials are correct
-SELECT id,
-       username,
-       password_hash
+SELECT users.id,
+       users.username,
+       users.password_hash,
+       users.pbkdf2,
+       users.salt,
+       COALESCE(array_agg(user_role.role ORDER BY user_role.role)
+                FILTER (WHERE user_role.role IS NOT NULL), '{}') AS roles,
+       login_lockout.locked_until,
+       COALESCE(user_totp.confirmed, false) AS totp_enabled,
+       COALESCE(user_account.disabled, false) AS disabled,
+       COALESCE(user_account.must_change_password, false) AS must_change_password
 FROM users
-WHERE username = $1;
+LEFT JOIN user_role ON user_role.user_id = users.id
+LEFT JOIN login_lockout ON login_lockout.user_id = users.id
+LEFT JOIN user_totp ON user_totp.user_id = users.id
+LEFT JOIN user_account ON user_account.user_id = users.id
+WHERE users.username = $1
+GROUP BY users.id, login_lockout.user_id, user_totp.user_id, user_account.user_id;
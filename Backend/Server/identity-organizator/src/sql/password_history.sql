@@ -0,0 +1,6 @@
+-- the latest passwords chosen by the user
+SELECT password_hash
+FROM password_history
+WHERE user_id = $1
+ORDER BY changed_on DESC, id DESC
+LIMIT $2;
@@ -0,0 +1,10 @@
+-- keep only the latest passwords of the user
+DELETE FROM password_history
+WHERE user_id = $1
+  AND id NOT IN (
+    SELECT id
+    FROM password_history
+    WHERE user_id = $1
+    ORDER BY changed_on DESC, id DESC
+    LIMIT $2
+  );
@@ -0,0 +1,4 @@
+-- a successful login
+INSERT INTO user_account (user_id, last_login)
+VALUES ($1, $2)
+ON CONFLICT (user_id) DO UPDATE SET last_login = EXCLUDED.last_login;
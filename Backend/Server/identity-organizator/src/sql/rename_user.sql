@@ -0,0 +1,4 @@
+-- the requester has to be an administrator
+UPDATE users
+SET username = $2
+WHERE username = $1;
@@ -0,0 +1,5 @@
+-- end a session, none of its refresh tokens can be used anymore
+UPDATE refresh_token
+SET revoked = true
+WHERE family = $1
+  AND NOT revoked;
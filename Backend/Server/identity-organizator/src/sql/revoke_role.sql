@@ -0,0 +1,3 @@
+DELETE FROM user_role
+WHERE user_id = $1
+  AND role = $2;
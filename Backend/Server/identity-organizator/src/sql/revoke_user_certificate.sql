@@ -0,0 +1,7 @@
+-- kept to show it was revoked, the services drop it at their next reload
+UPDATE user_certificate
+SET revoked_on = $3
+WHERE id = $1
+  AND user_id = $2
+  AND revoked_on IS NULL
+RETURNING id;
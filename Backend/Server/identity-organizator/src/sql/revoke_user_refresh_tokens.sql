@@ -0,0 +1,5 @@
+-- end all the sessions of a user
+UPDATE refresh_token
+SET revoked = true
+WHERE user_id = $1
+  AND NOT revoked;
@@ -0,0 +1,8 @@
+-- end a session, of the user $4 only when given; $2 is now, $3 when its tokens expire
+UPDATE user_session
+SET revoked_on = $2,
+    revoked_until = $3
+WHERE id = $1
+  AND ($4::integer IS NULL OR user_id = $4)
+  AND revoked_on IS NULL
+RETURNING id;
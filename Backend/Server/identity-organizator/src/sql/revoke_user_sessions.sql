@@ -0,0 +1,8 @@
+-- end all the sessions of the user; $2 is now, $3 when their tokens expire
+UPDATE user_session
+SET revoked_on = $2,
+    revoked_until = $3
+WHERE user_id = $1
+  AND revoked_on IS NULL
+  AND expires_at > $2
+RETURNING id;
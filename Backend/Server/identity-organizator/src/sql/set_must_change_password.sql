@@ -0,0 +1,4 @@
+-- the next login has to come with a new password, or not anymore
+INSERT INTO user_account (user_id, must_change_password)
+VALUES ($1, $2)
+ON CONFLICT (user_id) DO UPDATE SET must_change_password = EXCLUDED.must_change_password;
@@ -0,0 +1,4 @@
+-- enable or disable the login of a user
+INSERT INTO user_account (user_id, disabled)
+VALUES ($1, $2)
+ON CONFLICT (user_id) DO UPDATE SET disabled = EXCLUDED.disabled;
@@ -0,0 +1,4 @@
+-- a refresh token of the session was used
+UPDATE user_session
+SET last_refresh = $2
+WHERE id = $1;
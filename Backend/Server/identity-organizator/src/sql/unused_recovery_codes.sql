@@ -0,0 +1,5 @@
+SELECT id,
+       code_hash
+FROM totp_recovery_code
+WHERE user_id = $1
+  AND used_on IS NULL;
@@ -1 +1,2 @@
-update users set password_hash = $1 where username = $2;
+-- the legacy PBKDF2 hash goes, the Argon2 one replaces it
+update users set password_hash = $1, pbkdf2 = null, salt = null where username = $2;
@@ -0,0 +1,10 @@
+-- a code is redeemed once, whether the rest of the request is right or not
+WITH used AS (
+  DELETE FROM oidc_authorization_code
+  WHERE code_hash = $1
+  RETURNING *
+)
+SELECT used.client_id, used.user_id, used.redirect_uri, used.scope, used.nonce,
+  used.code_challenge, used.expires_at, users.username
+FROM used
+JOIN users ON users.id = used.user_id;
@@ -0,0 +1,5 @@
+-- a state is used once
+DELETE FROM oidc_login_state
+WHERE state_hash = $1
+  AND expires_at > $2
+RETURNING nonce, code_verifier, return_to;
@@ -0,0 +1,4 @@
+UPDATE totp_recovery_code
+SET used_on = $2
+WHERE id = $1
+  AND used_on IS NULL;
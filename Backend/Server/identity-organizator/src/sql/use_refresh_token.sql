@@ -0,0 +1,13 @@
+-- mark the token as used, only a valid token that was never used before matches
+UPDATE refresh_token
+SET used_on = $2
+FROM users
+WHERE refresh_token.token_hash = $1
+  AND refresh_token.used_on IS NULL
+  AND NOT refresh_token.revoked
+  AND refresh_token.expires_at > $2
+  AND users.id = refresh_token.user_id
+RETURNING refresh_token.family,
+          refresh_token.user_id,
+          refresh_token.expires_at,
+          users.username;
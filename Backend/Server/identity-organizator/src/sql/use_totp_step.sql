@@ -0,0 +1,6 @@
+-- the first code confirms the secret; a step is used once, even by concurrent logins
+UPDATE user_totp
+SET last_used_step = $2,
+    confirmed = true
+WHERE user_id = $1
+  AND (last_used_step IS NULL OR last_used_step < $2);
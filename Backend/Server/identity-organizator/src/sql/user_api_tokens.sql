@@ -0,0 +1,9 @@
+-- the tokens of the user, never the hashes
+SELECT id,
+       name,
+       scopes,
+       created_on,
+       expires_at
+FROM api_token
+WHERE user_id = $1
+ORDER BY id;
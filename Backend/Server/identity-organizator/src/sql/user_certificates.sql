@@ -0,0 +1,12 @@
+-- the certificates of the user, the revoked ones too
+SELECT id,
+       name,
+       subject_dn,
+       issuer_dn,
+       serial,
+       fingerprint,
+       created_on,
+       revoked_on
+FROM user_certificate
+WHERE user_id = $1
+ORDER BY id;
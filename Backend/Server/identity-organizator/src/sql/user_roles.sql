@@ -0,0 +1,8 @@
+-- all the users with the roles they have
+SELECT users.username,
+       COALESCE(array_agg(user_role.role ORDER BY user_role.role)
+                FILTER (WHERE user_role.role IS NOT NULL), '{}') AS roles
+FROM users
+LEFT JOIN user_role ON user_role.user_id = users.id
+GROUP BY users.id, users.username
+ORDER BY users.username;
@@ -0,0 +1,12 @@
+-- the sessions of the user not ended nor expired, $2 is now
+SELECT id,
+       user_agent,
+       ip,
+       created_on,
+       last_refresh,
+       expires_at
+FROM user_session
+WHERE user_id = $1
+  AND revoked_on IS NULL
+  AND expires_at > $2
+ORDER BY created_on DESC;
@@ -0,0 +1,12 @@
+-- all the users with their roles and account state
+SELECT users.username,
+       COALESCE(array_agg(user_role.role ORDER BY user_role.role)
+                FILTER (WHERE user_role.role IS NOT NULL), '{}') AS roles,
+       COALESCE(user_account.disabled, false) AS disabled,
+       COALESCE(user_account.must_change_password, false) AS must_change_password,
+       user_account.last_login
+FROM users
+LEFT JOIN user_role ON user_role.user_id = users.id
+LEFT JOIN user_account ON user_account.user_id = users.id
+GROUP BY users.id, users.username, user_account.user_id
+ORDER BY users.username;
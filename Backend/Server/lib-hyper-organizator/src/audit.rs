@@ -0,0 +1,256 @@
+//! Durable record of what matters for the security of the accounts.
+//!
+//! Logins, failed logins, password changes, ended sessions, changes to the certificates and
+//! API tokens and administrator actions go to the `audit_log` table, which only takes new rows. Administrators read it back as JSON, or export it as
+//! JSON lines, one event per line.
+use crate::authentication::rate_limit::client_ip;
+use crate::typedef::GenericError;
+use http::header::ACCEPT;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[cfg(feature = "postgres")]
+pub use database::{events, record};
+
+pub const LOGIN: &str = "login";
+pub const LOGIN_FAILED: &str = "login_failed";
+pub const PASSWORD_CHANGED: &str = "password_changed";
+pub const ADMIN_ACTION: &str = "admin_action";
+pub const SESSION_ENDED: &str = "session_ended";
+pub const CERTIFICATE_CHANGED: &str = "certificate_changed";
+pub const API_TOKEN_CHANGED: &str = "api_token_changed";
+
+const JSON_LINES: &str = "application/x-ndjson";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub event_type: &'static str,
+    /// whom the event is about
+    pub username: Option<String>,
+    /// who did it, when not the user
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub details: Value,
+}
+
+impl AuditEvent {
+    pub fn new(event_type: &'static str, username: &str) -> Self {
+        AuditEvent {
+            event_type,
+            username: Some(username.to_string()),
+            actor: None,
+            ip: None,
+            details: Value::Object(Default::default()),
+        }
+    }
+
+    /// An administrator action, `action` says which
+    pub fn admin(actor: &str, action: &str, username: Option<&str>) -> Self {
+        AuditEvent {
+            event_type: ADMIN_ACTION,
+            username: username.map(str::to_string),
+            actor: Some(actor.to_string()),
+            ip: None,
+            details: serde_json::json!({ "action": action }),
+        }
+    }
+
+    /// Set the actor when someone else acted for the user
+    pub fn by(mut self, actor: &str) -> Self {
+        if self.username.as_deref() != Some(actor) {
+            self.actor = Some(actor.to_string());
+        }
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<&str>) -> Self {
+        self.ip = ip.map(str::to_string);
+        self
+    }
+
+    /// The address of the client making the request
+    pub fn from_request<B>(self, request: &Request<B>) -> Self {
+        let ip = client_ip(request);
+        self.ip(ip.as_deref())
+    }
+
+    /// Add to the details, they are kept as a JSON object
+    pub fn detail(mut self, key: &str, value: impl Into<Value>) -> Self {
+        if let Value::Object(ref mut details) = self.details {
+            details.insert(key.to_string(), value.into());
+        }
+        self
+    }
+}
+
+/// The filter of `GET /admin/audit`
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, utoipa::IntoParams)]
+#[serde(default)]
+pub struct AuditQuery {
+    /// events about the user or done by the user
+    pub user: Option<String>,
+    /// only events of this type: login, login_failed, password_changed, session_ended,
+    /// certificate_changed, api_token_changed, admin_action
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+    /// seconds since epoch, the events from then on
+    pub from: i64,
+    /// at most this many events, the oldest first
+    pub limit: Option<i64>,
+    /// `jsonl` for JSON lines, also chosen by `Accept: application/x-ndjson`
+    pub format: Option<String>,
+}
+
+impl AuditQuery {
+    pub fn of<B>(request: &Request<B>) -> Result<Self, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default())
+    }
+
+    /// JSON lines for an export, a JSON array otherwise
+    pub fn wants_json_lines<B>(&self, request: &Request<B>) -> bool {
+        match self.format.as_deref() {
+            Some(format) => format == "jsonl" || format == "ndjson",
+            None => request
+                .headers()
+                .get(ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|accept| accept.contains(JSON_LINES)),
+        }
+    }
+}
+
+/// The events, each already JSON, as an array or as JSON lines
+pub fn events_response(
+    events: &[String],
+    json_lines: bool,
+) -> Result<Response<Body>, GenericError> {
+    let (content_type, body) = if json_lines {
+        let mut body = events.join("\n");
+        if !body.is_empty() {
+            body.push('\n');
+        }
+        (JSON_LINES, body)
+    } else {
+        (
+            "application/json; charset=utf-8",
+            format!("[{}]", events.join(",")),
+        )
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", content_type)
+        .header("server", "hyper")
+        .body(Body::from(body))?)
+}
+
+#[cfg(feature = "postgres")]
+mod database {
+    use super::*;
+    use deadpool_postgres::Client;
+    use jsonwebtoken::get_current_timestamp;
+    use tracing::debug;
+
+    /// Add the event to the log, a failure fails the request rather than go unrecorded
+    pub async fn record(client: &Client, event: AuditEvent) -> Result<(), GenericError> {
+        let now = get_current_timestamp() as i64;
+        let stmt = client
+            .prepare_cached(include_str!("sql/insert_audit_event.sql"))
+            .await?;
+        client
+            .execute(
+                &stmt,
+                &[
+                    &now,
+                    &event.event_type,
+                    &event.username,
+                    &event.actor,
+                    &event.ip,
+                    &event.details.to_string(),
+                ],
+            )
+            .await?;
+        debug!("Audit {} of {:?}", event.event_type, event.username);
+        Ok(())
+    }
+
+    /// The events matching the query, each as a JSON object
+    pub async fn events(client: &Client, query: &AuditQuery) -> Result<Vec<String>, GenericError> {
+        let stmt = client
+            .prepare_cached(include_str!("sql/audit_events.sql"))
+            .await?;
+        let rows = client
+            .query(
+                &stmt,
+                &[&query.user, &query.event_type, &query.from, &query.limit],
+            )
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("event")).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    #[test]
+    fn test_event() {
+        let event = AuditEvent::new(PASSWORD_CHANGED, "alice")
+            .by("alice")
+            .ip(Some("10.0.0.1"));
+        assert_eq!(event.actor, None);
+        let event = event.by("admin").detail("reason", "reset");
+        assert_eq!(event.actor.as_deref(), Some("admin"));
+        assert_eq!(event.details, serde_json::json!({ "reason": "reset" }));
+
+        let event = AuditEvent::admin("admin", "files_gc", None).detail("deleted", true);
+        assert_eq!(event.event_type, ADMIN_ACTION);
+        assert_eq!(event.username, None);
+        assert_eq!(
+            event.details,
+            serde_json::json!({ "action": "files_gc", "deleted": true })
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let request = Request::get("/admin/audit?user=alice&type=login&from=1700000000")
+            .body(())
+            .unwrap();
+        let query = AuditQuery::of(&request).unwrap();
+        assert_eq!(query.user.as_deref(), Some("alice"));
+        assert_eq!(query.event_type.as_deref(), Some("login"));
+        assert_eq!(query.from, 1_700_000_000);
+        assert!(!query.wants_json_lines(&request));
+
+        let request = Request::get("/admin/audit?format=jsonl").body(()).unwrap();
+        assert!(AuditQuery::of(&request).unwrap().wants_json_lines(&request));
+        let request = Request::get("/admin/audit")
+            .header(ACCEPT, JSON_LINES)
+            .body(())
+            .unwrap();
+        let query = AuditQuery::of(&request).unwrap();
+        assert_eq!(query, AuditQuery::default());
+        assert!(query.wants_json_lines(&request));
+        let request = Request::get("/admin/audit?from=yesterday")
+            .body(())
+            .unwrap();
+        assert!(AuditQuery::of(&request).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events_response() {
+        let events = vec![r#"{"id":1}"#.to_string(), r#"{"id":2}"#.to_string()];
+        let body = |response: Response<Body>| async {
+            String::from_utf8(to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+        };
+        let array = events_response(&events, false).unwrap();
+        assert_eq!(body(array).await, r#"[{"id":1},{"id":2}]"#);
+        let lines = events_response(&events, true).unwrap();
+        assert_eq!(lines.headers()["content-type"], JSON_LINES);
+        assert_eq!(body(lines).await, "{\"id\":1}\n{\"id\":2}\n");
+        assert_eq!(body(events_response(&[], true).unwrap()).await, "");
+    }
+}
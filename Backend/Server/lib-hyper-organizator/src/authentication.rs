@@ -1,3 +1,14 @@
+pub mod api_token;
 pub mod authentication_layers;
 pub mod check_security;
+pub mod client_certificate;
+pub mod csrf;
 pub mod jot;
+pub mod oidc;
+pub mod password_hash;
+pub mod password_policy;
+pub mod rate_limit;
+pub mod revocation;
+pub mod totp;
+pub mod trusted_proxy;
+pub mod upstream_oidc;
@@ -0,0 +1,203 @@
+//! Personal access tokens, for scripts and integrations.
+//!
+//! Opaque bearer tokens kept hashed in the `api_token` table, each limited to a few scopes.
+//! Every service holds a copy in memory, reloaded periodically like the revoked tokens.
+use crate::typedef::{ApiTokenScopes, GenericError};
+use base64::alphabet::URL_SAFE;
+use base64::engine::fast_portable::{FastPortable, NO_PAD};
+use http::Request;
+use jsonwebtoken::get_current_timestamp;
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::trace;
+
+/// Tells them apart from JWTs in the Authorization header
+pub const API_TOKEN_PREFIX: &str = "orgpat_";
+
+pub const MEMO_READ: &str = "memo:read";
+pub const MEMO_WRITE: &str = "memo:write";
+pub const FILES_READ: &str = "files:read";
+pub const FILES_WRITE: &str = "files:write";
+/// The scopes a token can be given, administration is never one of them
+pub const SCOPES: &[&str] = &[MEMO_READ, MEMO_WRITE, FILES_READ, FILES_WRITE];
+
+#[derive(Clone, Debug)]
+pub struct ApiToken {
+    pub id: i32,
+    pub username: String,
+    pub scopes: Vec<String>,
+    /// seconds since epoch, never if missing
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Default, Debug)]
+pub struct ApiTokenList {
+    /// by the hash of the token
+    tokens: RwLock<HashMap<String, ApiToken>>,
+}
+
+impl ApiTokenList {
+    /// The token if it exists and has not expired
+    pub fn find(&self, token: &str) -> Option<ApiToken> {
+        let now = get_current_timestamp();
+        self.tokens
+            .read()
+            .unwrap()
+            .get(&hash_token(token))
+            .filter(|api_token| api_token.expires_at.is_none_or(|exp| exp > now))
+            .cloned()
+    }
+
+    /// Add to the local copy, other services learn about it at their next reload
+    pub fn insert(&self, token_hash: String, api_token: ApiToken) {
+        self.tokens.write().unwrap().insert(token_hash, api_token);
+    }
+
+    pub fn remove(&self, token_hash: &str) {
+        self.tokens.write().unwrap().remove(token_hash);
+    }
+
+    /// Drop all the tokens of a user, e.g. when the user is disabled
+    pub fn remove_user(&self, username: &str) {
+        self.tokens
+            .write()
+            .unwrap()
+            .retain(|_, api_token| api_token.username != username);
+    }
+
+    /// Take the content of the table
+    pub fn replace(&self, tokens: HashMap<String, ApiToken>) {
+        let mut current = self.tokens.write().unwrap();
+        *current = tokens;
+        trace!("{} API tokens", current.len());
+    }
+}
+
+/// Only the hash is stored, a leaked table does not hand out access
+pub fn hash_token(token: &str) -> String {
+    digest(&SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// A new token, 256 random bits after the prefix
+pub fn generate_token() -> Result<String, GenericError> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| GenericError::from("Could not generate an API token"))?;
+    let random = base64::encode_engine(bytes, &FastPortable::from(&URL_SAFE, NO_PAD));
+    Ok(format!("{API_TOKEN_PREFIX}{random}"))
+}
+
+/// Requests authorized otherwise than by an API token can do anything their user can
+pub fn has_scope<B>(request: &Request<B>, scope: &str) -> bool {
+    request
+        .extensions()
+        .get::<ApiTokenScopes>()
+        .is_none_or(|ApiTokenScopes(scopes)| scopes.iter().any(|s| s == scope))
+}
+
+#[cfg(feature = "postgres")]
+pub use database::refresh_periodically;
+
+#[cfg(feature = "postgres")]
+mod database {
+    use super::*;
+    use deadpool_postgres::{Client, Pool};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::error;
+
+    async fn load(client: &Client) -> Result<HashMap<String, ApiToken>, GenericError> {
+        let now = get_current_timestamp() as i64;
+        let stmt = client
+            .prepare_cached(include_str!("sql/api_tokens.sql"))
+            .await?;
+        let rows = client.query(&stmt, &[&now]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let expires_at: Option<i64> = row.get("expires_at");
+                let api_token = ApiToken {
+                    id: row.get("id"),
+                    username: row.get("username"),
+                    scopes: row.get("scopes"),
+                    expires_at: expires_at.map(|exp| exp as u64),
+                };
+                (row.get("token_hash"), api_token)
+            })
+            .collect())
+    }
+
+    /// Reload the list from the database until the process ends
+    pub async fn refresh_periodically(
+        pool: Pool,
+        api_tokens: Arc<ApiTokenList>,
+        interval: Duration,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let tokens = match pool.get().await {
+                Ok(client) => load(&client).await,
+                Err(e) => Err(e.into()),
+            };
+            match tokens {
+                Ok(tokens) => api_tokens.replace(tokens),
+                // keep the copy we have, the next attempt may work
+                Err(e) => error!("Could not reload the API tokens: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_token(expires_at: Option<u64>) -> ApiToken {
+        ApiToken {
+            id: 1,
+            username: "admin".to_string(),
+            scopes: vec![MEMO_READ.to_string()],
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let list = ApiTokenList::default();
+        let token = generate_token().unwrap();
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+        assert!(list.find(&token).is_none());
+        list.insert(hash_token(&token), api_token(None));
+        assert_eq!(list.find(&token).unwrap().username, "admin");
+
+        let expired = generate_token().unwrap();
+        list.insert(hash_token(&expired), api_token(Some(1)));
+        assert!(list.find(&expired).is_none());
+
+        list.remove(&hash_token(&token));
+        assert!(list.find(&token).is_none());
+
+        list.insert(hash_token(&token), api_token(None));
+        list.remove_user("admin");
+        assert!(list.find(&token).is_none());
+    }
+
+    #[test]
+    fn test_has_scope() {
+        let mut request = Request::new(());
+        assert!(has_scope(&request, MEMO_WRITE));
+        request
+            .extensions_mut()
+            .insert(ApiTokenScopes(vec![MEMO_READ.to_string()]));
+        assert!(has_scope(&request, MEMO_READ));
+        assert!(!has_scope(&request, MEMO_WRITE));
+    }
+}
@@ -1,17 +1,23 @@
+use crate::authentication::api_token::ApiTokenList;
+use crate::authentication::client_certificate::CertificateList;
+use crate::authentication::revocation::RevocationList;
 use crate::settings::SecurityConfig;
 pub use authorization::add_authorization;
+use std::sync::Arc;
 use tower::ServiceBuilder;
 use tracing::info;
 
 #[cfg(feature = "security")]
 mod authorization {
 
-    use crate::authentication::check_security::OrganizatorAuthorization;
-    use crate::authentication::jot::Jot;
-    use http::header::AUTHORIZATION;
-    use std::sync::Arc;
+    use crate::authentication::check_security::{OrganizatorAuthorization, TOKEN_EXPIRES_HEADER};
+    use crate::authentication::csrf::CsrfConfig;
+    use crate::authentication::jot::{TokenIssuer, TokenVerifier, token_handlers};
+    use http::header::HeaderName;
+    use tower::layer::util::Identity;
+    use tower::util::Either;
     use tower_http::propagate_header::PropagateHeaderLayer;
-    use tower_http::{add_extension::AddExtensionLayer, auth::RequireAuthorizationLayer};
+    use tower_http::{add_extension::AddExtensionLayer, auth::AsyncRequireAuthorizationLayer};
     use tower_layer::Stack;
 
     use super::*;
@@ -19,25 +25,58 @@ mod authorization {
     pub async fn add_authorization<L>(
         service_builder: ServiceBuilder<L>,
         security_config: SecurityConfig,
+        revocation_list: Arc<RevocationList>,
+        api_tokens: Arc<ApiTokenList>,
+        certificates: Arc<CertificateList>,
     ) -> ServiceBuilder<
         Stack<
-            RequireAuthorizationLayer<OrganizatorAuthorization>,
-            Stack<PropagateHeaderLayer, Stack<AddExtensionLayer<Arc<Jot>>, L>>,
+            PropagateHeaderLayer,
+            Stack<
+                AsyncRequireAuthorizationLayer<OrganizatorAuthorization>,
+                Stack<
+                    AddExtensionLayer<Arc<CsrfConfig>>,
+                    Stack<
+                        Either<AddExtensionLayer<Arc<TokenIssuer>>, Identity>,
+                        Stack<
+                            AddExtensionLayer<Arc<CertificateList>>,
+                            Stack<
+                                AddExtensionLayer<Arc<ApiTokenList>>,
+                                Stack<
+                                    AddExtensionLayer<Arc<RevocationList>>,
+                                    Stack<AddExtensionLayer<Arc<TokenVerifier>>, L>,
+                                >,
+                            >,
+                        >,
+                    >,
+                >,
+            >,
         >,
     > {
         info!("Security enabled");
+        let (verifier, issuer) = token_handlers(&security_config).await.unwrap();
         service_builder
-            // Share an `Arc<Jot>` with all requests
-            .layer(AddExtensionLayer::new(Arc::new(
-                Jot::new(&security_config).await.unwrap(),
-            )))
-            // Propagate the JWT token from the request to the response; if it's close
-            // to expiring, a new one will be generated and returned in the response
-            .layer(PropagateHeaderLayer::new(AUTHORIZATION))
+            // Share an `Arc<TokenVerifier>` with all requests
+            .layer(AddExtensionLayer::new(Arc::new(verifier)))
+            // The revoked tokens, kept up to date by the database layer
+            .layer(AddExtensionLayer::new(revocation_list))
+            // The API tokens, kept up to date by the database layer as well
+            .layer(AddExtensionLayer::new(api_tokens))
+            // And the client certificates registered to users
+            .layer(AddExtensionLayer::new(certificates))
+            // Only the identity service has the keys to issue tokens
+            .option_layer(issuer.map(|issuer| AddExtensionLayer::new(Arc::new(issuer))))
+            // The origins allowed to use the cookie and the certificate of the browser
+            .layer(AddExtensionLayer::new(Arc::new(security_config.csrf)))
             // If the response has a known size set the `Content-Length` header
             // .layer(SetResponseHeaderLayer::overriding(CONTENT_TYPE, content_length_from_response))
             // Authorize requests using a token
-            .layer(RequireAuthorizationLayer::custom(OrganizatorAuthorization))
+            .layer(AsyncRequireAuthorizationLayer::new(
+                OrganizatorAuthorization,
+            ))
+            // Tell the client its token is about to expire, the header is set by the authorization
+            .layer(PropagateHeaderLayer::new(HeaderName::from_static(
+                TOKEN_EXPIRES_HEADER,
+            )))
     }
 }
 
@@ -48,6 +87,9 @@ mod authorization {
     pub async fn add_authorization<L>(
         service_builder: ServiceBuilder<L>,
         _security_config: SecurityConfig,
+        _revocation_list: Arc<RevocationList>,
+        _api_tokens: Arc<ApiTokenList>,
+        _certificates: Arc<CertificateList>,
     ) -> ServiceBuilder<L> {
         info!("Security disabled");
         service_builder
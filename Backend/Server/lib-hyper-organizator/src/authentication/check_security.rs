@@ -1,82 +1,192 @@
-use crate::authentication::jot::{ExpiredToken, Jot};
+use crate::authentication::api_token::{API_TOKEN_PREFIX, ApiTokenList};
+use crate::authentication::client_certificate::{CertificateList, PresentedCertificate};
+use crate::authentication::csrf::CsrfConfig;
+use crate::authentication::jot::{ExpiredToken, TokenVerifier};
+use crate::authentication::revocation::RevocationList;
+use crate::authentication::trusted_proxy::certificate_headers_trusted;
 use crate::response_utils::IntoHyperResponse;
-use crate::typedef::{UserId, UserRoles};
-/// Authentication is checked in two steps:
+use crate::typedef::{ApiTokenScopes, SessionId, UserId, UserRoles};
+/// Authentication is checked in three steps:
 ///  - check a header filled in by Nginx from a client certificate
+///  - check for an API token in the Authorization header
 ///  - check the JWT token in the Authorization header
 ///
+/// Credentials the browser sends by itself, the certificate and the JWT cookie, are then
+/// checked against cross-site request forgery.
+///
 use http::StatusCode;
 use http::header::{AUTHORIZATION, COOKIE};
 use hyper::{Body, Request, Response};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use tower_http::auth::AuthorizeRequest;
+use tower_http::auth::AsyncAuthorizeRequest;
 use tracing::{info, trace};
 
-const SSL_HEADER_VERIFY: &str = "X-SSL-Client-Verify";
-const SSL_HEADER_DN: &str = "X-SSL-Client-S-DN";
-
 /// Bearer token is described here: <https://www.rfc-editor.org/rfc/rfc6750>
 pub const BEARER: &str = "Bearer ";
 
+/// Set on the responses to requests made with a token in the grace period, holds its expiry.
+/// The client should get a new token from the identity service `/refresh`.
+pub const TOKEN_EXPIRES_HEADER: &str = "x-organizator-token-expires";
+
 #[derive(Clone, Copy)]
 pub struct OrganizatorAuthorization;
 
-impl<B> AuthorizeRequest<B> for OrganizatorAuthorization {
+impl<B: Send + 'static> AsyncAuthorizeRequest<B> for OrganizatorAuthorization {
+    type RequestBody = B;
     type ResponseBody = Body;
+    type Future = Pin<Box<dyn Future<Output = Result<Request<B>, Response<Body>>> + Send>>;
+
+    fn authorize(&mut self, mut request: Request<B>) -> Self::Future {
+        Box::pin(async move {
+            // a token signed with a key published since the last fetch is accepted at once
+            let verifier = request.extensions().get::<Arc<TokenVerifier>>().cloned();
+            let unknown_key = verifier.filter(|verifier| {
+                extract_jwt(&request).is_some_and(|jwt| verifier.needs_key_refresh(jwt))
+            });
+            if let Some(verifier) = unknown_key {
+                verifier.refresh_keys().await;
+            }
+            match refusal(&mut request) {
+                Some(response) => Err(response),
+                None => Ok(request),
+            }
+        })
+    }
+}
 
-    fn authorize(&mut self, request: &mut Request<B>) -> Result<(), Response<Self::ResponseBody>> {
-        trace!("Checking authorization");
-        // check if the url is in the list of allowed urls (e.g. /login)
-        let Some(jot) = request.extensions().get::<Arc<Jot>>() else {
-            return Err(
-                "No Jot in the request".to_text_response_with_status(StatusCode::UNAUTHORIZED)
-            );
-        };
-        if jot.is_ignored_path(request.uri().path()) {
-            return Ok(());
-        }
+/// Why the request is refused, `None` if it may go through
+fn refusal<B>(request: &mut Request<B>) -> Option<Response<Body>> {
+    trace!("Checking authorization");
+    // check if the url is in the list of allowed urls (e.g. /login)
+    let Some(jot) = request.extensions().get::<Arc<TokenVerifier>>() else {
+        return Some(
+            "No TokenVerifier in the request"
+                .to_text_response_with_status(StatusCode::UNAUTHORIZED),
+        );
+    };
+    if jot.is_ignored_path(request.uri().path()) {
+        return None;
+    }
 
-        if let Some(user_id) = check_ssl_header(request) {
-            trace!("User {} is authorized via ssl header", user_id.0);
-            request.extensions_mut().insert(user_id);
-            Ok(())
-        } else if let Some(user_id) = check_jwt_header(request) {
-            request.extensions_mut().insert(user_id);
-            Ok(())
-        } else {
-            Err("Unauthorized request".to_text_response_with_status(StatusCode::UNAUTHORIZED))
+    if let Some(user_id) = check_ssl_header(request) {
+        trace!("User {} is authorized via ssl header", user_id.0);
+        if forged(request) {
+            return Some(forgery_response());
+        }
+        request.extensions_mut().insert(user_id);
+        None
+    } else if let Some(user_id) = check_api_token(request) {
+        request.extensions_mut().insert(user_id);
+        None
+    } else if let Some(user_id) = check_jwt_header(request) {
+        if cookie_authenticated(request) && forged(request) {
+            return Some(forgery_response());
         }
+        request.extensions_mut().insert(user_id);
+        None
+    } else {
+        Some("Unauthorized request".to_text_response_with_status(StatusCode::UNAUTHORIZED))
+    }
+}
+
+/// Refused by the CSRF checks, never without the configuration
+fn forged<B>(request: &Request<B>) -> bool {
+    request
+        .extensions()
+        .get::<Arc<CsrfConfig>>()
+        .is_some_and(|csrf| csrf.refuses(request))
+}
+
+fn forgery_response() -> Response<Body> {
+    "Request from another site refused".to_text_response_with_status(StatusCode::FORBIDDEN)
+}
+
+/// The token came from the cookie, maybe copied into the Authorization header by nginx
+fn cookie_authenticated<B>(request: &Request<B>) -> bool {
+    match (
+        extract_bearer(request),
+        get_cookie_value(request, COOKIE_NAME_PREFIX),
+    ) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(bearer), Some(cookie)) => bearer == cookie.trim(),
     }
 }
 
 fn check_ssl_header<B>(request: &Request<B>) -> Option<UserId> {
     trace!("Check if the certificate verification was successful");
-    match request.headers().get(SSL_HEADER_VERIFY) {
-        Some(s) if s == "SUCCESS" => (),
-        Some(s) => {
-            info!(
-                "Content of {SSL_HEADER_VERIFY} is {:?} instead of SUCCESS, SSL verification failed",
-                s
-            );
-            return None;
-        }
-        None => {
-            info!("No {SSL_HEADER_VERIFY} header, can not allow access without SSL verification");
-            return None;
-        }
+    let presented = PresentedCertificate::from_headers(request)?;
+    if !certificate_headers_trusted(request) {
+        return None;
     }
-    trace!("Check if the DN is present");
-    match request.headers().get(SSL_HEADER_DN).map(|s| s.to_str()) {
-        Some(Ok(dn)) if dn.len() > 3 && &dn[0..3] == "CN=" => Some(UserId(dn[3..].to_string())),
-        Some(Ok(dn)) => {
-            info!("Invalid DN: 「{}」", dn);
-            None
-        }
-        _ => None,
+    let Some(certificate) = request
+        .extensions()
+        .get::<Arc<CertificateList>>()
+        .and_then(|list| list.find(&presented))
+    else {
+        // what an administrator needs to register it
+        info!(
+            "Client certificate 「{}」 issuer 「{}」 serial {} fingerprint {} is not registered \
+             or was revoked",
+            presented.subject,
+            presented
+                .issuer
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            presented.serial.as_deref().unwrap_or_default(),
+            presented.fingerprint.as_deref().unwrap_or_default()
+        );
+        return None;
+    };
+    trace!("Client certificate {} matched", certificate.id);
+    Some(UserId(certificate.username))
+}
+
+fn check_api_token<B>(request: &mut Request<B>) -> Option<UserId> {
+    let token = extract_bearer(request)?;
+    if !token.starts_with(API_TOKEN_PREFIX) {
+        return None;
     }
+    let Some(api_token) = request
+        .extensions()
+        .get::<Arc<ApiTokenList>>()
+        .and_then(|list| list.find(token))
+    else {
+        info!("Unknown, expired or deleted API token");
+        return None;
+    };
+    trace!(
+        "User {} is authorized via API token {}",
+        api_token.username, api_token.id
+    );
+    // no roles, a token does not administer anything
+    request
+        .extensions_mut()
+        .insert(ApiTokenScopes(api_token.scopes));
+    Some(UserId(api_token.username))
 }
 
-fn check_jwt_header<B>(request: &mut Request<B>) -> Option<UserId> {
+/// Also for the handlers of ignored paths that want to know who is logged in
+pub fn check_jwt_header<B>(request: &mut Request<B>) -> Option<UserId> {
+    check_jwt(request, None)
+}
+
+/// For the handlers of ignored paths taking the tokens issued to other applications,
+/// `accepts` tells which audiences
+pub fn check_client_jwt_header<B>(
+    request: &mut Request<B>,
+    accepts: &dyn Fn(&str) -> bool,
+) -> Option<UserId> {
+    check_jwt(request, Some(accepts))
+}
+
+fn check_jwt<B>(
+    request: &mut Request<B>,
+    accepts: Option<&dyn Fn(&str) -> bool>,
+) -> Option<UserId> {
     trace!("Checking the headers for a JWT bearer token");
     let jwt = extract_jwt(request);
     let jwt = match jwt {
@@ -87,33 +197,48 @@ fn check_jwt_header<B>(request: &mut Request<B>) -> Option<UserId> {
         }
     };
 
-    let jot = request.extensions().get::<Arc<Jot>>()?;
-    if let Ok(claims) = jot.validate_token(jwt) {
+    let jot = request.extensions().get::<Arc<TokenVerifier>>()?;
+    let validated = match accepts {
+        Some(accepts) => jot.validate_token_for(jwt, accepts),
+        None => jot.validate_token(jwt),
+    };
+    if let Ok(claims) = validated {
+        if request
+            .extensions()
+            .get::<Arc<RevocationList>>()
+            .is_some_and(|list| list.is_revoked(&claims.jti))
+        {
+            info!("Token {} of 「{}」 was revoked", claims.jti, claims.sub);
+            return None;
+        }
+        if request
+            .extensions()
+            .get::<Arc<RevocationList>>()
+            .is_some_and(|list| list.is_session_revoked(&claims.sid))
+        {
+            info!("Session {} of 「{}」 was ended", claims.sid, claims.sub);
+            return None;
+        }
         // verify the token has not expired
-        match jot.check_expiration(&claims) {
+        let expiration = jot.check_expiration(&claims);
+        if !claims.sid.is_empty() {
+            request
+                .extensions_mut()
+                .insert(SessionId(claims.sid.clone()));
+        }
+        match expiration {
             ExpiredToken::Valid => {
                 request.extensions_mut().insert(UserRoles(claims.roles));
                 Some(UserId(claims.sub))
             }
             ExpiredToken::GracePeriod => {
-                // refresh the token
-                let temp: Vec<&str> = claims.roles.iter().map(|s| s.as_str()).collect();
-                if let Ok(new_token) = jot.generate_token(&claims.sub, &temp) {
-                    let header = String::from(BEARER) + &new_token;
-                    let cookie = create_security_cookie(&new_token);
-
-                    request
-                        .headers_mut()
-                        .insert(AUTHORIZATION, header.parse().unwrap());
-
-                    request
-                        .headers_mut()
-                        .insert("Set-Cookie", cookie.parse().unwrap());
-                    request.extensions_mut().insert(UserRoles(claims.roles));
-                    Some(UserId(claims.sub))
-                } else {
-                    None
-                }
+                // only the identity service can issue a new token, tell the client to ask for it
+                trace!("Token of 「{}」 in the grace period", claims.sub);
+                request
+                    .headers_mut()
+                    .insert(TOKEN_EXPIRES_HEADER, claims.exp.into());
+                request.extensions_mut().insert(UserRoles(claims.roles));
+                Some(UserId(claims.sub))
             }
             ExpiredToken::Expired => {
                 info!("Token expired");
@@ -130,7 +255,12 @@ pub fn create_security_cookie(jwt: &str) -> String {
     format!("__Host-jwt={jwt}; HttpOnly; Secure; SameSite=Strict; Path=/;")
 }
 
-fn get_cookie_value<'a, B>(req: &'a Request<B>, cookie_name_prefix: &str) -> Option<&'a str> {
+/// Makes the browser drop the security cookie
+pub fn clear_security_cookie() -> String {
+    "__Host-jwt=; HttpOnly; Secure; SameSite=Strict; Path=/; Max-Age=0".to_string()
+}
+
+pub fn get_cookie_value<'a, B>(req: &'a Request<B>, cookie_name_prefix: &str) -> Option<&'a str> {
     req.headers()
         .get_all(COOKIE)
         .iter()
@@ -155,7 +285,17 @@ fn extract_bearer<B>(req: &Request<B>) -> Option<&str> {
 const COOKIE_NAME_PREFIX: &str = "__Host-jwt=";
 const COOKIE_NAME: &str = "__Host-jwt";
 
-fn extract_jwt<B>(req: &Request<B>) -> Option<&str> {
+/// The tokens in both the Authorization header and the cookie, they can differ
+pub fn extract_all_jwt<B>(req: &Request<B>) -> Vec<&str> {
+    let mut tokens: Vec<&str> = extract_bearer(req)
+        .into_iter()
+        .chain(get_cookie_value(req, COOKIE_NAME_PREFIX))
+        .collect();
+    tokens.dedup();
+    tokens
+}
+
+pub fn extract_jwt<B>(req: &Request<B>) -> Option<&str> {
     let bearer_token = extract_bearer(req);
     if bearer_token.is_some() {
         trace!("Found header {AUTHORIZATION} with bearer token");
@@ -176,42 +316,308 @@ fn extract_jwt<B>(req: &Request<B>) -> Option<&str> {
 mod tests {
 
     use super::*;
+    use crate::authentication::api_token::{
+        ApiToken, MEMO_READ, MEMO_WRITE, generate_token, has_scope, hash_token,
+    };
+    use crate::authentication::client_certificate::{
+        SSL_HEADER_DN, SSL_HEADER_ISSUER_DN, SSL_HEADER_SERIAL, SSL_HEADER_VERIFY, UserCertificate,
+    };
+    use crate::authentication::jot::TokenIssuer;
+    use crate::authentication::trusted_proxy::{RemotePeer, TrustedProxies};
     use crate::settings::SecurityConfig;
+    use http::header::HeaderName;
     use hyper::Error;
+    use jsonwebtoken::get_current_timestamp;
     use tower::{Service, ServiceBuilder, ServiceExt};
     use tower_http::add_extension::AddExtensionLayer;
-    use tower_http::auth::RequireAuthorizationLayer;
+    use tower_http::auth::AsyncRequireAuthorizationLayer;
+    use tower_http::propagate_header::PropagateHeaderLayer;
+
+    fn certificate_list() -> Arc<CertificateList> {
+        let certificates = Arc::new(CertificateList::default());
+        certificates.insert(UserCertificate {
+            id: 1,
+            username: "admin".to_string(),
+            subject_dn: "CN=admin,O=Organizator".to_string(),
+            issuer_dn: Some("CN=Organizator CA".to_string()),
+            serial: Some("1A".to_string()),
+            fingerprint: None,
+        });
+        certificates
+    }
 
-    #[test]
-    fn test_check_ssl_header() {
+    fn certificate_request(subject: &str, serial: &str) -> Request<Body> {
         let mut request = Request::new(Body::empty());
+        let headers = request.headers_mut();
+        headers.insert(SSL_HEADER_VERIFY, "SUCCESS".parse().unwrap());
+        headers.insert(SSL_HEADER_DN, subject.parse().unwrap());
+        headers.insert(SSL_HEADER_ISSUER_DN, "CN=Organizator CA".parse().unwrap());
+        headers.insert(SSL_HEADER_SERIAL, serial.parse().unwrap());
+        request.extensions_mut().insert(peer("10.0.0.2:40000"));
         request
-            .headers_mut()
-            .insert(SSL_HEADER_VERIFY, "SUCCESS".parse().unwrap());
-        request
-            .headers_mut()
-            .insert(SSL_HEADER_DN, "CN=admin".parse().unwrap());
+    }
+
+    /// Where nginx runs in the tests
+    fn peer(addr: &str) -> RemotePeer {
+        RemotePeer {
+            addr: addr.parse().unwrap(),
+            proxies: Arc::new(TrustedProxies::new(&["10.0.0.2".to_string()], None).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_check_ssl_header() {
+        let mut request = certificate_request("CN=admin,O=Organizator", "1A");
+        // nothing is trusted without the registered certificates
+        assert_eq!(check_ssl_header(&request), None);
+        request.extensions_mut().insert(certificate_list());
         assert_eq!(
-            check_ssl_header(&mut request),
+            check_ssl_header(&request),
             Some(UserId("admin".to_string()))
         );
+
+        // the CN alone is not enough, nor a certificate that did not verify
+        for mut request in [
+            certificate_request("CN=admin", "1A"),
+            certificate_request("CN=admin,O=Organizator", "1B"),
+        ] {
+            request.extensions_mut().insert(certificate_list());
+            assert_eq!(check_ssl_header(&request), None);
+        }
+        request.headers_mut().insert(
+            SSL_HEADER_VERIFY,
+            "FAILED:unable to verify".parse().unwrap(),
+        );
+        assert_eq!(check_ssl_header(&request), None);
+
+        // the same headers from anyone but nginx
+        let mut request = certificate_request("CN=admin,O=Organizator", "1A");
+        request.extensions_mut().insert(certificate_list());
+        request.extensions_mut().insert(peer("203.0.113.7:40000"));
+        assert_eq!(check_ssl_header(&request), None);
+    }
+
+    fn token_handlers(security_config: &SecurityConfig) -> (TokenIssuer, Arc<TokenVerifier>) {
+        let issuer = TokenIssuer::new(security_config).unwrap();
+        let verifier = Arc::new(issuer.verifier(security_config));
+        (issuer, verifier)
     }
 
     #[tokio::test]
     async fn test_check_jwt_header() {
         let mut request = Request::new(Body::empty());
-        let jot = Jot::new(&SecurityConfig::default()).await.unwrap();
-        let token = jot.generate_token("admin", &[]).unwrap();
+        let (issuer, verifier) = token_handlers(&SecurityConfig::default());
+        let token = issuer.generate_token("admin", &[]).unwrap();
         let header = String::from(BEARER) + &token;
 
         request
             .headers_mut()
             .insert(AUTHORIZATION, header.parse().unwrap());
-        request.extensions_mut().insert(Arc::new(jot));
+        request.extensions_mut().insert(verifier);
+        assert_eq!(
+            check_jwt_header(&mut request),
+            Some(UserId("admin".to_string()))
+        );
+        assert!(request.headers().get(TOKEN_EXPIRES_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_grace_period() {
+        let (issuer, verifier) = token_handlers(&SecurityConfig::default());
+        let make_request = |exp: u64| {
+            let claims = serde_json::json!({"sub": "admin", "exp": exp, "roles": []});
+            let header = String::from(BEARER) + &issuer.sign(&claims).unwrap();
+            let mut request = Request::new(Body::empty());
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, header.parse().unwrap());
+            request.extensions_mut().insert(verifier.clone());
+            request
+        };
+        // still accepted, the client is told to ask the identity service for a new token
+        let exp = get_current_timestamp() + verifier.session_expiry_grace_period / 2;
+        let mut request = make_request(exp);
         assert_eq!(
             check_jwt_header(&mut request),
             Some(UserId("admin".to_string()))
         );
+        assert_eq!(
+            request.headers().get(TOKEN_EXPIRES_HEADER),
+            Some(&exp.into())
+        );
+        // the services can not issue a token themselves, an expired one is refused
+        let mut request = make_request(get_current_timestamp() - 100);
+        assert_eq!(check_jwt_header(&mut request), None);
+    }
+
+    #[tokio::test]
+    async fn test_roles_from_token() {
+        let (issuer, verifier) = token_handlers(&SecurityConfig::default());
+        let token = issuer.generate_token("admin", &["org", "orgadm"]).unwrap();
+        let mut request = Request::new(Body::empty());
+        let header = String::from(BEARER) + &token;
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, header.parse().unwrap());
+        request.extensions_mut().insert(verifier);
+        assert!(check_jwt_header(&mut request).is_some());
+        assert_eq!(
+            request.extensions().get::<UserRoles>().map(|r| r.0.clone()),
+            Some(vec!["org".to_string(), "orgadm".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoked_token_rejected() {
+        let (issuer, verifier) = token_handlers(&SecurityConfig::default());
+        let token = issuer.generate_token("admin", &[]).unwrap();
+        let claims = verifier.validate_token(&token).unwrap();
+        let revocation_list = Arc::new(RevocationList::default());
+        let header = String::from(BEARER) + &token;
+
+        let make_request = || {
+            let mut request = Request::new(Body::empty());
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, header.parse().unwrap());
+            request.extensions_mut().insert(verifier.clone());
+            request.extensions_mut().insert(revocation_list.clone());
+            request
+        };
+        assert!(check_jwt_header(&mut make_request()).is_some());
+
+        revocation_list.insert(&claims.jti, claims.exp);
+        assert_eq!(check_jwt_header(&mut make_request()), None);
+    }
+
+    #[tokio::test]
+    async fn test_ended_session_rejected() {
+        let (issuer, verifier) = token_handlers(&SecurityConfig::default());
+        let token = issuer
+            .generate_session_token("admin", &[], "session")
+            .unwrap();
+        let revocation_list = Arc::new(RevocationList::default());
+        let header = String::from(BEARER) + &token;
+
+        let make_request = || {
+            let mut request = Request::new(Body::empty());
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, header.parse().unwrap());
+            request.extensions_mut().insert(verifier.clone());
+            request.extensions_mut().insert(revocation_list.clone());
+            request
+        };
+        let mut request = make_request();
+        assert!(check_jwt_header(&mut request).is_some());
+        assert_eq!(
+            request
+                .extensions()
+                .get::<SessionId>()
+                .map(|s| s.0.as_str()),
+            Some("session")
+        );
+
+        // every token of the session is refused, not only the one presented at the logout
+        revocation_list.insert_session("session", get_current_timestamp() + 3600);
+        assert_eq!(check_jwt_header(&mut make_request()), None);
+    }
+
+    #[test]
+    fn test_check_api_token() {
+        let api_tokens = Arc::new(ApiTokenList::default());
+        let token = generate_token().unwrap();
+        let make_request = |token: &str| {
+            let mut request = Request::new(Body::empty());
+            let header = String::from(BEARER) + token;
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, header.parse().unwrap());
+            request.extensions_mut().insert(api_tokens.clone());
+            request
+        };
+        assert_eq!(check_api_token(&mut make_request(&token)), None);
+
+        api_tokens.insert(
+            hash_token(&token),
+            ApiToken {
+                id: 1,
+                username: "admin".to_string(),
+                scopes: vec![MEMO_READ.to_string()],
+                expires_at: None,
+            },
+        );
+        let mut request = make_request(&token);
+        assert_eq!(
+            check_api_token(&mut request),
+            Some(UserId("admin".to_string()))
+        );
+        assert!(has_scope(&request, MEMO_READ));
+        assert!(!has_scope(&request, MEMO_WRITE));
+        assert!(request.extensions().get::<UserRoles>().is_none());
+
+        // a JWT is left to check_jwt_header
+        assert_eq!(check_api_token(&mut make_request("a.b.c")), None);
+    }
+
+    #[tokio::test]
+    async fn test_cross_site_request_refused() -> Result<(), Error> {
+        let (issuer, verifier) = token_handlers(&SecurityConfig::default());
+        let token = issuer.generate_token("admin", &[]).unwrap();
+        let mut service = ServiceBuilder::new()
+            .layer(AddExtensionLayer::new(verifier))
+            .layer(AddExtensionLayer::new(certificate_list()))
+            .layer(AddExtensionLayer::new(Arc::new(CsrfConfig::default())))
+            .layer(AsyncRequireAuthorizationLayer::new(
+                OrganizatorAuthorization,
+            ))
+            .service_fn(|_| async { Ok::<_, Error>(Response::new(Body::empty())) });
+
+        let post = |origin: &str, cookie: bool, bearer: bool| {
+            let mut request = Request::post("/memo")
+                .header("host", "organizator.lab")
+                .header("origin", origin)
+                .body(Body::empty())
+                .unwrap();
+            if cookie {
+                let cookie = format!("{COOKIE_NAME_PREFIX}{token}");
+                request
+                    .headers_mut()
+                    .insert(COOKIE, cookie.parse().unwrap());
+            }
+            if bearer {
+                let header = String::from(BEARER) + &token;
+                request
+                    .headers_mut()
+                    .insert(AUTHORIZATION, header.parse().unwrap());
+            }
+            request
+        };
+        // the cookie, as is and as copied by nginx, only from the site itself
+        for (origin, cookie, bearer, status) in [
+            ("https://evil.example", true, false, StatusCode::FORBIDDEN),
+            ("https://evil.example", true, true, StatusCode::FORBIDDEN),
+            ("https://organizator.lab", true, true, StatusCode::OK),
+            // a bearer token the page had to know
+            ("https://evil.example", false, true, StatusCode::OK),
+        ] {
+            let response = service
+                .ready()
+                .await?
+                .call(post(origin, cookie, bearer))
+                .await?;
+            assert_eq!(response.status(), status, "{origin} {cookie} {bearer}");
+        }
+
+        let mut request = certificate_request("CN=admin,O=Organizator", "1A");
+        *request.method_mut() = http::Method::POST;
+        request
+            .headers_mut()
+            .insert("origin", "https://evil.example".parse().unwrap());
+        let response = service.ready().await?.call(request).await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
     }
 
     // Test using the header set by Nginx from a client certificate
@@ -220,20 +626,17 @@ mod tests {
         pretty_env_logger::init();
 
         let mut service = ServiceBuilder::new()
-            .layer(AddExtensionLayer::new(Arc::new(
-                Jot::new(&SecurityConfig::default()).await.unwrap(),
-            )))
-            .layer(RequireAuthorizationLayer::custom(OrganizatorAuthorization))
+            .layer(AddExtensionLayer::new(
+                token_handlers(&SecurityConfig::default()).1,
+            ))
+            .layer(AddExtensionLayer::new(certificate_list()))
+            .layer(AsyncRequireAuthorizationLayer::new(
+                OrganizatorAuthorization,
+            ))
             .service_fn(|_| async { Ok::<_, Error>(Response::new(Body::empty())) });
 
-        let mut request = Request::new(Body::empty());
-        // request with the header should be authorized
-        request
-            .headers_mut()
-            .insert(SSL_HEADER_VERIFY, "SUCCESS".parse().unwrap());
-        request
-            .headers_mut()
-            .insert(SSL_HEADER_DN, "CN=admin".parse().unwrap());
+        // request with the headers of a registered certificate should be authorized
+        let request = certificate_request("CN=admin,O=Organizator", "1A");
         let response = service.ready().await?.call(request).await?;
         println!("Response: {:#?}", &response);
         assert_eq!(response.status(), StatusCode::OK);
@@ -253,14 +656,18 @@ mod tests {
                 session_expiry_grace_period: $grace,
                 ignore_paths: vec![],
                 public_key_url: None,
+                ..SecurityConfig::default()
             };
-            let mut jot = Jot::new(&security_config).await.unwrap();
-            jot.session_expiry = $expiry;
-            jot.session_expiry_grace_period = $grace;
-            let token = jot.generate_token("admin", &[]).unwrap();
+            let (issuer, verifier) = token_handlers(&security_config);
+            let token = issuer.generate_token("admin", &[]).unwrap();
             let mut service = ServiceBuilder::new()
-                .layer(AddExtensionLayer::new(Arc::new(jot)))
-                .layer(RequireAuthorizationLayer::custom(OrganizatorAuthorization))
+                .layer(AddExtensionLayer::new(verifier))
+                .layer(AsyncRequireAuthorizationLayer::new(
+                    OrganizatorAuthorization,
+                ))
+                .layer(PropagateHeaderLayer::new(HeaderName::from_static(
+                    TOKEN_EXPIRES_HEADER,
+                )))
                 .service_fn(|_| async { Ok::<_, Error>(Response::new(Body::empty())) });
             let header = String::from(BEARER) + &token;
             let mut request = Request::new(Body::empty());
@@ -278,12 +685,14 @@ mod tests {
     async fn integration_test_jwt() -> Result<(), Error> {
         test_with_env!(3600, 300, response);
         assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(TOKEN_EXPIRES_HEADER).is_none());
 
         // request with a header in the grace period should be authorized
         test_with_env!(0, 300, response);
         assert_eq!(response.status(), StatusCode::OK);
 
-        // check we got a new token
+        // check the client is told to get a new token
+        assert!(response.headers().get(TOKEN_EXPIRES_HEADER).is_some());
 
         // request with an expired JWT token should be unauthorized
         test_with_env!(0, 0, response);
@@ -0,0 +1,551 @@
+//! Client certificates registered to users, checked when nginx verified one.
+//!
+//! nginx passes the subject and issuer as RFC 4514 strings, the serial and the certificate
+//! itself. A certificate is known by its SHA-256 fingerprint, or by its issuer and serial,
+//! and its subject has to be the one registered. Every service holds the registered
+//! certificates in memory, reloaded periodically like the API tokens.
+use crate::typedef::GenericError;
+use http::Request;
+use percent_encoding::percent_decode_str;
+use ring::digest::{SHA256, digest};
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::RwLock;
+use tracing::{info, trace};
+
+pub const SSL_HEADER_VERIFY: &str = "X-SSL-Client-Verify";
+/// Subject, `$ssl_client_s_dn`
+pub const SSL_HEADER_DN: &str = "X-SSL-Client-S-DN";
+/// Issuer, `$ssl_client_i_dn`
+pub const SSL_HEADER_ISSUER_DN: &str = "X-SSL-Client-I-DN";
+/// `$ssl_client_serial`
+pub const SSL_HEADER_SERIAL: &str = "X-SSL-Client-Serial";
+/// The PEM, URL encoded, `$ssl_client_escaped_cert`
+pub const SSL_HEADER_CERT: &str = "X-SSL-Client-Cert";
+
+/// One `type=value` of a relative distinguished name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Attribute {
+    /// in upper case, e.g. `CN`, or a dotted OID
+    pub kind: String,
+    pub value: String,
+    /// the value is the hex of its BER encoding, as given after `#`
+    pub encoded: bool,
+}
+
+/// A distinguished name, the most specific RDN first as in RFC 4514
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DistinguishedName {
+    /// the attributes of a multi-valued RDN are sorted, their order does not matter
+    pub rdns: Vec<Vec<Attribute>>,
+}
+
+fn is_hex(c: char) -> bool {
+    c.is_ascii_hexdigit()
+}
+
+/// Type of an attribute, a name or a dotted OID
+fn parse_type(chars: &mut Peekable<Chars>) -> Result<String, GenericError> {
+    let mut kind = String::new();
+    while let Some(c) = chars.next_if(|c| *c != '=') {
+        kind.push(c);
+    }
+    if chars.next() != Some('=') {
+        return Err("Attribute without a value".into());
+    }
+    let kind = kind.trim();
+    let name = kind.starts_with(|c: char| c.is_ascii_alphabetic())
+        && kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    let oid = !kind.is_empty()
+        && kind
+            .split('.')
+            .all(|arc| !arc.is_empty() && arc.chars().all(|c| c.is_ascii_digit()));
+    if !name && !oid {
+        return Err(format!("Bad attribute type 「{kind}」").into());
+    }
+    Ok(kind.to_ascii_uppercase())
+}
+
+/// Value up to the next unescaped `,` or `+`, which is left in place
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<(String, bool), GenericError> {
+    while chars.next_if_eq(&' ').is_some() {}
+    if chars.next_if_eq(&'#').is_some() {
+        let mut hex = String::from("#");
+        while let Some(c) = chars.next_if(|c| is_hex(*c)) {
+            hex.push(c.to_ascii_lowercase());
+        }
+        while chars.next_if_eq(&' ').is_some() {}
+        if hex.len() < 3 || hex.len() % 2 == 0 || !matches!(chars.peek(), None | Some(',' | '+')) {
+            return Err("Bad hex value".into());
+        }
+        return Ok((hex, true));
+    }
+    let mut bytes = Vec::new();
+    // trailing spaces are dropped unless escaped
+    let mut significant = 0;
+    while let Some(c) = chars.next_if(|c| *c != ',' && *c != '+') {
+        match c {
+            '\\' => match chars.next() {
+                Some(high) if is_hex(high) => {
+                    let low = chars
+                        .next()
+                        .filter(|c| is_hex(*c))
+                        .ok_or("Bad hex escape")?;
+                    let byte = u8::from_str_radix(&format!("{high}{low}"), 16)?;
+                    bytes.push(byte);
+                    significant = bytes.len();
+                }
+                Some(special @ (' ' | '"' | '#' | '+' | ',' | ';' | '<' | '=' | '>' | '\\')) => {
+                    bytes.push(special as u8);
+                    significant = bytes.len();
+                }
+                _ => return Err("Bad escape".into()),
+            },
+            '"' | ';' | '<' | '>' => return Err(format!("Unescaped 「{c}」").into()),
+            c => {
+                let mut buffer = [0u8; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                if c != ' ' {
+                    significant = bytes.len();
+                }
+            }
+        }
+    }
+    bytes.truncate(significant);
+    Ok((String::from_utf8(bytes)?, false))
+}
+
+impl DistinguishedName {
+    pub fn parse(dn: &str) -> Result<DistinguishedName, GenericError> {
+        let mut rdns = vec![];
+        if dn.trim().is_empty() {
+            return Ok(DistinguishedName { rdns });
+        }
+        let mut chars = dn.chars().peekable();
+        let mut rdn = vec![];
+        loop {
+            let kind = parse_type(&mut chars)?;
+            let (value, encoded) = parse_value(&mut chars)?;
+            rdn.push(Attribute {
+                kind,
+                value,
+                encoded,
+            });
+            match chars.next() {
+                Some('+') => {}
+                next => {
+                    rdn.sort();
+                    rdns.push(std::mem::take(&mut rdn));
+                    if next.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(DistinguishedName { rdns })
+    }
+
+    /// The most specific common name
+    pub fn common_name(&self) -> Option<&str> {
+        self.rdns
+            .iter()
+            .flatten()
+            .find(|attribute| attribute.kind == "CN" && !attribute.encoded)
+            .map(|attribute| attribute.value.as_str())
+    }
+}
+
+/// RFC 4514 escaping of a string value
+fn escape(value: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => write!(f, "\\{c}")?,
+            '#' if i == 0 => f.write_str("\\#")?,
+            ' ' if i == 0 || i == last => f.write_str("\\ ")?,
+            '\0' => f.write_str("\\00")?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    Ok(())
+}
+
+/// The canonical form, stored and compared
+impl fmt::Display for DistinguishedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, rdn) in self.rdns.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            for (j, attribute) in rdn.iter().enumerate() {
+                if j > 0 {
+                    f.write_str("+")?;
+                }
+                write!(f, "{}=", attribute.kind)?;
+                if attribute.encoded {
+                    f.write_str(&attribute.value)?;
+                } else {
+                    escape(&attribute.value, f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hex in upper case without separators and leading zeros, as nginx has it
+pub fn normalize_serial(serial: &str) -> Option<String> {
+    let digits: String = serial
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if digits.is_empty() || !digits.chars().all(is_hex) {
+        return None;
+    }
+    let trimmed = digits.trim_start_matches('0');
+    Some(if trimmed.is_empty() { "0" } else { trimmed }.to_string())
+}
+
+/// Lower case hex of 32 bytes, with or without colons
+pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let hex: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    (hex.len() == 64 && hex.chars().all(is_hex)).then_some(hex)
+}
+
+/// SHA-256 of the DER of a PEM certificate, URL encoded the way nginx passes it or not
+pub fn fingerprint_from_pem(pem: &str) -> Option<String> {
+    let pem = percent_decode_str(pem).decode_utf8().ok()?;
+    let base64: String = pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = base64::decode(base64).ok()?;
+    if der.is_empty() {
+        return None;
+    }
+    Some(
+        digest(&SHA256, &der)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect(),
+    )
+}
+
+/// The certificate the client connected with, as nginx describes it
+#[derive(Debug, Clone)]
+pub struct PresentedCertificate {
+    pub subject: DistinguishedName,
+    pub issuer: Option<DistinguishedName>,
+    pub serial: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+impl PresentedCertificate {
+    /// From the headers, if nginx verified the certificate
+    pub fn from_headers<B>(request: &Request<B>) -> Option<PresentedCertificate> {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        match header(SSL_HEADER_VERIFY) {
+            Some("SUCCESS") => {}
+            Some(verify) => {
+                info!("Content of {SSL_HEADER_VERIFY} is 「{verify}」, SSL verification failed");
+                return None;
+            }
+            None => {
+                trace!("No {SSL_HEADER_VERIFY} header, no client certificate");
+                return None;
+            }
+        }
+        let subject = match DistinguishedName::parse(header(SSL_HEADER_DN)?) {
+            Ok(subject) => subject,
+            Err(e) => {
+                info!("Invalid subject DN: {e}");
+                return None;
+            }
+        };
+        Some(PresentedCertificate {
+            subject,
+            issuer: header(SSL_HEADER_ISSUER_DN).and_then(|dn| DistinguishedName::parse(dn).ok()),
+            serial: header(SSL_HEADER_SERIAL).and_then(normalize_serial),
+            fingerprint: header(SSL_HEADER_CERT).and_then(fingerprint_from_pem),
+        })
+    }
+}
+
+/// A certificate registered to a user, the DNs in canonical form
+#[derive(Debug, Clone)]
+pub struct UserCertificate {
+    pub id: i32,
+    pub username: String,
+    pub subject_dn: String,
+    pub issuer_dn: Option<String>,
+    pub serial: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+impl UserCertificate {
+    /// The fingerprint decides when registered, the issuer and serial otherwise.
+    /// A serial is only unique for its issuer, it never matches alone.
+    pub fn matches(&self, presented: &PresentedCertificate) -> bool {
+        if self.subject_dn != presented.subject.to_string() {
+            return false;
+        }
+        if let Some(ref fingerprint) = self.fingerprint {
+            return presented.fingerprint.as_ref() == Some(fingerprint);
+        }
+        let (Some(serial), Some(issuer_dn)) = (&self.serial, &self.issuer_dn) else {
+            return false;
+        };
+        presented.serial.as_ref() == Some(serial)
+            && presented
+                .issuer
+                .as_ref()
+                .is_some_and(|issuer| &issuer.to_string() == issuer_dn)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct CertificateList {
+    certificates: RwLock<Vec<UserCertificate>>,
+}
+
+impl CertificateList {
+    pub fn find(&self, presented: &PresentedCertificate) -> Option<UserCertificate> {
+        self.certificates
+            .read()
+            .unwrap()
+            .iter()
+            .find(|certificate| certificate.matches(presented))
+            .cloned()
+    }
+
+    /// Add to the local copy, other services learn about it at their next reload
+    pub fn insert(&self, certificate: UserCertificate) {
+        self.certificates.write().unwrap().push(certificate);
+    }
+
+    pub fn remove(&self, id: i32) {
+        self.certificates.write().unwrap().retain(|c| c.id != id);
+    }
+
+    /// Drop all the certificates of a user, e.g. when the user is disabled
+    pub fn remove_user(&self, username: &str) {
+        self.certificates
+            .write()
+            .unwrap()
+            .retain(|c| c.username != username);
+    }
+
+    /// Take the content of the table
+    pub fn replace(&self, certificates: Vec<UserCertificate>) {
+        let mut current = self.certificates.write().unwrap();
+        *current = certificates;
+        trace!("{} client certificates", current.len());
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub use database::refresh_periodically;
+
+#[cfg(feature = "postgres")]
+mod database {
+    use super::*;
+    use deadpool_postgres::{Client, Pool};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::error;
+
+    async fn load(client: &Client) -> Result<Vec<UserCertificate>, GenericError> {
+        let stmt = client
+            .prepare_cached(include_str!("sql/user_certificates.sql"))
+            .await?;
+        let rows = client.query(&stmt, &[]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| UserCertificate {
+                id: row.get("id"),
+                username: row.get("username"),
+                subject_dn: row.get("subject_dn"),
+                issuer_dn: row.get("issuer_dn"),
+                serial: row.get("serial"),
+                fingerprint: row.get("fingerprint"),
+            })
+            .collect())
+    }
+
+    /// Reload the list from the database until the process ends
+    pub async fn refresh_periodically(
+        pool: Pool,
+        certificates: Arc<CertificateList>,
+        interval: Duration,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let loaded = match pool.get().await {
+                Ok(client) => load(&client).await,
+                Err(e) => Err(e.into()),
+            };
+            match loaded {
+                Ok(loaded) => certificates.replace(loaded),
+                // keep the copy we have, the next attempt may work
+                Err(e) => error!("Could not reload the client certificates: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dn(text: &str) -> DistinguishedName {
+        DistinguishedName::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_parse_dn() {
+        let parsed = dn("CN=Jane Doe,OU=Sales+O=Organizator,C=RO");
+        assert_eq!(parsed.rdns.len(), 3);
+        assert_eq!(parsed.rdns[1].len(), 2);
+        assert_eq!(parsed.common_name(), Some("Jane Doe"));
+        // the order in a multi-valued RDN and the case of the types do not matter
+        assert_eq!(parsed, dn("cn=Jane Doe,o=Organizator+ou=Sales,c=RO"));
+        assert_eq!(
+            parsed.to_string(),
+            "CN=Jane Doe,O=Organizator+OU=Sales,C=RO"
+        );
+
+        // escapes, the CN is not cut at the escaped comma
+        let escaped = dn(r"CN=Doe\, Jane\2C Jr.,O=Sales \+ Marketing,C=RO");
+        assert_eq!(escaped.common_name(), Some("Doe, Jane, Jr."));
+        assert_eq!(escaped.rdns[1][0].value, "Sales + Marketing");
+        assert_eq!(
+            escaped.to_string(),
+            r"CN=Doe\, Jane\, Jr.,O=Sales \+ Marketing,C=RO"
+        );
+        assert_eq!(dn(r"CN=\C4\83").common_name(), Some("ă"));
+        assert_eq!(dn(r"CN=\ padded\ ").common_name(), Some(" padded "));
+
+        let encoded = dn("1.3.6.1.4.1.1466.0=#04024869,CN=x");
+        assert!(encoded.rdns[0][0].encoded);
+        assert_eq!(encoded.to_string(), "1.3.6.1.4.1.1466.0=#04024869,CN=x");
+
+        for bad in [
+            "CN", "=x", "CN=a\\", r"CN=a\zz", "CN=#123", "CN=\"q\"", "C N=x",
+        ] {
+            assert!(DistinguishedName::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize_serial("00:0a:1B").as_deref(), Some("A1B"));
+        assert_eq!(normalize_serial("000").as_deref(), Some("0"));
+        assert_eq!(normalize_serial("xyz"), None);
+        let fingerprint = "AB:".repeat(31) + "AB";
+        assert_eq!(normalize_fingerprint(&fingerprint), Some("ab".repeat(32)));
+        assert_eq!(normalize_fingerprint("abcd"), None);
+        // "hello" as DER, URL encoded like nginx does
+        let pem = "-----BEGIN%20CERTIFICATE-----%0AaGVsbG8=%0A-----END%20CERTIFICATE-----%0A";
+        assert_eq!(
+            fingerprint_from_pem(pem).as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+    }
+
+    fn presented(subject: &str, serial: &str, fingerprint: Option<&str>) -> PresentedCertificate {
+        PresentedCertificate {
+            subject: dn(subject),
+            issuer: Some(dn("CN=Organizator CA")),
+            serial: normalize_serial(serial),
+            fingerprint: fingerprint.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let list = CertificateList::default();
+        list.insert(UserCertificate {
+            id: 1,
+            username: "jane".to_string(),
+            subject_dn: "CN=Jane Doe,O=Organizator".to_string(),
+            issuer_dn: Some("CN=Organizator CA".to_string()),
+            serial: Some("1A".to_string()),
+            fingerprint: None,
+        });
+        list.insert(UserCertificate {
+            id: 2,
+            username: "admin".to_string(),
+            subject_dn: "CN=admin".to_string(),
+            issuer_dn: None,
+            serial: None,
+            fingerprint: Some("ab".repeat(32)),
+        });
+        let found = |p: &PresentedCertificate| list.find(p).map(|c| c.username);
+
+        assert_eq!(
+            found(&presented("CN=Jane Doe,O=Organizator", "001a", None)).as_deref(),
+            Some("jane")
+        );
+        // another serial of the same CA, or the same serial for someone else
+        assert_eq!(
+            found(&presented("CN=Jane Doe,O=Organizator", "1B", None)),
+            None
+        );
+        assert_eq!(
+            found(&presented("CN=Mallory,O=Organizator", "1A", None)),
+            None
+        );
+        // the CN alone no longer makes the user
+        assert_eq!(found(&presented("CN=admin", "1", None)), None);
+        assert_eq!(
+            found(&presented("CN=admin", "1", Some(&"ab".repeat(32)))).as_deref(),
+            Some("admin")
+        );
+
+        // the serial without the issuer nginx verified it against
+        let mut without_issuer = presented("CN=Jane Doe,O=Organizator", "1A", None);
+        without_issuer.issuer = None;
+        assert_eq!(found(&without_issuer), None);
+        let mut other_issuer = presented("CN=Jane Doe,O=Organizator", "1A", None);
+        other_issuer.issuer = Some(dn("CN=Other CA"));
+        assert_eq!(found(&other_issuer), None);
+
+        list.remove(1);
+        assert_eq!(
+            found(&presented("CN=Jane Doe,O=Organizator", "1A", None)),
+            None
+        );
+        // a row with a serial but no issuer, written around the CHECK of the table
+        list.insert(UserCertificate {
+            id: 3,
+            username: "jane".to_string(),
+            subject_dn: "CN=Jane Doe,O=Organizator".to_string(),
+            issuer_dn: None,
+            serial: Some("1A".to_string()),
+            fingerprint: None,
+        });
+        assert_eq!(
+            found(&presented("CN=Jane Doe,O=Organizator", "1A", None)),
+            None
+        );
+        list.remove_user("admin");
+        assert_eq!(
+            found(&presented("CN=admin", "1", Some(&"ab".repeat(32)))),
+            None
+        );
+    }
+}
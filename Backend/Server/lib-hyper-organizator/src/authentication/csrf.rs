@@ -0,0 +1,221 @@
+//! Cross-site request forgery protection for the credentials the browser sends by itself.
+//!
+//! The JWT cookie, which nginx copies into the Authorization header, and the client
+//! certificate come along with any request of the browser, also those made by the pages of
+//! other sites. State-changing requests authenticated by them have to come from a page of this
+//! site, as told by the `Origin`, `Referer` or `Sec-Fetch-Site` headers. Optionally they also
+//! have to carry the header of the web client, which other sites can not set without asking.
+//! Requests with a bearer token or an API token of their own are not affected.
+use http::header::{HOST, ORIGIN, REFERER};
+use http::{HeaderMap, Method, Request};
+use serde::Deserialize;
+use tracing::info;
+
+/// Set by the web client on all its requests
+pub const CLIENT_VERSION_HEADER: &str = "x-organizator-client-version";
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+const FORWARDED_HOST: &str = "x-forwarded-host";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CsrfConfig {
+    pub enabled: bool,
+    /// Origins allowed besides the site itself, e.g. `https://admin.example.com`
+    pub allowed_origins: Vec<String>,
+    /// Requests authenticated by cookie or certificate also need the x-organizator-client-version
+    /// header; this refuses clients that send no origin at all, older browsers or scripts
+    pub require_client_header: bool,
+    /// Path prefixes not checked, e.g. for forms other sites post on purpose
+    #[serde(rename = "exempt")]
+    pub exempt_paths: Vec<String>,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        CsrfConfig {
+            enabled: true,
+            allowed_origins: vec![],
+            require_client_header: false,
+            exempt_paths: vec![],
+        }
+    }
+}
+
+/// Only these change anything, the others are not checked
+fn is_state_changing(method: &Method) -> bool {
+    !matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// `scheme://host[:port]` of a URL, lower case
+fn origin_of(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    if authority.is_empty() {
+        return None;
+    }
+    Some(format!("{scheme}://{authority}").to_ascii_lowercase())
+}
+
+fn header(headers: &HeaderMap, name: impl http::header::AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+impl CsrfConfig {
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    /// Our own site, as the browser addressed it, or one of the allowed origins
+    fn is_allowed_origin(&self, origin: &str, headers: &HeaderMap) -> bool {
+        let authority = origin
+            .split_once("://")
+            .map_or("", |(_, authority)| authority);
+        let own_site = [header(headers, HOST), header(headers, FORWARDED_HOST)]
+            .into_iter()
+            .flatten()
+            .any(|host| host.eq_ignore_ascii_case(authority));
+        own_site
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+
+    /// Check a request authenticated by cookie or certificate, the reason if it is refused
+    pub fn check<B>(&self, request: &Request<B>) -> Result<(), &'static str> {
+        if !self.enabled
+            || !is_state_changing(request.method())
+            || self.is_exempt(request.uri().path())
+        {
+            return Ok(());
+        }
+        let headers = request.headers();
+        if self.require_client_header && !headers.contains_key(CLIENT_VERSION_HEADER) {
+            return Err("no client header");
+        }
+        // browsers send Origin with every POST, Referer is for the older ones
+        let origin = match header(headers, ORIGIN) {
+            Some("null") => return Err("opaque origin"),
+            Some(origin) => origin_of(origin),
+            None => header(headers, REFERER).and_then(origin_of),
+        };
+        match (origin, header(headers, SEC_FETCH_SITE)) {
+            (Some(origin), _) if self.is_allowed_origin(&origin, headers) => Ok(()),
+            (Some(_), _) => Err("cross-site origin"),
+            (None, Some("same-origin" | "none")) => Ok(()),
+            (None, Some(_)) => Err("cross-site fetch"),
+            // not a browser, it has no cookies of ours to abuse
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Log the refusal, with the reason
+    pub fn refuses<B>(&self, request: &Request<B>) -> bool {
+        match self.check(request) {
+            Ok(()) => false,
+            Err(reason) => {
+                info!(
+                    "Possible CSRF on {} {}: {reason}",
+                    request.method(),
+                    request.uri().path()
+                );
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri("/memo")
+            .header(HOST, "organizator.lab");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_origin() {
+        assert_eq!(
+            origin_of("https://Organizator.lab:8443/memo/?x=1").as_deref(),
+            Some("https://organizator.lab:8443")
+        );
+        assert_eq!(origin_of("/relative"), None);
+
+        let config = CsrfConfig {
+            allowed_origins: vec!["https://admin.example.com/".to_string()],
+            ..CsrfConfig::default()
+        };
+        let post = |headers| config.check(&request(Method::POST, headers));
+        assert_eq!(post(&[("origin", "https://organizator.lab")]), Ok(()));
+        assert_eq!(post(&[("origin", "https://admin.example.com")]), Ok(()));
+        assert_eq!(
+            post(&[("origin", "https://evil.example")]),
+            Err("cross-site origin")
+        );
+        assert_eq!(post(&[("origin", "null")]), Err("opaque origin"));
+        assert_eq!(
+            post(&[("referer", "https://organizator.lab/memo/")]),
+            Ok(())
+        );
+        assert_eq!(
+            post(&[("referer", "https://evil.example/organizator.lab")]),
+            Err("cross-site origin")
+        );
+        assert_eq!(post(&[("sec-fetch-site", "same-origin")]), Ok(()));
+        assert_eq!(
+            post(&[("sec-fetch-site", "cross-site")]),
+            Err("cross-site fetch")
+        );
+        assert_eq!(post(&[]), Ok(()));
+        // nginx passes the host it was addressed by along
+        let forwarded = [
+            ("origin", "https://www.lab"),
+            ("x-forwarded-host", "www.lab"),
+        ];
+        assert_eq!(post(&forwarded), Ok(()));
+
+        // reading changes nothing
+        let get = request(Method::GET, &[("origin", "https://evil.example")]);
+        assert_eq!(config.check(&get), Ok(()));
+    }
+
+    #[test]
+    fn test_config() {
+        let evil = [("origin", "https://evil.example")];
+        let exempt = CsrfConfig {
+            exempt_paths: vec!["/memo".to_string()],
+            ..CsrfConfig::default()
+        };
+        assert_eq!(exempt.check(&request(Method::POST, &evil)), Ok(()));
+        let disabled = CsrfConfig {
+            enabled: false,
+            ..CsrfConfig::default()
+        };
+        assert_eq!(disabled.check(&request(Method::DELETE, &evil)), Ok(()));
+
+        let strict = CsrfConfig {
+            require_client_header: true,
+            ..CsrfConfig::default()
+        };
+        assert_eq!(
+            strict.check(&request(Method::PUT, &[])),
+            Err("no client header")
+        );
+        let client = request(Method::PUT, &[(CLIENT_VERSION_HEADER, "3")]);
+        assert_eq!(strict.check(&client), Ok(()));
+        let forged = request(Method::PUT, &[(CLIENT_VERSION_HEADER, "3"), evil[0]]);
+        assert!(strict.refuses(&forged));
+    }
+}
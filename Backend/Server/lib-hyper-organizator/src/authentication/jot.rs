@@ -1,21 +1,50 @@
-use crate::settings::SecurityConfig;
+use crate::settings::{SecurityConfig, get_secret, save_secret};
 use crate::typedef::GenericError;
+use base64::alphabet::URL_SAFE;
+use base64::engine::fast_portable::{FastPortable, NO_PAD};
 use bytes::Buf as _;
 use hyper::Client;
 use hyper::client::HttpConnector;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters,
+    OctetKeyPairType, PublicKeyUse,
+};
 use jsonwebtoken::{
-    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode, get_current_timestamp,
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+    get_current_timestamp,
 };
+use ring::digest::{SHA256, digest};
 use ring::rand::SystemRandom;
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+// jot is the official pronunciation of JWT
+
+/// base64url without padding, the encoding used in JWK
+const BASE64_URL: FastPortable = FastPortable::from(&URL_SAFE, NO_PAD);
+
+/// Tokens with unknown key ids do not make us fetch the keys more often than this
+pub(crate) const MIN_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: u64,
     pub roles: Vec<String>,
+    /// token id, used to revoke it; empty in tokens issued before ids were added
+    #[serde(default)]
+    pub jti: String,
+    /// session id, the refresh token family the token was issued for; empty if none
+    #[serde(default)]
+    pub sid: String,
+    /// who the token is for, the organizator services or an OpenID Connect client;
+    /// empty in tokens issued before audiences were added
+    #[serde(default)]
+    pub aud: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -23,15 +52,74 @@ pub struct ClaimsView<'a> {
     pub sub: &'a str,
     pub exp: u64,
     pub roles: &'a [&'a str],
+    pub jti: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub sid: &'a str,
+    pub aud: &'a str,
 }
-// jot is the official pronunciation of JWT
-pub struct Jot {
+
+/// Private key used to issue tokens
+struct SigningKey {
+    kid: String,
     encoding_key: EncodingKey,
+}
+
+/// Public key used to check tokens
+#[derive(Clone)]
+struct VerifyingKey {
+    kid: String,
+    public_key: Vec<u8>,
     decoding_key: DecodingKey,
-    pub session_expiry: u64,
+}
+
+impl VerifyingKey {
+    fn new(public_key: &[u8]) -> Self {
+        VerifyingKey {
+            kid: key_id(public_key),
+            public_key: public_key.to_vec(),
+            decoding_key: DecodingKey::from_ed_der(public_key),
+        }
+    }
+
+    fn to_jwk(&self) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(Algorithm::EdDSA),
+                key_id: Some(self.kid.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: base64::encode_engine(&self.public_key, &BASE64_URL),
+            }),
+        }
+    }
+}
+
+/// Checks tokens against the public keys of the identity service, every service has one
+pub struct TokenVerifier {
+    verifying_keys: Arc<RwLock<Vec<VerifyingKey>>>,
+    /// refetched when a token names a key we don't have
+    jwks_url: Option<String>,
+    /// held while fetching, the requests waiting for the same keys wait for that fetch
+    last_key_refresh: Mutex<Option<Instant>>,
     pub session_expiry_grace_period: u64,
-    public_key: String,
     ignore_paths: Vec<String>,
+    /// tokens for anyone else are refused
+    audience: String,
+}
+
+/// Signs new tokens, only the identity service has the private keys
+pub struct TokenIssuer {
+    signing_key: SigningKey,
+    /// the signing key first, then the ones still accepted after a rotation
+    verifying_keys: Vec<VerifyingKey>,
+    pub session_expiry: u64,
+    pub session_expiry_grace_period: u64,
+    /// of the tokens for the organizator services
+    audience: String,
 }
 
 pub enum ExpiredToken {
@@ -47,42 +135,81 @@ struct PublicKey {
     public_key: String,
 }
 
-impl Jot {
-    fn autogenerate(security_config: &SecurityConfig) -> Result<Jot, GenericError> {
-        info!("Generating new keypair");
-        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
-        let encoding_key = EncodingKey::from_ed_der(document.as_ref());
-        let pair = Ed25519KeyPair::from_pkcs8(document.as_ref())?;
-        let decoding_key = DecodingKey::from_ed_der(pair.public_key().as_ref());
-
-        let session_expiry = security_config.session_expiry;
-        let session_expiry_grace_period = security_config.session_expiry_grace_period;
-
-        let public_key = base64::encode(pair.public_key().as_ref());
+/// RFC 7638 thumbprint of an Ed25519 public key, stable across restarts
+fn key_id(public_key: &[u8]) -> String {
+    let x = base64::encode_engine(public_key, &BASE64_URL);
+    let canonical = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#);
+    base64::encode_engine(digest(&SHA256, canonical.as_bytes()), &BASE64_URL)
+}
 
-        Ok(Jot {
-            encoding_key,
-            decoding_key,
-            session_expiry,
-            session_expiry_grace_period,
-            public_key,
-            ignore_paths: security_config.ignore_paths.clone(),
-        })
+/// The PKCS#8 documents in the secret, generating and saving one if there is none
+fn load_key_documents(secret_name: &str) -> Result<Vec<Vec<u8>>, GenericError> {
+    match get_secret(secret_name) {
+        Ok(content) => {
+            let documents = content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(base64::decode)
+                .collect::<Result<Vec<_>, _>>()?;
+            if documents.is_empty() {
+                return Err(format!("No keys in the secret {secret_name}").into());
+            }
+            info!("Loaded {} signing keys", documents.len());
+            Ok(documents)
+        }
+        Err(e) => {
+            info!("No signing keys in {secret_name}: {e}, generating a new keypair");
+            let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
+            if let Err(e) = save_secret(secret_name, &base64::encode(document.as_ref())) {
+                warn!("Could not save the signing key, tokens will not survive a restart: {e}");
+            }
+            Ok(vec![document.as_ref().to_vec()])
+        }
     }
+}
 
-    pub fn from_ed_der(
-        public_key: &[u8],
+impl TokenVerifier {
+    fn new(
+        verifying_keys: Vec<VerifyingKey>,
+        jwks_url: Option<String>,
         security_config: &SecurityConfig,
-    ) -> Result<Jot, GenericError> {
-        let decoding_key = DecodingKey::from_ed_der(public_key);
-        Ok(Jot {
-            encoding_key: EncodingKey::from_ed_der(public_key),
-            decoding_key,
-            session_expiry: security_config.session_expiry,
+    ) -> TokenVerifier {
+        TokenVerifier {
+            verifying_keys: Arc::new(RwLock::new(verifying_keys)),
+            jwks_url,
+            last_key_refresh: Mutex::new(None),
             session_expiry_grace_period: security_config.session_expiry_grace_period,
-            public_key: base64::encode(public_key),
             ignore_paths: security_config.ignore_paths.clone(),
-        })
+            audience: security_config.audience.clone(),
+        }
+    }
+
+    pub fn from_ed_der(public_key: &[u8], security_config: &SecurityConfig) -> TokenVerifier {
+        Self::new(vec![VerifyingKey::new(public_key)], None, security_config)
+    }
+
+    /// Get the keys published by the identity service
+    pub async fn fetch(security_config: &SecurityConfig) -> Result<TokenVerifier, GenericError> {
+        if let Some(ref jwks_url) = security_config.jwks_url {
+            let keys = fetch_jwks(jwks_url).await?;
+            info!("Got {} verification keys from identity service", keys.len());
+            return Ok(Self::new(keys, Some(jwks_url.clone()), security_config));
+        }
+        let Some(ref public_key_url) = security_config.public_key_url else {
+            return Err(GenericError::from(
+                "No jwks_url or public_key_url to get the keys from",
+            ));
+        };
+
+        let body = fetch(public_key_url).await?;
+        // parse into a PublicKey struct
+        let public_key_base64: PublicKey = serde_json::from_reader(body.reader())?;
+        let public_key = base64::decode(&public_key_base64.public_key)?;
+        info!("Got public key from identity service");
+
+        // a single key that is never refreshed, the way it was published before JWKS
+        Ok(Self::from_ed_der(&public_key, security_config))
     }
 
     pub fn check_expiration(&self, claims: &Claims) -> ExpiredToken {
@@ -97,90 +224,267 @@ impl Jot {
         }
     }
 
+    /// A token for the organizator services
+    pub fn validate_token(&self, token: &str) -> Result<Claims, GenericError> {
+        self.validate_token_for(token, |aud| aud == self.audience)
+    }
+
+    /// A token for the organizator services, expired or not; only to end its session at logout
+    pub fn validate_expired_token(&self, token: &str) -> Result<Claims, GenericError> {
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.validate_exp = false;
+        let claims = self.decode_token(token, &validation)?;
+        self.check_audience(claims, |aud| aud == self.audience)
+    }
+
+    /// A token for another audience, e.g. the access token of an OpenID Connect client
+    pub fn validate_token_for(
+        &self,
+        token: &str,
+        accepts: impl Fn(&str) -> bool,
+    ) -> Result<Claims, GenericError> {
+        let claims = self.decode_token(token, &Validation::new(Algorithm::EdDSA))?;
+        self.check_audience(claims, accepts)
+    }
+
+    fn check_audience(
+        &self,
+        claims: Claims,
+        accepts: impl Fn(&str) -> bool,
+    ) -> Result<Claims, GenericError> {
+        // tokens without audience were all issued for the services
+        let audience = if claims.aud.is_empty() {
+            &self.audience
+        } else {
+            &claims.aud
+        };
+        if !accepts(audience) {
+            return Err(format!("Token issued for another audience {}", claims.aud).into());
+        }
+        Ok(claims)
+    }
+
+    fn decode_token(&self, token: &str, validation: &Validation) -> Result<Claims, GenericError> {
+        let kid = decode_header(token)?.kid;
+        let keys = self.verifying_keys.read().unwrap();
+        // tokens issued before the key ids were added can be checked against any key
+        let candidates: Vec<&VerifyingKey> = match kid {
+            Some(ref kid) => keys.iter().filter(|k| &k.kid == kid).collect(),
+            None => keys.iter().collect(),
+        };
+        if candidates.is_empty() {
+            let kid = kid.unwrap_or_default();
+            return Err(format!("Token signed with unknown key {kid}").into());
+        }
+        let mut last_error = None;
+        for key in candidates {
+            match decode::<Claims>(token, &key.decoding_key, validation) {
+                Ok(token_data) => return Ok(token_data.claims),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap().into())
+    }
+
+    /// The token names a key we don't have and the identity service could publish it
+    pub fn needs_key_refresh(&self, token: &str) -> bool {
+        if self.jwks_url.is_none() {
+            return false;
+        }
+        let Ok(Some(kid)) = decode_header(token).map(|header| header.kid) else {
+            return false;
+        };
+        !self
+            .verifying_keys
+            .read()
+            .unwrap()
+            .iter()
+            .any(|k| k.kid == kid)
+    }
+
+    /// Fetch the keys again, at most once per `MIN_KEY_REFRESH_INTERVAL`.
+    /// The authorization waits for it, so the token that named the new key is accepted.
+    pub async fn refresh_keys(&self) {
+        let Some(ref url) = self.jwks_url else {
+            return;
+        };
+        let mut last_key_refresh = self.last_key_refresh.lock().await;
+        if last_key_refresh.is_some_and(|t| t.elapsed() < MIN_KEY_REFRESH_INTERVAL) {
+            debug!("Keys refreshed recently, not fetching them again");
+            return;
+        }
+        *last_key_refresh = Some(Instant::now());
+        match fetch_jwks(url).await {
+            Ok(keys) => {
+                info!("Refreshed the verification keys, {} keys", keys.len());
+                *self.verifying_keys.write().unwrap() = keys;
+            }
+            Err(e) => error!("Could not refresh the verification keys: {e}"),
+        }
+    }
+
+    pub fn is_ignored_path(&self, path: &str) -> bool {
+        debug!("path: {}, ignored_paths: {:#?}", path, &self.ignore_paths);
+        self.ignore_paths.contains(&path.to_string())
+    }
+}
+
+impl TokenIssuer {
+    /// From PKCS#8 documents at hand, the first one signs
+    pub(crate) fn from_key_documents(
+        documents: &[Vec<u8>],
+        security_config: &SecurityConfig,
+    ) -> Result<TokenIssuer, GenericError> {
+        let mut verifying_keys = Vec::with_capacity(documents.len());
+        for document in documents {
+            // openssl writes PKCS#8 v1, without the public key
+            let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(document)?;
+            verifying_keys.push(VerifyingKey::new(pair.public_key().as_ref()));
+        }
+        // the first key signs, the others are kept for the tokens they signed before a rotation
+        let signing_key = SigningKey {
+            kid: verifying_keys[0].kid.clone(),
+            encoding_key: EncodingKey::from_ed_der(&documents[0]),
+        };
+        info!("Signing tokens with key {}", signing_key.kid);
+
+        Ok(TokenIssuer {
+            signing_key,
+            verifying_keys,
+            session_expiry: security_config.session_expiry,
+            session_expiry_grace_period: security_config.session_expiry_grace_period,
+            audience: security_config.audience.clone(),
+        })
+    }
+
+    /// Load the signing keys from the secret, a new one is generated if there are none
+    pub fn new(security_config: &SecurityConfig) -> Result<TokenIssuer, GenericError> {
+        let documents = load_key_documents(&security_config.signing_keys_secret)?;
+        Self::from_key_documents(&documents, security_config)
+    }
+
+    /// A verifier for the tokens of this issuer
+    pub fn verifier(&self, security_config: &SecurityConfig) -> TokenVerifier {
+        TokenVerifier::new(self.verifying_keys.clone(), None, security_config)
+    }
+
     pub fn generate_token(&self, user_id: &str, roles: &[&str]) -> Result<String, GenericError> {
+        self.generate_session_token(user_id, roles, "")
+    }
+
+    /// A token tied to a session, ending the session ends the token as well
+    pub fn generate_session_token(
+        &self,
+        user_id: &str,
+        roles: &[&str],
+        sid: &str,
+    ) -> Result<String, GenericError> {
+        self.generate_token_for(user_id, roles, sid, &self.audience)
+    }
+
+    /// A token for another application, refused by the organizator services
+    pub fn generate_token_for(
+        &self,
+        user_id: &str,
+        roles: &[&str],
+        sid: &str,
+        audience: &str,
+    ) -> Result<String, GenericError> {
         let exp = get_current_timestamp() + self.session_expiry + self.session_expiry_grace_period;
+        let jti = new_token_id();
         let claims = ClaimsView {
             sub: user_id,
             exp,
             roles,
+            jti: &jti,
+            sid,
+            aud: audience,
         };
-        Ok(encode(
-            &Header::new(Algorithm::EdDSA),
-            &claims,
-            &self.encoding_key,
-        )?)
+        self.sign(&claims)
     }
 
-    pub fn refresh_token(&self, token: &str) -> Result<String, GenericError> {
-        let claims = self.validate_token(token)?;
-        let exp = get_current_timestamp() + self.session_expiry + self.session_expiry_grace_period;
-        let claims = Claims {
-            sub: claims.sub,
-            exp,
-            roles: claims.roles,
-        };
-        Ok(encode(
-            &Header::new(Algorithm::EdDSA),
-            &claims,
-            &self.encoding_key,
-        )?)
-    }
-
-    pub fn validate_token(&self, token: &str) -> Result<Claims, GenericError> {
-        let validation = Validation::new(Algorithm::EdDSA);
-        let token_data = decode::<Claims>(token, &self.decoding_key, &validation)?;
-        Ok(token_data.claims)
+    /// Sign any claims with the current key, e.g. an OpenID Connect ID token
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, GenericError> {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(self.signing_key.kid.clone());
+        Ok(encode(&header, claims, &self.signing_key.encoding_key)?)
     }
 
     pub fn get_public_key(&self) -> String {
         serde_json::to_string(&PublicKey {
             algorithm: "EdDSA".to_string(),
-            public_key: self.public_key.clone(),
+            public_key: base64::encode(&self.verifying_keys[0].public_key),
         })
         .unwrap()
     }
 
-    pub fn is_ignored_path(&self, path: &str) -> bool {
-        debug!("path: {}, ignored_paths: {:#?}", path, &self.ignore_paths);
-        self.ignore_paths.contains(&path.to_string())
+    /// All the keys tokens may be signed with, as a JWKS document
+    pub fn get_jwks(&self) -> String {
+        serde_json::to_string(&JwkSet {
+            keys: self
+                .verifying_keys
+                .iter()
+                .map(VerifyingKey::to_jwk)
+                .collect(),
+        })
+        .unwrap()
     }
+}
 
-    // creates a JOT out of a public key from the identity service
-    pub async fn new(security_config: &SecurityConfig) -> Result<Jot, GenericError> {
-        let Some(ref public_key_url) = security_config.public_key_url else {
-            return Self::autogenerate(security_config);
-        };
+/// The services that get the keys from the identity service only verify tokens,
+/// the identity service also issues them
+pub async fn token_handlers(
+    security_config: &SecurityConfig,
+) -> Result<(TokenVerifier, Option<TokenIssuer>), GenericError> {
+    if security_config.jwks_url.is_some() || security_config.public_key_url.is_some() {
+        return Ok((TokenVerifier::fetch(security_config).await?, None));
+    }
+    let issuer = TokenIssuer::new(security_config)?;
+    Ok((issuer.verifier(security_config), Some(issuer)))
+}
 
-        let client: Client<HttpConnector> = Client::builder().build_http();
-
-        let uri = public_key_url.parse()?;
-        let response = match client.get(uri).await {
-            Ok(response) => response,
-            Err(e) => {
-                let msg = format!(
-                    "Failed to get public key from identity service 「{public_key_url}」: {e}"
-                );
-                error!("{msg}");
-                return Err(GenericError::from(msg));
-            }
-        };
-        // asynchronously aggregate all the chunks of the body
-        let body = hyper::body::aggregate(response).await?;
-        // parse into a PublicKey struct
-        let public_key_base64: PublicKey = serde_json::from_reader(body.reader())?;
-        let public_key = base64::decode(&public_key_base64.public_key)?;
-        info!("Got public key from identity service");
+async fn fetch(url: &str) -> Result<impl bytes::Buf, GenericError> {
+    let client: Client<HttpConnector> = Client::builder().build_http();
+
+    let uri = url.parse()?;
+    let response = match client.get(uri).await {
+        Ok(response) => response,
+        Err(e) => {
+            let msg = format!("Failed to get public key from identity service 「{url}」: {e}");
+            error!("{msg}");
+            return Err(GenericError::from(msg));
+        }
+    };
+    // asynchronously aggregate all the chunks of the body
+    Ok(hyper::body::aggregate(response).await?)
+}
 
-        let encoding_key = EncodingKey::from_ed_der(&public_key);
-        Ok(Jot {
-            encoding_key,
-            decoding_key: DecodingKey::from_ed_der(&public_key),
-            session_expiry: 0,
-            session_expiry_grace_period: 0,
-            public_key: public_key_base64.public_key,
-            ignore_paths: vec![],
+/// The Ed25519 keys in a JWKS document, keys of other types are skipped
+async fn fetch_jwks(url: &str) -> Result<Vec<VerifyingKey>, GenericError> {
+    let body = fetch(url).await?;
+    let jwks: JwkSet = serde_json::from_reader(body.reader())?;
+    Ok(verifying_keys_from_jwks(&jwks))
+}
+
+fn verifying_keys_from_jwks(jwks: &JwkSet) -> Vec<VerifyingKey> {
+    jwks.keys
+        .iter()
+        .filter_map(|jwk| match &jwk.algorithm {
+            AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+                let public_key = base64::decode_engine(&params.x, &BASE64_URL).ok()?;
+                let mut key = VerifyingKey::new(&public_key);
+                if let Some(ref kid) = jwk.common.key_id {
+                    key.kid = kid.clone();
+                }
+                Some(key)
+            }
+            _ => None,
         })
-    }
+        .collect()
+}
+
+fn new_token_id() -> String {
+    uuid::Uuid::new_v4().to_string()
 }
 
 #[cfg(test)]
@@ -190,11 +494,226 @@ mod tests {
     #[test]
     fn test_encoding() {
         let security_config = SecurityConfig::default();
-        let jot = Jot::autogenerate(&security_config).unwrap();
-        let token = jot.generate_token("admin", &[]).unwrap();
+        let issuer = TokenIssuer::new(&security_config).unwrap();
+        let verifier = issuer.verifier(&security_config);
+        let token = issuer.generate_token("admin", &[]).unwrap();
         println!("{}", token);
 
-        let user_id = jot.validate_token(&token).unwrap().sub;
-        assert_eq!(user_id, "admin");
+        let claims = verifier.validate_token(&token).unwrap();
+        assert_eq!(claims.sub, "admin");
+        assert!(!claims.jti.is_empty());
+        let other = issuer.generate_token("admin", &[]).unwrap();
+        assert_ne!(verifier.validate_token(&other).unwrap().jti, claims.jti);
+    }
+
+    #[test]
+    fn test_check_expiration() {
+        let security_config = SecurityConfig::default();
+        let verifier = TokenIssuer::new(&security_config)
+            .unwrap()
+            .verifier(&security_config);
+        let claims = |exp: u64| Claims {
+            sub: "admin".to_string(),
+            exp,
+            roles: vec![],
+            jti: String::new(),
+            sid: String::new(),
+            aud: String::new(),
+        };
+        let now = get_current_timestamp();
+        let grace = verifier.session_expiry_grace_period;
+        assert!(matches!(
+            verifier.check_expiration(&claims(now + grace + 60)),
+            ExpiredToken::Valid
+        ));
+        assert!(matches!(
+            verifier.check_expiration(&claims(now + grace / 2)),
+            ExpiredToken::GracePeriod
+        ));
+        assert!(matches!(
+            verifier.check_expiration(&claims(now - 1)),
+            ExpiredToken::Expired
+        ));
+    }
+
+    #[test]
+    fn test_validate_expired_token() {
+        let security_config = SecurityConfig::default();
+        let issuer = TokenIssuer::new(&security_config).unwrap();
+        let verifier = issuer.verifier(&security_config);
+        let claims = serde_json::json!({"sub": "admin", "exp": 1, "roles": [], "sid": "session"});
+        let expired = issuer.sign(&claims).unwrap();
+        assert!(verifier.validate_token(&expired).is_err());
+        assert_eq!(
+            verifier.validate_expired_token(&expired).unwrap().sid,
+            "session"
+        );
+
+        // still only the tokens of this issuer, for the services
+        let other = TokenIssuer::from_key_documents(&[generate_document()], &security_config)
+            .unwrap()
+            .sign(&claims)
+            .unwrap();
+        assert!(verifier.validate_expired_token(&other).is_err());
+        let wiki = serde_json::json!({"sub": "admin", "exp": 1, "roles": [], "aud": "wiki"});
+        let wiki = issuer.sign(&wiki).unwrap();
+        assert!(verifier.validate_expired_token(&wiki).is_err());
+    }
+
+    #[test]
+    fn test_session_id() {
+        let security_config = SecurityConfig::default();
+        let issuer = TokenIssuer::new(&security_config).unwrap();
+        let verifier = issuer.verifier(&security_config);
+        let token = issuer.generate_token("admin", &[]).unwrap();
+        assert!(verifier.validate_token(&token).unwrap().sid.is_empty());
+        let token = issuer
+            .generate_session_token("admin", &["org"], "session")
+            .unwrap();
+        let claims = verifier.validate_token(&token).unwrap();
+        assert_eq!(claims.sid, "session");
+        assert_eq!(claims.roles, vec!["org"]);
+    }
+
+    #[test]
+    fn test_audience() {
+        let security_config = SecurityConfig::default();
+        let issuer = TokenIssuer::new(&security_config).unwrap();
+        let verifier = issuer.verifier(&security_config);
+        let token = issuer.generate_token("admin", &["orgadm"]).unwrap();
+        assert_eq!(verifier.validate_token(&token).unwrap().aud, "organizator");
+
+        let token = issuer
+            .generate_token_for("admin", &[], "session", "wiki")
+            .unwrap();
+        assert!(verifier.validate_token(&token).is_err());
+        let claims = verifier
+            .validate_token_for(&token, |aud| aud == "wiki")
+            .unwrap();
+        assert_eq!(
+            (claims.aud.as_str(), claims.sid.as_str()),
+            ("wiki", "session")
+        );
+
+        // issued before the audiences, for the services only
+        let legacy = issuer
+            .sign(&serde_json::json!({"sub": "admin", "exp": u64::MAX, "roles": []}))
+            .unwrap();
+        assert!(verifier.validate_token(&legacy).is_ok());
+        assert!(
+            verifier
+                .validate_token_for(&legacy, |aud| aud == "wiki")
+                .is_err()
+        );
+    }
+
+    fn generate_document() -> Vec<u8> {
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .unwrap()
+            .as_ref()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_key_rotation_through_jwks() {
+        let security_config = SecurityConfig::default();
+        let old = generate_document();
+        let new = generate_document();
+        let before =
+            TokenIssuer::from_key_documents(std::slice::from_ref(&old), &security_config).unwrap();
+        let old_token = before.generate_token("admin", &[]).unwrap();
+
+        // the new key signs, the old one is still published
+        let issuer = TokenIssuer::from_key_documents(&[new, old], &security_config).unwrap();
+        let new_token = issuer.generate_token("admin", &[]).unwrap();
+        assert_ne!(
+            decode_header(&old_token).unwrap().kid,
+            decode_header(&new_token).unwrap().kid
+        );
+
+        let jwks: JwkSet = serde_json::from_str(&issuer.get_jwks()).unwrap();
+        assert_eq!(jwks.keys.len(), 2);
+        let verifier = TokenVerifier::new(verifying_keys_from_jwks(&jwks), None, &security_config);
+        assert_eq!(verifier.validate_token(&old_token).unwrap().sub, "admin");
+        assert_eq!(verifier.validate_token(&new_token).unwrap().sub, "admin");
+
+        // a verifier that only knows the old key rejects the new tokens
+        let stale = before.verifier(&security_config);
+        assert!(stale.validate_token(&new_token).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_on_unknown_kid() -> Result<(), GenericError> {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Response, Server};
+
+        let security_config = SecurityConfig::default();
+        let old = generate_document();
+        let issuer = Arc::new(RwLock::new(TokenIssuer::from_key_documents(
+            std::slice::from_ref(&old),
+            &security_config,
+        )?));
+        let published = issuer.clone();
+        let make_service = make_service_fn(move |_| {
+            let published = published.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |_| {
+                    let jwks = published.read().unwrap().get_jwks();
+                    async move { Ok::<_, hyper::Error>(Response::new(Body::from(jwks))) }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse()?).serve(make_service);
+        let jwks_url = format!("http://{}/jwks.json", server.local_addr());
+        tokio::spawn(server);
+
+        let verifier = TokenVerifier::fetch(&SecurityConfig {
+            jwks_url: Some(jwks_url),
+            ..SecurityConfig::default()
+        })
+        .await?;
+
+        // the identity service rotates to a new key
+        *issuer.write().unwrap() =
+            TokenIssuer::from_key_documents(&[generate_document(), old], &security_config)?;
+        let token = issuer.read().unwrap().generate_token("admin", &[])?;
+        assert!(verifier.validate_token(&token).is_err());
+        assert!(verifier.needs_key_refresh(&token));
+        verifier.refresh_keys().await;
+        assert!(!verifier.needs_key_refresh(&token));
+        assert_eq!(verifier.validate_token(&token)?.sub, "admin");
+
+        // another rotation right away waits for the interval
+        *issuer.write().unwrap() =
+            TokenIssuer::from_key_documents(&[generate_document()], &security_config)?;
+        let token = issuer.read().unwrap().generate_token("admin", &[])?;
+        verifier.refresh_keys().await;
+        assert!(verifier.needs_key_refresh(&token));
+        Ok(())
+    }
+
+    #[test]
+    fn test_openssl_key() {
+        // openssl genpkey -algorithm ed25519 -outform DER | base64 -w0
+        let document =
+            base64::decode("MC4CAQAwBQYDK2VwBCIEILX0TV8af5Wign6xCkgDuSgRCBYDSyxTSnIaN6gCYWiA")
+                .unwrap();
+        let security_config = SecurityConfig::default();
+        let issuer = TokenIssuer::from_key_documents(&[document], &security_config).unwrap();
+        let token = issuer.generate_token("admin", &[]).unwrap();
+        let claims = issuer.verifier(&security_config).validate_token(&token);
+        assert_eq!(claims.unwrap().sub, "admin");
+    }
+
+    #[test]
+    fn test_key_id_is_stable() {
+        // the Ed25519 key and its thumbprint in RFC 8037, appendix A.3
+        let public_key =
+            base64::decode_engine("11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo", &BASE64_URL)
+                .unwrap();
+        assert_eq!(
+            key_id(&public_key),
+            "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k"
+        );
     }
 }
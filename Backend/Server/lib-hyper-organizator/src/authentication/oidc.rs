@@ -0,0 +1,153 @@
+//! OpenID Connect provider settings and the parts of the protocol worth testing alone.
+//!
+//! The identity service lets the registered clients log their users in with the
+//! authorization code flow, PKCE required, and signs the ID tokens with the JWT keys.
+use crate::settings::get_secret;
+use base64::alphabet::URL_SAFE;
+use base64::engine::fast_portable::{FastPortable, NO_PAD};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{SHA256, digest};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// The only code challenge method accepted, plain gives no protection
+pub const PKCE_METHOD: &str = "S256";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OidcConfig {
+    /// Public URL of the provider, the discovery document is under it; empty disables it
+    pub issuer: String,
+    /// Where users who are not logged in are sent, with the authorize URL appended
+    pub login_url: String,
+    pub clients: Vec<OidcClient>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct OidcClient {
+    pub client_id: String,
+    /// Name of the secret holding the client secret; public clients have none
+    pub secret_name: Option<String>,
+    /// Compared exactly, the code is only sent to these
+    pub redirect_uris: Vec<String>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            issuer: String::new(),
+            login_url: "/login.html?r=".to_string(),
+            clients: vec![],
+        }
+    }
+}
+
+impl OidcConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.issuer.is_empty()
+    }
+
+    pub fn client(&self, client_id: &str) -> Option<&OidcClient> {
+        self.clients.iter().find(|c| c.client_id == client_id)
+    }
+}
+
+impl OidcClient {
+    pub fn allows_redirect(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|uri| uri == redirect_uri)
+    }
+
+    /// Public clients authenticate with PKCE alone, confidential ones also with their secret
+    pub fn authenticate(&self, client_secret: Option<&str>) -> bool {
+        let Some(secret_name) = &self.secret_name else {
+            return true;
+        };
+        let expected = match get_secret(secret_name) {
+            Ok(expected) => expected,
+            Err(e) => {
+                warn!("No secret for the OIDC client 「{}」: {e}", self.client_id);
+                return false;
+            }
+        };
+        client_secret.is_some_and(|secret| {
+            verify_slices_are_equal(secret.as_bytes(), expected.as_bytes()).is_ok()
+        })
+    }
+}
+
+/// The S256 code challenge of a verifier, RFC 7636
+pub fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_engine(
+        digest(&SHA256, code_verifier.as_bytes()),
+        &FastPortable::from(&URL_SAFE, NO_PAD),
+    )
+}
+
+/// The verifier has the allowed length and characters and matches the challenge
+pub fn verify_pkce(code_verifier: &str, code_challenge_stored: &str) -> bool {
+    let well_formed = (43..=128).contains(&code_verifier.len())
+        && code_verifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+    well_formed
+        && verify_slices_are_equal(
+            code_challenge(code_verifier).as_bytes(),
+            code_challenge_stored.as_bytes(),
+        )
+        .is_ok()
+}
+
+/// The claims of an ID token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    /// the user id, stable across renames
+    pub sub: String,
+    /// the client id
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub preferred_username: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce() {
+        // RFC 7636, appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert_eq!(code_challenge(verifier), challenge);
+        assert!(verify_pkce(verifier, challenge));
+        assert!(!verify_pkce(
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXK",
+            challenge
+        ));
+        // too short, even if it matches
+        assert!(!verify_pkce("short", &code_challenge("short")));
+    }
+
+    #[test]
+    fn test_client() {
+        let config = OidcConfig {
+            issuer: "https://organizator.example/organizator/oidc".to_string(),
+            clients: vec![OidcClient {
+                client_id: "wiki".to_string(),
+                secret_name: None,
+                redirect_uris: vec!["https://wiki.example/callback".to_string()],
+            }],
+            ..OidcConfig::default()
+        };
+        let client = config.client("wiki").unwrap();
+        assert!(client.allows_redirect("https://wiki.example/callback"));
+        assert!(!client.allows_redirect("https://wiki.example/callback/"));
+        assert!(client.authenticate(None));
+        assert!(config.client("other").is_none());
+        assert!(!OidcConfig::default().is_enabled());
+    }
+}
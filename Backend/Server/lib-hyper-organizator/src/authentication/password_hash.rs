@@ -0,0 +1,217 @@
+//! Password hashes, Argon2id with configurable cost and the PBKDF2 ones from before it.
+//!
+//! A hash weaker than the configured parameters still verifies, the caller is told to
+//! store a new one while it has the password at hand.
+use crate::typedef::GenericError;
+use argon2::password_hash::{
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng,
+};
+use argon2::{ARGON2ID_IDENT, Algorithm, Argon2, Params, Version};
+use ring::pbkdf2;
+use serde::Deserialize;
+use std::num::NonZeroU32;
+use tracing::warn;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pbkdf2Digest {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PasswordHashing {
+    /// KiB of memory per hash
+    pub memory: u32,
+    /// Passes over the memory
+    pub iterations: u32,
+    /// Lanes computed in parallel
+    pub parallelism: u32,
+    /// Digest of the PBKDF2 hashes stored before Argon2
+    pub legacy_digest: Pbkdf2Digest,
+    /// Iterations of the PBKDF2 hashes stored before Argon2
+    pub legacy_iterations: u32,
+}
+
+/// The recommendations of OWASP, also the defaults of the argon2 crate
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        PasswordHashing {
+            memory: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            legacy_digest: Pbkdf2Digest::Sha512,
+            legacy_iterations: 10_000,
+        }
+    }
+}
+
+/// A password hash as found in the users table
+#[derive(Debug, Clone, Copy)]
+pub enum StoredPassword<'a> {
+    /// PHC string, e.g. `$argon2id$v=19$m=19456,t=2,p=1$...`
+    Phc(&'a str),
+    /// Before Argon2, the derived key and its salt
+    Pbkdf2 { hash: &'a [u8], salt: &'a [u8] },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// Valid, but the hash is weaker than the configured one and has to be replaced
+    ValidNeedsRehash,
+}
+
+impl PasswordHashing {
+    fn argon2(&self) -> Result<Argon2<'static>, GenericError> {
+        let params = Params::new(self.memory, self.iterations, self.parallelism, None)
+            .map_err(|e| GenericError::from(format!("Bad Argon2 parameters: {e}")))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// A new hash with the configured parameters, as PHC string
+    pub fn hash(&self, password: &str) -> Result<String, GenericError> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self
+            .argon2()?
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    /// Check the password, malformed hashes never match
+    pub fn verify(&self, password: &str, stored: StoredPassword) -> Verification {
+        match stored {
+            StoredPassword::Phc(phc) => self.verify_phc(password, phc),
+            StoredPassword::Pbkdf2 { hash, salt } => {
+                let Some(iterations) = NonZeroU32::new(self.legacy_iterations) else {
+                    warn!("No iterations configured for the legacy password hashes");
+                    return Verification::Invalid;
+                };
+                let algorithm = match self.legacy_digest {
+                    Pbkdf2Digest::Sha1 => pbkdf2::PBKDF2_HMAC_SHA1,
+                    Pbkdf2Digest::Sha256 => pbkdf2::PBKDF2_HMAC_SHA256,
+                    Pbkdf2Digest::Sha512 => pbkdf2::PBKDF2_HMAC_SHA512,
+                };
+                match pbkdf2::verify(algorithm, iterations, salt, password.as_bytes(), hash) {
+                    Ok(()) => Verification::ValidNeedsRehash,
+                    Err(_) => Verification::Invalid,
+                }
+            }
+        }
+    }
+
+    fn verify_phc(&self, password: &str, phc: &str) -> Verification {
+        let hash = match PasswordHash::new(phc) {
+            Ok(hash) => hash,
+            Err(e) => {
+                warn!("Malformed password hash: {e}");
+                return Verification::Invalid;
+            }
+        };
+        // the parameters of the hash are used, not the configured ones
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_err()
+        {
+            return Verification::Invalid;
+        }
+        if self.is_weaker(&hash) {
+            Verification::ValidNeedsRehash
+        } else {
+            Verification::Valid
+        }
+    }
+
+    fn is_weaker(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != ARGON2ID_IDENT || hash.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+        Params::try_from(hash).map_or(true, |params| {
+            params.m_cost() < self.memory
+                || params.t_cost() < self.iterations
+                || params.p_cost() < self.parallelism
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for tests
+    fn weak() -> PasswordHashing {
+        PasswordHashing {
+            memory: 1024,
+            iterations: 1,
+            parallelism: 1,
+            ..PasswordHashing::default()
+        }
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hashing = weak();
+        let phc = hashing.hash("secret").unwrap();
+        assert!(phc.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert_eq!(
+            hashing.verify("secret", StoredPassword::Phc(&phc)),
+            Verification::Valid
+        );
+        assert_eq!(
+            hashing.verify("wrong", StoredPassword::Phc(&phc)),
+            Verification::Invalid
+        );
+        // the configuration asks for more now
+        let stronger = PasswordHashing {
+            iterations: 2,
+            ..weak()
+        };
+        assert_eq!(
+            stronger.verify("secret", StoredPassword::Phc(&phc)),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(
+            stronger.verify("wrong", StoredPassword::Phc(&phc)),
+            Verification::Invalid
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(
+            weak().verify("secret", StoredPassword::Phc("not a hash")),
+            Verification::Invalid
+        );
+        assert_eq!(
+            weak().verify("secret", StoredPassword::Phc("")),
+            Verification::Invalid
+        );
+    }
+
+    #[test]
+    fn test_pbkdf2() {
+        let hashing = PasswordHashing {
+            legacy_digest: Pbkdf2Digest::Sha256,
+            legacy_iterations: 1000,
+            ..weak()
+        };
+        let salt = b"legacy salt";
+        let mut hash = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(1000).unwrap(),
+            salt,
+            b"secret",
+            &mut hash,
+        );
+        let stored = StoredPassword::Pbkdf2 { hash: &hash, salt };
+        assert_eq!(
+            hashing.verify("secret", stored),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(hashing.verify("wrong", stored), Verification::Invalid);
+    }
+}
@@ -0,0 +1,338 @@
+//! Rules a new password has to follow, for the users choosing one and for the CLI.
+//!
+//! The strength is estimated the way zxcvbn does it: the password is covered with the
+//! cheapest patterns an attacker would try first, dictionary words, repeats and sequences,
+//! and the bits of the cheapest cover are its entropy.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Passwords tried first by everyone, on top of the configured ones
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "passw0rd",
+    "123456",
+    "12345678",
+    "qwerty",
+    "azerty",
+    "qwertz",
+    "letmein",
+    "welcome",
+    "admin",
+    "administrator",
+    "login",
+    "master",
+    "dragon",
+    "monkey",
+    "football",
+    "baseball",
+    "iloveyou",
+    "sunshine",
+    "princess",
+    "shadow",
+    "superman",
+    "trustno1",
+    "secret",
+    "changeme",
+    "organizator",
+    "memo",
+    "parola",
+    "wachtwoord",
+];
+/// Keyboard rows, walked along they are as weak as words
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+/// Shortest run counted as a repeat, a sequence or a word
+const MIN_PATTERN_LENGTH: usize = 3;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// Longer passwords are refused before their strength is estimated or they are hashed
+    pub max_length: usize,
+    /// Refused outright, compared without case; the common passwords are always refused
+    pub banned: Vec<String>,
+    /// Bits of the estimated entropy
+    pub min_entropy: u32,
+    /// The last this many passwords of the user can not be used again
+    pub history: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 10,
+            max_length: 256,
+            banned: vec![],
+            min_entropy: 40,
+            history: 5,
+        }
+    }
+}
+
+/// A rule the password breaks, serialized for the client as `{"rule": "too_short", ...}`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Violation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    Banned,
+    TooWeak { entropy: u32, min_entropy: u32 },
+    Reused { history: usize },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::TooShort { min_length } => {
+                write!(f, "The password needs at least {min_length} characters")
+            }
+            Violation::TooLong { max_length } => {
+                write!(f, "The password can have at most {max_length} characters")
+            }
+            Violation::Banned => write!(f, "The password is too common"),
+            Violation::TooWeak {
+                entropy,
+                min_entropy,
+            } => write!(
+                f,
+                "The password is too easy to guess, {entropy} bits instead of {min_entropy}"
+            ),
+            Violation::Reused { history } => {
+                write!(f, "The password was used among the last {history}")
+            }
+        }
+    }
+}
+
+/// The body of the response refusing a password
+#[derive(Serialize, Debug)]
+pub struct Violations {
+    pub violations: Vec<Violation>,
+}
+
+impl PasswordPolicy {
+    /// The rules the password breaks, reuse aside as it needs the stored hashes.
+    /// `user_inputs` are words known about the user, e.g. the username.
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Vec<Violation> {
+        let length = password.chars().count();
+        if length > self.max_length {
+            // the estimate takes time quadratic in the length
+            return vec![Violation::TooLong {
+                max_length: self.max_length,
+            }];
+        }
+        let mut violations = vec![];
+        if length < self.min_length {
+            violations.push(Violation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        let lowercase = password.to_lowercase();
+        let mut banned = self
+            .banned
+            .iter()
+            .map(String::as_str)
+            .chain(COMMON_PASSWORDS.iter().copied());
+        if banned.any(|word| word.to_lowercase() == lowercase) {
+            violations.push(Violation::Banned);
+        }
+        let dictionary: Vec<String> = self
+            .banned
+            .iter()
+            .map(String::as_str)
+            .chain(user_inputs.iter().copied())
+            .map(str::to_lowercase)
+            .collect();
+        let entropy = estimate_entropy(password, &dictionary) as u32;
+        if entropy < self.min_entropy {
+            violations.push(Violation::TooWeak {
+                entropy,
+                min_entropy: self.min_entropy,
+            });
+        }
+        violations
+    }
+}
+
+/// Characters an attacker has to try for each position, from the classes used
+fn pool_size(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+    f64::from(pool.max(1))
+}
+
+/// Undo the usual letter substitutions, p@ssw0rd is still password
+fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        _ => c.to_ascii_lowercase(),
+    }
+}
+
+/// Length of the run of the same character starting at `start`
+fn repeat_length(chars: &[char], start: usize) -> usize {
+    chars[start..]
+        .iter()
+        .take_while(|c| **c == chars[start])
+        .count()
+}
+
+/// Length of the run of consecutive characters, up or down, starting at `start`
+fn sequence_length(chars: &[char], start: usize) -> usize {
+    let step = |a: char, b: char| b as i64 - a as i64;
+    let Some(direction) = chars.get(start + 1).map(|next| step(chars[start], *next)) else {
+        return 1;
+    };
+    if direction.abs() != 1 {
+        return 1;
+    }
+    1 + chars[start..]
+        .windows(2)
+        .take_while(|pair| step(pair[0], pair[1]) == direction)
+        .count()
+}
+
+/// Bits an attacker needs, guessing patterns before characters.
+/// The `dictionary` words, in lower case, are tried along with the common passwords.
+pub fn estimate_entropy(password: &str, dictionary: &[String]) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    let plain: Vec<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+    let unleeted: Vec<char> = chars.iter().map(|c| unleet(*c)).collect();
+    let char_bits = pool_size(password).log2();
+    let rows: Vec<Vec<char>> = KEYBOARD_ROWS.iter().map(|r| r.chars().collect()).collect();
+    let words: Vec<Vec<char>> = dictionary
+        .iter()
+        .map(String::as_str)
+        .chain(COMMON_PASSWORDS.iter().copied())
+        .map(|w| w.chars().collect())
+        .collect();
+    let word_bits = (words.len() as f64).log2();
+
+    // cheapest cover of the first i characters
+    let mut best = vec![f64::INFINITY; chars.len() + 1];
+    best[0] = 0.0;
+    for start in 0..chars.len() {
+        let base = best[start];
+        let mut cover = |end: usize, bits: f64| {
+            if base + bits < best[end] {
+                best[end] = base + bits;
+            }
+        };
+        cover(start + 1, char_bits);
+
+        let repeat = repeat_length(&plain, start);
+        for length in MIN_PATTERN_LENGTH..=repeat {
+            cover(start + length, char_bits + (length as f64).log2());
+        }
+        let sequence = sequence_length(&plain, start);
+        for length in MIN_PATTERN_LENGTH..=sequence {
+            cover(start + length, char_bits + (length as f64).log2() + 1.0);
+        }
+        for word in &words {
+            let length = word.len();
+            if length >= MIN_PATTERN_LENGTH
+                && (plain[start..].starts_with(word) || unleeted[start..].starts_with(word))
+            {
+                // one more bit for the case and the substitutions
+                cover(start + length, word_bits + 1.0);
+            }
+        }
+        for row in &rows {
+            for length in MIN_PATTERN_LENGTH..=row.len() {
+                if row
+                    .windows(length)
+                    .any(|walk| plain[start..].starts_with(walk))
+                {
+                    cover(
+                        start + length,
+                        (row.len() as f64).log2() + (length as f64).log2(),
+                    );
+                }
+            }
+        }
+    }
+    best[chars.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(policy.check("correct horse battery staple", &[]), vec![]);
+        assert_eq!(
+            policy.check("short", &[]),
+            vec![
+                Violation::TooShort { min_length: 10 },
+                Violation::TooWeak {
+                    entropy: 23,
+                    min_entropy: 40
+                }
+            ]
+        );
+        assert!(policy.check("Password", &[]).contains(&Violation::Banned));
+        assert_eq!(
+            policy.check(&"a".repeat(100_000), &[]),
+            vec![Violation::TooLong { max_length: 256 }]
+        );
+        assert_eq!(policy.check(&"x7#Kq9!mZ2vB".repeat(21), &[]), vec![]);
+        let policy = PasswordPolicy {
+            banned: vec!["Organizator2024".to_string()],
+            ..PasswordPolicy::default()
+        };
+        assert!(
+            policy
+                .check("organizator2024", &[])
+                .contains(&Violation::Banned)
+        );
+    }
+
+    #[test]
+    fn test_estimate_entropy() {
+        let entropy = |password| estimate_entropy(password, &["johndoe".to_string()]);
+        // patterns are cheap
+        assert!(entropy("aaaaaaaaaaaa") < 10.0);
+        assert!(entropy("abcdefghijkl") < 10.0);
+        assert!(entropy("qwertyuiop") < 10.0);
+        assert!(entropy("P@ssw0rd1234") < 20.0);
+        assert!(entropy("johndoe12345") < 20.0);
+        // random characters are not
+        assert!(entropy("x7#Kq9!mZ2vB") > 70.0);
+        assert!(entropy("correct horse battery staple") > 100.0);
+    }
+
+    #[test]
+    fn test_violation_json() {
+        let violations = Violations {
+            violations: vec![Violation::TooShort { min_length: 10 }, Violation::Banned],
+        };
+        assert_eq!(
+            serde_json::to_string(&violations).unwrap(),
+            r#"{"violations":[{"rule":"too_short","min_length":10},{"rule":"banned"}]}"#
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Limits on how often something may be attempted, e.g. a login.
+//!
+//! The attempts are kept in memory, each instance of a service counts its own.
+use crate::authentication::trusted_proxy::RemotePeer;
+use http::Request;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Nginx puts the address of the client here
+const REAL_IP_HEADER: &str = "X-Real-IP";
+/// Nginx appends the address of the client to whatever the client sent
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// Past this many keys the ones without recent attempts are dropped
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// At most `limit` attempts per key in any period of `window`
+#[derive(Debug)]
+pub struct SlidingWindow {
+    window: Duration,
+    limit: usize,
+    attempts: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl SlidingWindow {
+    pub fn new(window: Duration, limit: usize) -> Self {
+        SlidingWindow {
+            window,
+            limit,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an attempt, if over the limit returns how long to wait before the next one
+    pub fn attempt(&self, key: &str) -> Result<(), Duration> {
+        self.attempt_at(key, Instant::now())
+    }
+
+    fn attempt_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() > PRUNE_THRESHOLD {
+            attempts.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) < self.window)
+            });
+        }
+        let times = attempts.entry(key.to_string()).or_default();
+        while times
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= self.window)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            let first = times.front().copied().unwrap_or(now);
+            return Err(self.window - now.duration_since(first));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+/// The address of the client as seen by nginx, or the peer itself when it is not nginx
+pub fn client_ip<B>(request: &Request<B>) -> Option<String> {
+    let peer = request.extensions().get::<RemotePeer>();
+    if let Some(peer) = peer
+        && !peer.is_trusted_proxy()
+    {
+        return Some(peer.addr.ip().to_canonical().to_string());
+    }
+    let headers = request.headers();
+    if let Some(Ok(ip)) = headers.get(REAL_IP_HEADER).map(|h| h.to_str()) {
+        return Some(ip.trim().to_string());
+    }
+    // the last entry is the one added by nginx, the others come from the client
+    headers
+        .get(FORWARDED_FOR_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|list| list.rsplit(',').next())
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| peer.map(|peer| peer.addr.ip().to_canonical().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::trusted_proxy::TrustedProxies;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sliding_window() {
+        let window = SlidingWindow::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        assert!(window.attempt_at("a", at(0)).is_ok());
+        assert!(window.attempt_at("a", at(10)).is_ok());
+        assert_eq!(window.attempt_at("a", at(20)), Err(Duration::from_secs(40)));
+        // other keys are counted apart
+        assert!(window.attempt_at("b", at(20)).is_ok());
+        // the first attempt left the window
+        assert!(window.attempt_at("a", at(60)).is_ok());
+        assert!(window.attempt_at("a", at(61)).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let request = Request::builder()
+            .header(FORWARDED_FOR_HEADER, "10.0.0.1, 192.168.1.7")
+            .body(())
+            .unwrap();
+        assert_eq!(client_ip(&request).as_deref(), Some("192.168.1.7"));
+        let request = Request::builder()
+            .header(REAL_IP_HEADER, "192.168.1.8")
+            .header(FORWARDED_FOR_HEADER, "10.0.0.1, 192.168.1.7")
+            .body(())
+            .unwrap();
+        assert_eq!(client_ip(&request).as_deref(), Some("192.168.1.8"));
+        assert_eq!(client_ip(&Request::new(())), None);
+
+        // the headers count only when nginx sent them
+        let proxies = Arc::new(TrustedProxies::new(&["10.0.0.2".to_string()], None).unwrap());
+        let from = |addr: &str| {
+            let mut request = Request::builder()
+                .header(REAL_IP_HEADER, "192.168.1.8")
+                .body(())
+                .unwrap();
+            request.extensions_mut().insert(RemotePeer {
+                addr: addr.parse().unwrap(),
+                proxies: proxies.clone(),
+            });
+            request
+        };
+        assert_eq!(
+            client_ip(&from("10.0.0.2:4000")).as_deref(),
+            Some("192.168.1.8")
+        );
+        assert_eq!(
+            client_ip(&from("203.0.113.7:4000")).as_deref(),
+            Some("203.0.113.7")
+        );
+    }
+}
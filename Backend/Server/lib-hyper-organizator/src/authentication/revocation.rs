@@ -0,0 +1,174 @@
+//! Tokens revoked before their expiry, e.g. by a logout.
+//!
+//! The list is kept in the `revoked_token` table. Every service holds a copy in memory,
+//! checked on each request and reloaded periodically from the database. Ended sessions are
+//! kept the same way, from the `user_session` table, all the tokens of the session are refused.
+use jsonwebtoken::get_current_timestamp;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::trace;
+
+#[derive(Default, Debug)]
+pub struct RevocationList {
+    /// token id and the expiry of the token, after it the entry is of no use
+    revoked: RwLock<HashMap<String, u64>>,
+    /// session id and when the last token of the session expires
+    sessions: RwLock<HashMap<String, u64>>,
+}
+
+impl RevocationList {
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.revoked.read().unwrap().contains_key(jti)
+    }
+
+    /// Add to the local copy, other services learn about it at their next reload
+    pub fn insert(&self, jti: &str, exp: u64) {
+        self.revoked.write().unwrap().insert(jti.to_string(), exp);
+    }
+
+    /// Take the content of the table, the entries of expired tokens are dropped
+    pub fn replace(&self, entries: HashMap<String, u64>) {
+        let now = get_current_timestamp();
+        let mut revoked = self.revoked.write().unwrap();
+        *revoked = entries;
+        revoked.retain(|_, exp| *exp > now);
+        trace!("{} revoked tokens", revoked.len());
+    }
+
+    /// Tokens outside of a session have no session id, they are not refused for it
+    pub fn is_session_revoked(&self, sid: &str) -> bool {
+        !sid.is_empty() && self.sessions.read().unwrap().contains_key(sid)
+    }
+
+    /// Add to the local copy, `until` is when the last token of the session expires
+    pub fn insert_session(&self, sid: &str, until: u64) {
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(sid.to_string(), until);
+    }
+
+    /// Take the ended sessions from the table, those without valid tokens left are dropped
+    pub fn replace_sessions(&self, entries: HashMap<String, u64>) {
+        let now = get_current_timestamp();
+        let mut sessions = self.sessions.write().unwrap();
+        *sessions = entries;
+        sessions.retain(|_, until| *until > now);
+        trace!("{} ended sessions", sessions.len());
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub use database::{refresh_periodically, revoke};
+
+#[cfg(feature = "postgres")]
+mod database {
+    use super::*;
+    use crate::typedef::GenericError;
+    use deadpool_postgres::{Client, Pool};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::{debug, error};
+
+    /// Record the token as revoked in the database and in the local copy
+    pub async fn revoke(
+        client: &Client,
+        revocation_list: &RevocationList,
+        jti: &str,
+        exp: u64,
+    ) -> Result<(), GenericError> {
+        let now = get_current_timestamp() as i64;
+        let stmt = client
+            .prepare_cached(include_str!("sql/revoke_token.sql"))
+            .await?;
+        client.execute(&stmt, &[&jti, &(exp as i64), &now]).await?;
+        let stmt = client
+            .prepare_cached(include_str!("sql/delete_expired_revocations.sql"))
+            .await?;
+        let deleted = client.execute(&stmt, &[&now]).await?;
+        debug!("Revoked token {jti}, removed {deleted} expired revocations");
+        revocation_list.insert(jti, exp);
+        Ok(())
+    }
+
+    /// The revoked tokens, or the ended sessions, with the expiry of their last token
+    async fn load(client: &Client, sql: &str) -> Result<HashMap<String, u64>, GenericError> {
+        let now = get_current_timestamp() as i64;
+        let stmt = client.prepare_cached(sql).await?;
+        let rows = client.query(&stmt, &[&now]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let exp: i64 = row.get("expires_at");
+                (row.get("id"), exp as u64)
+            })
+            .collect())
+    }
+
+    async fn reload(client: &Client, revocation_list: &RevocationList) -> Result<(), GenericError> {
+        let tokens = load(client, include_str!("sql/revoked_tokens.sql")).await?;
+        let sessions = load(client, include_str!("sql/revoked_sessions.sql")).await?;
+        revocation_list.replace(tokens);
+        revocation_list.replace_sessions(sessions);
+        Ok(())
+    }
+
+    /// Reload the list from the database until the process ends
+    pub async fn refresh_periodically(
+        pool: Pool,
+        revocation_list: Arc<RevocationList>,
+        interval: Duration,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let reloaded = match pool.get().await {
+                Ok(client) => reload(&client, &revocation_list).await,
+                Err(e) => Err(e.into()),
+            };
+            // keep the copy we have, the next attempt may work
+            if let Err(e) = reloaded {
+                error!("Could not reload the revoked tokens: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_drops_expired() {
+        let list = RevocationList::default();
+        list.insert("a", 1);
+        assert!(list.is_revoked("a"));
+        let now = get_current_timestamp();
+        list.replace(HashMap::from([
+            ("old".to_string(), now - 10),
+            ("new".to_string(), now + 10),
+        ]));
+        assert!(!list.is_revoked("a"));
+        assert!(!list.is_revoked("old"));
+        assert!(list.is_revoked("new"));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let list = RevocationList::default();
+        let now = get_current_timestamp();
+        list.insert_session("ended", now + 10);
+        assert!(list.is_session_revoked("ended"));
+        // tokens of no session
+        assert!(!list.is_session_revoked(""));
+        list.replace_sessions(HashMap::from([
+            ("old".to_string(), now - 10),
+            ("new".to_string(), now + 10),
+        ]));
+        assert!(!list.is_session_revoked("ended"));
+        assert!(!list.is_session_revoked("old"));
+        assert!(list.is_session_revoked("new"));
+        // the tokens are apart from the sessions
+        assert!(!list.is_revoked("new"));
+    }
+}
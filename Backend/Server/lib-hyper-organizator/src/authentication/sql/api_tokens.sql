@@ -0,0 +1,12 @@
+-- the tokens still valid, with the user they act for
+SELECT api_token.id,
+       api_token.token_hash,
+       users.username,
+       api_token.scopes,
+       api_token.expires_at
+FROM api_token
+JOIN users ON users.id = api_token.user_id
+LEFT JOIN user_account ON user_account.user_id = users.id
+WHERE (api_token.expires_at IS NULL
+       OR api_token.expires_at > $1)
+  AND NOT COALESCE(user_account.disabled, false);
@@ -0,0 +1,2 @@
+-- expired tokens are refused anyway, no need to remember them
+DELETE FROM revoked_token WHERE expires_at <= $1;
@@ -0,0 +1,4 @@
+-- $1 jti of the token, $2 its expiry, $3 now, both in seconds since epoch
+INSERT INTO revoked_token (jti, expires_at, revoked_on)
+VALUES ($1, $2, $3)
+ON CONFLICT (jti) DO NOTHING;
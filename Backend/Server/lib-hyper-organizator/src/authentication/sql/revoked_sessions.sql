@@ -0,0 +1,5 @@
+-- the ended sessions that may still have valid tokens, $1 is now in seconds since epoch
+SELECT id::text AS id,
+       revoked_until AS expires_at
+FROM user_session
+WHERE revoked_until > $1;
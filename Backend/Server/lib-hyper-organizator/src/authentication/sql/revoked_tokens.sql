@@ -0,0 +1,4 @@
+-- the revocations still in effect, $1 is now in seconds since epoch
+SELECT jti AS id, expires_at
+FROM revoked_token
+WHERE expires_at > $1;
@@ -0,0 +1,12 @@
+-- the certificates not revoked, with the user they log in as
+SELECT user_certificate.id,
+       users.username,
+       user_certificate.subject_dn,
+       user_certificate.issuer_dn,
+       user_certificate.serial,
+       user_certificate.fingerprint
+FROM user_certificate
+JOIN users ON users.id = user_certificate.user_id
+LEFT JOIN user_account ON user_account.user_id = users.id
+WHERE user_certificate.revoked_on IS NULL
+  AND NOT COALESCE(user_account.disabled, false);
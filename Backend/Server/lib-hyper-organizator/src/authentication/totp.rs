@@ -0,0 +1,162 @@
+//! Time-based one-time passwords, RFC 6238, as produced by authenticator apps.
+//!
+//! HMAC-SHA1, 6 digits, 30 second steps; the only parameters the apps all agree on.
+use crate::typedef::GenericError;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of the steps next to the current one are accepted, clocks drift
+const ALLOWED_DRIFT: u64 = 1;
+/// RFC 4226 recommends at least 160 bits
+const SECRET_LENGTH: usize = 20;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Result<Vec<u8>, GenericError> {
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| GenericError::from("Could not generate a TOTP secret"))?;
+    Ok(secret)
+}
+
+/// RFC 4648 base32 without padding, the form authenticator apps expect
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Accepts lower case and padding, None if a character is not base32
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 5 / 8);
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u32;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// RFC 4226 one-time password for the counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// The code an authenticator shows at the time, seconds since epoch
+pub fn code_at(secret: &[u8], time: u64) -> String {
+    format!(
+        "{:0width$}",
+        hotp(secret, time / STEP),
+        width = DIGITS as usize
+    )
+}
+
+/// Check a code typed by the user, returns the step it belongs to.
+/// Steps up to `last_used_step` are refused, a code can not be used twice.
+pub fn verify(secret: &[u8], code: &str, time: u64, last_used_step: Option<i64>) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let current = time / STEP;
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .filter(|step| last_used_step.is_none_or(|last| *step as i64 > last))
+        .find(|step| code_at(secret, step * STEP) == code)
+}
+
+/// The URI authenticator apps read from the QR code, Key URI Format
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC);
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        base32_encode(secret)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA1 secret of the RFC 6238 test vectors
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // the RFC lists 8 digits, the last 6 are the same
+        assert_eq!(code_at(SECRET, 59), "287082");
+        assert_eq!(code_at(SECRET, 1111111109), "081804");
+        assert_eq!(code_at(SECRET, 1111111111), "050471");
+        assert_eq!(code_at(SECRET, 1234567890), "005924");
+        assert_eq!(code_at(SECRET, 2000000000), "279037");
+    }
+
+    #[test]
+    fn test_verify() {
+        let time = 1234567890;
+        let step = time / STEP;
+        assert_eq!(verify(SECRET, "005924", time, None), Some(step));
+        // the previous step is still fine
+        assert_eq!(verify(SECRET, "005924", time + STEP, None), Some(step));
+        assert_eq!(verify(SECRET, "005924", time + 2 * STEP, None), None);
+        // no replay
+        assert_eq!(verify(SECRET, "005924", time, Some(step as i64)), None);
+        assert_eq!(verify(SECRET, "00592", time, None), None);
+        assert_eq!(verify(SECRET, "abcdef", time, None), None);
+    }
+
+    #[test]
+    fn test_base32() {
+        // RFC 4648 test vectors
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"foob"), "MZXW6YQ");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(base32_decode("mzxw6yq=").unwrap(), b"foob");
+        assert!(base32_decode("MZ1").is_none());
+        let secret = generate_secret().unwrap();
+        assert_eq!(base32_decode(&base32_encode(&secret)).unwrap(), secret);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        assert_eq!(
+            otpauth_uri("Organizator", "john doe", SECRET),
+            "otpauth://totp/Organizator:john%20doe?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Organizator&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
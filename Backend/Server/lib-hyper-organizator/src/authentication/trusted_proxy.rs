@@ -0,0 +1,257 @@
+//! Headers nginx sets for the client, believed only when nginx sent them.
+//!
+//! The client certificate and the client address are passed as headers, anyone reaching the
+//! service port directly could set them too. Each connection is tagged with the address of
+//! the peer, and the headers count only if the peer is in `trusted_proxies`. Optionally nginx
+//! also signs the certificate headers with a secret shared with the services.
+use crate::authentication::client_certificate::{
+    SSL_HEADER_CERT, SSL_HEADER_DN, SSL_HEADER_ISSUER_DN, SSL_HEADER_SERIAL, SSL_HEADER_VERIFY,
+};
+use crate::settings::{SecurityConfig, get_secret};
+use crate::typedef::GenericError;
+use http::{HeaderMap, Request};
+use jsonwebtoken::get_current_timestamp;
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::info;
+
+/// `<seconds since epoch>:<hex of the HMAC-SHA256>` of the timestamp and the certificate
+/// headers, one per line, empty when missing
+pub const SSL_HEADER_SIGNATURE: &str = "X-SSL-Client-Signature";
+
+/// Seconds a signature is accepted for, either way to allow for clocks apart
+const SIGNATURE_MAX_AGE: u64 = 300;
+
+/// The headers covered by the signature, in this order
+const SIGNED_HEADERS: [&str; 5] = [
+    SSL_HEADER_VERIFY,
+    SSL_HEADER_DN,
+    SSL_HEADER_ISSUER_DN,
+    SSL_HEADER_SERIAL,
+    SSL_HEADER_CERT,
+];
+
+/// A network like `10.0.0.0/8`, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = GenericError;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match cidr.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (cidr.trim(), None),
+        };
+        let network: IpAddr = address.parse()?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse()?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(format!("Prefix of 「{cidr}」 is longer than the address").into());
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a v4 client of a dual stack socket shows up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The proxies whose headers are believed, and the key they sign with if they do
+#[derive(Debug)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+    signature_key: Option<hmac::Key>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: &[String], signature_secret: Option<&[u8]>) -> Result<Self, GenericError> {
+        Ok(TrustedProxies {
+            networks: networks
+                .iter()
+                .map(|cidr| cidr.parse())
+                .collect::<Result<_, _>>()?,
+            signature_key: signature_secret.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret)),
+        })
+    }
+
+    /// The secret is read at start, a missing one is an error rather than no signature
+    pub fn from_config(config: &SecurityConfig) -> Result<Self, GenericError> {
+        let secret = match config.proxy_signature_secret {
+            Some(ref name) => Some(
+                get_secret(name)
+                    .map_err(|e| format!("Proxy signature secret {name}: {e}"))?
+                    .into_bytes(),
+            ),
+            None => None,
+        };
+        Self::new(&config.trusted_proxies, secret.as_deref())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// True when no signature is required
+    fn verify_signature(&self, headers: &HeaderMap, now: u64) -> bool {
+        let Some(ref key) = self.signature_key else {
+            return true;
+        };
+        let Some((timestamp, signature)) = headers
+            .get(SSL_HEADER_SIGNATURE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(':'))
+        else {
+            info!("No valid {SSL_HEADER_SIGNATURE} header");
+            return false;
+        };
+        match timestamp.parse::<u64>() {
+            Ok(timestamp) if timestamp.abs_diff(now) <= SIGNATURE_MAX_AGE => {}
+            _ => {
+                info!("Expired {SSL_HEADER_SIGNATURE}: {timestamp}");
+                return false;
+            }
+        }
+        let expected = sign(key, timestamp, headers);
+        verify_slices_are_equal(expected.as_bytes(), signature.as_bytes()).is_ok()
+    }
+}
+
+fn sign(key: &hmac::Key, timestamp: &str, headers: &HeaderMap) -> String {
+    let mut message = String::from(timestamp);
+    for name in SIGNED_HEADERS {
+        message.push('\n');
+        message.push_str(
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default(),
+        );
+    }
+    hmac::sign(key, message.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The other end of the connection, set on every request by the server
+#[derive(Debug, Clone)]
+pub struct RemotePeer {
+    pub addr: SocketAddr,
+    pub proxies: Arc<TrustedProxies>,
+}
+
+impl RemotePeer {
+    pub fn is_trusted_proxy(&self) -> bool {
+        self.proxies.contains(self.addr.ip())
+    }
+}
+
+/// Whether the client certificate headers come from nginx, from an unknown peer they do not
+pub fn certificate_headers_trusted<B>(request: &Request<B>) -> bool {
+    let Some(peer) = request.extensions().get::<RemotePeer>() else {
+        info!("Peer unknown, client certificate headers ignored");
+        return false;
+    };
+    if !peer.is_trusted_proxy() {
+        info!(
+            "Client certificate headers from {}, not a trusted proxy",
+            peer.addr
+        );
+        return false;
+    }
+    peer.proxies
+        .verify_signature(request.headers(), get_current_timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let private: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains(ip("10.1.2.3")));
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+        assert!(!private.contains(ip("11.0.0.1")));
+        let single: Cidr = "192.168.1.5".parse().unwrap();
+        assert!(single.contains(ip("192.168.1.5")));
+        assert!(!single.contains(ip("192.168.1.6")));
+        let v6: Cidr = "fc00::/7".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("2001:db8::1")));
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("8.8.8.8")));
+        for bad in ["10.0.0.0/33", "::/129", "10.0.0/8", "nginx"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+
+        // the other containers or pods of the host are not trusted without configuration
+        let defaults = TrustedProxies::from_config(&SecurityConfig::default()).unwrap();
+        assert!(defaults.contains(ip("127.0.0.1")));
+        assert!(defaults.contains(ip("::1")));
+        assert!(!defaults.contains(ip("172.18.0.5")));
+        assert!(!defaults.contains(ip("10.244.0.12")));
+        assert!(!defaults.contains(ip("203.0.113.7")));
+    }
+
+    fn headers(timestamp: u64, key: &hmac::Key) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(SSL_HEADER_VERIFY, "SUCCESS".parse().unwrap());
+        headers.insert(SSL_HEADER_DN, "CN=admin".parse().unwrap());
+        let timestamp = timestamp.to_string();
+        let signature = format!("{timestamp}:{}", sign(key, &timestamp, &headers));
+        headers.insert(SSL_HEADER_SIGNATURE, signature.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_signature() {
+        let networks = vec!["127.0.0.1".to_string()];
+        let proxies = TrustedProxies::new(&networks, Some(b"shared")).unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"shared");
+        let now = 1_700_000_000;
+        assert!(proxies.verify_signature(&headers(now, &key), now + 10));
+        // too old, signed with another key, or another subject than signed
+        assert!(!proxies.verify_signature(&headers(now, &key), now + 1000));
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other");
+        assert!(!proxies.verify_signature(&headers(now, &other), now));
+        let mut forged = headers(now, &key);
+        forged.insert(SSL_HEADER_DN, "CN=someone else".parse().unwrap());
+        assert!(!proxies.verify_signature(&forged, now));
+        assert!(!proxies.verify_signature(&HeaderMap::new(), now));
+
+        // without a key only the address counts
+        let unsigned = TrustedProxies::new(&networks, None).unwrap();
+        assert!(unsigned.verify_signature(&HeaderMap::new(), now));
+        assert!(unsigned.contains(ip("127.0.0.1")));
+        assert!(!unsigned.contains(ip("127.0.0.2")));
+    }
+}
@@ -0,0 +1,490 @@
+//! Login through an upstream OpenID Connect provider, e.g. the company Keycloak.
+//!
+//! The identity service is the relying party here: the browser is sent to the provider with
+//! a PKCE challenge and comes back with a code, traded at the token endpoint for an ID token.
+//! The ID token is checked against the keys the provider publishes, refetched on rotation.
+use crate::authentication::jot::MIN_KEY_REFRESH_INTERVAL;
+use crate::settings::get_secret;
+use crate::typedef::GenericError;
+use bytes::Buf as _;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+use tracing::{info, warn};
+use url::Url;
+
+/// Signature algorithms accepted from the provider, the symmetric ones never
+const ACCEPTED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct UpstreamOidcConfig {
+    /// Issuer of the provider, its discovery document is under it; empty disables the login
+    pub issuer: String,
+    pub client_id: String,
+    /// Name of the secret holding the client secret; public clients have none
+    pub secret_name: Option<String>,
+    /// Our callback, as registered with the provider
+    pub redirect_uri: String,
+    /// Space separated, `openid` has to be among them
+    pub scopes: String,
+    /// Claim with the local username, for the subjects not linked to a user yet
+    pub username_claim: String,
+    /// Link a subject to the existing user of the same name, administrators and users with a
+    /// second factor aside; the provider then decides who gets the account
+    pub link_existing_users: bool,
+    /// Unknown users get an account, otherwise only the existing users can log in
+    pub auto_provision: bool,
+    /// The administrator the accounts are created on behalf of, row level security asks for one
+    pub provisioned_by: String,
+    /// Role granted to the accounts created
+    pub provisioned_role: String,
+}
+
+impl Default for UpstreamOidcConfig {
+    fn default() -> Self {
+        UpstreamOidcConfig {
+            issuer: String::new(),
+            client_id: String::new(),
+            secret_name: None,
+            redirect_uri: String::new(),
+            scopes: "openid email profile".to_string(),
+            username_claim: "email".to_string(),
+            link_existing_users: false,
+            auto_provision: false,
+            provisioned_by: String::new(),
+            provisioned_role: "org".to_string(),
+        }
+    }
+}
+
+impl UpstreamOidcConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.issuer.is_empty()
+    }
+}
+
+/// The parts of the discovery document we use
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+/// Who the provider says logged in
+#[derive(Debug, Clone)]
+pub struct UpstreamIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub claims: Map<String, Value>,
+}
+
+impl UpstreamIdentity {
+    /// The local username from the claim, an email only if the provider says it verified it
+    pub fn username(&self, claim: &str) -> Option<&str> {
+        if claim == "email" && self.claims.get("email_verified") != Some(&Value::Bool(true)) {
+            return None;
+        }
+        self.claims
+            .get(claim)
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty())
+    }
+}
+
+/// A provider found through its discovery document
+pub struct UpstreamProvider {
+    config: UpstreamOidcConfig,
+    metadata: ProviderMetadata,
+    keys: RwLock<JwkSet>,
+    /// ID tokens with unknown key ids do not make us fetch the keys more often than allowed
+    last_key_refresh: Mutex<Option<Instant>>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    // plain http for providers in the cluster and for tests
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+impl UpstreamProvider {
+    /// Fetch the discovery document and the keys
+    pub async fn discover(config: &UpstreamOidcConfig) -> Result<UpstreamProvider, GenericError> {
+        let client = https_client();
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = get_json(&client, &discovery_url).await?;
+        if metadata.issuer != config.issuer {
+            return Err(format!(
+                "The provider says its issuer is 「{}」, not 「{}」",
+                metadata.issuer, config.issuer
+            )
+            .into());
+        }
+        let keys: JwkSet = get_json(&client, &metadata.jwks_uri).await?;
+        info!(
+            "Upstream OpenID Connect provider {} with {} keys",
+            metadata.issuer,
+            keys.keys.len()
+        );
+        Ok(UpstreamProvider {
+            config: config.clone(),
+            metadata,
+            keys: RwLock::new(keys),
+            last_key_refresh: Mutex::new(None),
+            client,
+        })
+    }
+
+    pub fn config(&self) -> &UpstreamOidcConfig {
+        &self.config
+    }
+
+    /// Where to send the browser, the code verifier of the challenge is kept by the caller
+    pub fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String, GenericError> {
+        let mut url = Url::parse(&self.metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.into())
+    }
+
+    /// Trade the code for an ID token and check it
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<UpstreamIdentity, GenericError> {
+        let client_secret = match &self.config.secret_name {
+            Some(secret_name) => Some(get_secret(secret_name).map_err(|e| e.to_string())?),
+            None => None,
+        };
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(ref client_secret) = client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.metadata.token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(serde_urlencoded::to_string(&form)?))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::aggregate(response).await?;
+        if status != StatusCode::OK {
+            let mut text = String::new();
+            std::io::Read::read_to_string(&mut body.reader(), &mut text)?;
+            return Err(format!("The token endpoint answered {status}: {text}").into());
+        }
+        let token_response: TokenEndpointResponse = serde_json::from_reader(body.reader())?;
+        self.verify_id_token(&token_response.id_token, nonce).await
+    }
+
+    /// Signature, issuer, audience, expiry and nonce
+    pub async fn verify_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<UpstreamIdentity, GenericError> {
+        let header = decode_header(id_token)?;
+        if !ACCEPTED_ALGORITHMS.contains(&header.alg) {
+            return Err(format!("ID token signed with {:?}", header.alg).into());
+        }
+        let key = match self.find_key(header.kid.as_deref()) {
+            Some(key) => key,
+            // the provider rotated its keys
+            None if self.may_refresh_keys() => {
+                let keys: JwkSet = get_json(&self.client, &self.metadata.jwks_uri).await?;
+                *self.keys.write().unwrap() = keys;
+                self.find_key(header.kid.as_deref())
+                    .ok_or("No key of the provider matches the ID token")?
+            }
+            None => return Err("No key of the provider matches the ID token".into()),
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<Map<String, Value>>(id_token, &key, &validation)?.claims;
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err("The nonce of the ID token does not match".into());
+        }
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or("The ID token has no subject")?
+            .to_string();
+        Ok(UpstreamIdentity {
+            issuer: self.metadata.issuer.clone(),
+            subject,
+            claims,
+        })
+    }
+
+    /// Not more often than MIN_KEY_REFRESH_INTERVAL, forged key ids would have us hammer the
+    /// provider
+    fn may_refresh_keys(&self) -> bool {
+        let mut last_key_refresh = self.last_key_refresh.lock().unwrap();
+        if last_key_refresh.is_some_and(|t| t.elapsed() < MIN_KEY_REFRESH_INTERVAL) {
+            warn!("Keys of the provider refreshed recently, not fetching them again");
+            return false;
+        }
+        *last_key_refresh = Some(Instant::now());
+        true
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let keys = self.keys.read().unwrap();
+        let jwk = match kid {
+            Some(kid) => keys.find(kid)?,
+            // without a key id only a provider with a single key can be trusted
+            None if keys.keys.len() == 1 => &keys.keys[0],
+            None => return None,
+        };
+        DecodingKey::from_jwk(jwk)
+            .inspect_err(|e| warn!("Unusable key from the provider: {e}"))
+            .ok()
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &str,
+) -> Result<T, GenericError> {
+    let response = client.get(url.parse()?).await?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("{url} answered {}", response.status()).into());
+    }
+    let body = hyper::body::aggregate(response).await?;
+    Ok(serde_json::from_reader(body.reader())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::jot::TokenIssuer;
+    use crate::settings::SecurityConfig;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use jsonwebtoken::get_current_timestamp;
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An issuer answering discovery, keys and the token endpoint, any code is good;
+    /// also the number of times the keys were fetched
+    async fn mock_issuer(audience: &'static str) -> (String, Arc<AtomicUsize>) {
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .unwrap()
+            .as_ref()
+            .to_vec();
+        let signer = Arc::new(
+            TokenIssuer::from_key_documents(&[document], &SecurityConfig::default()).unwrap(),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let key_fetches = Arc::new(AtomicUsize::new(0));
+        let published = (issuer.clone(), signer.clone(), key_fetches.clone());
+        let make_service = make_service_fn(move |_| {
+            let (issuer, signer, key_fetches) = published.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                    let (issuer, signer) = (issuer.clone(), signer.clone());
+                    if request.uri().path() == "/jwks" {
+                        key_fetches.fetch_add(1, Ordering::SeqCst);
+                    }
+                    async move {
+                        let body = match request.uri().path() {
+                            "/.well-known/openid-configuration" => serde_json::json!({
+                                "issuer": issuer,
+                                "authorization_endpoint": format!("{issuer}/auth"),
+                                "token_endpoint": format!("{issuer}/token"),
+                                "jwks_uri": format!("{issuer}/jwks"),
+                            })
+                            .to_string(),
+                            "/jwks" => signer.get_jwks(),
+                            "/token" => {
+                                let body = hyper::body::to_bytes(request.into_body()).await?;
+                                let form: Vec<(String, String)> =
+                                    serde_urlencoded::from_bytes(&body).unwrap();
+                                // the nonce travels in the code, to keep the mock stateless
+                                let nonce = &form.iter().find(|(k, _)| k == "code").unwrap().1;
+                                let now = get_current_timestamp();
+                                let id_token = signer
+                                    .sign(&serde_json::json!({
+                                        "iss": issuer,
+                                        "sub": "f81d4fae",
+                                        "aud": audience,
+                                        "exp": now + 60,
+                                        "iat": now,
+                                        "nonce": nonce,
+                                        "email": "jane@example.com",
+                                        "email_verified": true,
+                                    }))
+                                    .unwrap();
+                                serde_json::json!({ "id_token": id_token }).to_string()
+                            }
+                            _ => {
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::NOT_FOUND;
+                                return Ok(response);
+                            }
+                        };
+                        Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                    }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener).unwrap().serve(make_service);
+        tokio::spawn(server);
+        (issuer, key_fetches)
+    }
+
+    fn config(issuer: &str) -> UpstreamOidcConfig {
+        UpstreamOidcConfig {
+            issuer: issuer.to_string(),
+            client_id: "organizator".to_string(),
+            redirect_uri: "https://organizator.example/organizator/login/oidc/callback".to_string(),
+            ..UpstreamOidcConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login() -> Result<(), GenericError> {
+        let (issuer, _) = mock_issuer("organizator").await;
+        let provider = UpstreamProvider::discover(&config(&issuer)).await?;
+        let url = provider.authorization_url("the state", "n-0S6", "E9Melhoa")?;
+        assert!(url.starts_with(&format!(
+            "{issuer}/auth?response_type=code&client_id=organizator"
+        )));
+        assert!(url.contains("state=the+state"));
+
+        let identity = provider.exchange_code("n-0S6", "verifier", "n-0S6").await?;
+        assert_eq!(identity.issuer, issuer);
+        assert_eq!(identity.subject, "f81d4fae");
+        assert_eq!(identity.username("email"), Some("jane@example.com"));
+        assert_eq!(identity.username("preferred_username"), None);
+
+        // a replayed ID token carries the nonce of another login
+        assert!(
+            provider
+                .exchange_code("n-0S6", "verifier", "other")
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrong_audience() -> Result<(), GenericError> {
+        let (issuer, _) = mock_issuer("another-client").await;
+        let provider = UpstreamProvider::discover(&config(&issuer)).await?;
+        assert!(
+            provider
+                .exchange_code("n-0S6", "verifier", "n-0S6")
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_issuer_mismatch() {
+        let (issuer, _) = mock_issuer("organizator").await;
+        let config = config(&format!("{issuer}/realms/other"));
+        assert!(UpstreamProvider::discover(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_refetch_limited() -> Result<(), GenericError> {
+        let (issuer, key_fetches) = mock_issuer("organizator").await;
+        let provider = UpstreamProvider::discover(&config(&issuer)).await?;
+        assert_eq!(key_fetches.load(Ordering::SeqCst), 1);
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .unwrap()
+            .as_ref()
+            .to_vec();
+        let stranger = TokenIssuer::from_key_documents(&[document], &SecurityConfig::default())?;
+        let forged = stranger.sign(&serde_json::json!({ "sub": "f81d4fae" }))?;
+
+        assert!(provider.verify_id_token(&forged, "n-0S6").await.is_err());
+        assert_eq!(key_fetches.load(Ordering::SeqCst), 2);
+        // the keys were just fetched, another unknown key id does not fetch them again
+        assert!(provider.verify_id_token(&forged, "n-0S6").await.is_err());
+        assert_eq!(key_fetches.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unverified_email() {
+        let identity = |claims: serde_json::Value| UpstreamIdentity {
+            issuer: "https://sso.example".to_string(),
+            subject: "f81d4fae".to_string(),
+            claims: claims.as_object().unwrap().clone(),
+        };
+        let unverified =
+            identity(serde_json::json!({ "email": "jane@example.com", "email_verified": false }));
+        assert_eq!(unverified.username("email"), None);
+        // not saying is not verifying
+        let unknown = identity(serde_json::json!({ "email": "jane@example.com" }));
+        assert_eq!(unknown.username("email"), None);
+        let stringly =
+            identity(serde_json::json!({ "email": "jane@example.com", "email_verified": "true" }));
+        assert_eq!(stringly.username("email"), None);
+        // other claims are the provider's own
+        let named = identity(serde_json::json!({ "preferred_username": "jane" }));
+        assert_eq!(named.username("preferred_username"), Some("jane"));
+    }
+}
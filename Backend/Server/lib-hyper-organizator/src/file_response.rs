@@ -0,0 +1,278 @@
+//! Serve files from disk with support for `Range`, `If-Range` and `If-None-Match`.
+//!
+//! Only single byte ranges are honoured, a request for multiple ranges gets the full file.
+use crate::typedef::GenericError;
+use http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+};
+use http::{HeaderMap, Request, Response, StatusCode};
+use hyper::Body;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, trace};
+
+/// Characters allowed unencoded in a RFC 5987 `filename*` value
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    /// first and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Stream the file at `path` to the client.
+///
+/// `download_name` is offered to the browser in `Content-Disposition`, the content type is
+/// guessed from the extension of `path` unless `content_type` is given.
+pub async fn serve_file<B>(
+    request: &Request<B>,
+    path: &Path,
+    download_name: &str,
+    content_type: Option<&str>,
+) -> Result<Response<Body>, GenericError> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("File {:?} not found on disk", path);
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("File not found"))?);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let etag = format!("\"{len:x}-{modified:x}\"");
+
+    let headers = request.headers();
+    if none_match(headers, &etag) {
+        trace!("ETag {etag} matches, file not modified");
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, etag)
+            .body(Body::empty())?);
+    }
+
+    let range = if if_range_matches(headers, &etag) {
+        parse_range(headers.get(RANGE).and_then(|v| v.to_str().ok()), len)
+    } else {
+        ByteRange::Full
+    };
+    debug!("Serving {:?}, size {len}, range {:?}", path, range);
+
+    let guessed_type = mime_guess::from_path(path).first_or_octet_stream();
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, content_type.unwrap_or(guessed_type.as_ref()))
+        .header(CONTENT_DISPOSITION, content_disposition(download_name))
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, &etag)
+        // the file is private, the browser has to come back and get the access checked
+        .header(CACHE_CONTROL, "private, no-cache")
+        .header("server", "hyper");
+
+    let (start, end) = match range {
+        ByteRange::Full => {
+            return Ok(builder
+                .status(StatusCode::OK)
+                .header(CONTENT_LENGTH, len)
+                .body(Body::wrap_stream(ReaderStream::new(file)))?);
+        }
+        ByteRange::Partial(start, end) => (start, end),
+        ByteRange::Unsatisfiable => {
+            return Ok(builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{len}"))
+                .body(Body::empty())?);
+        }
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let count = end - start + 1;
+    Ok(builder
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(CONTENT_LENGTH, count)
+        .header(CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))
+        .body(Body::wrap_stream(ReaderStream::new(file.take(count))))?)
+}
+
+/// `If-None-Match` lists the entity tags the client already has
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+}
+
+/// A range is only valid for the representation named in `If-Range`, if any
+fn if_range_matches(headers: &HeaderMap, etag: &str) -> bool {
+    match headers.get(IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(tag) => tag.trim() == etag,
+        None => true,
+    }
+}
+
+/// Parse a `Range` header, anything we don't understand is answered with the full file
+fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // bytes=-500 the last 500 bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(len.saturating_sub(suffix), len - 1)
+            }
+        }
+        // bytes=500- from byte 500 to the end
+        (Ok(start), Err(_)) if last.is_empty() => {
+            if start < len {
+                ByteRange::Partial(start, len - 1)
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        (Ok(start), Ok(end)) if start <= end => {
+            if start < len {
+                ByteRange::Partial(start, end.min(len - 1))
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        _ => ByteRange::Full,
+    }
+}
+
+/// Offer the original name, with an ASCII fallback for old clients
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "inline; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        utf8_percent_encode(file_name, ATTR_CHAR)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            parse_range(Some("bytes=0-9"), 100),
+            ByteRange::Partial(0, 9)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=90-"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-10"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-200"), 100),
+            ByteRange::Partial(0, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=50-500"), 100),
+            ByteRange::Partial(50, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=9-0"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-9"), 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("Café \"menu\".pdf"),
+            "inline; filename=\"Caf_ _menu_.pdf\"; filename*=UTF-8''Caf%C3%A9%20%22menu%22.pdf"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_file_range_and_etag() -> Result<(), GenericError> {
+        let path = std::env::temp_dir().join("file_response_test.txt");
+        tokio::fs::write(&path, b"0123456789").await?;
+
+        let mut request = Request::new(Body::empty());
+        request
+            .headers_mut()
+            .insert(RANGE, "bytes=2-4".parse().unwrap());
+        let response = serve_file(&request, &path, "digits.txt", None).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        let etag = response.headers()[ETAG].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(&body[..], b"234");
+
+        let mut request = Request::new(Body::empty());
+        request.headers_mut().insert(IF_NONE_MATCH, etag);
+        let response = serve_file(&request, &path, "digits.txt", None).await?;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = serve_file(
+            &Request::new(Body::empty()),
+            &path,
+            "digits.txt",
+            Some("application/octet-stream"),
+        )
+        .await?;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+        Ok(())
+    }
+}
@@ -13,12 +13,15 @@
 //! - `postgres`: Enables the `postgres` module.
 //! - `security`: Enables the `authentication` module.
 //!
+pub mod audit;
 pub mod authentication;
+pub mod file_response;
 mod logging;
 mod metrics;
 pub mod multipart;
 pub mod postgres;
 pub mod response_utils;
+pub mod scanner;
 pub mod server;
 mod settings;
 pub mod swagger;
@@ -1,6 +1,6 @@
 use futures_util::stream::StreamExt;
 use hyper::{Body, Request};
-use log::{debug, log_enabled, trace};
+use log::{debug, log_enabled, trace, warn};
 use memchr::memmem;
 use regex::Regex;
 use std::sync::LazyLock;
@@ -38,14 +38,26 @@ pub enum Field {
     File(FileField),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum MultipartError {
+    /// The uploaded files have been removed from disk
+    #[error("uploaded files exceed {0} bytes")]
+    TooLarge(u64),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
 // read first boundary
 // read until empty line \r\n\r\n
 // analyze headers
 // read until boundary
+///
+/// Uploading stops as soon as the files together grow over `max_file_size` bytes.
 pub async fn handle_multipart(
     mut req: Request<Body>,
     file_dir: &str,
-) -> Result<Vec<Field>, hyper::Error> {
+    max_file_size: u64,
+) -> Result<Vec<Field>, MultipartError> {
     // if we are on debug mode, dump the file to /tmp/file.bin
     let mut dump = if log_enabled!(log::Level::Debug) {
         Some(File::create("/tmp/file.bin").await.unwrap())
@@ -65,6 +77,8 @@ pub async fn handle_multipart(
     let mut buf: Vec<u8> = Vec::with_capacity(512);
 
     let mut file_dest = Destination::Unknown;
+    // bytes in the files that have been completely written
+    let mut files_size = 0;
     let mut status = Status::Start;
     while let Some(chunk) = req.body_mut().next().await {
         let chunk = chunk.unwrap();
@@ -110,6 +124,7 @@ pub async fn handle_multipart(
                                     let random_file_name = uuid::Uuid::new_v4().to_string();
                                     // get the extension from the file_name
                                     let ext = file_name.split('.').next_back().unwrap();
+                                    files_size += file_dest.written();
                                     file_dest = Destination::new_file(format!(
                                         "{file_dir}/{random_file_name}.{ext}"
                                     ))
@@ -169,6 +184,12 @@ pub async fn handle_multipart(
                     let res =
                         read_to_delimiter(&chunk, &mut file_dest, boundary.as_bytes(), offset)
                             .await;
+                    if files_size + file_dest.written() > max_file_size {
+                        debug!("Upload is larger than {max_file_size} bytes, discarding it");
+                        drop(file_dest);
+                        remove_files(&result, file_dir).await;
+                        return Err(MultipartError::TooLarge(max_file_size));
+                    }
                     offset = match res {
                         ReadResult::Done(offset) => {
                             status = Status::Headers;
@@ -196,6 +217,17 @@ pub async fn handle_multipart(
     Ok(result)
 }
 
+async fn remove_files(fields: &[Field], file_dir: &str) {
+    for field in fields {
+        if let Field::File(FileField { file_name, .. }) = field {
+            let path = format!("{file_dir}/{file_name}");
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Could not remove {path}: {e}");
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum ReadResult {
     Done(usize), // how many bytes were appended to the buffer
@@ -206,7 +238,8 @@ enum ReadResult {
 #[derive(Debug)]
 enum Destination<'a> {
     Buffer(&'a mut Vec<u8>),
-    DiskFile(File),
+    /// the file and the number of bytes written to it
+    DiskFile(File, u64),
     Unknown,
 }
 
@@ -214,14 +247,24 @@ impl Destination<'_> {
     async fn write(&mut self, chunk: &[u8]) {
         match self {
             Self::Buffer(buf) => buf.extend_from_slice(chunk),
-            Self::DiskFile(file) => file.write_all(chunk).await.unwrap(),
+            Self::DiskFile(file, written) => {
+                file.write_all(chunk).await.unwrap();
+                *written += chunk.len() as u64;
+            }
             Self::Unknown => panic!("Invalid destination"),
         }
     }
 
     async fn new_file(file_name: String) -> Self {
         let file = File::create(file_name).await.unwrap();
-        Destination::DiskFile(file)
+        Destination::DiskFile(file, 0)
+    }
+
+    fn written(&self) -> u64 {
+        match self {
+            Self::DiskFile(_, written) => *written,
+            _ => 0,
+        }
     }
 }
 
@@ -499,4 +542,45 @@ mod test1 {
 
         assert_eq!(res, ReadResult::End);
     }
+
+    fn upload_request() -> Request<Body> {
+        let body = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"memo_group_id\"\r\n\r\n\
+            -1\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"myFile\"; filename=\"payload.md\"\r\n\
+            Content-Type: text/markdown\r\n\r\n\
+            This is the payload file\r\n\
+            --XyZ--\r\n";
+        Request::builder()
+            .header("content-type", "multipart/form-data; boundary=XyZ")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handle_multipart_within_limit() {
+        let fields = handle_multipart(upload_request(), "/tmp", 1024)
+            .await
+            .unwrap();
+        let file_name = fields
+            .iter()
+            .find_map(|f| match f {
+                Field::File(file) => Some(file.file_name.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let path = format!("/tmp/{file_name}");
+        assert_eq!(
+            tokio::fs::read(&path).await.unwrap(),
+            b"This is the payload file"
+        );
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_multipart_too_large() {
+        let result = handle_multipart(upload_request(), "/tmp", 10).await;
+        assert!(matches!(result, Err(MultipartError::TooLarge(10))));
+    }
 }
@@ -1,4 +1,9 @@
+use crate::authentication::api_token::ApiTokenList;
+use crate::authentication::client_certificate::CertificateList;
+use crate::authentication::revocation::RevocationList;
 use crate::settings::PostgresConfig;
+use std::sync::Arc;
+use std::time::Duration;
 use tower::ServiceBuilder;
 use tracing::info;
 
@@ -12,6 +17,10 @@ mod submodule {
     pub async fn add_database<L>(
         service_builder: ServiceBuilder<L>,
         _: PostgresConfig,
+        _: Arc<RevocationList>,
+        _: Arc<ApiTokenList>,
+        _: Arc<CertificateList>,
+        _: Duration,
     ) -> ServiceBuilder<L> {
         info!("No database support");
         service_builder
@@ -22,6 +31,7 @@ mod submodule {
 mod submodule {
 
     use super::*;
+    use crate::authentication::{api_token, client_certificate, revocation};
     use crate::typedef::GenericError;
     use deadpool_postgres::Client;
     use deadpool_postgres::{Config, ManagerConfig, Pool, RecyclingMethod, Runtime};
@@ -33,9 +43,29 @@ mod submodule {
     pub async fn add_database<L>(
         service_builder: ServiceBuilder<L>,
         postgres: PostgresConfig,
+        revocation_list: Arc<RevocationList>,
+        api_tokens: Arc<ApiTokenList>,
+        certificates: Arc<CertificateList>,
+        revocation_refresh: Duration,
     ) -> ServiceBuilder<Stack<AddExtensionLayer<Pool>, L>> {
         info!("Database support enabled");
-        service_builder.layer(AddExtensionLayer::new(make_database_pool(postgres).await))
+        let pool = make_database_pool(postgres).await;
+        tokio::spawn(revocation::refresh_periodically(
+            pool.clone(),
+            revocation_list,
+            revocation_refresh,
+        ));
+        tokio::spawn(api_token::refresh_periodically(
+            pool.clone(),
+            api_tokens,
+            revocation_refresh,
+        ));
+        tokio::spawn(client_certificate::refresh_periodically(
+            pool.clone(),
+            certificates,
+            revocation_refresh,
+        ));
+        service_builder.layer(AddExtensionLayer::new(pool))
     }
 
     async fn make_database_pool(postgres: PostgresConfig) -> Pool {
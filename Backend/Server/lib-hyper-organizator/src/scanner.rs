@@ -0,0 +1,248 @@
+//! Scan uploaded files for malware before they are made available.
+//!
+//! The files are streamed to a clamd compatible daemon with the `INSTREAM` command, over a
+//! Unix socket or a local TCP port. Infected files are moved to a quarantine directory.
+//!
+//! [`FakeScanner`] flags files containing a given byte sequence, for tests that need a
+//! scanner without running clamd.
+pub use crate::settings::ScannerConfig;
+use crate::typedef::GenericError;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tracing::{debug, info, warn};
+
+/// The standard antivirus test file, in two pieces so this source file is not flagged
+pub const EICAR: &[u8] = concat!(
+    r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR",
+    r"-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*"
+)
+.as_bytes();
+
+#[derive(Debug, PartialEq)]
+pub enum ScanResult {
+    Clean,
+    /// name of the signature that matched
+    Infected(String),
+}
+
+pub trait Scanner {
+    fn scan(&self, path: &Path) -> impl Future<Output = Result<ScanResult, GenericError>> + Send;
+}
+
+/// Talks to clamd
+pub struct ClamdScanner {
+    address: String,
+    timeout: Duration,
+    chunk_size: usize,
+}
+
+impl ClamdScanner {
+    pub fn new(address: &str, timeout: Duration, chunk_size: usize) -> Self {
+        ClamdScanner {
+            address: address.to_string(),
+            timeout,
+            chunk_size,
+        }
+    }
+
+    pub fn from_config(config: &ScannerConfig) -> Self {
+        ClamdScanner::new(
+            &config.address,
+            Duration::from_secs(config.timeout),
+            config.chunk_size,
+        )
+    }
+
+    async fn scan_file(&self, path: &Path) -> Result<ScanResult, GenericError> {
+        let file = File::open(path).await?;
+        // anything that is not a path is taken for host:port
+        if self.address.starts_with('/') {
+            let stream = UnixStream::connect(&self.address).await?;
+            instream(stream, file, self.chunk_size).await
+        } else {
+            let stream = TcpStream::connect(&self.address).await?;
+            instream(stream, file, self.chunk_size).await
+        }
+    }
+}
+
+impl Scanner for ClamdScanner {
+    async fn scan(&self, path: &Path) -> Result<ScanResult, GenericError> {
+        debug!("Scanning {:?} with clamd at {}", path, self.address);
+        match tokio::time::timeout(self.timeout, self.scan_file(path)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("No answer from the scanner in {:?}", self.timeout).into()),
+        }
+    }
+}
+
+/// Send the file as length prefixed chunks, a zero length chunk ends it
+async fn instream<S, R>(
+    mut stream: S,
+    mut file: R,
+    chunk_size: usize,
+) -> Result<ScanResult, GenericError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut buffer = vec![0; chunk_size];
+    loop {
+        let count = file.read(&mut buffer).await?;
+        stream.write_all(&(count as u32).to_be_bytes()).await?;
+        if count == 0 {
+            break;
+        }
+        stream.write_all(&buffer[..count]).await?;
+    }
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    parse_reply(String::from_utf8_lossy(&reply).trim_end_matches(['\0', '\n']))
+}
+
+/// `stream: OK` or `stream: <signature> FOUND`, anything else is an error
+fn parse_reply(reply: &str) -> Result<ScanResult, GenericError> {
+    let verdict = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if verdict == "OK" {
+        Ok(ScanResult::Clean)
+    } else if let Some(signature) = verdict.strip_suffix(" FOUND") {
+        Ok(ScanResult::Infected(signature.to_string()))
+    } else {
+        Err(format!("Scanner error: {reply}").into())
+    }
+}
+
+/// Flags the files containing a byte sequence
+pub struct FakeScanner {
+    signature: Vec<u8>,
+}
+
+impl FakeScanner {
+    pub fn new(signature: &[u8]) -> Self {
+        FakeScanner {
+            signature: signature.to_vec(),
+        }
+    }
+}
+
+impl Default for FakeScanner {
+    fn default() -> Self {
+        FakeScanner::new(EICAR)
+    }
+}
+
+impl Scanner for FakeScanner {
+    async fn scan(&self, path: &Path) -> Result<ScanResult, GenericError> {
+        let content = tokio::fs::read(path).await?;
+        if content
+            .windows(self.signature.len())
+            .any(|window| window == self.signature)
+        {
+            Ok(ScanResult::Infected("Fake-Signature".to_string()))
+        } else {
+            Ok(ScanResult::Clean)
+        }
+    }
+}
+
+/// Move an infected file out of the storage directory, returns where it ended up
+pub async fn quarantine(path: &Path, quarantine_dir: &Path) -> Result<PathBuf, GenericError> {
+    tokio::fs::create_dir_all(quarantine_dir).await?;
+    let destination = quarantine_dir.join(path.file_name().ok_or("Not a file")?);
+    if let Err(e) = tokio::fs::rename(path, &destination).await {
+        // rename does not work across file systems
+        warn!("Could not move {:?} to quarantine: {e}, copying it", path);
+        tokio::fs::copy(path, &destination).await?;
+        tokio::fs::remove_file(path).await?;
+    }
+    info!("Quarantined {:?} in {:?}", path, destination);
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("stream: OK").unwrap(), ScanResult::Clean);
+        assert_eq!(
+            parse_reply("stream: Eicar-Signature FOUND").unwrap(),
+            ScanResult::Infected("Eicar-Signature".to_string())
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+
+    /// Answers like clamd, reassembling the chunks
+    async fn fake_clamd(listener: UnixListener) -> Vec<u8> {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut command = [0; 10];
+        socket.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+        let mut content = Vec::new();
+        loop {
+            let length = socket.read_u32().await.unwrap() as usize;
+            if length == 0 {
+                break;
+            }
+            let mut chunk = vec![0; length];
+            socket.read_exact(&mut chunk).await.unwrap();
+            content.extend_from_slice(&chunk);
+        }
+        let reply: &[u8] = if content.windows(EICAR.len()).any(|w| w == EICAR) {
+            b"stream: Eicar-Signature FOUND\0"
+        } else {
+            b"stream: OK\0"
+        };
+        socket.write_all(reply).await.unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn test_clamd_instream() -> Result<(), GenericError> {
+        let dir = std::env::temp_dir().join(format!("scanner_test_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let socket = dir.join("clamd.sock");
+        let _ = tokio::fs::remove_file(&socket).await;
+        let file = dir.join("upload.txt");
+        let mut content = b"some text before ".repeat(10);
+        content.extend_from_slice(EICAR);
+        tokio::fs::write(&file, &content).await?;
+
+        let server = tokio::spawn(fake_clamd(UnixListener::bind(&socket)?));
+        let scanner = ClamdScanner::new(socket.to_str().unwrap(), Duration::from_secs(5), 16);
+        assert_eq!(
+            scanner.scan(&file).await?,
+            ScanResult::Infected("Eicar-Signature".to_string())
+        );
+        assert_eq!(server.await?, content);
+
+        let quarantined = quarantine(&file, &dir.join("quarantine")).await?;
+        assert!(!file.exists());
+        assert!(quarantined.exists());
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fake_scanner() -> Result<(), GenericError> {
+        let file = std::env::temp_dir().join(format!("fake_scanner_{}.txt", std::process::id()));
+        tokio::fs::write(&file, b"harmless").await?;
+        assert_eq!(FakeScanner::default().scan(&file).await?, ScanResult::Clean);
+        tokio::fs::write(&file, EICAR).await?;
+        assert!(matches!(
+            FakeScanner::default().scan(&file).await?,
+            ScanResult::Infected(_)
+        ));
+        tokio::fs::remove_file(&file).await?;
+        Ok(())
+    }
+}
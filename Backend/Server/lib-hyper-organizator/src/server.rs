@@ -2,22 +2,32 @@ use crate::{
     logging::logging_trace_span::TraceRequestMakeSpan, settings::Settings, swagger::add_swagger,
 };
 use http::{
-    Request, Response,
-    header::{AUTHORIZATION, HeaderName},
+    Extensions, HeaderMap, Request, Response, StatusCode, Version,
+    header::{ACCEPT_RANGES, AUTHORIZATION, HeaderName},
 };
+use hyper::server::conn::AddrStream;
+use hyper::service::make_service_fn;
 use hyper::{Body, Error, server::Server};
 use std::{
+    convert::Infallible,
     iter::once,
     sync::{Arc, LazyLock},
+    time::Duration,
 };
-use tower::{ServiceBuilder, make::Shared};
+use tower::ServiceBuilder;
 use tower_http::{
-    add_extension::AddExtensionLayer, compression::CompressionLayer,
-    propagate_header::PropagateHeaderLayer, sensitive_headers::SetSensitiveRequestHeadersLayer,
+    add_extension::{AddExtension, AddExtensionLayer},
+    compression::{CompressionLayer, DefaultPredicate, Predicate},
+    propagate_header::PropagateHeaderLayer,
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
     trace::TraceLayer,
 };
 
+use crate::authentication::api_token::ApiTokenList;
 use crate::authentication::authentication_layers::add_authorization;
+use crate::authentication::client_certificate::CertificateList;
+use crate::authentication::revocation::RevocationList;
+use crate::authentication::trusted_proxy::{RemotePeer, TrustedProxies};
 use crate::metrics::metrics_layer::MetricsLayer;
 use crate::metrics::numeric_request_id::NumericMakeRequestId;
 use crate::metrics::prometheus_metrics::PrometheusMetrics;
@@ -54,24 +64,61 @@ where
                 // no events handled in trace yet, e.g. on_request, on_response
                 .make_span_with(TraceRequestMakeSpan::new(tracing::Level::INFO)),
         )
-        // Compress responses
-        .layer(CompressionLayer::new())
+        // Compress responses, except files served in byte ranges
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(not_for_byte_ranges as fn(_, _, &_, &_) -> _),
+        ))
         // Propagate `X-Request-Id`s from requests to responses
         .layer(PropagateHeaderLayer::new(x_request_id));
 
     let service_builder = add_swagger(service_builder, &settings.swagger_path, swagger_json).await;
+    // Shared by the security layer, which checks them, and the database layer, which fills them
+    let revocation_list = Arc::new(RevocationList::default());
+    let api_tokens = Arc::new(ApiTokenList::default());
+    let certificates = Arc::new(CertificateList::default());
     // Add security if enabled
-    let service_builder = add_authorization(service_builder, settings.security.clone()).await;
+    let service_builder = add_authorization(
+        service_builder,
+        settings.security.clone(),
+        revocation_list.clone(),
+        api_tokens.clone(),
+        certificates.clone(),
+    )
+    .await;
     // Add a database pool if enabled
-    let service_builder = add_database(service_builder, settings.postgres.clone()).await;
+    let service_builder = add_database(
+        service_builder,
+        settings.postgres.clone(),
+        revocation_list,
+        api_tokens,
+        certificates,
+        Duration::from_secs(settings.security.revocation_refresh),
+    )
+    .await;
     // Wrap a `Service` in our middleware stack
     let service = service_builder.service_fn(f);
 
     // And run our service using `hyper`
     let api_ip = settings.api_ip();
     info!("start server on {}", &api_ip);
-    let main_server = Server::bind(&api_ip).serve(Shared::new(service));
+    // Every request knows where it came from, the headers of nginx count only from nginx
+    let proxies =
+        Arc::new(TrustedProxies::from_config(&settings.security).expect("invalid trusted proxies"));
+    let make_service = make_service_fn(move |connection: &AddrStream| {
+        let peer = RemotePeer {
+            addr: connection.remote_addr(),
+            proxies: proxies.clone(),
+        };
+        let service = AddExtension::new(service.clone(), peer);
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let main_server = Server::bind(&api_ip).serve(make_service);
     let metrics_server = crate::metrics::metrics_endpoint::start_metrics_server(metrics, settings);
     futures::try_join!(main_server, metrics_server).expect("server error");
     Ok(())
 }
+
+/// Byte ranges refer to the file as stored, compressing them would make the offsets meaningless
+fn not_for_byte_ranges(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+    !headers.contains_key(ACCEPT_RANGES)
+}
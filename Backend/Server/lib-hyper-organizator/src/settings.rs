@@ -1,5 +1,10 @@
 use std::net::SocketAddr;
 
+use crate::authentication::csrf::CsrfConfig;
+use crate::authentication::oidc::OidcConfig;
+use crate::authentication::password_hash::PasswordHashing;
+use crate::authentication::password_policy::PasswordPolicy;
+use crate::authentication::upstream_oidc::UpstreamOidcConfig;
 use log::trace;
 use serde::Deserialize;
 use tracing::{info, warn};
@@ -28,13 +33,84 @@ pub struct SecurityConfig {
     pub session_expiry_grace_period: u64,
     #[serde(rename = "ignore")]
     pub ignore_paths: Vec<String>,
-    /// Get the public key from here
+    /// Get the public key from here, a single key that is never refreshed
     pub public_key_url: Option<String>,
+    /// Get the verification keys from this JWKS document, refetched when a token is signed with
+    /// a key it does not list
+    pub jwks_url: Option<String>,
+    /// The `aud` of the tokens for the organizator services, tokens issued to other
+    /// applications are refused
+    pub audience: String,
+    /// Name of the secret with the signing keys, one base64 PKCS#8 document per line.
+    /// The first one signs, the others are only published for verification.
+    pub signing_keys_secret: String,
+    /// Seconds between reloads of the revoked tokens and the API tokens from the database
+    pub revocation_refresh: u64,
+    /// The number of seconds a session lasts after login, refreshing does not extend it.
+    pub refresh_token_expiry: u64,
+    pub login_limits: LoginLimits,
+    pub password_policy: PasswordPolicy,
+    pub password_hashing: PasswordHashing,
+    /// The identity service as OpenID Connect provider for other applications
+    pub oidc: OidcConfig,
+    /// Login through the OpenID Connect provider of the organization
+    pub upstream_oidc: UpstreamOidcConfig,
+    /// Networks, as CIDR, whose client certificate and client address headers are believed,
+    /// i.e. where nginx runs
+    pub trusted_proxies: Vec<String>,
+    /// Name of the secret nginx signs the client certificate headers with, not checked if
+    /// missing
+    pub proxy_signature_secret: Option<String>,
+    /// Checks of the origin of the requests authenticated by cookie or certificate
+    pub csrf: CsrfConfig,
 }
 
+/// Brute force protection of the login
 #[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoginLimits {
+    /// Seconds the attempts are counted over
+    pub window: u64,
+    /// Attempts allowed for a username in the window
+    pub per_username: usize,
+    /// Attempts allowed from an address in the window
+    pub per_ip: usize,
+    /// Consecutive failures that lock the account
+    pub lockout_threshold: i32,
+    /// Seconds the account stays locked
+    pub lockout_duration: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct FileStorage {
     pub path: String,
+    /// Widths of the thumbnails generated for uploaded images
+    pub thumbnail_widths: Vec<u32>,
+    /// Remove the EXIF metadata, location included, from uploaded JPEG photos
+    pub strip_exif: bool,
+    /// Bytes a user may have in uploaded files, no limit when missing
+    pub user_quota: Option<u64>,
+    /// Bytes that may be uploaded into a memo group, no limit when missing
+    pub memo_group_quota: Option<u64>,
+    /// Uploads are scanned for malware when present
+    pub scanner: Option<ScannerConfig>,
+}
+
+/// A clamd compatible scanner
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ScannerConfig {
+    /// Unix socket path, or host:port for TCP
+    pub address: String,
+    /// Infected files are moved here
+    pub quarantine_path: String,
+    /// Seconds to wait for the verdict
+    pub timeout: u64,
+    /// Bytes sent to the scanner at once
+    pub chunk_size: usize,
+    /// Refuse uploads the scanner could not check, they are accepted unscanned otherwise
+    pub fail_closed: bool,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -85,9 +161,7 @@ impl Default for Settings {
             metrics_ip: "127.0.0.1:3001".to_string(),
             postgres: PostgresConfig::default(),
             security: SecurityConfig::default(),
-            file_storage: FileStorage {
-                path: "/tmp".to_string(),
-            },
+            file_storage: FileStorage::default(),
             swagger_path: "/swagger-ui".to_string(),
         }
     }
@@ -111,6 +185,31 @@ impl Default for PostgresConfig {
     }
 }
 
+impl Default for FileStorage {
+    fn default() -> Self {
+        FileStorage {
+            path: "/tmp".to_string(),
+            thumbnail_widths: vec![200, 800],
+            strip_exif: false,
+            user_quota: None,
+            memo_group_quota: None,
+            scanner: None,
+        }
+    }
+}
+
+impl Default for ScannerConfig {
+    fn default() -> Self {
+        ScannerConfig {
+            address: "/run/clamav/clamd.ctl".to_string(),
+            quarantine_path: "/var/lib/organizator/quarantine".to_string(),
+            timeout: 60,
+            chunk_size: 64 * 1024,
+            fail_closed: false,
+        }
+    }
+}
+
 impl Default for SecurityConfig {
     fn default() -> Self {
         SecurityConfig {
@@ -118,33 +217,33 @@ impl Default for SecurityConfig {
             session_expiry_grace_period: 300,
             ignore_paths: vec![],
             public_key_url: None,
+            jwks_url: None,
+            audience: "organizator".to_string(),
+            signing_keys_secret: "jwt_signing_keys".to_string(),
+            revocation_refresh: 60,
+            refresh_token_expiry: 30 * 24 * 3600,
+            login_limits: LoginLimits::default(),
+            password_policy: PasswordPolicy::default(),
+            password_hashing: PasswordHashing::default(),
+            oidc: OidcConfig::default(),
+            upstream_oidc: UpstreamOidcConfig::default(),
+            // nginx on the same host, anywhere else it has to be configured
+            trusted_proxies: ["127.0.0.0/8", "::1"].map(String::from).to_vec(),
+            proxy_signature_secret: None,
+            csrf: CsrfConfig::default(),
         }
     }
 }
 
-// test module
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use indoc::indoc;
-
-    #[test]
-    fn test_read_config() {
-        let config = read_config();
-        assert_eq!(config.api_ip, "127.0.0.1:3000");
-    }
-
-    #[test]
-    fn test_parse_config() {
-        let config = parse_config(indoc! {r#"
-            [postgres]
-            user = "user"
-            password = "password"
-            host = "host"
-            port = 5432
-            dbname = "db"
-        "#});
-        assert_eq!(config.postgres.user, "user");
+impl Default for LoginLimits {
+    fn default() -> Self {
+        LoginLimits {
+            window: 300,
+            per_username: 5,
+            per_ip: 20,
+            lockout_threshold: 10,
+            lockout_duration: 900,
+        }
     }
 }
 
@@ -152,7 +251,7 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-fn get_secret(secret_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+pub(crate) fn get_secret(secret_name: &str) -> Result<String, Box<dyn std::error::Error>> {
     // 1. Priority: Manual override for local development
     if let Ok(override_path) = env::var("SECRET_OVERRIDE_PATH") {
         let path = Path::new(&override_path).join(secret_name);
@@ -172,6 +271,20 @@ fn get_secret(secret_name: &str) -> Result<String, Box<dyn std::error::Error>> {
     Err("Secret not found in override or systemd credentials directory".into())
 }
 
+/// Store a secret generated by the application.
+/// Only the override directory is written to, the systemd credentials are read only.
+pub(crate) fn save_secret(
+    secret_name: &str,
+    content: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let override_path = env::var("SECRET_OVERRIDE_PATH")
+        .map_err(|_| "SECRET_OVERRIDE_PATH not set, nowhere to save the secret")?;
+    let path = Path::new(&override_path).join(secret_name);
+    fs::write(&path, content)?;
+    log::info!("Secret saved to : {:?}", path);
+    Ok(path)
+}
+
 fn get_config_content() -> Option<String> {
     let file_name = "settings.toml";
     let app_name = get_app_name().to_lowercase().replace(" ", "_");
@@ -208,3 +321,60 @@ fn get_app_name() -> String {
     env::var("APP_NAME")
         .unwrap_or_else(|_| exe.unwrap_or_else(|| "organizator_unknown_app".to_string()))
 }
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_read_config() {
+        let config = read_config();
+        assert_eq!(config.api_ip, "127.0.0.1:3000");
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(indoc! {r#"
+            [postgres]
+            user = "user"
+            password = "password"
+            host = "host"
+            port = 5432
+            dbname = "db"
+        "#});
+        assert_eq!(config.postgres.user, "user");
+    }
+
+    #[test]
+    fn test_parse_scanner_config() {
+        let config = parse_config(indoc! {r#"
+            [file_storage]
+            path = "/files"
+            [file_storage.scanner]
+            address = "127.0.0.1:3310"
+        "#});
+        let scanner = config.file_storage.scanner.unwrap();
+        assert_eq!(scanner.address, "127.0.0.1:3310");
+        assert_eq!(scanner.timeout, 60);
+        assert!(parse_config("").file_storage.scanner.is_none());
+    }
+
+    #[test]
+    fn test_parse_oidc_config() {
+        let config = parse_config(indoc! {r#"
+            [security.oidc]
+            issuer = "https://organizator.ro/organizator/oidc"
+            [[security.oidc.clients]]
+            client_id = "wiki"
+            redirect_uris = ["https://wiki.organizator.ro/callback"]
+        "#});
+        let oidc = config.security.oidc;
+        assert!(oidc.is_enabled());
+        assert_eq!(oidc.login_url, "/login.html?r=");
+        let client = oidc.client("wiki").unwrap();
+        assert!(client.secret_name.is_none());
+        assert!(client.allows_redirect("https://wiki.organizator.ro/callback"));
+    }
+}
@@ -0,0 +1,17 @@
+-- each event as a line of JSON, the oldest first
+SELECT
+  json_build_object(
+    'id', id,
+    'occurred_on', occurred_on,
+    'event_type', event_type,
+    'username', username,
+    'actor', actor,
+    'ip', ip,
+    'details', details
+  )::text AS event
+FROM audit_log
+WHERE ($1::text IS NULL OR username = $1 OR actor = $1)
+  AND ($2::text IS NULL OR event_type = $2)
+  AND occurred_on >= $3
+ORDER BY id
+LIMIT $4
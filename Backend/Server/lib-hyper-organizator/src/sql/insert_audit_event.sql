@@ -0,0 +1,2 @@
+INSERT INTO audit_log (occurred_on, event_type, username, actor, ip, details)
+VALUES ($1, $2, $3, $4, $5, CAST($6::text AS jsonb))
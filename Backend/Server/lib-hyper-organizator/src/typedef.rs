@@ -23,6 +23,12 @@ impl Display for UserId {
 
 pub struct UserRoles(pub Vec<String>);
 
+/// The session the access token belongs to, set when the token has one
+pub struct SessionId(pub String);
+
+/// Set when the request was authorized by an API token, it may do only this
+pub struct ApiTokenScopes(pub Vec<String>);
+
 pub struct SQLstr<'a>(pub &'a str);
 impl<'a> Deref for SQLstr<'a> {
     type Target = str;
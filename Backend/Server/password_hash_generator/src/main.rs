@@ -1,26 +1,80 @@
-use std::process;
+use std::io::{self, BufRead, Write};
+use std::{env, fs, process};
 
-use argon2::{
-    Argon2, PasswordHasher as _,
-    password_hash::{SaltString, rand_core::OsRng},
-};
+use lib_hyper_organizator::authentication::password_hash::PasswordHashing;
+use lib_hyper_organizator::authentication::password_policy::PasswordPolicy;
+use lib_hyper_organizator::typedef::GenericError;
+use serde::Deserialize;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// The part of the identity settings the generator cares about
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    security: Security,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Security {
+    password_policy: PasswordPolicy,
+    password_hashing: PasswordHashing,
+}
+
+/// The policy from the settings file given as argument, the default one otherwise
+fn read_config() -> Result<Config, GenericError> {
+    match env::args().nth(1) {
+        Some(path) => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+        None => Ok(Config::default()),
+    }
+}
+
+/// The text as an SQL string literal, its quotes doubled
+fn sql_literal(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn main() -> Result<(), GenericError> {
     println!("Generate password hash");
+    let Security {
+        password_policy: policy,
+        password_hashing: hashing,
+    } = read_config()?.security;
+    print!("Username: ");
+    io::stdout().flush()?;
+    let mut username = String::new();
+    io::stdin().lock().read_line(&mut username)?;
+    let username = username.trim();
     let password = rpassword::prompt_password("Your password: ").unwrap();
     let password_repeat = rpassword::prompt_password("Repeat password: ").unwrap();
     if password != password_repeat {
         eprintln!("The two passwords are not the same.");
         process::exit(1);
     }
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(password.as_bytes(), &salt)?
-        .to_string();
+    // the previous passwords are in the database, reuse is checked only by identity
+    let violations = policy.check(&password, &[username]);
+    if !violations.is_empty() {
+        for violation in violations {
+            eprintln!("{violation}");
+        }
+        process::exit(1);
+    }
+    let password_hash = hashing.hash(&password)?;
     println!("Password hash: {password_hash}\n");
     println!(
-        "UPDATE users SET password_hash='{password_hash}' where username = 'user';"
+        "UPDATE users SET password_hash='{password_hash}' where username = {};",
+        sql_literal(username)
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sql_literal() {
+        assert_eq!(sql_literal("jane"), "'jane'");
+        assert_eq!(sql_literal("o'hara"), "'o''hara'");
+        assert_eq!(sql_literal("x'; DROP TABLE users; --"), "'x''; DROP TABLE users; --'");
+    }
+}
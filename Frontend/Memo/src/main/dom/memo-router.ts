@@ -177,8 +177,7 @@ async function loadMemo() {
     options
   );
   if (response.status === 401) {
-    // more info at https://www.w3schools.com/howto/howto_js_redirect_webpage.asp
-    window.location.replace(`/login.html?r=${encodeURIComponent(window.location.href)}`);
+    await go_to_login();
     return;
   } else if (response.status === 200) {
     const json = await response.json();
@@ -201,6 +200,21 @@ console.log(text);
 */
 }
 
+/**
+ * The access token is gone, try the refresh token before asking for the password
+ */
+async function go_to_login() {
+  // a token refreshed moments ago and refused already will not get better
+  const last_refresh = Number(sessionStorage.getItem("last_refresh") ?? 0);
+  if (Date.now() - last_refresh > 10_000 && await server_comm.refresh_session()) {
+    sessionStorage.setItem("last_refresh", `${Date.now()}`);
+    window.location.reload();
+    return;
+  }
+  // more info at https://www.w3schools.com/howto/howto_js_redirect_webpage.asp
+  window.location.replace(`/login.html?r=${encodeURIComponent(window.location.href)}`);
+}
+
 async function display_synthetic_memo(id: string) {
   set_status_in_editor(`# Loading...`);
   set_status_in_editor(await create_synthetic_memo(id) ?? "# No synthetic content created");
@@ -235,8 +249,7 @@ async function loadMemoTitles(force_reload?: boolean) {
       options
     );
     if (response.status === 401) {
-      // more info at https://www.w3schools.com/howto/howto_js_redirect_webpage.asp
-      window.location.replace(`/login.html?r=${encodeURIComponent(window.location.href)}`);
+      await go_to_login();
       return;
     } else if (response.status === 200) {
       const responseJson: MemoTitleListDTO = await response.json();
@@ -364,8 +377,7 @@ export async function searchMemos() {
     }
   );
   if (response.status === 401) {
-    // more info at https://www.w3schools.com/howto/howto_js_redirect_webpage.asp
-    window.location.replace(`/login.html?r=${encodeURIComponent(window.location.href)}`);
+    await go_to_login();
     return;
   } else if (response.status === 200) {
     const responseJson: MemoTitleListDTO = await response.json();
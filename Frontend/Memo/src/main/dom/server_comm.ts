@@ -66,6 +66,7 @@ export const save_to_server = async (memo: Memo): Promise<ServerMemoReply> => {
     method: "POST",
     mode: "cors",
   });
+  refresh_token_if_expiring(response);
 
   switch (response.status) {
     case 200: // OK
@@ -172,12 +173,30 @@ const get_options: RequestInit = {
   mode: "cors",
 };
 
+// trade the refresh cookie for new cookies, false if the session is over
+export const refresh_session = async (): Promise<boolean> => {
+  try {
+    const server_response = await fetch("/organizator/refresh", get_options);
+    if (server_response.status === 204) return true;
+    konsole.error("Failed to refresh the token, server status", server_response.status);
+  } catch (e) {
+    konsole.error("Failed to refresh the token, error", e);
+  }
+  return false;
+};
+
+// the services flag a token in its grace period
+const refresh_token_if_expiring = (server_response: Response) => {
+  if (server_response.headers.has("x-organizator-token-expires")) refresh_session();
+};
+
 export const read_memo = async (id: number): Promise<ServerMemoReply> => {
   konsole.log(`Fetching from server, memo`, id);
   const server_response = await fetch(
     `/organizator/memo/${id}?request.preventCache=${+new Date()}`,
     get_options
   );
+  refresh_token_if_expiring(server_response);
   if (server_response.status === 200) {
     const json: ServerMemoReply = await server_response.json();
     if (!json.memo) {
@@ -257,7 +276,9 @@ export const upload_file = async (file_input: HTMLInputElement, memogroup_id: st
   formData.append('myFile', file);
   formData.append('end_parameter', '2');
 
-  const server_response = await fetch('/organizator/upload', {
+  // in the query too, so the server checks the quota of the memo group while receiving the file
+  const query = memogroup_id ? `?memo_group_id=${encodeURIComponent(memogroup_id)}` : '';
+  const server_response = await fetch(`/organizator/upload${query}`, {
     method: 'PUT',
     body: formData
   });
@@ -286,6 +307,7 @@ const get_generic = async <T>(url: string, context: string): Promise<T> => {
       `${url}?request.preventCache=${+new Date()}`,
       get_options
     );
+    refresh_token_if_expiring(server_response);
     if (server_response.status === 200) {
       const json: T = await server_response.json();
       konsole.log(`Fetched ${context} from server`, json);
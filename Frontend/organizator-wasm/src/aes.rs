@@ -210,7 +210,7 @@ mod tests_aes_add_round_key {
 }
 
 
-fn aes_key_expansion(key: &[u8; 32]) -> [[u8; 4]; 60] {  // generate Key Schedule (byte-array Nr+1 x Nb) from Key [§5.2]
+pub(crate) fn aes_key_expansion(key: &[u8; 32]) -> [[u8; 4]; 60] {  // generate Key Schedule (byte-array Nr+1 x Nb) from Key [§5.2]
     //4 int Nb = 4;            // block size (in words): no of columns in state (fixed at 4 for AES)
     let key_length_words = key.len() / 4;  // key length (in words): 4/6/8 for 128/192/256-bit keys
     let _rounds = key_length_words + 6;       // no of rounds: 10/12/14 for 128/192/256-bit keys
@@ -333,7 +333,7 @@ const ROUNDS: usize = 14;
  * @param w   Key schedule as 2D byte-array (Nr+1 x Nb bytes) 11 x 4
  * @return Encrypted output state array
  */
-fn aes_cipher(input: &[u8], w: &[[u8; 4]; 60]) -> [u8; 16] {    // main Cipher function [§5.1]
+pub(crate) fn aes_cipher(input: &[u8], w: &[[u8; 4]; 60]) -> [u8; 16] {    // main Cipher function [§5.1]
     // initialise 4xNb byte-array 'state' with input [§3.4]
     let mut state: [[u8; 4]; 4] = [
         [input[0], input[4], input[8], input[12]],
@@ -365,9 +365,28 @@ fn aes_cipher(input: &[u8], w: &[[u8; 4]; 60]) -> [u8; 16] {    // main Cipher f
 }
 
 // number of bytes in the key, 256 max for AES = 32 bytes
-const KEY_BYTES: usize = 32;
+pub(crate) const KEY_BYTES: usize = 32;
 // block size fixed at 16 bytes / 128 bits (Nb=4) for AES
-const BLOCK_SIZE: usize = 16;
+pub(crate) const BLOCK_SIZE: usize = 16;
+
+/// Derive the cipher key from the password
+///
+/// Uses AES itself to encrypt the password to get the cipher key (using the plain password as
+/// source for key expansion), this gives us a well encrypted key.
+pub(crate) fn password_key(password: &str) -> [u8; KEY_BYTES] {
+    let mut password_bytes:[u8; KEY_BYTES] = [0; KEY_BYTES];
+    // copy as much from the password as possible, longer passwords will not be fully used
+    let min_size = std::cmp::min(password.len(), KEY_BYTES);
+    password_bytes[..min_size].copy_from_slice(&password.as_bytes()[..min_size]);
+
+    let key16 = aes_cipher(&password_bytes[..16], &aes_key_expansion(&password_bytes));  // gives us 16-byte key
+    let mut key = [0; KEY_BYTES];
+
+    // expand key to 16/24/32 bytes long
+    key[..16].copy_from_slice(&key16);
+    key[16..].copy_from_slice(&key16);
+    key
+}
 
 ///
 /// Encrypt a text using AES encryption in Counter mode of operation
@@ -391,19 +410,7 @@ pub fn aes_ctr_encrypt(plain_text: &str, password: &str, nonce: u64) -> String {
     // let blockSize = 16;  
     //if (!(nBits==128 || nBits==192 || nBits==256)) return "";  // standard allows 128/192/256 bit keys
 
-    // use AES itself to encrypt password to get cipher key (using plain password as source for key
-    // expansion) - gives us well encrypted key
-    let mut password_bytes:[u8; KEY_BYTES] = [0; KEY_BYTES];
-    // copy as much from the password as possible, longer passwords will not be fully used
-    let min_size = std::cmp::min(password.len(), KEY_BYTES);
-    password_bytes[..min_size].copy_from_slice(&password.as_bytes()[..min_size]);
-    
-    let key16 = aes_cipher(&password_bytes[..16], &aes_key_expansion(&password_bytes));  // gives us 16-byte key
-    let mut key = [0; KEY_BYTES];
-    
-    // expand key to 16/24/32 bytes long
-    key[..16].copy_from_slice(&key16);
-    key[16..].copy_from_slice(&key16);
+    let key = password_key(password);
 
     // initialise counter block (NIST SP800-38A §B.2): millisecond time-stamp for nonce in 1st 8 bytes,
     // block counter in 2nd 8 bytes
@@ -501,17 +508,7 @@ pub fn aes_ctr_decrypt(original_ciphertext: &str, password: &str) -> String {
     let mut ciphertext = BASE64_STANDARD.decode(original_ciphertext).unwrap();
 
     // use AES to encrypt password (mirroring encrypt routine)
-    let mut password_bytes:[u8; KEY_BYTES] = [0; KEY_BYTES];
-    // copy as much from the password as possible, longer passwords will not be fully used
-    let min_size = std::cmp::min(password.len(), KEY_BYTES);
-    password_bytes[..min_size].copy_from_slice(&password.as_bytes()[..min_size]);
-    
-    let key16 = aes_cipher(&password_bytes[..16], &aes_key_expansion(&password_bytes));  // gives us 16-byte key
-    let mut key = [0; KEY_BYTES];
-    
-    // expand key to 16/24/32 bytes long
-    key[..16].copy_from_slice(&key16);
-    key[16..].copy_from_slice(&key16);
+    let key = password_key(password);
 
     // recover nonce from 1st 8 bytes of ciphertext
     let mut counter_block:[u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
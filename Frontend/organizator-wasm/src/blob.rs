@@ -0,0 +1,366 @@
+//! Encryption of binary attachments, using the same key as the memo text.
+//!
+//! The key is derived from the password exactly like for [`crate::memo::memo_encrypt`]
+//! and the data is encrypted with AES-256 in counter mode, with the same counter
+//! block layout. Unlike memo text, attachments are authenticated: the data is
+//! split in chunks and every chunk carries an AES-CMAC tag, so a chunk that was
+//! altered, reordered or dropped is detected when decrypting.
+//!
+//! ### Format
+//! The header is `ORGB`, a version byte, the chunk size (u32 little endian) and
+//! the nonce (u64 little endian).\
+//! Each chunk is the ciphertext followed by a 16 byte tag. All chunks but the
+//! last have exactly chunk size bytes of ciphertext.\
+//! The tag covers the header, the chunk index, a flag marking the last chunk and
+//! the ciphertext.
+//!
+//! ### Nonce
+//! As for memos, the nonce must not be reused with the same password, a
+//! millisecond timestamp is fine. The block counters of attachments have the top
+//! bit set, they never overlap the counters used for memo text.
+//!
+//! ```rust
+//! # use organizator_wasm::blob::{blob_decrypt, blob_encrypt};
+//! let encrypted = blob_encrypt(b"\x89PNG picture", "secret", 1_700_000_000_000);
+//! assert_eq!(blob_decrypt(&encrypted, "secret").unwrap(), b"\x89PNG picture");
+//! assert!(blob_decrypt(&encrypted, "wrong").is_err());
+//! ```
+
+use crate::aes::{aes_cipher, aes_key_expansion, password_key, BLOCK_SIZE, KEY_BYTES};
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"ORGB";
+const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 17;
+pub const TAG_SIZE: usize = 16;
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+// encrypted to get the authentication key, as counter blocks they would need a counter of 2^62
+const MAC_KEY_LABELS: [&[u8; BLOCK_SIZE]; 2] = [b"organizator mac1", b"organizator mac2"];
+// keeps the attachment keystream apart from the memo keystream
+const BLOB_COUNTER_BIT: u64 = 1 << 63;
+
+#[derive(Debug, PartialEq)]
+pub enum BlobError {
+    InvalidHeader,
+    /// the chunk size has to be a positive multiple of the AES block size
+    InvalidChunkSize,
+    /// a chunk that is not the last one has to be exactly chunk size long
+    ChunkLength,
+    /// wrong password, or the data was tampered with
+    Authentication,
+    /// the last chunk has already been processed
+    Finished,
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BlobError::InvalidHeader => "Not an encrypted attachment",
+            BlobError::InvalidChunkSize => "Invalid chunk size",
+            BlobError::ChunkLength => "Chunk has the wrong length",
+            BlobError::Authentication => "Wrong password or damaged attachment",
+            BlobError::Finished => "Attachment already complete",
+        })
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+/// AES-CMAC as in NIST SP 800-38B
+struct Cmac {
+    key_schedule: [[u8; 4]; 60],
+    k1:           [u8; BLOCK_SIZE],
+    k2:           [u8; BLOCK_SIZE],
+}
+
+/// Multiply by x in GF(2^128)
+fn double(block: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let mut result = [0; BLOCK_SIZE];
+    let mut carry = 0;
+    for i in (0..BLOCK_SIZE).rev() {
+        result[i] = (block[i] << 1) | carry;
+        carry = block[i] >> 7;
+    }
+    if block[0] & 0x80 != 0 {
+        result[BLOCK_SIZE - 1] ^= 0x87;
+    }
+    result
+}
+
+fn xor_into(target: &mut [u8], source: &[u8]) {
+    target.iter_mut().zip(source.iter()).for_each(|(x, y)| *x ^= *y);
+}
+
+impl Cmac {
+    fn new(key: &[u8; KEY_BYTES]) -> Cmac {
+        let key_schedule = aes_key_expansion(key);
+        let l = aes_cipher(&[0; BLOCK_SIZE], &key_schedule);
+        let k1 = double(&l);
+        let k2 = double(&k1);
+        Cmac { key_schedule, k1, k2 }
+    }
+
+    fn tag(&self, message: &[u8]) -> [u8; BLOCK_SIZE] {
+        let block_count = std::cmp::max(1, message.len().div_ceil(BLOCK_SIZE));
+        let mut state = [0; BLOCK_SIZE];
+        for block in message.chunks(BLOCK_SIZE).take(block_count - 1) {
+            xor_into(&mut state, block);
+            state = aes_cipher(&state, &self.key_schedule);
+        }
+
+        let last = &message[(block_count - 1) * BLOCK_SIZE..];
+        let mut last_block = [0; BLOCK_SIZE];
+        last_block[..last.len()].copy_from_slice(last);
+        if last.len() == BLOCK_SIZE {
+            xor_into(&mut last_block, &self.k1);
+        } else {
+            last_block[last.len()] = 0x80;
+            xor_into(&mut last_block, &self.k2);
+        }
+        xor_into(&mut state, &last_block);
+        aes_cipher(&state, &self.key_schedule)
+    }
+}
+
+/// Compare without leaking where the first difference is
+fn same_tag(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// State shared by encryption and decryption
+struct ChunkCipher {
+    header:       [u8; HEADER_SIZE],
+    chunk_size:   usize,
+    nonce:        [u8; 8],
+    key_schedule: [[u8; 4]; 60],
+    mac:          Cmac,
+    chunk_index:  u64,
+    finished:     bool,
+}
+
+impl ChunkCipher {
+    fn new(password: &str, nonce: u64, chunk_size: u32) -> Result<ChunkCipher, BlobError> {
+        if chunk_size == 0 || !(chunk_size as usize).is_multiple_of(BLOCK_SIZE) {
+            return Err(BlobError::InvalidChunkSize);
+        }
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = VERSION;
+        header[5..9].copy_from_slice(&chunk_size.to_le_bytes());
+        header[9..].copy_from_slice(&nonce.to_le_bytes());
+
+        let key_schedule = aes_key_expansion(&password_key(password));
+        let mut mac_key = [0; KEY_BYTES];
+        mac_key[..16].copy_from_slice(&aes_cipher(MAC_KEY_LABELS[0], &key_schedule));
+        mac_key[16..].copy_from_slice(&aes_cipher(MAC_KEY_LABELS[1], &key_schedule));
+
+        Ok(ChunkCipher {
+            header,
+            chunk_size: chunk_size as usize,
+            nonce: nonce.to_le_bytes(),
+            key_schedule,
+            mac: Cmac::new(&mac_key),
+            chunk_index: 0,
+            finished: false,
+        })
+    }
+
+    fn check_chunk(&self, length: usize, last: bool) -> Result<(), BlobError> {
+        if self.finished {
+            return Err(BlobError::Finished);
+        }
+        if length > self.chunk_size || (!last && length != self.chunk_size) {
+            return Err(BlobError::ChunkLength);
+        }
+        Ok(())
+    }
+
+    /// Counter mode, the counter block layout is the one used for memos
+    fn apply_keystream(&self, data: &mut [u8]) {
+        let first_block = self.chunk_index * (self.chunk_size / BLOCK_SIZE) as u64;
+        let mut counter_block = [0; BLOCK_SIZE];
+        counter_block[..8].copy_from_slice(&self.nonce);
+        for (b, block) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let counter = BLOB_COUNTER_BIT | (first_block + b as u64);
+            counter_block[8..].copy_from_slice(&counter.to_be_bytes());
+            xor_into(block, &aes_cipher(&counter_block, &self.key_schedule));
+        }
+    }
+
+    fn tag(&self, ciphertext: &[u8], last: bool) -> [u8; TAG_SIZE] {
+        let mut message = Vec::with_capacity(HEADER_SIZE + 9 + ciphertext.len());
+        message.extend_from_slice(&self.header);
+        message.extend_from_slice(&self.chunk_index.to_be_bytes());
+        message.push(last as u8);
+        message.extend_from_slice(ciphertext);
+        self.mac.tag(&message)
+    }
+
+    fn advance(&mut self, last: bool) {
+        self.chunk_index += 1;
+        self.finished = last;
+    }
+}
+
+/// Encrypts an attachment chunk by chunk, so large files don't have to be in memory at once
+pub struct BlobEncryptor(ChunkCipher);
+
+impl BlobEncryptor {
+    pub fn new(password: &str, nonce: u64, chunk_size: u32) -> Result<BlobEncryptor, BlobError> {
+        ChunkCipher::new(password, nonce, chunk_size).map(BlobEncryptor)
+    }
+
+    /// Goes in front of the first chunk
+    pub fn header(&self) -> Vec<u8> {
+        self.0.header.to_vec()
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.0.chunk_size
+    }
+
+    /// All the chunks but the last have to be exactly chunk size long
+    pub fn encrypt_chunk(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>, BlobError> {
+        self.0.check_chunk(data.len(), last)?;
+        let mut result = Vec::with_capacity(data.len() + TAG_SIZE);
+        result.extend_from_slice(data);
+        self.0.apply_keystream(&mut result);
+        let tag = self.0.tag(&result, last);
+        result.extend_from_slice(&tag);
+        self.0.advance(last);
+        Ok(result)
+    }
+}
+
+/// Decrypts an attachment chunk by chunk, every chunk is checked before it is returned
+pub struct BlobDecryptor(ChunkCipher);
+
+impl BlobDecryptor {
+    pub fn new(password: &str, header: &[u8]) -> Result<BlobDecryptor, BlobError> {
+        if header.len() < HEADER_SIZE || &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(BlobError::InvalidHeader);
+        }
+        let chunk_size = u32::from_le_bytes(header[5..9].try_into().unwrap());
+        let nonce = u64::from_le_bytes(header[9..HEADER_SIZE].try_into().unwrap());
+        ChunkCipher::new(password, nonce, chunk_size).map(BlobDecryptor)
+    }
+
+    /// Length of an encrypted chunk, tag included
+    pub fn encrypted_chunk_size(&self) -> usize {
+        self.0.chunk_size + TAG_SIZE
+    }
+
+    pub fn decrypt_chunk(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>, BlobError> {
+        if data.len() < TAG_SIZE {
+            return Err(BlobError::ChunkLength);
+        }
+        let (ciphertext, tag) = data.split_at(data.len() - TAG_SIZE);
+        self.0.check_chunk(ciphertext.len(), last)?;
+        if !same_tag(&self.0.tag(ciphertext, last), tag) {
+            return Err(BlobError::Authentication);
+        }
+        let mut result = ciphertext.to_vec();
+        self.0.apply_keystream(&mut result);
+        self.0.advance(last);
+        Ok(result)
+    }
+}
+
+/// Encrypt a whole attachment held in memory
+pub fn blob_encrypt(data: &[u8], password: &str, nonce: u64) -> Vec<u8> {
+    let mut encryptor = BlobEncryptor::new(password, nonce, DEFAULT_CHUNK_SIZE).unwrap();
+    let chunk_count = std::cmp::max(1, data.len().div_ceil(encryptor.chunk_size()));
+    let mut result = Vec::with_capacity(HEADER_SIZE + data.len() + chunk_count * TAG_SIZE);
+    result.extend_from_slice(&encryptor.header());
+    for i in 0..chunk_count {
+        let start = i * encryptor.chunk_size();
+        let end = std::cmp::min(start + encryptor.chunk_size(), data.len());
+        // chunks have the right size by construction
+        result.extend_from_slice(&encryptor.encrypt_chunk(&data[start..end], i == chunk_count - 1).unwrap());
+    }
+    result
+}
+
+/// Decrypt a whole attachment held in memory
+pub fn blob_decrypt(data: &[u8], password: &str) -> Result<Vec<u8>, BlobError> {
+    let mut decryptor = BlobDecryptor::new(password, data)?;
+    let chunks = &data[HEADER_SIZE..];
+    if chunks.len() < TAG_SIZE {
+        return Err(BlobError::ChunkLength);
+    }
+    let mut result = Vec::with_capacity(chunks.len());
+    let mut pieces = chunks.chunks(decryptor.encrypted_chunk_size()).peekable();
+    while let Some(piece) = pieces.next() {
+        result.extend_from_slice(&decryptor.decrypt_chunk(piece, pieces.peek().is_none())?);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn cmac_sp800_38b() {
+        let key: [u8; 32] = from_hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4")
+            .try_into()
+            .unwrap();
+        let cmac = Cmac::new(&key);
+        assert_eq!(cmac.tag(&[]).to_vec(), from_hex("028962f61b7bf89efc6b551f4667d983"));
+        assert_eq!(
+            cmac.tag(&from_hex("6bc1bee22e409f96e93d7e117393172a")).to_vec(),
+            from_hex("28a7023f452e8f82bd4bf28d8c37c35c")
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for size in [0, 1, 16, 1000, DEFAULT_CHUNK_SIZE as usize, 2 * DEFAULT_CHUNK_SIZE as usize + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let encrypted = blob_encrypt(&data, "secret", 42);
+            let chunk_count = std::cmp::max(1, size.div_ceil(DEFAULT_CHUNK_SIZE as usize));
+            assert_eq!(encrypted.len(), HEADER_SIZE + size + chunk_count * TAG_SIZE);
+            assert_eq!(blob_decrypt(&encrypted, "secret").unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let data = vec![7; 100];
+        let mut encrypted = blob_encrypt(&data, "secret", 42);
+        assert_eq!(blob_decrypt(&encrypted, "other"), Err(BlobError::Authentication));
+        encrypted[HEADER_SIZE + 3] ^= 1;
+        assert_eq!(blob_decrypt(&encrypted, "secret"), Err(BlobError::Authentication));
+        assert_eq!(blob_decrypt(b"not encrypted at all", "secret"), Err(BlobError::InvalidHeader));
+    }
+
+    #[test]
+    fn reordered_and_truncated_chunks_are_detected() {
+        let mut encryptor = BlobEncryptor::new("secret", 42, 32).unwrap();
+        let header = encryptor.header();
+        let first = encryptor.encrypt_chunk(&[1; 32], false).unwrap();
+        let second = encryptor.encrypt_chunk(&[2; 32], false).unwrap();
+        let third = encryptor.encrypt_chunk(&[3; 5], true).unwrap();
+        assert_eq!(encryptor.encrypt_chunk(&[4; 5], true), Err(BlobError::Finished));
+
+        let mut decryptor = BlobDecryptor::new("secret", &header).unwrap();
+        assert_eq!(decryptor.decrypt_chunk(&second, false), Err(BlobError::Authentication));
+        assert_eq!(decryptor.decrypt_chunk(&first, false).unwrap(), vec![1; 32]);
+        // dropping the end of the attachment
+        assert_eq!(decryptor.decrypt_chunk(&second, true), Err(BlobError::Authentication));
+        assert_eq!(decryptor.decrypt_chunk(&second, false).unwrap(), vec![2; 32]);
+        assert_eq!(decryptor.decrypt_chunk(&third, true).unwrap(), vec![3; 5]);
+    }
+
+    #[test]
+    fn chunk_size_is_checked() {
+        assert!(matches!(BlobEncryptor::new("secret", 42, 30), Err(BlobError::InvalidChunkSize)));
+        let mut encryptor = BlobEncryptor::new("secret", 42, 32).unwrap();
+        assert_eq!(encryptor.encrypt_chunk(&[1; 16], false), Err(BlobError::ChunkLength));
+    }
+}
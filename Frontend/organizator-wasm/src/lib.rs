@@ -3,6 +3,7 @@ mod markdown;
 mod barcode_svg;
 pub mod aes;
 pub mod memo;
+pub mod blob;
 mod merge;
 
 use aes::{aes_ctr_encrypt, aes_ctr_decrypt};
@@ -54,6 +55,64 @@ pub fn memo_encrypt(text: &str, password: &str, nonce: f64) -> String {
     }
 }
 
+#[wasm_bindgen]
+pub fn blob_encrypt(data: &[u8], password: &str, nonce: f64) -> Vec<u8> {
+    blob::blob_encrypt(data, password, nonce as u64)
+}
+
+#[wasm_bindgen]
+pub fn blob_decrypt(data: &[u8], password: &str) -> Result<Vec<u8>, JsValue> {
+    blob::blob_decrypt(data, password).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Encrypts an attachment a chunk at a time, for files too large to hold in memory
+#[wasm_bindgen]
+pub struct BlobEncryptor(blob::BlobEncryptor);
+
+#[wasm_bindgen]
+impl BlobEncryptor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(password: &str, nonce: f64) -> BlobEncryptor {
+        BlobEncryptor(blob::BlobEncryptor::new(password, nonce as u64, blob::DEFAULT_CHUNK_SIZE).unwrap())
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        self.0.header()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn chunk_size(&self) -> usize {
+        self.0.chunk_size()
+    }
+
+    pub fn encrypt_chunk(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>, JsValue> {
+        self.0.encrypt_chunk(data, last).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
+
+/// Decrypts an attachment a chunk at a time, each chunk is authenticated before it is returned
+#[wasm_bindgen]
+pub struct BlobDecryptor(blob::BlobDecryptor);
+
+#[wasm_bindgen]
+impl BlobDecryptor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(password: &str, header: &[u8]) -> Result<BlobDecryptor, JsValue> {
+        blob::BlobDecryptor::new(password, header)
+            .map(BlobDecryptor)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn encrypted_chunk_size(&self) -> usize {
+        self.0.encrypted_chunk_size()
+    }
+
+    pub fn decrypt_chunk(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>, JsValue> {
+        self.0.decrypt_chunk(data, last).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
+
 #[wasm_bindgen]
 pub fn truncate_base64(text: &str, base_64_limit: usize) -> String {
     memo::truncate_base64(text, base_64_limit)